@@ -1,10 +1,12 @@
 use core::arch::x86_64::*;
+use memmap2::Mmap;
 use merand48::*;
 use std::any::Any;
 use std::error::Error;
 use std::f32::consts::PI;
 use std::io;
 use std::mem::{self, MaybeUninit};
+use std::sync::Arc;
 
 use crate::block_helpers;
 use crate::consts;
@@ -16,9 +18,10 @@ use crate::optimizer;
 use crate::port_buffer;
 use crate::regressor;
 
-use block_helpers::WeightAndOptimizerData;
+use block_helpers::{InferenceWeights, WeightAndOptimizerData, WeightsReader, WeightsSparse};
+use model_instance::WeightsQuantization;
 use optimizer::OptimizerTrait;
-use regressor::BlockTrait;
+use regressor::{BlockTrait, PrunedWeights};
 
 const FFM_STACK_BUF_LEN: usize = 32768;
 const FFM_CONTRA_BUF_LEN: usize = 16384;
@@ -34,6 +37,10 @@ pub struct BlockFFM<L: OptimizerTrait> {
     pub field_embedding_len: u32,
     pub weights: Vec<WeightAndOptimizerData<L>>,
     pub output_offset: usize,
+    pub weights_quantization: WeightsQuantization,
+    pub weights_sparse: bool,
+    // Only set on immutable regressors with quantized or memory-mapped weights, replaces weights
+    pub inference_weights: Option<InferenceWeights>,
 }
 
 macro_rules! specialize_1f32 {
@@ -73,6 +80,180 @@ impl<L: OptimizerTrait + 'static> BlockFFM<L> {
             self.weights[i as usize].optimizer_data = self.optimizer_ffm.initial_data();
         }
     }
+
+    // Drops chunks of weights that are still at their initial values, i.e. rows of
+    // features never seen in training. Chunks are as long as FFM hashes are aligned
+    fn prune_with_weights<W: WeightsReader + ?Sized>(
+        &self,
+        weights: &W,
+        initial_weights: &Vec<WeightAndOptimizerData<optimizer::OptimizerSGD>>,
+    ) -> WeightsSparse {
+        let mut chunk_bits = 0;
+        while self.ffm_k > (1 << chunk_bits) {
+            chunk_bits += 1;
+        }
+        WeightsSparse::from_weights(
+            weights,
+            self.ffm_weights_len as usize,
+            chunk_bits,
+            &|start, end| {
+                (start..end).any(|i| unsafe { weights.get_weight(i) } != initial_weights[i].weight)
+            },
+        )
+    }
+
+    #[inline(always)]
+    fn forward_with_weights<W: WeightsReader + ?Sized>(
+        &self,
+        ffm_weights: &W,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
+        let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+        myslice.fill(0.0);
+
+        unsafe {
+            if true {
+                _mm_prefetch(
+                    ffm_weights.get_weight_ptr(fb.ffm_buffer.get_unchecked(0).hash as usize),
+                    _MM_HINT_T0,
+                );
+                let field_embedding_len = self.field_embedding_len as usize;
+                let mut contra_fields: [f32; FFM_STACK_BUF_LEN] =
+                    MaybeUninit::uninit().assume_init();
+
+                specialize_k!(self.ffm_k, FFMK, wsumbuf, {
+                    /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
+                       We need to be careful to:
+                       - handle fields with zero features present
+                       - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
+                       - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
+                       - optiize for very common case of value of the feature being 1.0 - avoid multiplications
+                       -
+                    */
+
+                    let mut ffm_buffer_index = 0;
+                    for field_index in 0..fb.ffm_fields_count {
+                        let field_index_ffmk = field_index * FFMK;
+                        let offset = (field_index_ffmk * fb.ffm_fields_count) as usize;
+                        // first we handle fields with no features
+                        if ffm_buffer_index >= fb.ffm_buffer.len()
+                            || fb
+                                .ffm_buffer
+                                .get_unchecked(ffm_buffer_index)
+                                .contra_field_index
+                                > field_index_ffmk
+                        {
+                            for z in 0..field_embedding_len as usize {
+                                // first time we see this field - just overwrite
+                                *contra_fields.get_unchecked_mut(offset + z) = 0.0;
+                            }
+                            continue;
+                        }
+                        let mut feature_num = 0;
+                        while ffm_buffer_index < fb.ffm_buffer.len()
+                            && fb
+                                .ffm_buffer
+                                .get_unchecked(ffm_buffer_index)
+                                .contra_field_index
+                                == field_index_ffmk
+                        {
+                            _mm_prefetch(
+                                ffm_weights.get_weight_ptr(
+                                    fb.ffm_buffer.get_unchecked(ffm_buffer_index + 1).hash as usize,
+                                ),
+                                _MM_HINT_T0,
+                            );
+                            let left_hash = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
+                            let left_hash_hash = left_hash.hash as usize;
+                            let left_hash_value = left_hash.value;
+                            let contra_offset2 = left_hash.contra_field_index / FFMK;
+                            let field_embedding_len2 = field_embedding_len / FFMK as usize;
+                            specialize_1f32!(left_hash_value, LEFT_HASH_VALUE, {
+                                if feature_num == 0 {
+                                    for z in 0..field_embedding_len {
+                                        // first feature of the field - just overwrite
+                                        *contra_fields.get_unchecked_mut(offset + z) = ffm_weights
+                                            .get_weight(left_hash_hash + z)
+                                            * LEFT_HASH_VALUE;
+                                    }
+                                } else {
+                                    for z in 0..field_embedding_len {
+                                        // additional features of the field - addition
+                                        *contra_fields.get_unchecked_mut(offset + z) += ffm_weights
+                                            .get_weight(left_hash_hash + z)
+                                            * LEFT_HASH_VALUE;
+                                    }
+                                }
+                                let vv = SQRT_OF_ONE_HALF * LEFT_HASH_VALUE; // To avoid one additional multiplication, we square root 0.5 into vv
+                                for k in 0..FFMK as usize {
+                                    let ss = ffm_weights
+                                        .get_weight(left_hash_hash + field_index_ffmk as usize + k)
+                                        * vv;
+                                    myslice
+                                        [(contra_offset2 * (fb.ffm_fields_count + 1)) as usize] -=
+                                        ss * ss;
+                                }
+                            });
+                            ffm_buffer_index += 1;
+                            feature_num += 1;
+                        }
+                    }
+
+                    for f1 in 0..fb.ffm_fields_count as usize {
+                        let f1_offset = f1 * field_embedding_len as usize;
+                        let f1_offset2 = f1 * fb.ffm_fields_count as usize;
+                        let f1_ffmk = f1 * FFMK as usize;
+                        let mut f2_offset_ffmk = f1_offset + f1_ffmk;
+                        let mut f1_offset_ffmk = f1_offset + f1_ffmk;
+                        // This is self-interaction
+                        for k in 0..FFMK as usize {
+                            let v = contra_fields.get_unchecked(f1_offset_ffmk + k);
+                            myslice[f1_offset2 + f1] += v * v * 0.5;
+                        }
+
+                        for f2 in f1 + 1..fb.ffm_fields_count as usize {
+                            f2_offset_ffmk += field_embedding_len as usize;
+                            f1_offset_ffmk += FFMK as usize;
+                            for k in 0..FFMK {
+                                myslice[f1 * fb.ffm_fields_count as usize + f2] += contra_fields
+                                    .get_unchecked(f1_offset_ffmk + k as usize)
+                                    * contra_fields.get_unchecked(f2_offset_ffmk + k as usize)
+                                    * 0.5;
+                                myslice[f2 * fb.ffm_fields_count as usize + f1] += contra_fields
+                                    .get_unchecked(f1_offset_ffmk + k as usize)
+                                    * contra_fields.get_unchecked(f2_offset_ffmk + k as usize)
+                                    * 0.5;
+                            }
+                        }
+                    }
+                });
+            } else {
+                // Old straight-forward method. As soon as we have multiple feature values per field, it is slower
+                specialize_k!(self.ffm_k, FFMK, wsumbuf, {
+                    for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
+                        for right_hash in fb.ffm_buffer.get_unchecked(i + 1..).iter() {
+                            //if left_hash.contra_field_index == right_hash.contra_field_index {
+                            //    continue	// not combining within a field
+                            //}
+                            let joint_value = left_hash.value * right_hash.value;
+                            let lindex = (left_hash.hash + right_hash.contra_field_index) as u32;
+                            let rindex = (right_hash.hash + left_hash.contra_field_index) as u32;
+                            for k in 0..FFMK {
+                                let left_hash_weight =
+                                    ffm_weights.get_weight((lindex + k) as usize);
+                                let right_hash_weight =
+                                    ffm_weights.get_weight((rindex + k) as usize);
+                                *wsumbuf.get_unchecked_mut(k as usize) +=
+                                    left_hash_weight * right_hash_weight * joint_value;
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    }
 }
 
 pub fn new_ffm_block(
@@ -109,6 +290,9 @@ fn new_ffm_block_without_weights<L: OptimizerTrait + 'static>(
         field_embedding_len: mi.ffm_k * ffm_num_fields,
         optimizer_ffm: L::new(),
         output_offset: usize::MAX,
+        weights_quantization: mi.weights_quantization,
+        weights_sparse: mi.weights_sparse,
+        inference_weights: None,
     };
 
     if mi.ffm_k > 0 {
@@ -117,9 +301,8 @@ fn new_ffm_block_without_weights<L: OptimizerTrait + 'static>(
             mi.ffm_power_t,
             mi.ffm_init_acc_gradient,
         );
-        // At the end we add "spillover buffer", so we can do modulo only on the base address and add offset
-        reg_ffm.ffm_weights_len =
-            (1 << mi.ffm_bit_precision) + (mi.ffm_fields.len() as u32 * reg_ffm.ffm_k);
+        // At the end of each hash space we add "spillover buffer", so we can do modulo only on the base address and add offset
+        reg_ffm.ffm_weights_len = mi.ffm_hash_spaces().1 as u32;
     }
 
     // Verify that forward pass will have enough stack for temporary buffer
@@ -137,6 +320,10 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
     }
 
     fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
+        if mi.has_inference_weights_layout() {
+            // Weights get loaded directly into inference_weights
+            return;
+        }
         self.weights = vec![
             WeightAndOptimizerData::<L> {
                 weight: 0.0,
@@ -443,163 +630,12 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
     ) {
         debug_assert!(self.output_offset != usize::MAX);
 
-        let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
-        let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
-        myslice.fill(0.0);
-
-        unsafe {
-            let ffm_weights = &self.weights;
-            if true {
-                _mm_prefetch(
-                    mem::transmute::<&f32, &i8>(
-                        &ffm_weights
-                            .get_unchecked(fb.ffm_buffer.get_unchecked(0).hash as usize)
-                            .weight,
-                    ),
-                    _MM_HINT_T0,
-                );
-                let field_embedding_len = self.field_embedding_len as usize;
-                let mut contra_fields: [f32; FFM_STACK_BUF_LEN] =
-                    MaybeUninit::uninit().assume_init();
-
-                specialize_k!(self.ffm_k, FFMK, wsumbuf, {
-                    /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
-                       We need to be careful to:
-                       - handle fields with zero features present
-                       - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
-                       - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
-                       - optiize for very common case of value of the feature being 1.0 - avoid multiplications
-                       -
-                    */
-
-                    let mut ffm_buffer_index = 0;
-                    for field_index in 0..fb.ffm_fields_count {
-                        let field_index_ffmk = field_index * FFMK;
-                        let offset = (field_index_ffmk * fb.ffm_fields_count) as usize;
-                        // first we handle fields with no features
-                        if ffm_buffer_index >= fb.ffm_buffer.len()
-                            || fb
-                                .ffm_buffer
-                                .get_unchecked(ffm_buffer_index)
-                                .contra_field_index
-                                > field_index_ffmk
-                        {
-                            for z in 0..field_embedding_len as usize {
-                                // first time we see this field - just overwrite
-                                *contra_fields.get_unchecked_mut(offset + z) = 0.0;
-                            }
-                            continue;
-                        }
-                        let mut feature_num = 0;
-                        while ffm_buffer_index < fb.ffm_buffer.len()
-                            && fb
-                                .ffm_buffer
-                                .get_unchecked(ffm_buffer_index)
-                                .contra_field_index
-                                == field_index_ffmk
-                        {
-                            _mm_prefetch(
-                                mem::transmute::<&f32, &i8>(
-                                    &ffm_weights
-                                        .get_unchecked(
-                                            fb.ffm_buffer.get_unchecked(ffm_buffer_index + 1).hash
-                                                as usize,
-                                        )
-                                        .weight,
-                                ),
-                                _MM_HINT_T0,
-                            );
-                            let left_hash = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
-                            let left_hash_hash = left_hash.hash as usize;
-                            let left_hash_value = left_hash.value;
-                            let contra_offset2 = left_hash.contra_field_index / FFMK;
-                            let field_embedding_len2 = field_embedding_len / FFMK as usize;
-                            specialize_1f32!(left_hash_value, LEFT_HASH_VALUE, {
-                                if feature_num == 0 {
-                                    for z in 0..field_embedding_len {
-                                        // first feature of the field - just overwrite
-                                        *contra_fields.get_unchecked_mut(offset + z) =
-                                            ffm_weights.get_unchecked(left_hash_hash + z).weight
-                                                * LEFT_HASH_VALUE;
-                                    }
-                                } else {
-                                    for z in 0..field_embedding_len {
-                                        // additional features of the field - addition
-                                        *contra_fields.get_unchecked_mut(offset + z) +=
-                                            ffm_weights.get_unchecked(left_hash_hash + z).weight
-                                                * LEFT_HASH_VALUE;
-                                    }
-                                }
-                                let vv = SQRT_OF_ONE_HALF * LEFT_HASH_VALUE; // To avoid one additional multiplication, we square root 0.5 into vv
-                                for k in 0..FFMK as usize {
-                                    let ss = ffm_weights
-                                        .get_unchecked(
-                                            left_hash_hash + field_index_ffmk as usize + k,
-                                        )
-                                        .weight
-                                        * vv;
-                                    myslice
-                                        [(contra_offset2 * (fb.ffm_fields_count + 1)) as usize] -=
-                                        ss * ss;
-                                }
-                            });
-                            ffm_buffer_index += 1;
-                            feature_num += 1;
-                        }
-                    }
-
-                    for f1 in 0..fb.ffm_fields_count as usize {
-                        let f1_offset = f1 * field_embedding_len as usize;
-                        let f1_offset2 = f1 * fb.ffm_fields_count as usize;
-                        let f1_ffmk = f1 * FFMK as usize;
-                        let mut f2_offset_ffmk = f1_offset + f1_ffmk;
-                        let mut f1_offset_ffmk = f1_offset + f1_ffmk;
-                        // This is self-interaction
-                        for k in 0..FFMK as usize {
-                            let v = contra_fields.get_unchecked(f1_offset_ffmk + k);
-                            myslice[f1_offset2 + f1] += v * v * 0.5;
-                        }
-
-                        for f2 in f1 + 1..fb.ffm_fields_count as usize {
-                            f2_offset_ffmk += field_embedding_len as usize;
-                            f1_offset_ffmk += FFMK as usize;
-                            for k in 0..FFMK {
-                                myslice[f1 * fb.ffm_fields_count as usize + f2] += contra_fields
-                                    .get_unchecked(f1_offset_ffmk + k as usize)
-                                    * contra_fields.get_unchecked(f2_offset_ffmk + k as usize)
-                                    * 0.5;
-                                myslice[f2 * fb.ffm_fields_count as usize + f1] += contra_fields
-                                    .get_unchecked(f1_offset_ffmk + k as usize)
-                                    * contra_fields.get_unchecked(f2_offset_ffmk + k as usize)
-                                    * 0.5;
-                            }
-                        }
-                    }
-                });
-            } else {
-                // Old straight-forward method. As soon as we have multiple feature values per field, it is slower
-                let ffm_weights = &self.weights;
-                specialize_k!(self.ffm_k, FFMK, wsumbuf, {
-                    for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
-                        for right_hash in fb.ffm_buffer.get_unchecked(i + 1..).iter() {
-                            //if left_hash.contra_field_index == right_hash.contra_field_index {
-                            //    continue	// not combining within a field
-                            //}
-                            let joint_value = left_hash.value * right_hash.value;
-                            let lindex = (left_hash.hash + right_hash.contra_field_index) as u32;
-                            let rindex = (right_hash.hash + left_hash.contra_field_index) as u32;
-                            for k in 0..FFMK {
-                                let left_hash_weight =
-                                    ffm_weights.get_unchecked((lindex + k) as usize).weight;
-                                let right_hash_weight =
-                                    ffm_weights.get_unchecked((rindex + k) as usize).weight;
-                                *wsumbuf.get_unchecked_mut(k as usize) +=
-                                    left_hash_weight * right_hash_weight * joint_value;
-                            }
-                        }
-                    }
-                });
-            }
+        match &self.inference_weights {
+            None => self.forward_with_weights(&self.weights[..], fb, pb),
+            Some(InferenceWeights::F32(w)) => self.forward_with_weights(w, fb, pb),
+            Some(InferenceWeights::F16(w)) => self.forward_with_weights(w, fb, pb),
+            Some(InferenceWeights::Int8(w)) => self.forward_with_weights(w, fb, pb),
+            Some(InferenceWeights::Sparse(w)) => self.forward_with_weights(w, fb, pb),
         }
         block_helpers::forward(further_blocks, fb, pb);
     }
@@ -619,7 +655,10 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
         &self,
         output_bufwriter: &mut dyn io::Write,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)
+        match &self.inference_weights {
+            None => block_helpers::write_weights_to_buf(&self.weights, output_bufwriter),
+            Some(inference_weights) => inference_weights.write_to_buf(output_bufwriter),
+        }
     }
 
     fn read_weights_from_buf_into_forward_only(
@@ -631,6 +670,20 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
             .as_any()
             .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
             .unwrap();
+        if self.weights_sparse {
+            forward.inference_weights = Some(InferenceWeights::Sparse(
+                WeightsSparse::new_from_buf(self.ffm_weights_len as usize, input_bufreader)?,
+            ));
+            return Ok(());
+        }
+        if self.weights_quantization != WeightsQuantization::None {
+            forward.inference_weights = Some(InferenceWeights::new_from_buf(
+                self.ffm_weights_len as usize,
+                self.weights_quantization,
+                input_bufreader,
+            )?);
+            return Ok(());
+        }
         block_helpers::read_weights_only_from_buf2::<L>(
             self.ffm_weights_len as usize,
             &mut forward.weights,
@@ -638,6 +691,92 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
         )
     }
 
+    fn scale_weights(&mut self, scale: f32) {
+        block_helpers::scale_weights(&mut self.weights, scale);
+    }
+
+    fn add_weights_from(
+        &mut self,
+        other: &mut Box<dyn BlockTrait>,
+        scale: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = match other.as_any().downcast_mut::<BlockFFM<L>>() {
+            Some(other) => other,
+            None => return Err("Cannot merge weights of different kinds of blocks")?,
+        };
+        block_helpers::add_weights_from(
+            &mut self.weights,
+            &other.weights,
+            scale,
+            &self.optimizer_ffm,
+        );
+        Ok(())
+    }
+
+    fn read_weights_from_mmap_into_forward_only(
+        &self,
+        mmap: &Arc<Mmap>,
+        offset: &mut usize,
+        forward: &mut Box<dyn BlockTrait>,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+        if self.weights_sparse {
+            forward.inference_weights = Some(InferenceWeights::Sparse(
+                WeightsSparse::new_from_mmap(self.ffm_weights_len as usize, mmap, offset)?,
+            ));
+            return Ok(());
+        }
+        forward.inference_weights = Some(InferenceWeights::new_from_mmap(
+            self.ffm_weights_len as usize,
+            self.weights_quantization,
+            mmap,
+            offset,
+        )?);
+        Ok(())
+    }
+
+    fn prune_weights(
+        &mut self,
+        initial: &mut Box<dyn BlockTrait>,
+        _lr_threshold: f32,
+    ) -> Option<PrunedWeights> {
+        let initial = initial
+            .as_any()
+            .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+        let sparse = match &self.inference_weights {
+            None => self.prune_with_weights(&self.weights[..], &initial.weights),
+            Some(w) => self.prune_with_weights(w, &initial.weights),
+        };
+        let pruned = PrunedWeights {
+            block_name: "FFM",
+            kept: sparse.num_values(),
+            total: self.ffm_weights_len as usize,
+        };
+        self.inference_weights = Some(InferenceWeights::Sparse(sparse));
+        self.weights = Vec::new();
+        self.weights_sparse = true;
+        Some(pruned)
+    }
+
+    fn quantize_weights(&mut self, quantization: WeightsQuantization) {
+        self.inference_weights = Some(match &self.inference_weights {
+            None => InferenceWeights::from_weights(
+                &self.weights[..],
+                self.ffm_weights_len as usize,
+                quantization,
+            ),
+            Some(w) => {
+                InferenceWeights::from_weights(w, self.ffm_weights_len as usize, quantization)
+            }
+        });
+        self.weights = Vec::new();
+        self.weights_quantization = quantization;
+    }
+
     /// Sets internal state of weights based on some completely object-dependent parameters
     fn testing_set_weights(
         &mut self,
@@ -672,6 +811,8 @@ mod tests {
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: Vec::new(),
             ffm_buffer: v,
             ffm_fields_count: ffm_fields_count,
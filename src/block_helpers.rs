@@ -5,12 +5,16 @@ use std::io::Read;
 
 use crate::feature_buffer;
 use crate::graph;
+use crate::model_instance::WeightsQuantization;
 use crate::optimizer::OptimizerSGD;
 use crate::port_buffer;
 use crate::regressor::BlockTrait;
+use half::f16;
+use memmap2::Mmap;
 use std::cmp::min;
 use std::mem::{self};
 use std::slice;
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
 #[repr(C)]
@@ -31,6 +35,520 @@ pub struct WeightAndOptimizerData<L: OptimizerTrait> {
     pub optimizer_data: L::PerWeightStore,
 }
 
+// Int8 quantization stores one scale per this many consecutive weights
+pub const QUANTIZATION_INT8_BLOCK_LEN: usize = 64;
+
+// Forward passes are generic over where the weights come from, so immutable blocks
+// can compute directly from the quantized representation
+pub trait WeightsReader {
+    unsafe fn get_weight(&self, index: usize) -> f32;
+    // Used for prefetching only
+    unsafe fn get_weight_ptr(&self, index: usize) -> *const i8;
+}
+
+impl<L: OptimizerTrait> WeightsReader for [WeightAndOptimizerData<L>] {
+    #[inline(always)]
+    unsafe fn get_weight(&self, index: usize) -> f32 {
+        self.get_unchecked(index).weight
+    }
+    #[inline(always)]
+    unsafe fn get_weight_ptr(&self, index: usize) -> *const i8 {
+        mem::transmute::<&f32, &i8>(&self.get_unchecked(index).weight)
+    }
+}
+
+impl WeightsReader for [Weight] {
+    #[inline(always)]
+    unsafe fn get_weight(&self, index: usize) -> f32 {
+        self.get_unchecked(index).weight
+    }
+    #[inline(always)]
+    unsafe fn get_weight_ptr(&self, index: usize) -> *const i8 {
+        mem::transmute::<&f32, &i8>(&self.get_unchecked(index).weight)
+    }
+}
+
+// Read-only array of weights, either owned or pointing into a memory-mapped regressor file
+pub struct WeightsBuffer<T> {
+    ptr: *const T,
+    len: usize,
+    _owner: WeightsBufferOwner<T>,
+}
+
+// Keeps the memory that WeightsBuffer points to alive, it is only held and never read
+#[allow(dead_code)]
+enum WeightsBufferOwner<T> {
+    Vec(Vec<T>),
+    Mmap(Arc<Mmap>),
+}
+
+impl<T> WeightsBuffer<T> {
+    pub fn from_vec(values: Vec<T>) -> WeightsBuffer<T> {
+        WeightsBuffer {
+            ptr: values.as_ptr(),
+            len: values.len(),
+            _owner: WeightsBufferOwner::Vec(values),
+        }
+    }
+
+    // Points into the mapping at offset and moves offset past the array
+    pub fn from_mmap(
+        mmap: &Arc<Mmap>,
+        offset: &mut usize,
+        len: usize,
+    ) -> Result<WeightsBuffer<T>, Box<dyn Error>> {
+        let end = *offset + len * mem::size_of::<T>();
+        if end > mmap.len() {
+            return Err(format!(
+                "Regressor file is too short: weights end at {}, file length is {}",
+                end,
+                mmap.len()
+            ))?;
+        }
+        if *offset % mem::align_of::<T>() != 0 {
+            return Err(format!("Weights at offset {} are not aligned", *offset))?;
+        }
+        let ptr = unsafe { mmap.as_ptr().add(*offset) as *const T };
+        *offset = end;
+        Ok(WeightsBuffer {
+            ptr: ptr,
+            len: len,
+            _owner: WeightsBufferOwner::Mmap(mmap.clone()),
+        })
+    }
+
+    #[inline(always)]
+    pub unsafe fn get_unchecked(&self, index: usize) -> &T {
+        &*self.ptr.add(index)
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+pub struct WeightsF32 {
+    values: WeightsBuffer<f32>,
+}
+
+impl WeightsReader for WeightsF32 {
+    #[inline(always)]
+    unsafe fn get_weight(&self, index: usize) -> f32 {
+        *self.values.get_unchecked(index)
+    }
+    #[inline(always)]
+    unsafe fn get_weight_ptr(&self, index: usize) -> *const i8 {
+        mem::transmute::<&f32, &i8>(self.values.get_unchecked(index))
+    }
+}
+
+pub struct WeightsF16 {
+    values: WeightsBuffer<u16>,
+}
+
+impl WeightsReader for WeightsF16 {
+    #[inline(always)]
+    unsafe fn get_weight(&self, index: usize) -> f32 {
+        f16::from_bits(*self.values.get_unchecked(index)).to_f32()
+    }
+    #[inline(always)]
+    unsafe fn get_weight_ptr(&self, index: usize) -> *const i8 {
+        mem::transmute::<&u16, &i8>(self.values.get_unchecked(index))
+    }
+}
+
+pub struct WeightsInt8 {
+    values: WeightsBuffer<i8>,
+    scales: WeightsBuffer<f32>,
+}
+
+impl WeightsReader for WeightsInt8 {
+    #[inline(always)]
+    unsafe fn get_weight(&self, index: usize) -> f32 {
+        *self.values.get_unchecked(index) as f32
+            * *self
+                .scales
+                .get_unchecked(index / QUANTIZATION_INT8_BLOCK_LEN)
+    }
+    #[inline(always)]
+    unsafe fn get_weight_ptr(&self, index: usize) -> *const i8 {
+        self.values.get_unchecked(index)
+    }
+}
+
+const SPARSE_EMPTY_SLOT: u32 = u32::MAX;
+
+// Pruned weights: only chunks of (1 << chunk_bits) consecutive weights that were kept are stored,
+// in an open-addressing hash table keyed by chunk index. Weights of dropped chunks read as zero.
+pub struct WeightsSparse {
+    chunk_bits: u32,
+    table_bits: u32,
+    // Chunk index stored in each slot, or SPARSE_EMPTY_SLOT
+    slot_chunks: WeightsBuffer<u32>,
+    // Where in values weights of the chunk in each slot start
+    slot_offsets: WeightsBuffer<u32>,
+    values: WeightsBuffer<f32>,
+}
+
+#[inline(always)]
+fn sparse_first_slot(chunk: u32, table_bits: u32) -> usize {
+    // Fibonacci hashing, takes the upper bits of the product
+    (chunk.wrapping_mul(0x9E3779B1) >> (32 - table_bits)) as usize
+}
+
+impl WeightsSparse {
+    #[inline(always)]
+    unsafe fn find_slot(&self, chunk: u32) -> usize {
+        let table_mask = (1 << self.table_bits) - 1;
+        let mut slot = sparse_first_slot(chunk, self.table_bits);
+        loop {
+            let slot_chunk = *self.slot_chunks.get_unchecked(slot);
+            if slot_chunk == chunk || slot_chunk == SPARSE_EMPTY_SLOT {
+                return slot;
+            }
+            slot = (slot + 1) & table_mask;
+        }
+    }
+
+    pub fn from_weights<W: WeightsReader + ?Sized>(
+        weights: &W,
+        weights_len: usize,
+        chunk_bits: u32,
+        keep_chunk: &dyn Fn(usize, usize) -> bool,
+    ) -> WeightsSparse {
+        let chunk_len = 1 << chunk_bits;
+        let kept_chunks: Vec<u32> = (0..weights_len)
+            .step_by(chunk_len)
+            .filter(|start| keep_chunk(*start, min(start + chunk_len, weights_len)))
+            .map(|start| (start >> chunk_bits) as u32)
+            .collect();
+        // Keep the load factor at or below one half
+        let mut table_bits = 1;
+        while (1 << table_bits) < kept_chunks.len() * 2 {
+            table_bits += 1;
+        }
+        let mut slot_chunks: Vec<u32> = vec![SPARSE_EMPTY_SLOT; 1 << table_bits];
+        let mut slot_offsets: Vec<u32> = vec![0; 1 << table_bits];
+        let mut values: Vec<f32> = Vec::with_capacity(kept_chunks.len() * chunk_len);
+        for chunk in kept_chunks {
+            let table_mask = (1 << table_bits) - 1;
+            let mut slot = sparse_first_slot(chunk, table_bits);
+            while slot_chunks[slot] != SPARSE_EMPTY_SLOT {
+                slot = (slot + 1) & table_mask;
+            }
+            slot_chunks[slot] = chunk;
+            slot_offsets[slot] = values.len() as u32;
+            let start = (chunk as usize) << chunk_bits;
+            for i in start..start + chunk_len {
+                // Last chunk can be shorter, we pad it so lookups never need to check
+                values.push(if i < weights_len {
+                    unsafe { weights.get_weight(i) }
+                } else {
+                    0.0
+                });
+            }
+        }
+        WeightsSparse {
+            chunk_bits: chunk_bits,
+            table_bits: table_bits,
+            slot_chunks: WeightsBuffer::from_vec(slot_chunks),
+            slot_offsets: WeightsBuffer::from_vec(slot_offsets),
+            values: WeightsBuffer::from_vec(values),
+        }
+    }
+
+    pub fn num_values(&self) -> usize {
+        self.values.len
+    }
+
+    // Header comes from the regressor file, so it is checked before anything is allocated or mapped.
+    // chunk_bits can be above table_bits, e.g. when only one chunk of ffm_k weights was kept
+    fn check_header(header: &[u32], weights_len: usize) -> Result<(), Box<dyn Error>> {
+        let (chunk_bits, table_bits, num_values) = (header[0], header[1], header[2] as usize);
+        if chunk_bits >= 32 || table_bits == 0 || table_bits >= 32 {
+            return Err(format!(
+                "Pruned weights are corrupt: chunk bits {}, table bits {}",
+                chunk_bits, table_bits
+            ))?;
+        }
+        // At most every chunk is kept, and the table is at most four times as big as the kept chunks
+        let chunk_len = 1usize << chunk_bits;
+        let num_chunks = (weights_len + chunk_len - 1) / chunk_len;
+        if (1usize << table_bits) > (4 * num_chunks).max(2)
+            || num_values % chunk_len != 0
+            || num_values > num_chunks * chunk_len
+        {
+            return Err(format!(
+                "Pruned weights are corrupt: {} values in a table of {} slots, chunk bits {}, for {} weights",
+                num_values,
+                1usize << table_bits,
+                chunk_bits,
+                weights_len
+            ))?;
+        }
+        Ok(())
+    }
+
+    // Lookups assume that every stored chunk lies within values and that the table has an empty slot
+    fn check_slots(&self, weights_len: usize) -> Result<(), Box<dyn Error>> {
+        let chunk_len = 1usize << self.chunk_bits;
+        let num_chunks = (weights_len + chunk_len - 1) / chunk_len;
+        let mut has_empty_slot = false;
+        for (chunk, offset) in self
+            .slot_chunks
+            .as_slice()
+            .iter()
+            .zip(self.slot_offsets.as_slice().iter())
+        {
+            if *chunk == SPARSE_EMPTY_SLOT {
+                has_empty_slot = true;
+            } else if *chunk as usize >= num_chunks
+                || *offset as usize + chunk_len > self.values.len
+            {
+                return Err(format!(
+                    "Pruned weights are corrupt: chunk {} at value {} is out of range",
+                    chunk, offset
+                ))?;
+            }
+        }
+        if !has_empty_slot {
+            return Err("Pruned weights are corrupt: table has no empty slot")?;
+        }
+        Ok(())
+    }
+
+    // Layout: [chunk_bits, table_bits, number of values] as u32, slot chunks, slot offsets, values
+    pub fn new_from_buf(
+        weights_len: usize,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<WeightsSparse, Box<dyn Error>> {
+        let mut header: Vec<u32> = vec![0; 3];
+        read_weights_from_buf(&mut header, input_bufreader)?;
+        WeightsSparse::check_header(&header, weights_len)?;
+        let mut slot_chunks: Vec<u32> = vec![0; 1 << header[1]];
+        read_weights_from_buf(&mut slot_chunks, input_bufreader)?;
+        let mut slot_offsets: Vec<u32> = vec![0; 1 << header[1]];
+        read_weights_from_buf(&mut slot_offsets, input_bufreader)?;
+        let mut values: Vec<f32> = vec![0.0; header[2] as usize];
+        if header[2] > 0 {
+            read_weights_from_buf(&mut values, input_bufreader)?;
+        }
+        let sparse = WeightsSparse {
+            chunk_bits: header[0],
+            table_bits: header[1],
+            slot_chunks: WeightsBuffer::from_vec(slot_chunks),
+            slot_offsets: WeightsBuffer::from_vec(slot_offsets),
+            values: WeightsBuffer::from_vec(values),
+        };
+        sparse.check_slots(weights_len)?;
+        Ok(sparse)
+    }
+
+    pub fn new_from_mmap(
+        weights_len: usize,
+        mmap: &Arc<Mmap>,
+        offset: &mut usize,
+    ) -> Result<WeightsSparse, Box<dyn Error>> {
+        let header: WeightsBuffer<u32> = WeightsBuffer::from_mmap(mmap, offset, 3)?;
+        let header = header.as_slice();
+        WeightsSparse::check_header(header, weights_len)?;
+        let sparse = WeightsSparse {
+            chunk_bits: header[0],
+            table_bits: header[1],
+            slot_chunks: WeightsBuffer::from_mmap(mmap, offset, 1 << header[1])?,
+            slot_offsets: WeightsBuffer::from_mmap(mmap, offset, 1 << header[1])?,
+            values: WeightsBuffer::from_mmap(mmap, offset, header[2] as usize)?,
+        };
+        sparse.check_slots(weights_len)?;
+        Ok(sparse)
+    }
+
+    pub fn write_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+        let header: Vec<u32> = vec![self.chunk_bits, self.table_bits, self.values.len as u32];
+        write_weights_to_buf(&header, output_bufwriter)?;
+        write_weights_to_buf(self.slot_chunks.as_slice(), output_bufwriter)?;
+        write_weights_to_buf(self.slot_offsets.as_slice(), output_bufwriter)?;
+        if self.values.len > 0 {
+            write_weights_to_buf(self.values.as_slice(), output_bufwriter)?;
+        }
+        Ok(())
+    }
+}
+
+impl WeightsReader for WeightsSparse {
+    #[inline(always)]
+    unsafe fn get_weight(&self, index: usize) -> f32 {
+        let slot = self.find_slot((index >> self.chunk_bits) as u32);
+        if *self.slot_chunks.get_unchecked(slot) == SPARSE_EMPTY_SLOT {
+            return 0.0;
+        }
+        let chunk_mask = (1 << self.chunk_bits) - 1;
+        *self
+            .values
+            .get_unchecked(*self.slot_offsets.get_unchecked(slot) as usize + (index & chunk_mask))
+    }
+    #[inline(always)]
+    unsafe fn get_weight_ptr(&self, index: usize) -> *const i8 {
+        let slot = sparse_first_slot((index >> self.chunk_bits) as u32, self.table_bits);
+        mem::transmute::<&u32, &i8>(self.slot_chunks.get_unchecked(slot))
+    }
+}
+
+// Weights of immutable blocks that are not kept in the regular (trainable) layout:
+// quantized, pruned, memory-mapped, or a combination
+pub enum InferenceWeights {
+    F32(WeightsF32),
+    F16(WeightsF16),
+    Int8(WeightsInt8),
+    Sparse(WeightsSparse),
+}
+
+fn int8_num_scales(weights_len: usize) -> usize {
+    (weights_len + QUANTIZATION_INT8_BLOCK_LEN - 1) / QUANTIZATION_INT8_BLOCK_LEN
+}
+
+impl InferenceWeights {
+    pub fn from_weights<W: WeightsReader + ?Sized>(
+        weights: &W,
+        weights_len: usize,
+        quantization: WeightsQuantization,
+    ) -> InferenceWeights {
+        unsafe {
+            match quantization {
+                WeightsQuantization::None => InferenceWeights::F32(WeightsF32 {
+                    values: WeightsBuffer::from_vec(
+                        (0..weights_len).map(|i| weights.get_weight(i)).collect(),
+                    ),
+                }),
+                WeightsQuantization::F16 => InferenceWeights::F16(WeightsF16 {
+                    values: WeightsBuffer::from_vec(
+                        (0..weights_len)
+                            .map(|i| f16::from_f32(weights.get_weight(i)).to_bits())
+                            .collect(),
+                    ),
+                }),
+                WeightsQuantization::Int8 => {
+                    let mut values: Vec<i8> = Vec::with_capacity(weights_len);
+                    let mut scales: Vec<f32> = Vec::new();
+                    for block_start in (0..weights_len).step_by(QUANTIZATION_INT8_BLOCK_LEN) {
+                        let block_end = min(block_start + QUANTIZATION_INT8_BLOCK_LEN, weights_len);
+                        let max_abs = (block_start..block_end)
+                            .map(|i| weights.get_weight(i).abs())
+                            .fold(0.0, f32::max);
+                        let scale = max_abs / 127.0;
+                        for i in block_start..block_end {
+                            let value = if scale > 0.0 {
+                                (weights.get_weight(i) / scale)
+                                    .round()
+                                    .max(-127.0)
+                                    .min(127.0)
+                            } else {
+                                0.0
+                            };
+                            values.push(value as i8);
+                        }
+                        scales.push(scale);
+                    }
+                    InferenceWeights::Int8(WeightsInt8 {
+                        values: WeightsBuffer::from_vec(values),
+                        scales: WeightsBuffer::from_vec(scales),
+                    })
+                }
+            }
+        }
+    }
+
+    pub fn new_from_buf(
+        weights_len: usize,
+        quantization: WeightsQuantization,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<InferenceWeights, Box<dyn Error>> {
+        match quantization {
+            WeightsQuantization::None => {
+                let mut values: Vec<f32> = vec![0.0; weights_len];
+                read_weights_from_buf(&mut values, input_bufreader)?;
+                Ok(InferenceWeights::F32(WeightsF32 {
+                    values: WeightsBuffer::from_vec(values),
+                }))
+            }
+            WeightsQuantization::F16 => {
+                let mut values: Vec<u16> = vec![0; weights_len];
+                read_weights_from_buf(&mut values, input_bufreader)?;
+                Ok(InferenceWeights::F16(WeightsF16 {
+                    values: WeightsBuffer::from_vec(values),
+                }))
+            }
+            WeightsQuantization::Int8 => {
+                let mut scales: Vec<f32> = vec![0.0; int8_num_scales(weights_len)];
+                read_weights_from_buf(&mut scales, input_bufreader)?;
+                let mut values: Vec<i8> = vec![0; weights_len];
+                read_weights_from_buf(&mut values, input_bufreader)?;
+                Ok(InferenceWeights::Int8(WeightsInt8 {
+                    values: WeightsBuffer::from_vec(values),
+                    scales: WeightsBuffer::from_vec(scales),
+                }))
+            }
+        }
+    }
+
+    // Same layout as new_from_buf(), but without copying anything
+    pub fn new_from_mmap(
+        weights_len: usize,
+        quantization: WeightsQuantization,
+        mmap: &Arc<Mmap>,
+        offset: &mut usize,
+    ) -> Result<InferenceWeights, Box<dyn Error>> {
+        match quantization {
+            WeightsQuantization::None => Ok(InferenceWeights::F32(WeightsF32 {
+                values: WeightsBuffer::from_mmap(mmap, offset, weights_len)?,
+            })),
+            WeightsQuantization::F16 => Ok(InferenceWeights::F16(WeightsF16 {
+                values: WeightsBuffer::from_mmap(mmap, offset, weights_len)?,
+            })),
+            WeightsQuantization::Int8 => {
+                let scales = WeightsBuffer::from_mmap(mmap, offset, int8_num_scales(weights_len))?;
+                let values = WeightsBuffer::from_mmap(mmap, offset, weights_len)?;
+                Ok(InferenceWeights::Int8(WeightsInt8 {
+                    values: values,
+                    scales: scales,
+                }))
+            }
+        }
+    }
+
+    pub fn write_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+        match self {
+            InferenceWeights::F32(w) => write_weights_to_buf(w.values.as_slice(), output_bufwriter),
+            InferenceWeights::F16(w) => write_weights_to_buf(w.values.as_slice(), output_bufwriter),
+            InferenceWeights::Int8(w) => {
+                write_weights_to_buf(w.scales.as_slice(), output_bufwriter)?;
+                write_weights_to_buf(w.values.as_slice(), output_bufwriter)
+            }
+            InferenceWeights::Sparse(w) => w.write_to_buf(output_bufwriter),
+        }
+    }
+}
+
+// Slow, as it dispatches on every weight. Only to be used when converting whole arrays
+impl WeightsReader for InferenceWeights {
+    unsafe fn get_weight(&self, index: usize) -> f32 {
+        match self {
+            InferenceWeights::F32(w) => w.get_weight(index),
+            InferenceWeights::F16(w) => w.get_weight(index),
+            InferenceWeights::Int8(w) => w.get_weight(index),
+            InferenceWeights::Sparse(w) => w.get_weight(index),
+        }
+    }
+    unsafe fn get_weight_ptr(&self, index: usize) -> *const i8 {
+        match self {
+            InferenceWeights::F32(w) => w.get_weight_ptr(index),
+            InferenceWeights::F16(w) => w.get_weight_ptr(index),
+            InferenceWeights::Int8(w) => w.get_weight_ptr(index),
+            InferenceWeights::Sparse(w) => w.get_weight_ptr(index),
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! assert_epsilon {
     ($x:expr, $y:expr) => {
@@ -77,7 +595,7 @@ pub fn skip_weights_from_buf<L>(
 }
 
 pub fn write_weights_to_buf<L>(
-    weights: &Vec<L>,
+    weights: &[L],
     output_bufwriter: &mut dyn io::Write,
 ) -> Result<(), Box<dyn Error>> {
     if weights.len() == 0 {
@@ -94,6 +612,25 @@ pub fn write_weights_to_buf<L>(
     Ok(())
 }
 
+pub fn scale_weights<L: OptimizerTrait>(weights: &mut [WeightAndOptimizerData<L>], scale: f32) {
+    for w in weights.iter_mut() {
+        w.weight *= scale;
+    }
+}
+
+pub fn add_weights_from<L: OptimizerTrait>(
+    weights: &mut [WeightAndOptimizerData<L>],
+    other_weights: &[WeightAndOptimizerData<L>],
+    scale: f32,
+    optimizer: &L,
+) {
+    assert_eq!(weights.len(), other_weights.len());
+    for (w, other) in weights.iter_mut().zip(other_weights.iter()) {
+        w.weight += other.weight * scale;
+        optimizer.merge_data(&mut w.optimizer_data, &other.optimizer_data);
+    }
+}
+
 pub fn read_weights_only_from_buf2<L: OptimizerTrait>(
     weights_len: usize,
     out_weights: &mut Vec<WeightAndOptimizerData<OptimizerSGD>>,
@@ -213,3 +750,87 @@ pub fn forward(
         None => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    // 64 weights in chunks of 4, every other chunk is kept
+    fn sparse_buf() -> Vec<u8> {
+        let weights: Vec<Weight> = (0..64).map(|i| Weight { weight: i as f32 }).collect();
+        let sparse =
+            WeightsSparse::from_weights(&weights[..], 64, 2, &|start, _end| (start / 4) % 2 == 0);
+        let mut buf: Vec<u8> = Vec::new();
+        sparse.write_to_buf(&mut buf).unwrap();
+        buf
+    }
+
+    fn set_u32(buf: &mut Vec<u8>, index: usize, value: u32) {
+        buf[index * 4..index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn load_from_mmap(buf: &Vec<u8>) -> Result<WeightsSparse, Box<dyn Error>> {
+        let dir = tempdir().unwrap();
+        let filepath = dir.path().join("sparse.bin");
+        fs::File::create(&filepath).unwrap().write_all(buf).unwrap();
+        let mmap = Arc::new(unsafe { Mmap::map(&fs::File::open(&filepath).unwrap()).unwrap() });
+        let mut offset = 0;
+        WeightsSparse::new_from_mmap(64, &mmap, &mut offset)
+    }
+
+    #[test]
+    fn test_sparse_roundtrip() {
+        let buf = sparse_buf();
+        for sparse in vec![
+            WeightsSparse::new_from_buf(64, &mut io::Cursor::new(&buf)).unwrap(),
+            load_from_mmap(&buf).unwrap(),
+        ] {
+            assert_eq!(sparse.num_values(), 32);
+            assert_eq!(unsafe { sparse.get_weight(9) }, 9.0);
+            assert_eq!(unsafe { sparse.get_weight(13) }, 0.0);
+        }
+    }
+
+    #[test]
+    fn test_sparse_corrupt_header() {
+        let mut corruptions: Vec<Vec<u8>> = Vec::new();
+        // chunk bits and table bits that would overflow shifts
+        for (index, value) in vec![(0, 32), (1, 0), (1, 32), (1, 200)] {
+            let mut buf = sparse_buf();
+            set_u32(&mut buf, index, value);
+            corruptions.push(buf);
+        }
+        // table that is too big for the weights
+        let mut buf = sparse_buf();
+        set_u32(&mut buf, 1, 20);
+        corruptions.push(buf);
+        // more values than weights, and values that are not whole chunks
+        for value in vec![u32::MAX - 3, 68, 33] {
+            let mut buf = sparse_buf();
+            set_u32(&mut buf, 2, value);
+            corruptions.push(buf);
+        }
+        // truncated file
+        let mut buf = sparse_buf();
+        buf.truncate(buf.len() - 4);
+        corruptions.push(buf);
+        // chunk that points past the values
+        let buf = sparse_buf();
+        let table_len = 1 << u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let slot = (0..table_len)
+            .find(|slot| buf[12 + slot * 4..16 + slot * 4] != [0xff; 4])
+            .unwrap();
+        let mut buf = buf;
+        set_u32(&mut buf, 3 + table_len + slot, 30);
+        corruptions.push(buf);
+
+        for buf in corruptions.iter() {
+            assert!(WeightsSparse::new_from_buf(64, &mut io::Cursor::new(buf)).is_err());
+            assert!(load_from_mmap(buf).is_err());
+        }
+    }
+}
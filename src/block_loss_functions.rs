@@ -109,7 +109,7 @@ impl BlockTrait for BlockSigmoid {
                 let myslice = &pb
                     .tape
                     .get_unchecked(self.input_offset..(self.input_offset + self.num_inputs));
-                myslice.iter().sum()
+                myslice.iter().sum::<f32>() + fb.initial_prediction
             };
             // vowpal compatibility
 
@@ -162,7 +162,7 @@ impl BlockTrait for BlockSigmoid {
                 let myslice = &pb
                     .tape
                     .get_unchecked(self.input_offset..(self.input_offset + self.num_inputs));
-                myslice.iter().sum()
+                myslice.iter().sum::<f32>() + fb.initial_prediction
             };
 
             let prediction_probability: f32;
@@ -188,3 +188,115 @@ impl BlockTrait for BlockSigmoid {
         }
     }
 }
+
+// Squared loss with identity link, for regression
+pub struct BlockSquared {
+    num_inputs: usize,
+    input_offset: usize,
+    output_offset: usize,
+    copy_to_result: bool,
+}
+
+pub fn new_squared_loss_block(
+    bg: &mut graph::BlockGraph,
+    input: graph::BlockPtrOutput,
+    copy_to_result: bool,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input]);
+    let block = Box::new(BlockSquared {
+        num_inputs: num_inputs as usize,
+        input_offset: usize::MAX,
+        output_offset: usize::MAX,
+        copy_to_result: copy_to_result,
+    });
+    let mut block_outputs = bg.add_node(block, vec![input]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+impl BlockSquared {
+    #[inline(always)]
+    fn prediction(&self, fb: &feature_buffer::FeatureBuffer, pb: &port_buffer::PortBuffer) -> f32 {
+        let wsum: f32 = unsafe {
+            pb.tape
+                .get_unchecked(self.input_offset..(self.input_offset + self.num_inputs))
+                .iter()
+                .sum::<f32>()
+                + fb.initial_prediction
+        };
+        if wsum.is_nan() {
+            eprintln!(
+                "NAN prediction in example {}, forcing 0.0",
+                fb.example_number
+            );
+            return 0.0;
+        }
+        wsum
+    }
+}
+
+impl BlockTrait for BlockSquared {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_num_output_slots(&self) -> usize {
+        1
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert!(output.get_output_index() == 0);
+        1
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert!(input.get_input_index() == 0);
+        assert!(self.input_offset == usize::MAX); // We only allow a single call
+        self.input_offset = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert!(self.output_offset == usize::MAX); // We only allow a single call
+        assert!(output.get_output_index() == 0);
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+
+        let prediction = self.prediction(fb, pb);
+        let general_gradient = (prediction - fb.label) * fb.example_importance;
+        pb.tape[self.output_offset] = prediction;
+        if self.copy_to_result {
+            pb.observations.push(prediction);
+        }
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+        // replace inputs with their gradients
+        pb.tape[self.input_offset..(self.input_offset + self.num_inputs)].fill(general_gradient);
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+
+        let prediction = self.prediction(fb, pb);
+        pb.tape[self.output_offset] = prediction;
+        if self.copy_to_result {
+            pb.observations.push(prediction);
+        }
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+}
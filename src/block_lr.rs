@@ -6,14 +6,17 @@ use crate::model_instance;
 use crate::optimizer;
 use crate::regressor;
 
+use memmap2::Mmap;
 use std::error::Error;
 use std::io;
+use std::sync::Arc;
 
 use crate::block_helpers;
 use crate::port_buffer;
-use block_helpers::WeightAndOptimizerData;
+use block_helpers::{InferenceWeights, WeightAndOptimizerData, WeightsReader, WeightsSparse};
+use model_instance::WeightsQuantization;
 use optimizer::OptimizerTrait;
-use regressor::BlockTrait;
+use regressor::{BlockTrait, PrunedWeights};
 
 pub struct BlockLR<L: OptimizerTrait> {
     pub weights: Vec<WeightAndOptimizerData<L>>,
@@ -21,6 +24,10 @@ pub struct BlockLR<L: OptimizerTrait> {
     pub optimizer_lr: L,
     pub output_offset: usize,
     pub num_combos: u32,
+    pub weights_quantization: WeightsQuantization,
+    pub weights_sparse: bool,
+    // Only set on immutable regressors with quantized or memory-mapped weights, replaces weights
+    pub inference_weights: Option<InferenceWeights>,
 }
 
 fn new_lr_block_without_weights<L: OptimizerTrait + 'static>(
@@ -36,11 +43,14 @@ fn new_lr_block_without_weights<L: OptimizerTrait + 'static>(
         optimizer_lr: L::new(),
         output_offset: usize::MAX,
         num_combos: num_combos,
+        weights_quantization: mi.weights_quantization,
+        weights_sparse: mi.weights_sparse,
+        inference_weights: None,
     };
     reg_lr
         .optimizer_lr
         .init(mi.learning_rate, mi.power_t, mi.init_acc_gradient);
-    reg_lr.weights_len = 1 << mi.bit_precision;
+    reg_lr.weights_len = mi.lr_hash_spaces().1 as u32;
     Ok(Box::new(reg_lr))
 }
 
@@ -71,6 +81,10 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
     }
 
     fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
+        if mi.has_inference_weights_layout() {
+            // Weights get loaded directly into inference_weights
+            return;
+        }
         self.weights = vec![
             WeightAndOptimizerData::<L> {
                 weight: 0.0,
@@ -160,21 +174,12 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
     ) {
         debug_assert!(self.output_offset != usize::MAX);
 
-        let fbuf = &fb.lr_buffer;
-        let mut wsum: f32 = 0.0;
-
-        {
-            unsafe {
-                let myslice = &mut pb.tape
-                    [self.output_offset..(self.output_offset + self.num_combos as usize)];
-                myslice.fill(0.0);
-                for val in fbuf {
-                    let hash = val.hash as usize;
-                    let feature_value: f32 = val.value;
-                    *myslice.get_unchecked_mut(val.combo_index as usize) +=
-                        self.weights.get_unchecked(hash).weight * feature_value;
-                }
-            }
+        match &self.inference_weights {
+            None => self.forward_with_weights(&self.weights[..], fb, pb),
+            Some(InferenceWeights::F32(w)) => self.forward_with_weights(w, fb, pb),
+            Some(InferenceWeights::F16(w)) => self.forward_with_weights(w, fb, pb),
+            Some(InferenceWeights::Int8(w)) => self.forward_with_weights(w, fb, pb),
+            Some(InferenceWeights::Sparse(w)) => self.forward_with_weights(w, fb, pb),
         }
         block_helpers::forward(further_blocks, fb, pb);
     }
@@ -194,7 +199,10 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         &self,
         output_bufwriter: &mut dyn io::Write,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)
+        match &self.inference_weights {
+            None => block_helpers::write_weights_to_buf(&self.weights, output_bufwriter),
+            Some(inference_weights) => inference_weights.write_to_buf(output_bufwriter),
+        }
     }
 
     fn read_weights_from_buf_into_forward_only(
@@ -206,6 +214,20 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
             .as_any()
             .downcast_mut::<BlockLR<optimizer::OptimizerSGD>>()
             .unwrap();
+        if self.weights_sparse {
+            forward.inference_weights = Some(InferenceWeights::Sparse(
+                WeightsSparse::new_from_buf(self.weights_len as usize, input_bufreader)?,
+            ));
+            return Ok(());
+        }
+        if self.weights_quantization != WeightsQuantization::None {
+            forward.inference_weights = Some(InferenceWeights::new_from_buf(
+                self.weights_len as usize,
+                self.weights_quantization,
+                input_bufreader,
+            )?);
+            return Ok(());
+        }
         block_helpers::read_weights_only_from_buf2::<L>(
             self.weights_len as usize,
             &mut forward.weights,
@@ -213,6 +235,87 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         )
     }
 
+    fn scale_weights(&mut self, scale: f32) {
+        block_helpers::scale_weights(&mut self.weights, scale);
+    }
+
+    fn add_weights_from(
+        &mut self,
+        other: &mut Box<dyn BlockTrait>,
+        scale: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = match other.as_any().downcast_mut::<BlockLR<L>>() {
+            Some(other) => other,
+            None => return Err("Cannot merge weights of different kinds of blocks")?,
+        };
+        block_helpers::add_weights_from(
+            &mut self.weights,
+            &other.weights,
+            scale,
+            &self.optimizer_lr,
+        );
+        Ok(())
+    }
+
+    fn read_weights_from_mmap_into_forward_only(
+        &self,
+        mmap: &Arc<Mmap>,
+        offset: &mut usize,
+        forward: &mut Box<dyn BlockTrait>,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockLR<optimizer::OptimizerSGD>>()
+            .unwrap();
+        if self.weights_sparse {
+            forward.inference_weights = Some(InferenceWeights::Sparse(
+                WeightsSparse::new_from_mmap(self.weights_len as usize, mmap, offset)?,
+            ));
+            return Ok(());
+        }
+        forward.inference_weights = Some(InferenceWeights::new_from_mmap(
+            self.weights_len as usize,
+            self.weights_quantization,
+            mmap,
+            offset,
+        )?);
+        Ok(())
+    }
+
+    fn prune_weights(
+        &mut self,
+        _initial: &mut Box<dyn BlockTrait>,
+        lr_threshold: f32,
+    ) -> Option<PrunedWeights> {
+        // LR weights start at zero, so untouched ones are dropped by any threshold
+        let sparse = match &self.inference_weights {
+            None => self.prune_with_weights(&self.weights[..], lr_threshold),
+            Some(w) => self.prune_with_weights(w, lr_threshold),
+        };
+        let pruned = PrunedWeights {
+            block_name: "LR",
+            kept: sparse.num_values(),
+            total: self.weights_len as usize,
+        };
+        self.inference_weights = Some(InferenceWeights::Sparse(sparse));
+        self.weights = Vec::new();
+        self.weights_sparse = true;
+        Some(pruned)
+    }
+
+    fn quantize_weights(&mut self, quantization: WeightsQuantization) {
+        self.inference_weights = Some(match &self.inference_weights {
+            None => InferenceWeights::from_weights(
+                &self.weights[..],
+                self.weights_len as usize,
+                quantization,
+            ),
+            Some(w) => InferenceWeights::from_weights(w, self.weights_len as usize, quantization),
+        });
+        self.weights = Vec::new();
+        self.weights_quantization = quantization;
+    }
+
     /// Sets internal state of weights based on some completely object-dependent parameters
     fn testing_set_weights(
         &mut self,
@@ -226,3 +329,35 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         Ok(())
     }
 }
+
+impl<L: OptimizerTrait + 'static> BlockLR<L> {
+    fn prune_with_weights<W: WeightsReader + ?Sized>(
+        &self,
+        weights: &W,
+        lr_threshold: f32,
+    ) -> WeightsSparse {
+        WeightsSparse::from_weights(weights, self.weights_len as usize, 0, &|start, _end| {
+            unsafe { weights.get_weight(start) }.abs() > lr_threshold
+        })
+    }
+
+    #[inline(always)]
+    fn forward_with_weights<W: WeightsReader + ?Sized>(
+        &self,
+        weights: &W,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        unsafe {
+            let myslice =
+                &mut pb.tape[self.output_offset..(self.output_offset + self.num_combos as usize)];
+            myslice.fill(0.0);
+            for val in &fb.lr_buffer {
+                let hash = val.hash as usize;
+                let feature_value: f32 = val.value;
+                *myslice.get_unchecked_mut(val.combo_index as usize) +=
+                    weights.get_weight(hash) * feature_value;
+            }
+        }
+    }
+}
@@ -18,7 +18,8 @@ use crate::model_instance;
 use crate::optimizer;
 use crate::port_buffer;
 use crate::regressor;
-use block_helpers::{OptimizerData, Weight};
+use block_helpers::{InferenceWeights, OptimizerData, Weight, WeightsReader};
+use model_instance::WeightsQuantization;
 use optimizer::OptimizerTrait;
 use regressor::BlockTrait;
 
@@ -60,6 +61,8 @@ pub struct BlockNeuronLayer<L: OptimizerTrait> {
     pub dropout_inv: f32,
     pub max_norm: f32,
     pub layer_norm: bool,
+    // Quantized weights are dequantized on load, since BLAS works on f32 only
+    pub weights_quantization: WeightsQuantization,
     rng: Xoshiro256PlusPlus,
     rng_scratchpad: Vec<u32>,
     dropout_threshold: u32,
@@ -100,6 +103,7 @@ fn new_neuronlayer_without_weights<L: OptimizerTrait + 'static>(
         dropout_inv: 1.0 / (1.0 - dropout),
         max_norm: max_norm,
         layer_norm: layer_norm,
+        weights_quantization: mi.weights_quantization,
         rng: Xoshiro256PlusPlus::seed_from_u64(0 as u64),
         rng_scratchpad: Vec::new(),
         dropout_threshold: ((u32::MAX as f64) * (dropout as f64)) as u32,
@@ -519,7 +523,16 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
         &self,
         output_bufwriter: &mut dyn io::Write,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)?;
+        if self.weights_quantization == WeightsQuantization::None {
+            block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)?;
+        } else {
+            InferenceWeights::from_weights(
+                &self.weights[..],
+                self.weights_len as usize,
+                self.weights_quantization,
+            )
+            .write_to_buf(output_bufwriter)?;
+        }
         block_helpers::write_weights_to_buf(&self.weights_optimizer, output_bufwriter)?;
         Ok(())
     }
@@ -533,7 +546,18 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
             .as_any()
             .downcast_mut::<BlockNeuronLayer<optimizer::OptimizerSGD>>()
             .unwrap();
-        block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader)?;
+        if self.weights_quantization == WeightsQuantization::None {
+            block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader)?;
+        } else {
+            let quantized_weights = InferenceWeights::new_from_buf(
+                self.weights_len as usize,
+                self.weights_quantization,
+                input_bufreader,
+            )?;
+            for (i, w) in forward.weights.iter_mut().enumerate() {
+                w.weight = unsafe { quantized_weights.get_weight(i) };
+            }
+        }
         block_helpers::skip_weights_from_buf(
             self.weights_len as usize,
             &self.weights_optimizer,
@@ -542,6 +566,49 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
         Ok(())
     }
 
+    fn scale_weights(&mut self, scale: f32) {
+        for w in self.weights.iter_mut() {
+            w.weight *= scale;
+        }
+    }
+
+    fn add_weights_from(
+        &mut self,
+        other: &mut Box<dyn BlockTrait>,
+        scale: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = match other.as_any().downcast_mut::<BlockNeuronLayer<L>>() {
+            Some(other) => other,
+            None => return Err("Cannot merge weights of different kinds of blocks")?,
+        };
+        assert_eq!(self.weights.len(), other.weights.len());
+        for (w, other_w) in self.weights.iter_mut().zip(other.weights.iter()) {
+            w.weight += other_w.weight * scale;
+        }
+        for (o, other_o) in self
+            .weights_optimizer
+            .iter_mut()
+            .zip(other.weights_optimizer.iter())
+        {
+            self.optimizer
+                .merge_data(&mut o.optimizer_data, &other_o.optimizer_data);
+        }
+        Ok(())
+    }
+
+    fn quantize_weights(&mut self, quantization: WeightsQuantization) {
+        // Keep computing in f32, but with the precision that will be stored
+        let quantized_weights = InferenceWeights::from_weights(
+            &self.weights[..],
+            self.weights_len as usize,
+            quantization,
+        );
+        for (i, w) in self.weights.iter_mut().enumerate() {
+            w.weight = unsafe { quantized_weights.get_weight(i) };
+        }
+        self.weights_quantization = quantization;
+    }
+
     /// Sets internal state of weights based on some completely object-dependent parameters
     fn testing_set_weights(
         &mut self,
@@ -572,6 +639,8 @@ mod tests {
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: Vec::new(),
             ffm_buffer: Vec::new(),
             ffm_fields_count: 0,
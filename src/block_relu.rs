@@ -132,6 +132,8 @@ mod tests {
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: Vec::new(),
             ffm_buffer: Vec::new(),
             ffm_fields_count: 0,
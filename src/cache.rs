@@ -1,23 +1,30 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rand::seq::SliceRandom;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
 use std::error::Error;
 use std::fs;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::path;
 use std::{mem, slice};
-//use flate2::write::DeflateEncoder;
-//use flate2::Compression;
-//use flate2::read::DeflateDecoder;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-//use zstd::stream::{Encoder, Decoder};
-//use lz4::{Decoder, EncoderBuilder};
 
+use crate::parser;
 use crate::vwmap;
 
 const CACHE_HEADER_MAGIC_STRING: &[u8; 4] = b"FWCA"; // Fwumious Wabbit CAche
-const CACHE_HEADER_VERSION: u32 = 11;
+const CACHE_INDEX_MAGIC_STRING: &[u8; 4] = b"FWCI"; // Fwumious Wabbit Cache Index
+const CACHE_HEADER_VERSION: u32 = 14;
+const CACHE_OLDEST_READABLE_VERSION: u32 = 11; // records of version 11 are converted while reading
+const CACHE_FIRST_INDEXED_VERSION: u32 = 14;
 /*
 Version incompatibilites:
+13->14: records in separately compressed blocks with a block index at the end, vw_source is not compressed
+12->13: compression codec in the uncompressed part of the header (before, caches of .gz inputs were lz4 compressed as a whole)
+11->12: real valued labels, initial prediction and tag in the parsed buffer format
 10->11: float namespaces cannot have a weight attached
 9->10: enable binning
 8->9: enabled multi-byte feature names in vw files
@@ -27,96 +34,251 @@ Version incompatibilites:
 // Cache layout:
 // 4 bytes: Magic bytes
 // u32: Version of the cache format
+// u32: Compression codec (0 none, 1 lz4, 2 zstd)
+// i32: Compression level
 // u_size + blob: json encoding of vw_source
-// ...cached examples
+// ...blocks of cached examples, each one compressed on its own
+// u64 + entries: block index, an entry is u64 offset, u64 compressed length, u32 length in words, u32 number of examples
+// u64: offset of the block index
+// 4 bytes: Magic bytes of the block index
+// Caches of versions 11-13 have no index, everything after the header is one compressed stream (see open_for_reading)
 
 const READBUF_LEN: usize = 1024 * 100;
+const BLOCK_LEN: usize = 256 * 1024; // in u32 words, a block is written when it gets at least this long
+const CACHE_FILENAME_SUFFIX: &str = ".fwcache";
+const CACHE_TEMPORARY_FILENAME_SUFFIX: &str = ".writing";
+
+pub fn default_cache_filename(input_filename: &str) -> String {
+    format!("{}{}", input_filename, CACHE_FILENAME_SUFFIX)
+}
+
+pub fn is_cache_filename(filename: &str) -> bool {
+    filename.ends_with(CACHE_FILENAME_SUFFIX)
+        || filename.ends_with(&format!(
+            "{}{}",
+            CACHE_FILENAME_SUFFIX, CACHE_TEMPORARY_FILENAME_SUFFIX
+        ))
+}
 
-// This is super ugly hack around the fact that we need to call finish() before closing the lz4 stream
-// Effectively lz4 implementation we're using is kind of bad
-// More info (and where workaround comes from): https://github.com/bozaro/lz4-rs/issues/9
-struct Wrapper<W: Write> {
-    s: Option<lz4::Encoder<W>>,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheCompression {
+    None,
+    Lz4,
+    Zstd(i32), // compression level
 }
-impl<W: io::Write> Write for Wrapper<W> {
-    fn write(&mut self, buffer: &[u8]) -> Result<usize, std::io::Error> {
-        self.s.as_mut().unwrap().write(buffer)
+
+const LZ4_LEVEL: u32 = 3;
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+impl CacheCompression {
+    pub fn from_cmdline_str(s: &str) -> Result<CacheCompression, Box<dyn Error>> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().unwrap(), parts.next()) {
+            ("none", None) => Ok(CacheCompression::None),
+            ("lz4", None) => Ok(CacheCompression::Lz4),
+            ("zstd", None) => Ok(CacheCompression::Zstd(ZSTD_DEFAULT_LEVEL)),
+            ("zstd", Some(level)) => match level.parse::<i32>() {
+                Ok(level) if zstd::compression_level_range().contains(&level) => {
+                    Ok(CacheCompression::Zstd(level))
+                }
+                _ => Err(format!(
+                    "Unknown zstd compression level: \"{}\", it has to be between {} and {}",
+                    level,
+                    zstd::compression_level_range().start(),
+                    zstd::compression_level_range().end()
+                ))?,
+            },
+            _ => Err(format!(
+                "Unknown cache compression: \"{}\", possible values are none, lz4 and zstd[:level]",
+                s
+            ))?,
+        }
     }
 
-    fn flush(&mut self) -> Result<(), std::io::Error> {
-        self.s.as_mut().unwrap().flush()
+    fn to_header(&self) -> (u32, i32) {
+        match self {
+            CacheCompression::None => (0, 0),
+            CacheCompression::Lz4 => (1, LZ4_LEVEL as i32),
+            CacheCompression::Zstd(level) => (2, *level),
+        }
     }
-}
-impl<W: Write> Drop for Wrapper<W> {
-    fn drop(&mut self) {
-        match self.s.take() {
-            Some(s) => {
-                let a = s.finish();
+
+    fn from_header(codec: u32, level: i32) -> Result<CacheCompression, Box<dyn Error>> {
+        match codec {
+            0 => Ok(CacheCompression::None),
+            1 => Ok(CacheCompression::Lz4),
+            2 => Ok(CacheCompression::Zstd(level)),
+            _ => Err(format!("Unknown cache compression codec: {}", codec))?,
+        }
+    }
+
+    fn compress_block(
+        &self,
+        block: &[u32],
+        compressed: &mut Vec<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        compressed.truncate(0);
+        let block_bytes = unsafe {
+            slice::from_raw_parts(
+                block.as_ptr() as *const u8,
+                block.len() * mem::size_of::<u32>(),
+            )
+        };
+        match self {
+            CacheCompression::None => compressed.extend_from_slice(block_bytes),
+            CacheCompression::Lz4 => {
+                let mut encoder = lz4::EncoderBuilder::new()
+                    .level(LZ4_LEVEL)
+                    .build(compressed)?;
+                encoder.write_all(block_bytes)?;
+                encoder.finish().1?;
+            }
+            CacheCompression::Zstd(level) => {
+                zstd::stream::copy_encode(block_bytes, compressed, *level)?
             }
-            None => {}
         }
+        Ok(())
     }
+
+    fn decompress_block(
+        &self,
+        compressed: &[u8],
+        num_words: usize,
+        block: &mut Vec<u32>,
+    ) -> Result<(), Box<dyn Error>> {
+        block.resize(num_words, 0);
+        let block_bytes = unsafe {
+            slice::from_raw_parts_mut(
+                block.as_mut_ptr() as *mut u8,
+                num_words * mem::size_of::<u32>(),
+            )
+        };
+        match self {
+            CacheCompression::None => (&compressed[..]).read_exact(block_bytes)?,
+            CacheCompression::Lz4 => lz4::Decoder::new(compressed)?.read_exact(block_bytes)?,
+            CacheCompression::Zstd(_) => {
+                zstd::stream::Decoder::new(compressed)?.read_exact(block_bytes)?
+            }
+        }
+        Ok(())
+    }
+}
+
+// A compressed block of records, ready to be appended to a cache
+pub struct CacheBlock {
+    compressed: Vec<u8>,
+    num_words: u32,
+    num_examples: u32,
+}
+
+// Collects records into blocks and compresses them, it doesn't need a RecordCache,
+// so blocks can be built on other threads and written with RecordCache::push_block()
+pub struct CacheBlockBuilder {
+    compression: CacheCompression,
+    block_len: usize,
+    block_buffer: Vec<u32>,
+    num_examples: u32,
+}
+
+impl CacheBlockBuilder {
+    pub fn new(compression: CacheCompression) -> CacheBlockBuilder {
+        CacheBlockBuilder {
+            compression: compression,
+            block_len: BLOCK_LEN,
+            block_buffer: Vec::new(),
+            num_examples: 0,
+        }
+    }
+
+    // Returns a block once enough records have been pushed
+    pub fn push_record(
+        &mut self,
+        record_buf: &[u32],
+    ) -> Result<Option<CacheBlock>, Box<dyn Error>> {
+        self.block_buffer.extend_from_slice(record_buf);
+        self.num_examples += 1;
+        if self.block_buffer.len() >= self.block_len {
+            return self.finish();
+        }
+        Ok(None)
+    }
+
+    // Returns the block of the records pushed so far, if there are any
+    pub fn finish(&mut self) -> Result<Option<CacheBlock>, Box<dyn Error>> {
+        if self.num_examples == 0 {
+            return Ok(None);
+        }
+        let mut compressed: Vec<u8> = Vec::new();
+        self.compression
+            .compress_block(&self.block_buffer, &mut compressed)?;
+        let block = CacheBlock {
+            compressed: compressed,
+            num_words: self.block_buffer.len() as u32,
+            num_examples: self.num_examples,
+        };
+        self.block_buffer.truncate(0);
+        self.num_examples = 0;
+        Ok(Some(block))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockIndexEntry {
+    offset: u64,
+    compressed_len: u64,
+    num_words: u32,
+    num_examples: u32,
 }
 
 pub struct RecordCache {
     output_bufwriter: Box<dyn io::Write>,
-    input_bufreader: Box<dyn io::Read>,
+    input_bufreader: Box<dyn io::Read>, // sequential reading of caches without block index
+    input_file: Option<fs::File>,       // reading of blocks
     temporary_filename: String,
     final_filename: String,
     pub writing: bool,
     pub reading: bool,
+    pub compression: CacheCompression,
     //    pub output_buffer: Vec<u32>,
     pub byte_buffer: Vec<u8>, //[u8; READBUF_LEN],
     start_pointer: usize,
     end_pointer: usize,
     total_read: usize,
+    num_namespaces: usize,
+    convert_v11_records: bool,
+    converted_record: Vec<u32>,
+    block_builder: CacheBlockBuilder,
+    block_index: Option<Vec<BlockIndexEntry>>,
+    block_order: Vec<usize>, // blocks to read, in this order
+    next_block: usize,       // position in block_order
+    block_buffer: Vec<u32>,  // block being read
+    block_pointer: usize,    // position of the next record in block_buffer
+    compressed_buffer: Vec<u8>,
+    output_offset: u64,
 }
 
 impl RecordCache {
-    pub fn new(input_filename: &str, enabled: bool, vw_map: &vwmap::VwNamespaceMap) -> RecordCache {
-        let temporary_filename: String;
-        let final_filename: String;
-        let gz: bool;
-        temporary_filename = format!("{}.fwcache.writing", input_filename);
-        final_filename = format!("{}.fwcache", input_filename);
-        if !input_filename.ends_with("gz") {
-            gz = false;
-        } else {
-            gz = true;
-        }
-
-        let mut rc = RecordCache {
-            output_bufwriter: Box::new(io::BufWriter::new(io::sink())),
-            input_bufreader: Box::new(io::empty()),
-            temporary_filename: temporary_filename.to_string(),
-            final_filename: final_filename.to_string(),
-            writing: false,
-            reading: false,
-            byte_buffer: Vec::new(),
-            start_pointer: 0,
-            end_pointer: 0,
-            total_read: 0,
-        };
-
-        if enabled {
-            if path::Path::new(&final_filename).exists() {
-                rc.reading = true;
-                if !gz {
-                    // we buffer ourselves, otherwise i would be wise to use bufreader
-                    rc.input_bufreader = Box::new(fs::File::open(&final_filename).unwrap());
-                } else {
-                    //                    rc.input_bufreader = Box::new(zstd::stream::Decoder::new(fs::File::open(&final_filename).unwrap()).unwrap());
-                    rc.input_bufreader = Box::new(
-                        lz4::Decoder::new(fs::File::open(&final_filename).unwrap()).unwrap(),
-                    );
-                }
-                println!("using cache_file = {}", final_filename);
+    // With cache_filename of None, the cache is neither read nor written.
+    // An existing cache is read with the compression it was written with, compression is used for new caches.
+    pub fn new(
+        cache_filename: Option<&str>,
+        vw_map: &vwmap::VwNamespaceMap,
+        compression: CacheCompression,
+    ) -> RecordCache {
+        let mut rc = RecordCache::new_closed(cache_filename, vw_map.num_namespaces, compression);
+        if cache_filename.is_some() {
+            if path::Path::new(&rc.final_filename).exists() {
+                println!("using cache_file = {}", rc.final_filename);
                 println!("ignoring text input in favor of cache input");
-                match rc.verify_header(vw_map) {
-                    Ok(()) => {}
+                match rc.open_for_reading(Some(vw_map)) {
+                    Ok(_) => rc.reading = true,
                     Err(e) => {
                         println!("Couldn't use the existing cache file: {:?}", e);
-                        rc.reading = false;
+                        rc.input_bufreader = Box::new(io::empty());
+                        rc.input_file = None;
+                        rc.block_index = None;
+                        rc.compression = compression;
+                        rc.block_builder = CacheBlockBuilder::new(compression);
                     }
                 }
                 rc.byte_buffer.resize(READBUF_LEN, 0);
@@ -124,87 +286,303 @@ impl RecordCache {
 
             if !rc.reading {
                 rc.writing = true;
-                println!("creating cache file = {}", final_filename);
-                if !gz {
-                    rc.output_bufwriter = Box::new(io::BufWriter::new(
-                        fs::File::create(temporary_filename).unwrap(),
-                    ));
-                } else {
-                    //                    rc.output_bufwriter = Box::new(io::BufWriter::new(DeflateEncoder::new(fs::File::create(temporary_filename).unwrap(),
-                    //                                                                    Compression::fast())));
-
-                    //                      rc.output_bufwriter = Box::new(io::BufWriter::new(zstd::stream::Encoder::new(fs::File::create(temporary_filename).unwrap(),
-                    //                                                                    -5).unwrap().auto_finish()));
-                    //                      rc.output_bufwriter = Box::new(io::BufWriter::new(lz4::EncoderBuilder::new()
-                    //                                                                      .level(3).build(fs::File::create(temporary_filename).unwrap()
-                    //                                                                    ).unwrap()));
-                    let w = Wrapper {
-                        s: Some(
-                            lz4::EncoderBuilder::new()
-                                .level(3)
-                                .build(fs::File::create(temporary_filename).unwrap())
-                                .unwrap(),
-                        ),
-                    };
-                    rc.output_bufwriter = Box::new(io::BufWriter::new(w));
-                }
-                rc.write_header(vw_map).unwrap();
+                println!("creating cache file = {}", rc.final_filename);
+                rc.open_for_writing(vw_map).unwrap();
             }
         }
         rc
     }
 
+    // Opens an existing cache for reading with the vw_namespace_map it was written with (for inspecting caches)
+    pub fn open_existing(
+        cache_filename: &str,
+    ) -> Result<(RecordCache, vwmap::VwNamespaceMap), Box<dyn Error>> {
+        let mut rc = RecordCache::new_closed(Some(cache_filename), 0, CacheCompression::None);
+        let vw_map = rc.open_for_reading(None)?;
+        rc.reading = true;
+        rc.byte_buffer.resize(READBUF_LEN, 0);
+        Ok((rc, vw_map))
+    }
+
+    fn new_closed(
+        cache_filename: Option<&str>,
+        num_namespaces: usize,
+        compression: CacheCompression,
+    ) -> RecordCache {
+        let final_filename = cache_filename.unwrap_or("").to_string();
+        let temporary_filename = format!("{}{}", final_filename, CACHE_TEMPORARY_FILENAME_SUFFIX);
+
+        RecordCache {
+            output_bufwriter: Box::new(io::BufWriter::new(io::sink())),
+            input_bufreader: Box::new(io::empty()),
+            input_file: None,
+            temporary_filename: temporary_filename,
+            final_filename: final_filename,
+            writing: false,
+            reading: false,
+            compression: compression,
+            byte_buffer: Vec::new(),
+            start_pointer: 0,
+            end_pointer: 0,
+            total_read: 0,
+            num_namespaces: num_namespaces,
+            convert_v11_records: false,
+            converted_record: Vec::new(),
+            block_builder: CacheBlockBuilder::new(compression),
+            block_index: None,
+            block_order: Vec::new(),
+            next_block: 0,
+            block_buffer: Vec::new(),
+            block_pointer: 0,
+            compressed_buffer: Vec::new(),
+            output_offset: 0,
+        }
+    }
+
     pub fn push_record(&mut self, record_buf: &[u32]) -> Result<(), Box<dyn Error>> {
         if self.writing {
-            let element_size = mem::size_of::<u32>();
-            unsafe {
-                let vv: &[u8] = slice::from_raw_parts(
-                    record_buf.as_ptr() as *const u8,
-                    record_buf.len() * element_size,
-                );
-                self.output_bufwriter.write_all(&vv)?;
+            if let Some(block) = self.block_builder.push_record(record_buf)? {
+                self.push_block(&block)?;
             }
         }
         Ok(())
     }
 
+    // Appends a block built with the compression of this cache
+    pub fn push_block(&mut self, block: &CacheBlock) -> Result<(), Box<dyn Error>> {
+        if !self.writing {
+            return Ok(());
+        }
+        self.output_bufwriter.write_all(&block.compressed)?;
+        self.block_index.as_mut().unwrap().push(BlockIndexEntry {
+            offset: self.output_offset,
+            compressed_len: block.compressed.len() as u64,
+            num_words: block.num_words,
+            num_examples: block.num_examples,
+        });
+        self.output_offset += block.compressed.len() as u64;
+        Ok(())
+    }
+
     pub fn write_finish(&mut self) -> Result<(), Box<dyn Error>> {
         if self.writing {
+            if let Some(block) = self.block_builder.finish()? {
+                self.push_block(&block)?;
+            }
+            let block_index = self.block_index.as_ref().unwrap();
+            self.output_bufwriter
+                .write_u64::<LittleEndian>(block_index.len() as u64)?;
+            for entry in block_index {
+                self.output_bufwriter
+                    .write_u64::<LittleEndian>(entry.offset)?;
+                self.output_bufwriter
+                    .write_u64::<LittleEndian>(entry.compressed_len)?;
+                self.output_bufwriter
+                    .write_u32::<LittleEndian>(entry.num_words)?;
+                self.output_bufwriter
+                    .write_u32::<LittleEndian>(entry.num_examples)?;
+            }
+            self.output_bufwriter
+                .write_u64::<LittleEndian>(self.output_offset)?;
+            self.output_bufwriter.write_all(CACHE_INDEX_MAGIC_STRING)?;
             self.output_bufwriter.flush()?;
+            self.output_bufwriter = Box::new(io::sink());
             fs::rename(&self.temporary_filename, &self.final_filename)?;
         }
         Ok(())
     }
 
-    pub fn write_header(&mut self, vw_map: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
-        self.output_bufwriter.write_all(CACHE_HEADER_MAGIC_STRING)?;
-        self.output_bufwriter
-            .write_u32::<LittleEndian>(CACHE_HEADER_VERSION)?;
-        vw_map.save_to_buf(&mut self.output_bufwriter)?;
+    fn open_for_writing(&mut self, vw_map: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+        let mut header: Vec<u8> = Vec::new();
+        header.write_all(CACHE_HEADER_MAGIC_STRING)?;
+        header.write_u32::<LittleEndian>(CACHE_HEADER_VERSION)?;
+        let (codec, level) = self.compression.to_header();
+        header.write_u32::<LittleEndian>(codec)?;
+        header.write_i32::<LittleEndian>(level)?;
+        vw_map.save_to_buf(&mut header)?;
+        self.output_bufwriter = Box::new(io::BufWriter::new(fs::File::create(
+            &self.temporary_filename,
+        )?));
+        self.output_bufwriter.write_all(&header)?;
+        self.output_offset = header.len() as u64;
+        self.block_index = Some(Vec::new());
         Ok(())
     }
 
-    pub fn verify_header(&mut self, vwmap: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+    // Returns the vw_namespace_map stored in the cache, which has to be the same as vw_map (when given)
+    fn open_for_reading(
+        &mut self,
+        vw_map: Option<&vwmap::VwNamespaceMap>,
+    ) -> Result<vwmap::VwNamespaceMap, Box<dyn Error>> {
+        let mut file = fs::File::open(&self.final_filename)?;
         let mut magic_string: [u8; 4] = [0; 4];
-        self.input_bufreader.read(&mut magic_string)?;
-        if &magic_string != CACHE_HEADER_MAGIC_STRING {
-            return Err("Cache header does not begin with magic bytes FWFW")?;
+        file.read_exact(&mut magic_string)?;
+        let version: u32;
+        if &magic_string == CACHE_HEADER_MAGIC_STRING {
+            version = file.read_u32::<LittleEndian>()?;
+            if version >= 13 {
+                let codec = file.read_u32::<LittleEndian>()?;
+                let level = file.read_i32::<LittleEndian>()?;
+                self.compression = CacheCompression::from_header(codec, level)?;
+            } else {
+                self.compression = CacheCompression::None;
+            }
+            if version < CACHE_FIRST_INDEXED_VERSION {
+                self.input_bufreader = match self.compression {
+                    CacheCompression::None => Box::new(file),
+                    CacheCompression::Lz4 => Box::new(lz4::Decoder::new(file)?),
+                    CacheCompression::Zstd(_) => Box::new(zstd::stream::Decoder::new(file)?),
+                };
+            } else {
+                self.input_file = Some(file);
+            }
+        } else {
+            // Before version 13, caches of .gz inputs were lz4 compressed including the header
+            let mut decoder = lz4::Decoder::new(fs::File::open(&self.final_filename)?)?;
+            decoder.read_exact(&mut magic_string)?;
+            if &magic_string != CACHE_HEADER_MAGIC_STRING {
+                return Err("Cache header does not begin with magic bytes FWCA")?;
+            }
+            version = decoder.read_u32::<LittleEndian>()?;
+            if version >= 13 {
+                return Err(format!(
+                    "Cache file of version {} is not expected to be compressed as a whole",
+                    version
+                ))?;
+            }
+            self.compression = CacheCompression::Lz4;
+            self.input_bufreader = Box::new(decoder);
         }
 
-        let version = self.input_bufreader.read_u32::<LittleEndian>()?;
-        if CACHE_HEADER_VERSION != version {
+        if version < CACHE_OLDEST_READABLE_VERSION || version > CACHE_HEADER_VERSION {
             return Err(format!(
-                "Cache file version of this binary: {}, version of the cache file: {}",
-                CACHE_HEADER_VERSION, version
+                "Cache file version of this binary: {} (reads from {}), version of the cache file: {}",
+                CACHE_HEADER_VERSION, CACHE_OLDEST_READABLE_VERSION, version
             ))?;
         }
+        self.convert_v11_records = version == 11;
 
         // Compare vwmap in cache and the one we've been given. If they differ, rebuild cache
-        let vwmap_from_cache = vwmap::VwNamespaceMap::new_from_buf(&mut self.input_bufreader)?;
-        if vwmap_from_cache.vw_source != vwmap.vw_source {
-            return Err("vw_namespace_map.csv and the one from cache file differ")?;
+        let vwmap_from_cache = match self.input_file.as_mut() {
+            Some(file) => vwmap::VwNamespaceMap::new_from_buf(file)?,
+            None => vwmap::VwNamespaceMap::new_from_buf(&mut self.input_bufreader)?,
+        };
+        if let Some(vw_map) = vw_map {
+            if vwmap_from_cache.vw_source != vw_map.vw_source {
+                return Err("vw_namespace_map.csv and the one from cache file differ")?;
+            }
         }
+        self.num_namespaces = vwmap_from_cache.num_namespaces;
+
+        if let Some(file) = self.input_file.as_mut() {
+            file.seek(SeekFrom::End(-12))?;
+            let index_offset = file.read_u64::<LittleEndian>()?;
+            file.read_exact(&mut magic_string)?;
+            if &magic_string != CACHE_INDEX_MAGIC_STRING {
+                return Err("Cache file does not end with a block index")?;
+            }
+            file.seek(SeekFrom::Start(index_offset))?;
+            let num_blocks = file.read_u64::<LittleEndian>()?;
+            let mut block_index: Vec<BlockIndexEntry> = Vec::new();
+            for _ in 0..num_blocks {
+                block_index.push(BlockIndexEntry {
+                    offset: file.read_u64::<LittleEndian>()?,
+                    compressed_len: file.read_u64::<LittleEndian>()?,
+                    num_words: file.read_u32::<LittleEndian>()?,
+                    num_examples: file.read_u32::<LittleEndian>()?,
+                });
+            }
+            self.block_order = (0..block_index.len()).collect();
+            self.block_index = Some(block_index);
+        }
+
+        Ok(vwmap_from_cache)
+    }
+
+    // Number of examples in the cache, known only for caches with a block index
+    pub fn num_examples(&self) -> Option<u64> {
+        self.block_index
+            .as_ref()
+            .map(|index| index.iter().map(|entry| entry.num_examples as u64).sum())
+    }
 
+    fn require_block_index(&self, what: &str) -> Result<&Vec<BlockIndexEntry>, Box<dyn Error>> {
+        match (self.reading, self.block_index.as_ref()) {
+            (true, Some(block_index)) => Ok(block_index),
+            _ => Err(format!(
+                "{} needs an existing cache file of version {} or newer, {} is not one",
+                what, CACHE_FIRST_INDEXED_VERSION, self.final_filename
+            ))?,
+        }
+    }
+
+    // Restarts reading with blocks of the given shard only (every num_shards-th block, starting with shard),
+    // optionally in an order shuffled by the seed. Calling it again with another seed starts another pass.
+    pub fn select_blocks(
+        &mut self,
+        shard: usize,
+        num_shards: usize,
+        shuffle_seed: Option<u64>,
+    ) -> Result<(), Box<dyn Error>> {
+        let num_blocks = self.require_block_index("Sharding or shuffling")?.len();
+        self.block_order = (0..num_blocks)
+            .filter(|block| block % num_shards == shard)
+            .collect();
+        if let Some(seed) = shuffle_seed {
+            let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+            self.block_order.shuffle(&mut rng);
+        }
+        self.next_block = 0;
+        self.block_buffer.truncate(0);
+        self.block_pointer = 0;
+        Ok(())
+    }
+
+    // Continues reading (in file order) with the example of the given number, counted from 0
+    pub fn seek(&mut self, example_number: u64) -> Result<(), Box<dyn Error>> {
+        let block_index = self.require_block_index("Seeking")?;
+        let num_blocks = block_index.len();
+        let mut first_example: u64 = 0;
+        let mut found: Option<(usize, u64)> = None;
+        for (block, entry) in block_index.iter().enumerate() {
+            if example_number < first_example + entry.num_examples as u64 {
+                found = Some((block, example_number - first_example));
+                break;
+            }
+            first_example += entry.num_examples as u64;
+        }
+        self.block_buffer.truncate(0);
+        self.block_pointer = 0;
+        match found {
+            Some((block, skip)) => {
+                self.block_order = (block..num_blocks).collect();
+                self.next_block = 1;
+                self.read_block(block)?;
+                for _ in 0..skip {
+                    self.block_pointer += self.block_buffer[self.block_pointer] as usize;
+                }
+            }
+            None => {
+                self.block_order = Vec::new();
+                self.next_block = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&mut self, block: usize) -> Result<(), Box<dyn Error>> {
+        let entry = self.block_index.as_ref().unwrap()[block];
+        let file = self.input_file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(entry.offset))?;
+        self.compressed_buffer
+            .resize(entry.compressed_len as usize, 0);
+        file.read_exact(&mut self.compressed_buffer)?;
+        self.compression.decompress_block(
+            &self.compressed_buffer,
+            entry.num_words as usize,
+            &mut self.block_buffer,
+        )?;
+        self.block_pointer = 0;
         Ok(())
     }
 
@@ -212,6 +590,89 @@ impl RecordCache {
         if !self.reading {
             return Err("next_recrod() called on reading cache, when not opened in reading mode")?;
         }
+        if self.block_index.is_none() {
+            return self.get_next_record_sequential();
+        }
+        while self.block_pointer >= self.block_buffer.len() {
+            if self.next_block >= self.block_order.len() {
+                return Ok(&[]);
+            }
+            let block = self.block_order[self.next_block];
+            self.next_block += 1;
+            self.read_block(block)?;
+        }
+        let record_start = self.block_pointer;
+        let record_len = self.block_buffer[record_start] as usize;
+        if record_len == 0 || record_start + record_len > self.block_buffer.len() {
+            return Err(format!(
+                "Corrupted block in cache file {}",
+                self.final_filename
+            ))?;
+        }
+        self.block_pointer += record_len;
+        Ok(&self.block_buffer[record_start..self.block_pointer])
+    }
+
+    // Reads the whole cache checking that records fill their blocks exactly and that every record
+    // is consistent (see parser::check_record), returns the number of examples
+    pub fn validate(&mut self) -> Result<u64, Box<dyn Error>> {
+        let num_namespaces = self.num_namespaces;
+        let mut num_examples: u64 = 0;
+        let block_index = match self.block_index.clone() {
+            Some(block_index) => block_index,
+            None => {
+                loop {
+                    let record = self.get_next_record()?;
+                    if record.is_empty() {
+                        break;
+                    }
+                    parser::check_record(record, num_namespaces)
+                        .map_err(|e| format!("Example {}: {}", num_examples, e))?;
+                    num_examples += 1;
+                }
+                if self.end_pointer != self.start_pointer {
+                    return Err(format!(
+                        "The cache ends with an incomplete record after {} examples",
+                        num_examples
+                    ))?;
+                }
+                return Ok(num_examples);
+            }
+        };
+        for (block, entry) in block_index.iter().enumerate() {
+            self.read_block(block)
+                .map_err(|e| format!("Block {}: {}", block, e))?;
+            let mut block_examples: u32 = 0;
+            while self.block_pointer < self.block_buffer.len() {
+                let record_start = self.block_pointer;
+                let record_len = self.block_buffer[record_start] as usize;
+                if record_len == 0 || record_start + record_len > self.block_buffer.len() {
+                    return Err(format!(
+                        "Block {}: example {} has length {}, which doesn't fit the block",
+                        block, num_examples, record_len
+                    ))?;
+                }
+                self.block_pointer += record_len;
+                parser::check_record(
+                    &self.block_buffer[record_start..self.block_pointer],
+                    num_namespaces,
+                )
+                .map_err(|e| format!("Block {}: example {}: {}", block, num_examples, e))?;
+                block_examples += 1;
+                num_examples += 1;
+            }
+            if block_examples != entry.num_examples {
+                return Err(format!(
+                    "Block {}: has {} examples, while the block index says {}",
+                    block, block_examples, entry.num_examples
+                ))?;
+            }
+        }
+        self.next_block = self.block_order.len();
+        Ok(num_examples)
+    }
+
+    fn get_next_record_sequential(&mut self) -> Result<&[u32], Box<dyn Error>> {
         unsafe {
             // We're going to cast another view over the data, so we can read it as u32
             // This requires that the allocator we're using gives us sufficiently-aligned bytes,
@@ -232,6 +693,14 @@ impl RecordCache {
                         let ret_buf =
                             &buf_view[self.start_pointer / 4..self.start_pointer / 4 + record_len];
                         self.start_pointer += record_len * 4;
+                        if self.convert_v11_records {
+                            convert_v11_record(
+                                ret_buf,
+                                self.num_namespaces,
+                                &mut self.converted_record,
+                            );
+                            return Ok(&self.converted_record);
+                        }
                         return Ok(ret_buf);
                     }
                 }
@@ -255,3 +724,224 @@ impl RecordCache {
         }
     }
 }
+
+// Version 11 records had a header of three words (length, label as 1/0/0xff, importance), so namespaces
+// with multiple features pointed to positions two words before where they are now
+fn convert_v11_record(record: &[u32], num_namespaces: usize, converted: &mut Vec<u32>) {
+    const V11_HEADER_LEN: usize = 3;
+    const V11_NO_LABEL: u32 = 0xff;
+    let shift = parser::HEADER_LEN as usize - V11_HEADER_LEN;
+    converted.truncate(0);
+    converted.push((record.len() + shift) as u32);
+    converted.push(match record[parser::LABEL_OFFSET] {
+        V11_NO_LABEL => parser::NO_LABEL,
+        0 => (-1.0f32).to_bits(),
+        _ => 1.0f32.to_bits(),
+    });
+    converted.push(record[parser::EXAMPLE_IMPORTANCE_OFFSET]);
+    converted.push(0.0f32.to_bits());
+    converted.push(parser::NO_TAG);
+    for namespace_desc in &record[V11_HEADER_LEN..V11_HEADER_LEN + num_namespaces] {
+        if *namespace_desc & parser::IS_NOT_SINGLE_MASK != 0
+            && *namespace_desc != parser::NO_FEATURES
+        {
+            converted.push(namespace_desc + ((shift << 16) + shift) as u32);
+        } else {
+            converted.push(*namespace_desc);
+        }
+    }
+    converted.extend_from_slice(&record[V11_HEADER_LEN + num_namespaces..]);
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser::{VowpalParser, FLOAT32_ONE, IS_NOT_SINGLE_MASK, MASK31, NO_FEATURES};
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    const VW_MAP_STRING: &str = "A,featureA\nB,featureB\n";
+    const EXAMPLES: &str = "1 |A a |B b\n-1 0.5 |A a b\n|B c:2\n";
+
+    fn parsed_examples(vw: &vwmap::VwNamespaceMap) -> Vec<Vec<u32>> {
+        let mut vp = VowpalParser::new(vw);
+        let mut buf = Cursor::new(EXAMPLES.as_bytes().to_vec());
+        (0..3)
+            .map(|_| vp.next_vowpal(&mut buf).unwrap().to_vec())
+            .collect()
+    }
+
+    fn read_all(cache: &mut RecordCache) -> Vec<Vec<u32>> {
+        let mut records = Vec::new();
+        loop {
+            let record = cache.get_next_record().unwrap();
+            if record.is_empty() {
+                return records;
+            }
+            records.push(record.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_compressions() {
+        let vw = vwmap::VwNamespaceMap::new(VW_MAP_STRING).unwrap();
+        let records = parsed_examples(&vw);
+        let dir = tempdir().unwrap();
+        for compression in vec![
+            CacheCompression::None,
+            CacheCompression::Lz4,
+            CacheCompression::Zstd(3),
+            CacheCompression::Zstd(-5),
+        ] {
+            let cache_filename = dir.path().join(format!("{:?}.fwcache", compression));
+            let cache_filename = cache_filename.to_str().unwrap();
+            let mut cache = RecordCache::new(Some(cache_filename), &vw, compression);
+            assert!(cache.writing);
+            for record in &records {
+                cache.push_record(record).unwrap();
+            }
+            cache.write_finish().unwrap();
+
+            // Compression of an existing cache comes from its header
+            let mut cache = RecordCache::new(Some(cache_filename), &vw, CacheCompression::None);
+            assert!(cache.reading);
+            assert_eq!(cache.compression, compression);
+            assert_eq!(read_all(&mut cache), records);
+        }
+
+        assert_eq!(
+            CacheCompression::from_cmdline_str("zstd:19").unwrap(),
+            CacheCompression::Zstd(19)
+        );
+        assert_eq!(
+            CacheCompression::from_cmdline_str("zstd").unwrap(),
+            CacheCompression::Zstd(ZSTD_DEFAULT_LEVEL)
+        );
+        assert!(CacheCompression::from_cmdline_str("zstd:x").is_err());
+        assert!(CacheCompression::from_cmdline_str("zstd:1000").is_err());
+        assert!(CacheCompression::from_cmdline_str("lz4:1").is_err());
+        assert!(CacheCompression::from_cmdline_str("gzip").is_err());
+    }
+
+    #[test]
+    fn test_blocks_shards_and_seek() {
+        let vw = vwmap::VwNamespaceMap::new(VW_MAP_STRING).unwrap();
+        let mut vp = VowpalParser::new(&vw);
+        let examples: String = (0..20).map(|i| format!("{} |A a\n", i)).collect();
+        let mut buf = Cursor::new(examples.as_bytes().to_vec());
+        let records: Vec<Vec<u32>> = (0..20)
+            .map(|_| vp.next_vowpal(&mut buf).unwrap().to_vec())
+            .collect();
+        let dir = tempdir().unwrap();
+        let cache_filename = dir.path().join("blocks.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        let mut cache = RecordCache::new(Some(cache_filename), &vw, CacheCompression::Lz4);
+        cache.block_builder.block_len = 20; // records are 7 words long, so there are 3 of them in a block
+        for record in &records {
+            cache.push_record(record).unwrap();
+        }
+        cache.write_finish().unwrap();
+
+        let mut cache = RecordCache::new(Some(cache_filename), &vw, CacheCompression::None);
+        assert_eq!(cache.num_examples(), Some(20));
+        assert_eq!(cache.block_index.as_ref().unwrap().len(), 7);
+        assert_eq!(read_all(&mut cache), records);
+
+        // Shards split the blocks between them
+        cache.select_blocks(0, 2, None).unwrap();
+        let shard_0 = read_all(&mut cache);
+        cache.select_blocks(1, 2, None).unwrap();
+        let shard_1 = read_all(&mut cache);
+        assert_eq!(shard_0.len() + shard_1.len(), 20);
+        assert_eq!(shard_0[..3], records[0..3]);
+        assert_eq!(shard_1[..3], records[3..6]);
+        assert_eq!(shard_0[3..6], records[6..9]);
+
+        // Shuffling changes the order of blocks, but not of records in them
+        cache.select_blocks(0, 1, Some(1)).unwrap();
+        let shuffled_1 = read_all(&mut cache);
+        cache.select_blocks(0, 1, Some(2)).unwrap();
+        let shuffled_2 = read_all(&mut cache);
+        cache.select_blocks(0, 1, Some(1)).unwrap();
+        assert_eq!(read_all(&mut cache), shuffled_1);
+        assert_ne!(shuffled_1, shuffled_2);
+        let mut sorted = shuffled_1.clone();
+        sorted.sort_by_key(|record| f32::from_bits(record[parser::LABEL_OFFSET]) as u32);
+        assert_eq!(sorted, records);
+
+        cache.seek(10).unwrap();
+        assert_eq!(read_all(&mut cache), records[10..]);
+        cache.seek(0).unwrap();
+        assert_eq!(read_all(&mut cache), records);
+        cache.seek(20).unwrap();
+        assert_eq!(read_all(&mut cache).len(), 0);
+    }
+
+    #[test]
+    fn test_read_v11_caches() {
+        let vw = vwmap::VwNamespaceMap::new(VW_MAP_STRING).unwrap();
+        // The same examples as parsed by version 11 (labels 1/0/0xff and a three word header)
+        let v11_records: Vec<Vec<u32>> = vec![
+            vec![5, 1, FLOAT32_ONE, 2988156968 & MASK31, 2422381320 & MASK31],
+            vec![
+                9,
+                0,
+                0.5f32.to_bits(),
+                IS_NOT_SINGLE_MASK | (5 << 16) + 9,
+                NO_FEATURES,
+                2988156968 & MASK31,
+                FLOAT32_ONE,
+                3529656005 & MASK31,
+                FLOAT32_ONE,
+            ],
+            vec![
+                7,
+                0xff,
+                FLOAT32_ONE,
+                NO_FEATURES,
+                IS_NOT_SINGLE_MASK | (5 << 16) + 7,
+                parsed_examples(&vw)[2][7],
+                2.0f32.to_bits(),
+            ],
+        ];
+        let mut v11_cache: Vec<u8> = Vec::new();
+        v11_cache.write_all(CACHE_HEADER_MAGIC_STRING).unwrap();
+        v11_cache.write_u32::<LittleEndian>(11).unwrap();
+        vw.save_to_buf(&mut v11_cache).unwrap();
+        for record in &v11_records {
+            for word in record {
+                v11_cache.write_u32::<LittleEndian>(*word).unwrap();
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        let plain_filename = dir.path().join("plain.fwcache");
+        fs::write(&plain_filename, &v11_cache).unwrap();
+        // v11 caches of .gz inputs were lz4 compressed as a whole
+        let lz4_filename = dir.path().join("lz4.fwcache");
+        let mut encoder = lz4::EncoderBuilder::new()
+            .build(fs::File::create(&lz4_filename).unwrap())
+            .unwrap();
+        encoder.write_all(&v11_cache).unwrap();
+        encoder.finish().1.unwrap();
+
+        for (filename, compression) in vec![
+            (plain_filename, CacheCompression::None),
+            (lz4_filename, CacheCompression::Lz4),
+        ] {
+            let mut cache = RecordCache::new(
+                Some(filename.to_str().unwrap()),
+                &vw,
+                CacheCompression::None,
+            );
+            assert!(cache.reading);
+            assert_eq!(cache.compression, compression);
+            assert_eq!(read_all(&mut cache), parsed_examples(&vw));
+            // Caches without block index can only be read sequentially
+            assert_eq!(cache.num_examples(), None);
+            assert!(cache.select_blocks(0, 2, None).is_err());
+            assert!(cache.seek(1).is_err());
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::BufRead;
+use std::io::Read;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::cache;
+use crate::parser;
+use crate::parser::RecordParser;
+
+const CHUNK_LEN: usize = 4 * 1024 * 1024; // in bytes, a chunk is extended to the end of its last line
+
+// A chunk of the input, parsed on a worker thread by its own clone of the parser
+struct Chunk {
+    chunk_number: u64,
+    lines_before: u64,
+    data: Vec<u8>,
+    pa: parser::FaultTolerantParser,
+}
+
+struct ParsedChunk {
+    chunk_number: u64,
+    blocks: Vec<cache::CacheBlock>,
+    bad_rows: u64, // bad rows skipped in this chunk
+}
+
+// Builds a cache by splitting the text input into chunks of lines, parsing them on worker threads
+// and appending their blocks to the cache in the input order (or in the order they are parsed in, when unordered)
+pub struct ParallelCacheBuilder {
+    num_threads: usize,
+    unordered: bool,
+    chunk_len: usize,
+}
+
+impl ParallelCacheBuilder {
+    pub fn new(num_threads: usize, unordered: bool) -> ParallelCacheBuilder {
+        ParallelCacheBuilder {
+            num_threads: num_threads,
+            unordered: unordered,
+            chunk_len: CHUNK_LEN,
+        }
+    }
+
+    // pa is cloned for every chunk, its bad rows count is updated with the bad rows of all chunks
+    pub fn build(
+        &self,
+        input: &mut dyn BufRead,
+        has_header: bool,
+        pa: &mut parser::FaultTolerantParser,
+        cache: &mut cache::RecordCache,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut lines_read = 0;
+        if has_header {
+            // Chunks are parsed by clones of a parser that has already read the header
+            let mut header: Vec<u8> = Vec::new();
+            input.read_until(0x0a, &mut header)?;
+            lines_read = 1;
+            pa.next_record(&mut &header[..])?;
+        }
+
+        let compression = cache.compression;
+        let (chunk_sender, chunk_receiver) = mpsc::sync_channel::<Chunk>(self.num_threads * 2);
+        let chunk_receiver = Arc::new(Mutex::new(chunk_receiver));
+        let (parsed_sender, parsed_receiver) = mpsc::channel::<Result<ParsedChunk, String>>();
+        let mut workers = Vec::new();
+        for _ in 0..self.num_threads {
+            let chunk_receiver = chunk_receiver.clone();
+            let parsed_sender = parsed_sender.clone();
+            workers.push(thread::spawn(move || loop {
+                let chunk = match chunk_receiver.lock().unwrap().recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => break, // all chunks were sent
+                };
+                let parsed = parse_chunk(chunk, compression).map_err(|e| e.to_string());
+                if parsed_sender.send(parsed).is_err() {
+                    break; // writing has failed
+                }
+            }));
+        }
+        drop(parsed_sender);
+
+        let result =
+            self.write_chunks(input, lines_read, pa, cache, chunk_sender, &parsed_receiver);
+        drop(parsed_receiver);
+        for worker in workers {
+            worker
+                .join()
+                .map_err(|_| "A cache building thread has panicked")?;
+        }
+        result
+    }
+
+    fn write_chunks(
+        &self,
+        input: &mut dyn BufRead,
+        mut lines_read: u64,
+        pa: &mut parser::FaultTolerantParser,
+        cache: &mut cache::RecordCache,
+        chunk_sender: mpsc::SyncSender<Chunk>,
+        parsed_receiver: &mpsc::Receiver<Result<ParsedChunk, String>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut pending: BTreeMap<u64, ParsedChunk> = BTreeMap::new();
+        let mut chunks_sent: u64 = 0;
+        let mut chunks_written: u64 = 0;
+        let mut write_parsed = |parsed: Result<ParsedChunk, String>,
+                                pa: &mut parser::FaultTolerantParser,
+                                cache: &mut cache::RecordCache|
+         -> Result<(), Box<dyn Error>> {
+            let parsed = parsed?;
+            pa.add_bad_rows(parsed.bad_rows)?;
+            // Unordered chunks are written as they come, by giving them the next chunk number to be written
+            let chunk_number = if self.unordered {
+                chunks_written
+            } else {
+                parsed.chunk_number
+            };
+            pending.insert(chunk_number, parsed);
+            while let Some(parsed) = pending.remove(&chunks_written) {
+                for block in &parsed.blocks {
+                    cache.push_block(block)?;
+                }
+                chunks_written += 1;
+            }
+            Ok(())
+        };
+
+        loop {
+            let mut data: Vec<u8> = Vec::with_capacity(self.chunk_len + 0x1000);
+            (&mut *input)
+                .take(self.chunk_len as u64)
+                .read_to_end(&mut data)?;
+            if data.is_empty() {
+                break;
+            }
+            if data[data.len() - 1] != 0x0a {
+                input.read_until(0x0a, &mut data)?;
+            }
+            let mut lines = data.iter().filter(|&&c| c == 0x0a).count() as u64;
+            if data[data.len() - 1] != 0x0a {
+                lines += 1;
+            }
+            let chunk = Chunk {
+                chunk_number: chunks_sent,
+                lines_before: lines_read,
+                data: data,
+                pa: pa.clone(),
+            };
+            chunk_sender
+                .send(chunk)
+                .map_err(|_| "Cache building threads have stopped")?;
+            chunks_sent += 1;
+            lines_read += lines;
+            while let Ok(parsed) = parsed_receiver.try_recv() {
+                write_parsed(parsed, pa, cache)?;
+            }
+        }
+        drop(chunk_sender);
+        for parsed in parsed_receiver.iter() {
+            write_parsed(parsed, pa, cache)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_chunk(
+    mut chunk: Chunk,
+    compression: cache::CacheCompression,
+) -> Result<ParsedChunk, Box<dyn Error>> {
+    chunk.pa.start_chunk(chunk.lines_before);
+    let mut block_builder = cache::CacheBlockBuilder::new(compression);
+    let mut blocks: Vec<cache::CacheBlock> = Vec::new();
+    let mut input: &[u8] = &chunk.data;
+    loop {
+        let buffer = match chunk.pa.next_record(&mut input)? {
+            [] => break, // end of the chunk
+            buffer => buffer,
+        };
+        if let Some(block) = block_builder.push_record(buffer)? {
+            blocks.push(block);
+        }
+    }
+    if let Some(block) = block_builder.finish()? {
+        blocks.push(block);
+    }
+    Ok(ParsedChunk {
+        chunk_number: chunk.chunk_number,
+        blocks: blocks,
+        bad_rows: chunk.pa.bad_rows,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::vwmap;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    const VW_MAP_STRING: &str = "A,featureA\nB,featureB\n";
+
+    // Parses the input on a single thread, like the cache would be built without cache_builder
+    fn parse_sequentially(
+        vw: &vwmap::VwNamespaceMap,
+        data_format: parser::DataFormat,
+        input: &str,
+        max_bad_rows: u64,
+    ) -> Result<Vec<Vec<u32>>, Box<dyn Error>> {
+        let mut pa = parser::FaultTolerantParser::new(
+            parser::new_record_parser(data_format, vw),
+            max_bad_rows,
+            None,
+        )?;
+        let mut buf = Cursor::new(input.as_bytes().to_vec());
+        let mut records = Vec::new();
+        loop {
+            match pa.next_record(&mut buf)? {
+                [] => return Ok(records),
+                record => records.push(record.to_vec()),
+            }
+        }
+    }
+
+    fn build_in_parallel(
+        vw: &vwmap::VwNamespaceMap,
+        data_format: parser::DataFormat,
+        input: &str,
+        max_bad_rows: u64,
+        unordered: bool,
+    ) -> Result<Vec<Vec<u32>>, Box<dyn Error>> {
+        let dir = tempdir()?;
+        let cache_filename = dir.path().join("parallel.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        let mut cache =
+            cache::RecordCache::new(Some(cache_filename), vw, cache::CacheCompression::Lz4);
+        let mut pa = parser::FaultTolerantParser::new(
+            parser::new_record_parser(data_format, vw),
+            max_bad_rows,
+            None,
+        )?;
+        pa.start_input("input.txt");
+        let mut cache_builder = ParallelCacheBuilder::new(3, unordered);
+        cache_builder.chunk_len = 40; // a few lines per chunk
+        let mut buf = Cursor::new(input.as_bytes().to_vec());
+        cache_builder.build(&mut buf, data_format.has_header(), &mut pa, &mut cache)?;
+        cache.write_finish()?;
+
+        let mut cache =
+            cache::RecordCache::new(Some(cache_filename), vw, cache::CacheCompression::None);
+        let mut records = Vec::new();
+        loop {
+            match cache.get_next_record()? {
+                [] => return Ok(records),
+                record => records.push(record.to_vec()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_cache_building() {
+        let vw = vwmap::VwNamespaceMap::new(VW_MAP_STRING).unwrap();
+        let examples: String = (0..50)
+            .map(|i| format!("{} |A a{} |B b{}\n", i % 2, i, i % 7))
+            .collect();
+        let records = parse_sequentially(&vw, parser::DataFormat::Vw, &examples, 0).unwrap();
+        assert_eq!(records.len(), 50);
+        let parallel = build_in_parallel(&vw, parser::DataFormat::Vw, &examples, 0, false).unwrap();
+        assert_eq!(parallel, records);
+
+        // Unordered caches have the same examples, in chunks of lines
+        let mut parallel =
+            build_in_parallel(&vw, parser::DataFormat::Vw, &examples, 0, true).unwrap();
+        let mut sorted_records = records.clone();
+        parallel.sort();
+        sorted_records.sort();
+        assert_eq!(parallel, sorted_records);
+
+        // Line numbers of errors are the line numbers of the input, not of a chunk
+        let bad_examples = examples.replacen("1 |A a23", "x |A a23", 1);
+        let err = build_in_parallel(&vw, parser::DataFormat::Vw, &bad_examples, 0, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Error on line 24 of input.txt:"), "{}", err);
+        let parallel =
+            build_in_parallel(&vw, parser::DataFormat::Vw, &bad_examples, 1, false).unwrap();
+        assert_eq!(parallel.len(), 49);
+        assert_eq!(
+            parallel,
+            parse_sequentially(&vw, parser::DataFormat::Vw, &bad_examples, 1).unwrap()
+        );
+
+        // The limit of bad rows is for the whole input, not for every chunk
+        let bad_examples = bad_examples.replacen("1 |A a41", "x |A a41", 1);
+        let err = build_in_parallel(&vw, parser::DataFormat::Vw, &bad_examples, 1, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("--max_bad_rows"), "{}", err);
+    }
+
+    #[test]
+    fn test_parallel_cache_building_with_header() {
+        let vw_map_string = "_label_column,click\nA,featureA,,col a\n";
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let examples: String = "click,col a\n".to_string()
+            + &(0..30)
+                .map(|i| format!("{},a{}\n", i % 2, i))
+                .collect::<String>();
+        let records = parse_sequentially(&vw, parser::DataFormat::Csv, &examples, 0).unwrap();
+        assert_eq!(records.len(), 30);
+        let parallel =
+            build_in_parallel(&vw, parser::DataFormat::Csv, &examples, 0, false).unwrap();
+        assert_eq!(parallel, records);
+    }
+}
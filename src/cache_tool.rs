@@ -0,0 +1,490 @@
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::path;
+
+use crate::cache;
+use crate::parser;
+use crate::vwmap;
+
+const MAX_LABELS_IN_STATS: usize = 20; // the most frequent labels are listed, the rest are summed up
+
+// Namespaces by their index: vw name, verbose name and format.
+// Namespaces made by string transforms have no vw name, they come after the namespaces of the input.
+fn namespaces_by_index(
+    vw: &vwmap::VwNamespaceMap,
+) -> Vec<(Option<String>, String, vwmap::NamespaceFormat)> {
+    let mut namespaces =
+        vec![(None, String::new(), vwmap::NamespaceFormat::Categorical); vw.num_namespaces];
+    for (vwname, namespace_descriptor) in &vw.map_vwname_to_namespace_descriptor {
+        namespaces[namespace_descriptor.namespace_index as usize] = (
+            Some(String::from_utf8_lossy(vwname).to_string()),
+            vw.map_vwname_to_name[vwname].clone(),
+            namespace_descriptor.namespace_format,
+        );
+    }
+    let num_input_namespaces = vw.num_namespaces - vw.vw_source.string_transforms.len();
+    for (i, string_transform) in vw.vw_source.string_transforms.iter().enumerate() {
+        namespaces[num_input_namespaces + i].1 = string_transform.to_namespace_verbose.clone();
+    }
+    namespaces
+}
+
+// (hash, weight) of features of a categorical namespace or (hash, value) of a float namespace
+fn namespace_features(record: &[u32], namespace_index: usize) -> Vec<(u32, f32)> {
+    let namespace_desc = record[parser::HEADER_LEN as usize + namespace_index];
+    if namespace_desc == parser::NO_FEATURES {
+        return Vec::new();
+    }
+    if namespace_desc & parser::IS_NOT_SINGLE_MASK == 0 {
+        return vec![(namespace_desc, 1.0)];
+    }
+    let start = ((namespace_desc >> 16) & 0x3fff) as usize;
+    let end = (namespace_desc & 0xffff) as usize;
+    (start..end)
+        .step_by(2)
+        .map(|i| (record[i], f32::from_bits(record[i + 1])))
+        .collect()
+}
+
+fn num_namespace_features(record: &[u32], namespace_index: usize) -> usize {
+    let namespace_desc = record[parser::HEADER_LEN as usize + namespace_index];
+    if namespace_desc == parser::NO_FEATURES {
+        0
+    } else if namespace_desc & parser::IS_NOT_SINGLE_MASK == 0 {
+        1
+    } else {
+        ((namespace_desc & 0xffff) as usize - ((namespace_desc >> 16) & 0x3fff) as usize) / 2
+    }
+}
+
+fn label_of(record: &[u32]) -> Option<f32> {
+    match record[parser::LABEL_OFFSET] {
+        parser::NO_LABEL => None,
+        label => Some(f32::from_bits(label)),
+    }
+}
+
+pub fn write_stats(cache_filename: &str, output: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let (mut cache, vw) = cache::RecordCache::open_existing(cache_filename)?;
+    let namespaces = namespaces_by_index(&vw);
+    let mut num_examples: u64 = 0;
+    let mut label_counts: HashMap<u32, u64> = HashMap::new();
+    let mut label_sum: f64 = 0.0;
+    let mut label_min = f32::INFINITY;
+    let mut label_max = f32::NEG_INFINITY;
+    let mut namespace_features: Vec<u64> = vec![0; vw.num_namespaces];
+    let mut namespace_examples: Vec<u64> = vec![0; vw.num_namespaces];
+    loop {
+        let record = cache.get_next_record()?;
+        if record.is_empty() {
+            break;
+        }
+        num_examples += 1;
+        *label_counts
+            .entry(record[parser::LABEL_OFFSET])
+            .or_insert(0) += 1;
+        if let Some(label) = label_of(record) {
+            label_sum += label as f64;
+            label_min = label_min.min(label);
+            label_max = label_max.max(label);
+        }
+        for namespace_index in 0..vw.num_namespaces {
+            let num_features = num_namespace_features(record, namespace_index) as u64;
+            namespace_features[namespace_index] += num_features;
+            if num_features > 0 {
+                namespace_examples[namespace_index] += 1;
+            }
+        }
+    }
+
+    let per_example = |n: u64| n as f64 / num_examples.max(1) as f64;
+    writeln!(output, "cache file: {}", cache_filename)?;
+    writeln!(output, "compression: {:?}", cache.compression)?;
+    writeln!(output, "examples: {}", num_examples)?;
+    let unlabeled = label_counts.remove(&parser::NO_LABEL).unwrap_or(0);
+    let labeled = num_examples - unlabeled;
+    writeln!(output, "labels:")?;
+    let mut label_counts: Vec<(u32, u64)> = label_counts.into_iter().collect();
+    label_counts.sort_by(|a, b| {
+        b.1.cmp(&a.1)
+            .then(f32::from_bits(a.0).total_cmp(&f32::from_bits(b.0)))
+    });
+    for (label, count) in label_counts.iter().take(MAX_LABELS_IN_STATS) {
+        writeln!(
+            output,
+            "  {}: {} ({:.2}%)",
+            f32::from_bits(*label),
+            count,
+            100.0 * per_example(*count)
+        )?;
+    }
+    if label_counts.len() > MAX_LABELS_IN_STATS {
+        let others = &label_counts[MAX_LABELS_IN_STATS..];
+        let count: u64 = others.iter().map(|(_, count)| count).sum();
+        writeln!(
+            output,
+            "  {} other labels: {} ({:.2}%)",
+            others.len(),
+            count,
+            100.0 * per_example(count)
+        )?;
+    }
+    if unlabeled > 0 {
+        writeln!(
+            output,
+            "  no label: {} ({:.2}%)",
+            unlabeled,
+            100.0 * per_example(unlabeled)
+        )?;
+    }
+    if labeled > 0 {
+        writeln!(
+            output,
+            "label mean: {:.6}, min: {}, max: {}",
+            label_sum / labeled as f64,
+            label_min,
+            label_max
+        )?;
+    }
+    writeln!(
+        output,
+        "features per example: {:.2}",
+        per_example(namespace_features.iter().sum())
+    )?;
+    writeln!(output, "namespaces:")?;
+    for (namespace_index, (vwname, verbose, _)) in namespaces.iter().enumerate() {
+        let name = match vwname {
+            Some(vwname) => format!("{} ({})", vwname, verbose),
+            None => format!("{} (string transform)", verbose),
+        };
+        writeln!(
+            output,
+            "  {}: {} features in {} examples, {:.2} per example",
+            name,
+            namespace_features[namespace_index],
+            namespace_examples[namespace_index],
+            per_example(namespace_features[namespace_index])
+        )?;
+    }
+    Ok(())
+}
+
+// Writes the first examples as vw text. Feature names aren't stored, so categorical features are written
+// as #hash (with :weight when it isn't 1) and features of float namespaces as their values.
+// Namespaces made by string transforms are left out, the parser makes them again when the text is read.
+pub fn write_head(
+    cache_filename: &str,
+    num_examples: u64,
+    output: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let (mut cache, vw) = cache::RecordCache::open_existing(cache_filename)?;
+    let namespaces = namespaces_by_index(&vw);
+    for _ in 0..num_examples {
+        let record = cache.get_next_record()?;
+        if record.is_empty() {
+            break;
+        }
+        let mut line = String::new();
+        if let Some(label) = label_of(record) {
+            line.push_str(&format!("{}", label));
+            let importance = f32::from_bits(record[parser::EXAMPLE_IMPORTANCE_OFFSET]);
+            let initial_prediction = f32::from_bits(record[parser::INITIAL_PREDICTION_OFFSET]);
+            if importance != 1.0 || initial_prediction != 0.0 {
+                line.push_str(&format!(" {}", importance));
+            }
+            if initial_prediction != 0.0 {
+                line.push_str(&format!(" {}", initial_prediction));
+            }
+        }
+        let tag = parser::get_tag(record);
+        if !tag.is_empty() {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&format!("'{}", String::from_utf8_lossy(tag)));
+        }
+        for (namespace_index, (vwname, _, namespace_format)) in namespaces.iter().enumerate() {
+            let vwname = match vwname {
+                Some(vwname) => vwname,
+                None => continue,
+            };
+            let features = namespace_features(record, namespace_index);
+            if features.is_empty() {
+                continue;
+            }
+            line.push_str(&format!(" |{}", vwname));
+            for (hash, value) in features {
+                if *namespace_format == vwmap::NamespaceFormat::F32 {
+                    line.push_str(&format!(" {}", value));
+                } else if value != 1.0 {
+                    line.push_str(&format!(" #{}:{}", hash, value));
+                } else {
+                    line.push_str(&format!(" #{}", hash));
+                }
+            }
+        }
+        writeln!(output, "{}", line.trim_start())?;
+    }
+    Ok(())
+}
+
+pub fn validate(cache_filename: &str, output: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let (mut cache, _) = cache::RecordCache::open_existing(cache_filename)?;
+    let num_examples = cache
+        .validate()
+        .map_err(|e| format!("Cache file {} is corrupted: {}", cache_filename, e))?;
+    writeln!(output, "{}: OK, {} examples", cache_filename, num_examples)?;
+    Ok(())
+}
+
+// Which examples are written by filter()
+pub struct ExampleFilter {
+    pub sample_rate: f64, // every example is kept with this probability
+    pub seed: u64,
+    pub labels: Option<Vec<Option<f32>>>, // only examples with one of these labels (None for no label) are kept
+}
+
+// Writes the examples selected by example_filter to a new cache, returns the numbers of read and written examples
+pub fn filter(
+    input_filename: &str,
+    output_filename: &str,
+    example_filter: &ExampleFilter,
+    compression: Option<cache::CacheCompression>,
+) -> Result<(u64, u64), Box<dyn Error>> {
+    if path::Path::new(output_filename).exists() {
+        return Err(format!(
+            "Output cache file {} already exists",
+            output_filename
+        ))?;
+    }
+    let (mut input_cache, vw) = cache::RecordCache::open_existing(input_filename)?;
+    let compression = compression.unwrap_or(input_cache.compression);
+    let mut output_cache = cache::RecordCache::new(Some(output_filename), &vw, compression);
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(example_filter.seed);
+    let mut num_read: u64 = 0;
+    let mut num_written: u64 = 0;
+    loop {
+        let record = input_cache.get_next_record()?;
+        if record.is_empty() {
+            break;
+        }
+        num_read += 1;
+        if let Some(labels) = &example_filter.labels {
+            if !labels.contains(&label_of(record)) {
+                continue;
+            }
+        }
+        if example_filter.sample_rate < 1.0 && rng.gen::<f64>() >= example_filter.sample_rate {
+            continue;
+        }
+        output_cache.push_record(record)?;
+        num_written += 1;
+    }
+    output_cache.write_finish()?;
+    Ok((num_read, num_written))
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser::VowpalParser;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    const VW_MAP_STRING: &str = "A,featureA\nB,featureB,f32\n";
+
+    fn write_cache(cache_filename: &str, examples: &str) -> Vec<Vec<u32>> {
+        let vw = vwmap::VwNamespaceMap::new(VW_MAP_STRING).unwrap();
+        write_cache_of_vw(cache_filename, &vw, examples)
+    }
+
+    fn write_cache_of_vw(
+        cache_filename: &str,
+        vw: &vwmap::VwNamespaceMap,
+        examples: &str,
+    ) -> Vec<Vec<u32>> {
+        let mut vp = VowpalParser::new(vw);
+        let mut buf = Cursor::new(examples.as_bytes().to_vec());
+        let mut cache =
+            cache::RecordCache::new(Some(cache_filename), vw, cache::CacheCompression::Lz4);
+        let mut records = Vec::new();
+        loop {
+            match vp.next_vowpal(&mut buf).unwrap() {
+                [] => break,
+                record => records.push(record.to_vec()),
+            }
+            cache.push_record(records.last().unwrap()).unwrap();
+        }
+        cache.write_finish().unwrap();
+        records
+    }
+
+    fn read_all(cache_filename: &str) -> Vec<Vec<u32>> {
+        let (mut cache, _) = cache::RecordCache::open_existing(cache_filename).unwrap();
+        let mut records = Vec::new();
+        loop {
+            match cache.get_next_record().unwrap() {
+                [] => return records,
+                record => records.push(record.to_vec()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats_head_and_validate() {
+        let dir = tempdir().unwrap();
+        let cache_filename = dir.path().join("tool.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        write_cache(
+            cache_filename,
+            "1 |A a b:0.5 |B 3\n-1 2 0.5 'tag |A a\n|B 1.5\n-1 |A c\n",
+        );
+
+        let mut output: Vec<u8> = Vec::new();
+        write_stats(cache_filename, &mut output).unwrap();
+        let stats = String::from_utf8(output).unwrap();
+        assert!(stats.contains("examples: 4\n"), "{}", stats);
+        assert!(stats.contains("  -1: 2 (50.00%)\n  1: 1 (25.00%)\n  no label: 1 (25.00%)\n"));
+        assert!(stats.contains("features per example: 1.50\n"));
+        assert!(stats.contains("  A (featureA): 4 features in 3 examples, 1.00 per example\n"));
+        assert!(stats.contains("  B (featureB): 2 features in 2 examples, 0.50 per example\n"));
+
+        let mut output: Vec<u8> = Vec::new();
+        write_head(cache_filename, 3, &mut output).unwrap();
+        let head = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = head.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let hashes: Vec<&str> = lines[0].split(' ').filter(|t| t.starts_with('#')).collect();
+        assert_eq!(hashes.len(), 2);
+        assert!(hashes[1].ends_with(":0.5"));
+        assert_eq!(lines[0], format!("1 |A {} {} |B 3", hashes[0], hashes[1]));
+        assert_eq!(lines[1], format!("-1 2 0.5 'tag |A {}", hashes[0]));
+        assert_eq!(lines[2], "|B 1.5");
+
+        let mut output: Vec<u8> = Vec::new();
+        validate(cache_filename, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("{}: OK, 4 examples\n", cache_filename)
+        );
+
+        // Namespaces of string transforms are named by their verbose name and left out of head
+        let cache_filename = dir.path().join("transformed.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        let vw = vwmap::VwNamespaceMap::new(VW_MAP_STRING)
+            .unwrap()
+            .with_string_transforms(&vec!["prefix=Prefix(featureA)(1)"])
+            .unwrap();
+        write_cache_of_vw(
+            cache_filename,
+            &vw,
+            "1 |A ab ac |B 3
+",
+        );
+
+        let mut output: Vec<u8> = Vec::new();
+        write_stats(cache_filename, &mut output).unwrap();
+        let stats = String::from_utf8(output).unwrap();
+        assert!(
+            stats.contains(
+                "  prefix (string transform): 2 features in 1 examples, 2.00 per example\n"
+            ),
+            "{}",
+            stats
+        );
+
+        let mut output: Vec<u8> = Vec::new();
+        write_head(cache_filename, 1, &mut output).unwrap();
+        let head = String::from_utf8(output).unwrap();
+        let hashes: Vec<&str> = head.split(' ').filter(|t| t.starts_with('#')).collect();
+        assert_eq!(head, format!("1 |A {} {} |B 3\n", hashes[0], hashes[1]));
+    }
+
+    #[test]
+    fn test_validate_corrupted_cache() {
+        let dir = tempdir().unwrap();
+        let cache_filename = dir.path().join("corrupted.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        let vw = vwmap::VwNamespaceMap::new(VW_MAP_STRING).unwrap();
+        let mut vp = VowpalParser::new(&vw);
+        let mut record = vp
+            .next_vowpal(&mut Cursor::new(b"1 |A a b\n".to_vec()))
+            .unwrap()
+            .to_vec();
+        let mut cache =
+            cache::RecordCache::new(Some(cache_filename), &vw, cache::CacheCompression::None);
+        cache.push_record(&record).unwrap();
+        // Features of namespace A point past the end of the record
+        record[parser::HEADER_LEN as usize] += 2;
+        cache.push_record(&record).unwrap();
+        cache.write_finish().unwrap();
+
+        let mut output: Vec<u8> = Vec::new();
+        let err = validate(cache_filename, &mut output)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.ends_with(
+                "Block 0: example 1: features of namespace 0 at words 7..13 are outside the record"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let dir = tempdir().unwrap();
+        let input_filename = dir.path().join("input.fwcache");
+        let input_filename = input_filename.to_str().unwrap();
+        let examples: String = (0..200)
+            .map(|i| format!("{} |A a{}\n", if i % 4 == 0 { "1" } else { "-1" }, i))
+            .collect();
+        let records = write_cache(input_filename, &examples);
+
+        let output_filename = dir.path().join("positives.fwcache");
+        let output_filename = output_filename.to_str().unwrap();
+        let example_filter = ExampleFilter {
+            sample_rate: 1.0,
+            seed: 0,
+            labels: Some(vec![Some(1.0)]),
+        };
+        assert_eq!(
+            filter(input_filename, output_filename, &example_filter, None).unwrap(),
+            (200, 50)
+        );
+        let positives: Vec<Vec<u32>> = records
+            .iter()
+            .filter(|record| label_of(record) == Some(1.0))
+            .cloned()
+            .collect();
+        assert_eq!(read_all(output_filename), positives);
+        // The output is not overwritten
+        assert!(filter(input_filename, output_filename, &example_filter, None).is_err());
+
+        let output_filename = dir.path().join("sample.fwcache");
+        let output_filename = output_filename.to_str().unwrap();
+        let example_filter = ExampleFilter {
+            sample_rate: 0.5,
+            seed: 1,
+            labels: None,
+        };
+        let (num_read, num_written) = filter(
+            input_filename,
+            output_filename,
+            &example_filter,
+            Some(cache::CacheCompression::Zstd(3)),
+        )
+        .unwrap();
+        assert_eq!(num_read, 200);
+        assert!(num_written > 70 && num_written < 130, "{}", num_written);
+        let sample = read_all(output_filename);
+        assert_eq!(sample.len() as u64, num_written);
+        assert!(sample.iter().all(|record| records.contains(record)));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::version;
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 
 pub fn parse<'a>() -> clap::ArgMatches<'a> {
     let matches = create_expected_args().get_matches();
@@ -16,7 +16,31 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .long("data")
              .short("d")
              .value_name("filename")
-             .help("File with input examples")
+             .help("File with input examples, - for stdin. Can be given multiple times and can be a glob (e.g. 'data/2024-01-01-*.gz'), files are read in the given (globs in sorted) order")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("vw_namespace_map")
+             .long("vw_namespace_map")
+             .value_name("filename")
+             .help("Path of vw_namespace_map.csv, by default it is taken from the directory of the first --data file")
+             .takes_value(true))
+        .arg(Arg::with_name("data_format")
+             .long("data_format")
+             .value_name("vw|csv|tsv|jsonl")
+             .help("Format of --data: vw text (default), csv/tsv with a header row whose columns are mapped to namespaces by vw_namespace_map.csv, or jsonl with features keyed by verbose namespace names")
+             .possible_values(&["vw", "csv", "tsv", "jsonl"])
+             .takes_value(true))
+        .arg(Arg::with_name("max_bad_rows")
+             .long("max_bad_rows")
+             .value_name("N")
+             .help("Skip up to N rows of --data that can't be parsed, instead of stopping at the first one")
+             .takes_value(true))
+        .arg(Arg::with_name("bad_rows_file")
+             .long("bad_rows_file")
+             .value_name("filename")
+             .requires("max_bad_rows")
+             .help("Write rows skipped because of --max_bad_rows to this file")
              .takes_value(true))
         .arg(Arg::with_name("quiet")
              .long("quiet")
@@ -30,8 +54,34 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
         .arg(Arg::with_name("cache")
              .short("c")
              .long("cache")
-             .help("Use cache file")
+             .help("Use cache files, one next to each --data file (stdin is not cached)")
              .takes_value(false))
+        .arg(Arg::with_name("cache_file")
+             .long("cache_file")
+             .value_name("filename")
+             .help("Use this cache file instead of the one next to --data (implies --cache). With multiple --data files, this is a directory for their caches")
+             .takes_value(true))
+        .arg(Arg::with_name("cache_compression")
+             .long("cache_compression")
+             .value_name("none|lz4|zstd[:level]")
+             .help("Compression of newly created cache files, by default lz4 for .gz inputs and none otherwise. Existing caches are read with the compression they were written with")
+             .takes_value(true))
+        .arg(Arg::with_name("shard")
+             .long("shard")
+             .value_name("i/N")
+             .help("Read only shard i (counted from 0) of N from every cache file. Shards are made of blocks of examples, so they are only roughly equal. Needs existing caches")
+             .takes_value(true))
+        .arg(Arg::with_name("shuffle_seed")
+             .long("shuffle_seed")
+             .value_name("seed")
+             .help("Read blocks of examples of every cache file in an order shuffled by the seed (use a different seed for every pass). Needs existing caches")
+             .takes_value(true))
+        .arg(Arg::with_name("cache_seek")
+             .long("cache_seek")
+             .value_name("N")
+             .conflicts_with_all(&["shard", "shuffle_seed"])
+             .help("Skip the first N examples (over all --data files) by seeking in their caches. Needs existing caches")
+             .takes_value(true))
         .arg(Arg::with_name("save_resume")
              .long("save_resume")
              .help("save extra state so learning can be resumed later with new data")
@@ -59,6 +109,16 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("arg")
              .help("Build cache file without training the first model instance")
              .takes_value(false))
+        .arg(Arg::with_name("cache_threads")
+             .long("cache_threads")
+             .value_name("N")
+             .help("With --build_cache_without_training, parse chunks of the input on N threads (default: 1)")
+             .takes_value(true))
+        .arg(Arg::with_name("cache_unordered")
+             .long("cache_unordered")
+             .requires("cache_threads")
+             .help("With --cache_threads, write blocks of examples in the order they are parsed in, instead of the input order")
+             .takes_value(false))
 
         .arg(Arg::with_name("learning_rate")
              .short("l")
@@ -120,20 +180,42 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .takes_value(false))
         .arg(Arg::with_name("link")
              .long("link")
-             .value_name("logistic")
-             .help("What link function to use")
+             .value_name("logistic|identity")
+             .help("What link function to use (identity goes with squared loss)")
              .takes_value(true))
         .arg(Arg::with_name("loss_function")
              .long("loss_function")
-             .value_name("logistic")
+             .value_name("logistic|squared")
              .help("What loss function to use")
              .takes_value(true))
+        .arg(Arg::with_name("negative_sampling_rate")
+             .long("negative_sampling_rate")
+             .value_name("r")
+             .help("Train on negatives (label <= 0) kept with probability r. Predictions of the model (also when serving) are recalibrated for the rate with p / (p + (1-p)/r), unless --negative_sampling_importance is used")
+             .takes_value(true))
+        .arg(Arg::with_name("negative_sampling_seed")
+             .long("negative_sampling_seed")
+             .value_name("seed")
+             .requires("negative_sampling_rate")
+             .help("Seed for choosing the negatives kept by --negative_sampling_rate (default: 0)")
+             .takes_value(true))
+        .arg(Arg::with_name("negative_sampling_importance")
+             .long("negative_sampling_importance")
+             .requires("negative_sampling_rate")
+             .help("Multiply example importance of kept negatives by 1/r instead of recalibrating predictions. Doing both would correct for the sampling twice")
+             .takes_value(false))
         .arg(Arg::with_name("bit_precision")
              .short("b")
              .long("bit_precision")
              .value_name("18")
              .help("Size of the hash space for feature weights")
              .takes_value(true))
+        .arg(Arg::with_name("combo_bit_precision")
+             .long("combo_bit_precision")
+             .value_name("verbose_namespace,verbose_namespace,...=bits")
+             .help("Give a feature combo its own hash space of this many bits, instead of sharing the one of --bit_precision")
+             .multiple(true)
+             .takes_value(true))
         .arg(Arg::with_name("hash")
              .long("hash")
              .value_name("all")
@@ -169,11 +251,34 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .conflicts_with("adaptive")
              .help("Inference regressor to save (arg is filename)")
              .takes_value(true))
+        .arg(Arg::with_name("weights_quantization")
+             .long("weights_quantization")
+             .value_name("none|f16|int8")
+             .requires("convert_inference_regressor")
+             .help("Store weights of the inference regressor quantized. With --data, predictions before and after quantization are compared")
+             .conflicts_with("prune")
+             .takes_value(true))
+        .arg(Arg::with_name("prune")
+             .long("prune")
+             .requires("convert_inference_regressor")
+             .help("Store LR and FFM weights of the inference regressor in a sparse table, dropping FFM weights untouched by training. Dropped FFM weights read as 0 instead of their random initial value, so predictions on hashes unseen in training intentionally differ from the unpruned regressor. With --data, predictions before and after pruning are compared")
+             .takes_value(false))
+        .arg(Arg::with_name("prune_lr_threshold")
+             .long("prune_lr_threshold")
+             .value_name("threshold")
+             .requires("prune")
+             .help("Drop LR weights with absolute value not above threshold (default 0.0)")
+             .takes_value(true))
+        .arg(Arg::with_name("weights_page_aligned")
+             .long("weights_page_aligned")
+             .requires("convert_inference_regressor")
+             .help("Save the inference regressor page aligned, so its weights are memory-mapped read-only when loaded. Such regressors can not be hot-swapped with hogwild_load")
+             .takes_value(false))
 
         .arg(Arg::with_name("transform")
              .long("transform")
              .value_name("target_namespace=func(source_namespaces)(parameters)")
-             .help("Create new namespace by transforming one or more other namespaces")
+             .help("Create new namespace by transforming one or more other namespaces (Prefix, Domain, Lowercase and SplitTokens transform feature names while parsing)")
              .multiple(true)
              .takes_value(true))
 
@@ -199,6 +304,12 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("N")
              .help("Bits to use for ffm hash space")
              .takes_value(true))
+        .arg(Arg::with_name("ffm_field_bit_precision")
+             .long("ffm_field_bit_precision")
+             .value_name("verbose_namespace,verbose_namespace,...=bits")
+             .help("Give a FFM field its own hash space of this many bits, instead of sharing the one of --ffm_bit_precision")
+             .multiple(true)
+             .takes_value(true))
         .arg(Arg::with_name("ffm_k_threshold")
              .long("ffm_k_threshold")
              .help("A minum gradient on left and right side to increase k")
@@ -287,6 +398,15 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("examples (0)")
              .help("Output predictions with a model that is delayed by a number of examples")
              .takes_value(true))
+        .arg(Arg::with_name("debug_transforms")
+             .long("debug_transforms")
+             .value_name("examples (=0)")
+             .help("Print namespaces made by --transform (with values of their from namespaces) for this many first examples")
+             .takes_value(true))
+        .arg(Arg::with_name("hash_statistics")
+             .long("hash_statistics")
+             .help("Count distinct feature hashes of each feature combo and ffm field, and report estimated collision rates of --bit_precision and --ffm_bit_precision")
+             .takes_value(false))
         .arg(Arg::with_name("predictions_after")
              .long("predictions_after")
              .value_name("examples (=0)")
@@ -299,4 +419,91 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("examples")
              .help("After how many examples stop updating weights")
              .takes_value(true))
+
+    // Subcommands
+        .subcommand(SubCommand::with_name("upgrade-model")
+             .about("Rewrites a regressor saved by an older version of fw in the current format")
+             .arg(Arg::with_name("initial_regressor")
+                  .short("i")
+                  .long("initial_regressor")
+                  .value_name("arg")
+                  .help("Regressor to upgrade (arg is filename)")
+                  .required(true)
+                  .takes_value(true))
+             .arg(Arg::with_name("final_regressor")
+                  .short("f")
+                  .long("final_regressor")
+                  .value_name("arg")
+                  .help("Where to save the upgraded regressor (arg is filename)")
+                  .required(true)
+                  .takes_value(true)))
+        .subcommand(SubCommand::with_name("merge")
+             .about("Averages weights of regressors trained with the same configuration (e.g. on different data shards)")
+             .arg(Arg::with_name("input_regressors")
+                  .value_name("regressor")
+                  .help("Regressors to merge")
+                  .required(true)
+                  .multiple(true)
+                  .min_values(2))
+             .arg(Arg::with_name("final_regressor")
+                  .short("f")
+                  .long("final_regressor")
+                  .value_name("arg")
+                  .help("Where to save the merged regressor (arg is filename)")
+                  .required(true)
+                  .takes_value(true))
+             .arg(Arg::with_name("example_counts")
+                  .long("example_counts")
+                  .value_name("n,n,...")
+                  .help("Weigh regressors by number of examples each was trained on, instead of equally")
+                  .takes_value(true)))
+        .subcommand(SubCommand::with_name("cache")
+             .about("Inspects and converts cache files")
+             .setting(AppSettings::SubcommandRequiredElseHelp)
+             .subcommand(SubCommand::with_name("stats")
+                  .about("Reports the number of examples, label distribution and features of every namespace")
+                  .arg(Arg::with_name("cache_file")
+                       .value_name("cache_file")
+                       .required(true)))
+             .subcommand(SubCommand::with_name("head")
+                  .about("Writes the first N examples as vw text (feature names are not cached, so features are written as #hash)")
+                  .arg(Arg::with_name("cache_file")
+                       .value_name("cache_file")
+                       .required(true))
+                  .arg(Arg::with_name("num_examples")
+                       .value_name("N")
+                       .help("Number of examples (default: 10)")))
+             .subcommand(SubCommand::with_name("validate")
+                  .about("Reads the whole cache file and checks that its records are consistent")
+                  .arg(Arg::with_name("cache_file")
+                       .value_name("cache_file")
+                       .required(true)))
+             .subcommand(SubCommand::with_name("filter")
+                  .about("Writes a new cache file with a sample of the examples and/or the examples with given labels")
+                  .arg(Arg::with_name("cache_file")
+                       .value_name("cache_file")
+                       .required(true))
+                  .arg(Arg::with_name("output_cache_file")
+                       .value_name("output_cache_file")
+                       .required(true))
+                  .arg(Arg::with_name("sample_rate")
+                       .long("sample_rate")
+                       .value_name("rate")
+                       .help("Keep every example with this probability (default: 1.0)")
+                       .takes_value(true))
+                  .arg(Arg::with_name("seed")
+                       .long("seed")
+                       .value_name("seed")
+                       .help("Seed of sampling (default: 0)")
+                       .takes_value(true))
+                  .arg(Arg::with_name("labels")
+                       .long("labels")
+                       .value_name("label,label,...")
+                       .help("Keep only examples with one of these labels, \"none\" stands for examples without a label")
+                       .takes_value(true))
+                  .arg(Arg::with_name("cache_compression")
+                       .long("cache_compression")
+                       .value_name("none|lz4|zstd[:level]")
+                       .help("Compression of the output cache file, by default the one of the input")
+                       .takes_value(true))))
 }
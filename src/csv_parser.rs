@@ -0,0 +1,450 @@
+use crate::feature_transform_string;
+use crate::parser::*;
+use crate::vwmap;
+use fasthash::murmur3;
+use std::error::Error;
+use std::io::BufRead;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::str;
+
+/*
+Columnar (csv/tsv) input. The first row is a header, every column is looked up in vw_namespace_map.csv:
+    - by default a namespace is read from the column named by its verbose name, fourth field of the map overrides that
+    - "_label_column" (default: "label") holds the label, empty means no label
+    - "_importance_column", "_initial_prediction_column" and "_tag_column" (all optional) hold the rest of vw label syntax
+    - every non-empty cell of a categorical namespace is a single feature, f32 namespaces parse the cell as a float
+    - columns not declared in vw_namespace_map.csv are ignored
+Output is the same records buffer that VowpalParser produces.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Column {
+    Ignored,
+    Label,
+    Importance,
+    InitialPrediction,
+    Tag,
+    Namespace(vwmap::NamespaceDescriptor),
+}
+
+#[derive(Clone)]
+pub struct CsvParser {
+    vw_map: vwmap::VwNamespaceMap,
+    delimiter: u8,
+    namespace_hash_seeds: Vec<u32>,
+    columns: Option<Vec<Column>>, // None until the header has been read
+    tmp_read_buf: Vec<u8>,
+    line_number: u64,
+    field_buf: Vec<u8>, // unquoted contents of all fields of the current row
+    field_bounds: Vec<(usize, usize)>, // start and end of every field in field_buf
+    string_transformer: feature_transform_string::StringTransformer,
+    pub output_buffer: Vec<u32>,
+}
+
+impl CsvParser {
+    pub fn new(vw: &vwmap::VwNamespaceMap, delimiter: u8) -> CsvParser {
+        let mut namespace_hash_seeds: Vec<u32> = vec![0; vw.num_namespaces];
+        for (i, entry) in vw.vw_source.entries.iter().enumerate() {
+            namespace_hash_seeds[i] = murmur3::hash32(&entry.namespace_vwname);
+        }
+        CsvParser {
+            vw_map: (*vw).clone(),
+            delimiter: delimiter,
+            namespace_hash_seeds: namespace_hash_seeds,
+            columns: None,
+            tmp_read_buf: Vec::new(),
+            line_number: 0,
+            field_buf: Vec::new(),
+            field_bounds: Vec::new(),
+            string_transformer: feature_transform_string::StringTransformer::new(vw),
+            output_buffer: Vec::new(),
+        }
+    }
+
+    // Reads the next non-empty row into field_buf and field_bounds, returns false on EOF
+    fn read_row(&mut self, input_bufread: &mut dyn BufRead) -> Result<bool, Box<dyn Error>> {
+        loop {
+            self.tmp_read_buf.truncate(0);
+            if input_bufread.read_until(0x0a, &mut self.tmp_read_buf)? == 0 {
+                return Ok(false);
+            }
+            self.line_number += 1;
+            while let Some(0x0a) | Some(0x0d) = self.tmp_read_buf.last() {
+                self.tmp_read_buf.pop();
+            }
+            if !self.tmp_read_buf.is_empty() {
+                break;
+            }
+        }
+
+        self.field_buf.truncate(0);
+        self.field_bounds.truncate(0);
+        let line = &self.tmp_read_buf;
+        let mut i = 0;
+        loop {
+            let start = self.field_buf.len();
+            if i < line.len() && line[i] == b'"' {
+                // quoted field, "" stands for a quote, quoted fields can't span lines
+                i += 1;
+                loop {
+                    if i >= line.len() {
+                        return Err(Box::new(IOError::new(
+                            ErrorKind::Other,
+                            format!(
+                                "Unterminated quoted field in row: {}",
+                                String::from_utf8_lossy(line)
+                            ),
+                        )));
+                    }
+                    if line[i] == b'"' {
+                        if i + 1 < line.len() && line[i + 1] == b'"' {
+                            self.field_buf.push(b'"');
+                            i += 2;
+                        } else {
+                            i += 1;
+                            break;
+                        }
+                    } else {
+                        self.field_buf.push(line[i]);
+                        i += 1;
+                    }
+                }
+                if i < line.len() && line[i] != self.delimiter {
+                    return Err(Box::new(IOError::new(
+                        ErrorKind::Other,
+                        format!(
+                            "Unexpected characters after quoted field in row: {}",
+                            String::from_utf8_lossy(line)
+                        ),
+                    )));
+                }
+            } else {
+                while i < line.len() && line[i] != self.delimiter {
+                    self.field_buf.push(line[i]);
+                    i += 1;
+                }
+            }
+            self.field_bounds.push((start, self.field_buf.len()));
+            if i >= line.len() {
+                break;
+            }
+            i += 1; // skip delimiter
+        }
+        Ok(true)
+    }
+
+    fn columns_from_header(&self) -> Result<Vec<Column>, Box<dyn Error>> {
+        let label_column = match &self.vw_map.vw_source.label_column {
+            Some(column) => column.as_str(),
+            None => vwmap::DEFAULT_LABEL_COLUMN,
+        };
+        let importance_column = self.vw_map.vw_source.importance_column.as_deref();
+        let initial_prediction_column = self.vw_map.vw_source.initial_prediction_column.as_deref();
+        let tag_column = self.vw_map.vw_source.tag_column.as_deref();
+        let mut columns: Vec<Column> = Vec::new();
+        let mut ignored: Vec<String> = Vec::new();
+        for (start, end) in &self.field_bounds {
+            let name = str::from_utf8(&self.field_buf[*start..*end])?;
+            let column = if name == label_column {
+                Column::Label
+            } else if Some(name) == importance_column {
+                Column::Importance
+            } else if Some(name) == initial_prediction_column {
+                Column::InitialPrediction
+            } else if Some(name) == tag_column {
+                Column::Tag
+            } else {
+                match self.vw_map.map_column_to_namespace_descriptor.get(name) {
+                    Some(namespace_descriptor) => Column::Namespace(*namespace_descriptor),
+                    None => {
+                        ignored.push(name.to_string());
+                        Column::Ignored
+                    }
+                }
+            };
+            if column != Column::Ignored && columns.contains(&column) {
+                return Err(format!("Column \"{}\" appears twice in the header", name))?;
+            }
+            columns.push(column);
+        }
+        if !ignored.is_empty() {
+            println!(
+                "Ignoring columns not declared in vw_namespace_map.csv: {}",
+                ignored.join(", ")
+            );
+        }
+        Ok(columns)
+    }
+
+    fn parse_float_or_error(
+        &self,
+        i_start: usize,
+        i_end: usize,
+        error_str: &str,
+    ) -> Result<f32, Box<dyn Error>> {
+        let field = &self.field_buf[i_start..i_end];
+        match str::from_utf8(field)
+            .ok()
+            .and_then(|s| s.trim().parse::<f32>().ok())
+        {
+            Some(f) => Ok(f),
+            None => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("{}: {}", error_str, String::from_utf8_lossy(field)),
+            ))),
+        }
+    }
+
+    pub fn next_csv(&mut self, input_bufread: &mut dyn BufRead) -> Result<&[u32], Box<dyn Error>> {
+        if self.columns.is_none() {
+            // Rows can't be parsed without a header, so its errors are fatal
+            match self.read_row(input_bufread) {
+                Ok(false) => return Ok(&[]),
+                Ok(true) => {}
+                Err(e) => {
+                    return Err(Box::new(FatalParseError {
+                        message: format!("Cannot parse the header: {}", e),
+                    }))
+                }
+            }
+            match self.columns_from_header() {
+                Ok(columns) => self.columns = Some(columns),
+                Err(e) => {
+                    return Err(Box::new(FatalParseError {
+                        message: format!("Cannot parse the header: {}", e),
+                    }))
+                }
+            }
+        }
+        if !self.read_row(input_bufread)? {
+            return Ok(&[]);
+        }
+        let columns = self.columns.as_ref().unwrap();
+        if columns.len() != self.field_bounds.len() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Row has {} columns, while the header has {}",
+                    self.field_bounds.len(),
+                    columns.len()
+                ),
+            )));
+        }
+
+        let bufpos: usize = self.vw_map.num_namespaces + HEADER_LEN as usize;
+        self.output_buffer.truncate(0);
+        self.output_buffer.resize(bufpos, NO_FEATURES);
+        self.output_buffer[LABEL_OFFSET] = NO_LABEL;
+        self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = FLOAT32_ONE;
+        self.output_buffer[INITIAL_PREDICTION_OFFSET] = 0.0f32.to_bits();
+        self.output_buffer[TAG_OFFSET] = NO_TAG;
+        self.string_transformer.start_record();
+
+        for (column, (i_start, i_end)) in columns.iter().zip(self.field_bounds.iter()) {
+            let (i_start, i_end) = (*i_start, *i_end);
+            if i_start == i_end {
+                continue; // empty cell, no feature/label
+            }
+            match column {
+                Column::Ignored => {}
+                Column::Label => {
+                    let label =
+                        self.parse_float_or_error(i_start, i_end, "Failed parsing label")?;
+                    self.output_buffer[LABEL_OFFSET] = label.to_bits();
+                }
+                Column::InitialPrediction => {
+                    let initial_prediction = self.parse_float_or_error(
+                        i_start,
+                        i_end,
+                        "Failed parsing initial prediction",
+                    )?;
+                    self.output_buffer[INITIAL_PREDICTION_OFFSET] = initial_prediction.to_bits();
+                }
+                Column::Tag => {
+                    push_tag(&mut self.output_buffer, &self.field_buf[i_start..i_end])?;
+                }
+                Column::Importance => {
+                    let importance = self.parse_float_or_error(
+                        i_start,
+                        i_end,
+                        "Failed parsing example importance",
+                    )?;
+                    if importance < 0.0 {
+                        return Err(Box::new(IOError::new(
+                            ErrorKind::Other,
+                            format!("Example importance cannot be negative: {:?}! ", importance),
+                        )));
+                    }
+                    self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = importance.to_bits();
+                }
+                Column::Namespace(namespace_descriptor) => {
+                    let namespace_index = namespace_descriptor.namespace_index as usize;
+                    let h = murmur3::hash32_with_seed(
+                        &self.field_buf[i_start..i_end],
+                        self.namespace_hash_seeds[namespace_index],
+                    ) & MASK31;
+                    let namespace_index_offset =
+                        namespace_index * NAMESPACE_DESC_LEN as usize + HEADER_LEN as usize;
+                    match namespace_descriptor.namespace_format {
+                        vwmap::NamespaceFormat::Categorical => {
+                            self.output_buffer[namespace_index_offset] = h;
+                            self.string_transformer.add_feature(
+                                namespace_index,
+                                &self.field_buf[i_start..i_end],
+                                1.0,
+                            );
+                        }
+                        vwmap::NamespaceFormat::F32 => {
+                            let float_value = self.parse_float_or_error(
+                                i_start,
+                                i_end,
+                                "Failed parsing feature value to float (for float namespace)",
+                            )?;
+                            let bufpos_namespace_start = self.output_buffer.len();
+                            self.output_buffer.push(h);
+                            self.output_buffer.push(float_value.to_bits());
+                            self.output_buffer[namespace_index_offset] = IS_NOT_SINGLE_MASK
+                                | (((bufpos_namespace_start << 16) + self.output_buffer.len())
+                                    as u32);
+                        }
+                    }
+                }
+            }
+        }
+        self.string_transformer
+            .write_namespaces(&mut self.output_buffer);
+        self.output_buffer[0] = self.output_buffer.len() as u32;
+        Ok(&self.output_buffer)
+    }
+}
+
+impl RecordParser for CsvParser {
+    fn next_record(&mut self, input_bufread: &mut dyn BufRead) -> Result<&[u32], Box<dyn Error>> {
+        self.next_csv(input_bufread)
+    }
+
+    fn last_line(&self) -> (u64, &[u8]) {
+        (self.line_number, &self.tmp_read_buf)
+    }
+
+    fn last_record(&self) -> &[u32] {
+        &self.output_buffer
+    }
+
+    fn reset_input(&mut self) {
+        self.line_number = 0;
+        self.columns = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::io::Cursor;
+
+    fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+        Cursor::new(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_same_records_as_vowpal() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+C,featureC,f32
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut vp = VowpalParser::new(&vw);
+        let mut rr = CsvParser::new(&vw, b'\t');
+        let mut buf = str_to_cursor(
+            "featureB\tlabel\tunknown\tfeatureA\tfeatureC\nb\t1\tx\ta\t2.5\n\t-1\tx\ta\t\n\t\t\t\t\n",
+        );
+        let mut vw_buf = str_to_cursor("1 |A a |B b |C 2.5\n-1 |A a\n|A\n");
+        for _ in 0..3 {
+            let expected = vp.next_vowpal(&mut vw_buf).unwrap().to_vec();
+            assert_eq!(rr.next_csv(&mut buf).unwrap(), expected.as_slice());
+        }
+        assert_eq!(rr.next_csv(&mut buf).unwrap().len(), 0);
+
+        // The next input has its own header
+        rr.reset_input();
+        let mut buf = str_to_cursor("label\tfeatureA\n1\ta\n");
+        let mut vw_buf = str_to_cursor("1 |A a\n");
+        let expected = vp.next_vowpal(&mut vw_buf).unwrap().to_vec();
+        assert_eq!(rr.next_csv(&mut buf).unwrap(), expected.as_slice());
+        assert_eq!(rr.last_line().0, 2);
+    }
+
+    #[test]
+    fn test_csv_quoting_and_columns() {
+        let vw_map_string = r#"
+_label_column,click
+_importance_column,weight
+A,featureA,,col a
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut vp = VowpalParser::new(&vw);
+        let mut rr = CsvParser::new(&vw, b',');
+        let mut buf =
+            str_to_cursor("\"col a\",click,weight\r\n\"a\",0,2.0\r\n\r\n\"x,\"\"y\",1,\n");
+        let mut vw_buf = str_to_cursor("0 2.0 |A a\n1 |A x,\"y\n");
+        for _ in 0..2 {
+            let expected = vp.next_vowpal(&mut vw_buf).unwrap().to_vec();
+            assert_eq!(rr.next_csv(&mut buf).unwrap(), expected.as_slice());
+        }
+        assert_eq!(rr.next_csv(&mut buf).unwrap().len(), 0);
+
+        let mut rr = CsvParser::new(&vw, b',');
+        let mut buf = str_to_cursor("col a,click\na,1,2\n");
+        assert!(rr.next_csv(&mut buf).is_err());
+        let mut rr = CsvParser::new(&vw, b',');
+        let mut buf = str_to_cursor("col a,click\na,x\n");
+        assert!(rr.next_csv(&mut buf).is_err());
+        let mut rr = CsvParser::new(&vw, b',');
+        let mut buf = str_to_cursor("col a,click\n\"a,1\n");
+        assert!(rr.next_csv(&mut buf).is_err());
+
+        // Bad rows can be skipped, a bad header can't
+        let mut pa =
+            FaultTolerantParser::new(Box::new(CsvParser::new(&vw, b',')), 5, None).unwrap();
+        let mut buf = str_to_cursor("col a,click\na,x\nb,1\n");
+        assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], FLOAT32_ONE);
+        assert_eq!(pa.bad_rows, 1);
+        let mut pa =
+            FaultTolerantParser::new(Box::new(CsvParser::new(&vw, b',')), 5, None).unwrap();
+        let mut buf = str_to_cursor("click,click\n1,1\n");
+        let err = pa.next_record(&mut buf).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Cannot parse the header: Column \"click\" appears twice in the header"
+        );
+    }
+
+    #[test]
+    fn test_initial_prediction_and_tag_columns() {
+        let vw_map_string = r#"
+_initial_prediction_column,base
+_tag_column,id
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut vp = VowpalParser::new(&vw);
+        let mut rr = CsvParser::new(&vw, b',');
+        let mut buf = str_to_cursor("id,label,featureA,base\nrow 1,3.5,a,-0.5\n,1,b,\n");
+        let mut vw_buf = str_to_cursor("3.5 1 -0.5 'row1 |A a\n1 |A b\n");
+        let record = rr.next_csv(&mut buf).unwrap().to_vec();
+        let expected = vp.next_vowpal(&mut vw_buf).unwrap();
+        assert_eq!(record[LABEL_OFFSET], 3.5f32.to_bits());
+        assert_eq!(
+            record[INITIAL_PREDICTION_OFFSET],
+            expected[INITIAL_PREDICTION_OFFSET]
+        );
+        assert_eq!(get_tag(&record), b"row 1");
+        assert_eq!(record[HEADER_LEN as usize], expected[HEADER_LEN as usize]);
+        // Empty tag and initial prediction columns are the defaults
+        let expected = vp.next_vowpal(&mut vw_buf).unwrap().to_vec();
+        assert_eq!(rr.next_csv(&mut buf).unwrap(), expected.as_slice());
+    }
+}
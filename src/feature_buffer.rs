@@ -1,4 +1,5 @@
 use crate::feature_transform_executor;
+use crate::hash_statistics;
 use crate::model_instance;
 use crate::parser;
 use crate::vwmap::{NamespaceFormat, NamespaceType};
@@ -26,6 +27,8 @@ pub struct FeatureBuffer {
     pub label: f32,
     pub example_importance: f32,
     pub example_number: u64,
+    pub initial_prediction: f32,
+    pub tag: Vec<u8>, // example id from the input, written next to predictions
     pub lr_buffer: Vec<HashAndValue>,
     pub ffm_buffer: Vec<HashAndValueAndSeq>,
     pub ffm_fields_count: u32,
@@ -39,8 +42,10 @@ pub struct FeatureBufferTranslator {
     hashes_vec_out: Vec<HashAndValue>,
     pub feature_buffer: FeatureBuffer,
     pub lr_hash_mask: u32,
-    pub ffm_hash_mask: u32,
+    pub lr_hash_spaces: Vec<model_instance::HashSpace>, // of each feature combo
+    pub ffm_hash_spaces: Vec<model_instance::HashSpace>, // of each ffm field
     pub transform_executors: feature_transform_executor::TransformExecutors,
+    pub hash_statistics: Option<hash_statistics::HashStatistics>, // collected only with --hash_statistics
 }
 
 // A macro that takes care of decoding the individual feature - which can have two different encodings
@@ -61,7 +66,7 @@ macro_rules! feature_reader {
                     .get_unchecked($namespace_descriptor.namespace_index as usize)
             };
 
-            // If we have a cyclic defintion (which is a bug), this will panic!
+            // Borrowing can't fail: NamespaceTransformsParser::resolve() rejects cyclic definitions
             let mut namespace_to = executor.namespace_to.borrow_mut();
             namespace_to.tmp_data.truncate(0);
 
@@ -137,21 +142,18 @@ macro_rules! feature_reader_float_namespace {
 
 impl FeatureBufferTranslator {
     pub fn new(mi: &model_instance::ModelInstance) -> FeatureBufferTranslator {
-        // Calculate lr_hash_mask
+        // Calculate lr_hash_mask (of the constant and combos without their own bit precision)
         let lr_hash_mask = (1 << mi.bit_precision) - 1;
-        // Calculate ffm_hash_mask
-        let mut ffm_bits_for_dimensions = 0;
-        while mi.ffm_k > (1 << (ffm_bits_for_dimensions)) {
-            ffm_bits_for_dimensions += 1;
-        }
-        let dimensions_mask = (1 << ffm_bits_for_dimensions) - 1;
-        // in ffm we will simply mask the lower bits, so we spare them for k
-        let ffm_hash_mask = ((1 << mi.ffm_bit_precision) - 1) ^ dimensions_mask;
+        // Hash spaces of combos and ffm fields (in ffm the lower bits are masked, so we spare them for k)
+        let (lr_hash_spaces, _) = mi.lr_hash_spaces();
+        let (ffm_hash_spaces, _) = mi.ffm_hash_spaces();
 
         let mut fb = FeatureBuffer {
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: Vec::new(),
             ffm_buffer: Vec::new(),
             ffm_fields_count: 0,
@@ -164,11 +166,13 @@ impl FeatureBufferTranslator {
             hashes_vec_out: Vec::with_capacity(100),
             feature_buffer: fb,
             lr_hash_mask: lr_hash_mask,
-            ffm_hash_mask: ffm_hash_mask,
+            lr_hash_spaces: lr_hash_spaces,
+            ffm_hash_spaces: ffm_hash_spaces,
             transform_executors:
                 feature_transform_executor::TransformExecutors::from_namespace_transforms(
                     &mi.transform_namespaces,
                 ),
+            hash_statistics: None,
         };
         fbt
     }
@@ -177,20 +181,47 @@ impl FeatureBufferTranslator {
         println!("item out {:?}", self.feature_buffer.lr_buffer);
     }
 
+    pub fn print_transforms(&self, record_buffer: &[u32], example_number: u64) -> () {
+        println!(
+            "Example {}, transformed namespaces:\n{}",
+            example_number,
+            self.transform_executors
+                .debug_transforms(&self.model_instance.transform_namespaces, record_buffer)
+        );
+    }
+
     pub fn translate(&mut self, record_buffer: &[u32], example_number: u64) -> () {
         {
             let lr_buffer = &mut self.feature_buffer.lr_buffer;
             lr_buffer.truncate(0);
-            self.feature_buffer.label = record_buffer[parser::LABEL_OFFSET] as f32; // copy label
+            let label = record_buffer[parser::LABEL_OFFSET];
+            self.feature_buffer.label = if label == parser::NO_LABEL {
+                f32::NAN
+            } else {
+                match self.model_instance.loss_function {
+                    model_instance::LossFunction::Logistic => {
+                        f32::from_bits(label).max(0.0).min(1.0)
+                    }
+                    model_instance::LossFunction::Squared => f32::from_bits(label),
+                }
+            };
             self.feature_buffer.example_importance =
                 f32::from_bits(record_buffer[parser::EXAMPLE_IMPORTANCE_OFFSET]);
+            self.feature_buffer.initial_prediction =
+                f32::from_bits(record_buffer[parser::INITIAL_PREDICTION_OFFSET]);
+            self.feature_buffer.tag.truncate(0);
+            self.feature_buffer
+                .tag
+                .extend_from_slice(parser::get_tag(record_buffer));
             self.feature_buffer.example_number = example_number;
             let mut output_len: usize = 0;
             let mut hashes_vec_in: &mut Vec<HashAndValue> = &mut self.hashes_vec_in;
             let mut hashes_vec_out: &mut Vec<HashAndValue> = &mut self.hashes_vec_out;
+            let hash_statistics = &mut self.hash_statistics;
             for (combo_index, feature_combo_desc) in
                 self.model_instance.feature_combo_descs.iter().enumerate()
             {
+                let hash_space = unsafe { *self.lr_hash_spaces.get_unchecked(combo_index) };
                 let combo_index = combo_index as u32;
                 let feature_combo_weight = feature_combo_desc.weight;
                 // we unroll first iteration of the loop and optimize
@@ -206,8 +237,11 @@ impl FeatureBufferTranslator {
                         hash_index,
                         hash_value,
                         {
+                            if let Some(hs) = hash_statistics.as_mut() {
+                                hs.add_lr(combo_index, hash_index);
+                            }
                             lr_buffer.push(HashAndValue {
-                                hash: hash_index & self.lr_hash_mask,
+                                hash: (hash_index & hash_space.mask) + hash_space.offset,
                                 value: hash_value * feature_combo_weight,
                                 combo_index: combo_index,
                             });
@@ -254,9 +288,14 @@ impl FeatureBufferTranslator {
                         }
                         std::mem::swap(&mut hashes_vec_in, &mut hashes_vec_out);
                     }
+                    if let Some(hs) = hash_statistics.as_mut() {
+                        for handv in &(*hashes_vec_in) {
+                            hs.add_lr(combo_index, handv.hash);
+                        }
+                    }
                     for handv in &(*hashes_vec_in) {
                         lr_buffer.push(HashAndValue {
-                            hash: handv.hash & self.lr_hash_mask,
+                            hash: (handv.hash & hash_space.mask) + hash_space.offset,
                             value: handv.value * feature_combo_weight,
                             combo_index: combo_index,
                         });
@@ -284,6 +323,8 @@ impl FeatureBufferTranslator {
                 for (contra_field_index, ffm_field) in
                     self.model_instance.ffm_fields.iter().enumerate()
                 {
+                    let hash_space =
+                        unsafe { *self.ffm_hash_spaces.get_unchecked(contra_field_index) };
                     for namespace_descriptor in ffm_field {
                         feature_reader!(
                             record_buffer,
@@ -292,8 +333,11 @@ impl FeatureBufferTranslator {
                             hash_index,
                             hash_value,
                             {
+                                if let Some(hs) = hash_statistics.as_mut() {
+                                    hs.add_ffm(contra_field_index, hash_index);
+                                }
                                 ffm_buffer.push(HashAndValueAndSeq {
-                                    hash: hash_index & self.ffm_hash_mask,
+                                    hash: (hash_index & hash_space.mask) + hash_space.offset,
                                     value: hash_value,
                                     contra_field_index: contra_field_index as u32
                                         * self.model_instance.ffm_k as u32,
@@ -314,7 +358,7 @@ mod tests {
     use crate::vwmap::{NamespaceDescriptor, NamespaceFormat, NamespaceType};
 
     fn add_header(v2: Vec<u32>) -> Vec<u32> {
-        let mut rr: Vec<u32> = vec![100, 1, 1.0f32.to_bits()];
+        let mut rr: Vec<u32> = vec![100, 1.0f32.to_bits(), 1.0f32.to_bits(), 0, parser::NO_TAG];
         rr.extend(v2);
         rr
     }
@@ -347,6 +391,7 @@ mod tests {
             .push(model_instance::FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(0)],
                 weight: 1.0,
+                bit_precision: None,
             });
 
         let mut fbt = FeatureBufferTranslator::new(&mi);
@@ -370,6 +415,7 @@ mod tests {
             .push(model_instance::FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(0)],
                 weight: 1.0,
+                bit_precision: None,
             });
 
         let mut fbt = FeatureBufferTranslator::new(&mi);
@@ -389,7 +435,7 @@ mod tests {
         );
 
         let rb = add_header(vec![
-            parser::IS_NOT_SINGLE_MASK | nd(4, 8),
+            parser::IS_NOT_SINGLE_MASK | nd(6, 10),
             0xfea,
             1.0f32.to_bits(),
             0xfeb,
@@ -421,11 +467,13 @@ mod tests {
             .push(model_instance::FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(0)],
                 weight: 1.0,
+                bit_precision: None,
             });
         mi.feature_combo_descs
             .push(model_instance::FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(1)],
                 weight: 1.0,
+                bit_precision: None,
             });
 
         let mut fbt = FeatureBufferTranslator::new(&mi);
@@ -474,6 +522,7 @@ mod tests {
             .push(model_instance::FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(0), ns_desc(1)],
                 weight: 1.0,
+                bit_precision: None,
             });
 
         let mut fbt = FeatureBufferTranslator::new(&mi);
@@ -510,6 +559,7 @@ mod tests {
             .push(model_instance::FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(0)],
                 weight: 2.0,
+                bit_precision: None,
             });
 
         let mut fbt = FeatureBufferTranslator::new(&mi);
@@ -525,6 +575,97 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hash_statistics() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![ns_desc(0)],
+                weight: 1.0,
+                bit_precision: None,
+            });
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![ns_desc(0), ns_desc(1)],
+                weight: 1.0,
+                bit_precision: None,
+            });
+        mi.ffm_fields.push(vec![ns_desc(1)]);
+        mi.ffm_k = 1;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.hash_statistics = Some(hash_statistics::HashStatistics::new(&mi));
+        fbt.translate(&add_header(vec![0xfea, 0xfeb]), 0);
+        fbt.translate(&add_header(vec![0xfea, 0xfec]), 0);
+        fbt.translate(&add_header(vec![0xfea, 0xfec]), 0);
+        // Distinct hashes are counted before they are masked, the constant is not counted
+        let hs = fbt.hash_statistics.as_ref().unwrap();
+        let distinct: Vec<f64> = hs.lr_counters.iter().map(|c| c.distinct()).collect();
+        assert_eq!(distinct, vec![1.0, 2.0]);
+        assert_eq!(hs.ffm_counters[0].distinct(), 2.0);
+    }
+
+    #[test]
+    fn test_hash_spaces() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 4;
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![ns_desc(0)],
+                weight: 1.0,
+                bit_precision: None,
+            });
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![ns_desc(1)],
+                weight: 1.0,
+                bit_precision: Some(8),
+            });
+        mi.ffm_bit_precision = 4;
+        mi.ffm_fields.push(vec![ns_desc(0)]);
+        mi.ffm_fields.push(vec![ns_desc(1)]);
+        mi.ffm_fields_bit_precision = vec![None, Some(8)];
+        mi.ffm_k = 1;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(&add_header(vec![0xfea, 0xfeb]), 0);
+        // Own hash space comes after the 16 shared weights
+        assert_eq!(
+            fbt.feature_buffer.lr_buffer,
+            vec![
+                HashAndValue {
+                    hash: 0xa,
+                    value: 1.0,
+                    combo_index: 0
+                },
+                HashAndValue {
+                    hash: 16 + 0xeb,
+                    value: 1.0,
+                    combo_index: 1
+                },
+                HashAndValue {
+                    hash: CONSTANT_HASH & 0xf,
+                    value: 1.0,
+                    combo_index: 2
+                }
+            ]
+        );
+        // In ffm each hash space is followed by spillover of ffm_k * 2 fields
+        assert_eq!(
+            fbt.feature_buffer.ffm_buffer,
+            vec![
+                HashAndValueAndSeq {
+                    hash: 0xa,
+                    value: 1.0,
+                    contra_field_index: 0
+                },
+                HashAndValueAndSeq {
+                    hash: 16 + 2 + 0xeb,
+                    value: 1.0,
+                    contra_field_index: 1
+                }
+            ]
+        );
+    }
+
     #[test]
     fn test_ffm_empty() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
@@ -565,7 +706,7 @@ mod tests {
         mi.ffm_k = 1;
         let mut fbt = FeatureBufferTranslator::new(&mi);
         let rb = add_header(vec![
-            parser::IS_NOT_SINGLE_MASK | nd(5, 9),
+            parser::IS_NOT_SINGLE_MASK | nd(7, 11),
             0xfec,
             0xfea,
             2.0f32.to_bits(),
@@ -615,7 +756,7 @@ mod tests {
         mi.ffm_k = 1;
         let mut fbt = FeatureBufferTranslator::new(&mi);
         let rb = add_header(vec![
-            parser::IS_NOT_SINGLE_MASK | nd(5, 9),
+            parser::IS_NOT_SINGLE_MASK | nd(7, 11),
             0x1,
             0xfff,
             2.0f32.to_bits(),
@@ -663,7 +804,7 @@ mod tests {
         mi.ffm_k = 3;
         let mut fbt = FeatureBufferTranslator::new(&mi);
         let rb = add_header(vec![
-            parser::IS_NOT_SINGLE_MASK | nd(5, 9),
+            parser::IS_NOT_SINGLE_MASK | nd(7, 11),
             0x1,
             0xfff,
             2.0f32.to_bits(),
@@ -717,6 +858,7 @@ mod tests {
             .push(model_instance::FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(0)],
                 weight: 1.0,
+                bit_precision: None,
             });
 
         let mut fbt = FeatureBufferTranslator::new(&mi);
@@ -733,12 +875,13 @@ mod tests {
             .push(model_instance::FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc_f32(1)],
                 weight: 1.0,
+                bit_precision: None,
             });
 
         let mut fbt = FeatureBufferTranslator::new(&mi);
         let rb = add_header(vec![
             NO_FEATURES,
-            nd(6, 10) | IS_NOT_SINGLE_MASK,
+            nd(8, 12) | IS_NOT_SINGLE_MASK,
             NO_FEATURES,
             0xffc & MASK31,
             3.0f32.to_bits(),
@@ -1,6 +1,10 @@
+use crate::feature_reader;
+use crate::feature_reader_float_namespace;
 use crate::parser;
 use crate::vwmap;
+use crate::vwmap::{NamespaceFormat, NamespaceType};
 use std::error::Error;
+use std::fmt::Write;
 use std::io::Error as IOError;
 use std::io::ErrorKind;
 
@@ -10,7 +14,10 @@ use dyn_clone::{clone_trait_object, DynClone};
 use fasthash::murmur3;
 
 use crate::feature_transform_implementations::{
-    TransformerBinner, TransformerCombine, TransformerLogRatioBinner, TransformerWeight,
+    TransformerAgeBucket, TransformerBinner, TransformerBoundariesBinner, TransformerClip,
+    TransformerCombine, TransformerCountFeatures, TransformerDayOfWeek, TransformerHourOfDay,
+    TransformerLog1p, TransformerLogRatioBinner, TransformerMissing, TransformerStandardize,
+    TransformerWeight,
 };
 use crate::feature_transform_parser;
 
@@ -35,6 +42,8 @@ pub enum SeedNumber {
     Four = 4,
 }
 
+const SEED_NUMBER_NAMES: [&str; 5] = ["Default", "One", "Two", "Three", "Four"];
+
 #[derive(Clone)]
 pub struct ExecutorToNamespace {
     pub namespace_descriptor: vwmap::NamespaceDescriptor,
@@ -123,6 +132,7 @@ impl TransformExecutor {
                 &namespace_transform.function_name,
                 &namespace_transform.from_namespaces,
                 &namespace_transform.function_parameters,
+                &namespace_transform.fitted_parameters,
             )?,
         };
         Ok(te)
@@ -132,6 +142,7 @@ impl TransformExecutor {
         function_name: &str,
         namespaces_from: &Vec<feature_transform_parser::Namespace>,
         function_params: &Vec<f32>,
+        fitted_params: &Option<Vec<f32>>,
     ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
         /*        let mut executor_namespaces_from: Vec<ExecutorFromNamespace> = Vec::new();
          for namespace in namespaces_from {
@@ -184,8 +195,57 @@ impl TransformExecutor {
                 function_params,
                 true,
             )
+        } else if function_name == "BinnerBoundaries" {
+            TransformerBoundariesBinner::create_function(
+                function_name,
+                namespaces_from,
+                function_params,
+            )
+        } else if function_name == "BinnerQuantile" {
+            TransformerBoundariesBinner::create_quantile_function(
+                function_name,
+                namespaces_from,
+                function_params,
+                fitted_params,
+            )
+        } else if function_name == "Missing" {
+            TransformerMissing::create_function(function_name, namespaces_from, function_params)
+        } else if function_name == "CountFeatures" {
+            TransformerCountFeatures::create_function(
+                function_name,
+                namespaces_from,
+                function_params,
+            )
+        } else if function_name == "Standardize" {
+            TransformerStandardize::create_function(
+                function_name,
+                namespaces_from,
+                function_params,
+                fitted_params,
+            )
+        } else if function_name == "Clip" {
+            TransformerClip::create_function(
+                function_name,
+                namespaces_from,
+                function_params,
+                fitted_params,
+            )
+        } else if function_name == "Log1p" {
+            TransformerLog1p::create_function(function_name, namespaces_from, function_params)
+        } else if function_name == "HourOfDay" {
+            TransformerHourOfDay::create_function(function_name, namespaces_from, function_params)
+        } else if function_name == "DayOfWeek" {
+            TransformerDayOfWeek::create_function(function_name, namespaces_from, function_params)
+        } else if function_name == "AgeBucket" {
+            TransformerAgeBucket::create_function(function_name, namespaces_from, function_params)
         } else if function_name == "Combine" {
             TransformerCombine::create_function(function_name, namespaces_from, function_params)
+        } else if function_name == "CrossTopK" {
+            TransformerCombine::create_top_k_function(
+                function_name,
+                namespaces_from,
+                function_params,
+            )
         } else if function_name == "Weight" {
             TransformerWeight::create_function(function_name, namespaces_from, function_params)
         } else {
@@ -218,6 +278,130 @@ impl TransformExecutors {
         }
     }
 
+    // Describes namespaces made by transforms from a record (for --debug_transforms): values of the from namespaces
+    // and emitted features with the SeedNumber they were emitted with
+    pub fn debug_transforms(
+        &self,
+        namespace_transforms: &feature_transform_parser::NamespaceTransforms,
+        record_buffer: &[u32],
+    ) -> String {
+        let mut s = String::new();
+        for (namespace_transform, executor) in namespace_transforms.v.iter().zip(&self.executors) {
+            let _ = write!(
+                s,
+                "  {}={}({})({})",
+                namespace_transform.to_namespace.namespace_verbose,
+                namespace_transform.function_name,
+                namespace_transform
+                    .from_namespaces
+                    .iter()
+                    .map(|n| n.namespace_verbose.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(","),
+                namespace_transform
+                    .function_parameters
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+            if let Some(fitted_parameters) = &namespace_transform.fitted_parameters {
+                let _ = write!(s, " fitted: {:?}", fitted_parameters);
+            }
+            s.push('\n');
+            for from_namespace in &namespace_transform.from_namespaces {
+                let namespace_descriptor = from_namespace.namespace_descriptor;
+                let mut values: Vec<String> = Vec::new();
+                if namespace_descriptor.namespace_format == NamespaceFormat::F32 {
+                    feature_reader_float_namespace!(
+                        record_buffer,
+                        namespace_descriptor,
+                        hash_index,
+                        hash_value,
+                        float_value,
+                        {
+                            values.push(float_value.to_string());
+                        }
+                    );
+                } else {
+                    feature_reader!(
+                        record_buffer,
+                        self,
+                        namespace_descriptor,
+                        hash_index,
+                        hash_value,
+                        {
+                            values.push(format!("{}:{}", hash_index, hash_value));
+                        }
+                    );
+                }
+                let _ = writeln!(
+                    s,
+                    "    from {}: [{}]",
+                    from_namespace.namespace_verbose,
+                    values.join(", ")
+                );
+            }
+            let emitted: Vec<String> = self
+                .emitted_with_seed_numbers(executor, record_buffer)
+                .iter()
+                .map(|(hash_index, hash_value, seed_number)| match seed_number {
+                    Some(seed_number) => format!(
+                        "{}:{} ({})",
+                        hash_index, hash_value, SEED_NUMBER_NAMES[*seed_number]
+                    ),
+                    None => format!("{}:{}", hash_index, hash_value),
+                })
+                .collect();
+            let _ = writeln!(
+                s,
+                "    to {}: [{}]",
+                namespace_transform.to_namespace.namespace_verbose,
+                emitted.join(", ")
+            );
+        }
+        s
+    }
+
+    // Features aren't tagged with the seed they were emitted with. So the transform is executed again with each of
+    // the seeds kept and the others changed, features whose hashes stay the same were emitted with the kept seed.
+    fn emitted_with_seed_numbers(
+        &self,
+        executor: &TransformExecutor,
+        record_buffer: &[u32],
+    ) -> Vec<(u32, f32, Option<usize>)> {
+        let execute = |namespace_seeds: [u32; 5]| {
+            let mut namespace_to = executor.namespace_to.borrow().clone();
+            namespace_to.namespace_seeds = namespace_seeds;
+            namespace_to.tmp_data.truncate(0);
+            executor
+                .function_executor
+                .execute_function(record_buffer, &mut namespace_to, self);
+            namespace_to.tmp_data
+        };
+        let namespace_seeds = executor.namespace_to.borrow().namespace_seeds;
+        let emitted = execute(namespace_seeds);
+        let mut seed_numbers: Vec<Option<usize>> = vec![None; emitted.len()];
+        for seed_number in 0..namespace_seeds.len() {
+            let mut changed_seeds = namespace_seeds;
+            for (i, seed) in changed_seeds.iter_mut().enumerate() {
+                if i != seed_number {
+                    *seed = !*seed;
+                }
+            }
+            for (i, (probe, original)) in execute(changed_seeds).iter().zip(&emitted).enumerate() {
+                if probe.0 == original.0 {
+                    seed_numbers[i] = Some(seed_number);
+                }
+            }
+        }
+        emitted
+            .iter()
+            .zip(seed_numbers)
+            .map(|((hash_index, hash_value), seed_number)| (*hash_index, *hash_value, seed_number))
+            .collect()
+    }
+
     /*
     //  We don't use this function as we have put it into feature_reader! macro
         #[inline(always)]
@@ -290,4 +474,109 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_debug_transforms() {
+        let vw = vwmap::VwNamespaceMap::new("A,price,f32\nB,site\n").unwrap();
+        let mut nstp = feature_transform_parser::NamespaceTransformsParser::new();
+        for transform in vec![
+            "bin=BinnerBoundaries(price)(10, 100)",
+            "missing=Missing(site)()",
+        ] {
+            nstp.add_transform_namespace(&vw, transform).unwrap();
+        }
+        let nst = nstp.resolve(&vw).unwrap();
+        let transform_executors = TransformExecutors::from_namespace_transforms(&nst);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut buf = std::io::Cursor::new(b"1 |A 14.5 NONE\n".to_vec());
+        let record_buffer = pa.next_vowpal(&mut buf).unwrap();
+
+        let mut to_namespace = transform_executors.executors[0]
+            .namespace_to
+            .borrow()
+            .clone();
+        to_namespace.tmp_data.truncate(0);
+        to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(1, 1.0);
+        to_namespace.emit_i32::<{ SeedNumber::One as usize }>(f32::NAN.to_bits() as i32, 1.0);
+        let mut missing = transform_executors.executors[1]
+            .namespace_to
+            .borrow()
+            .clone();
+        missing.tmp_data.truncate(0);
+        missing.emit_i32::<{ SeedNumber::Default as usize }>(0, 1.0);
+
+        assert_eq!(
+            transform_executors.debug_transforms(&nst, record_buffer),
+            format!(
+                "  bin=BinnerBoundaries(price)(10, 100)\n    from price: [14.5, NaN]\n    to bin: [{}:1 (Default), {}:1 (One)]\n  missing=Missing(site)()\n    from site: []\n    to missing: [{}:1 (Default)]\n",
+                to_namespace.tmp_data[0].0, to_namespace.tmp_data[1].0, missing.tmp_data[0].0
+            )
+        );
+    }
+
+    #[test]
+    fn test_chained_transforms() {
+        // A binned namespace, combined with another one and weighted
+        let vw = vwmap::VwNamespaceMap::new("A,site\nB,price,f32\n").unwrap();
+        let mut nstp = feature_transform_parser::NamespaceTransformsParser::new();
+        for transform in vec![
+            "weighted=Weight(cross)(2.0)",
+            "cross=Combine(bin,site)()",
+            "bin=BinnerLog(price)(20.0, 2.0)",
+        ] {
+            nstp.add_transform_namespace(&vw, transform).unwrap();
+        }
+        let nst = nstp.resolve(&vw).unwrap();
+        // Transforms come after the ones they use
+        let to_namespaces: Vec<&str> = nst
+            .v
+            .iter()
+            .map(|t| t.to_namespace.namespace_verbose.as_str())
+            .collect();
+        assert_eq!(to_namespaces, vec!["bin", "cross", "weighted"]);
+
+        let transform_executors = TransformExecutors::from_namespace_transforms(&nst);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut buf = std::io::Cursor::new(b"1 |A s1 |B 100\n".to_vec());
+        let record_buffer = pa.next_vowpal(&mut buf).unwrap();
+        let site_hash = record_buffer[parser::HEADER_LEN as usize];
+
+        let execute = |i: usize| {
+            let executor = &transform_executors.executors[i];
+            let mut namespace_to = executor.namespace_to.borrow().clone();
+            namespace_to.tmp_data.truncate(0);
+            executor.function_executor.execute_function(
+                record_buffer,
+                &mut namespace_to,
+                &transform_executors,
+            );
+            namespace_to
+        };
+        let bin = execute(0);
+        assert_eq!(bin.tmp_data.len(), 2); // interpolated
+
+        let mut cross = transform_executors.executors[1]
+            .namespace_to
+            .borrow()
+            .clone();
+        cross.tmp_data.truncate(0);
+        for (hash_index, hash_value) in &bin.tmp_data {
+            cross.emit_i32::<{ SeedNumber::Default as usize }>(
+                (hash_index ^ site_hash) as i32,
+                *hash_value,
+            );
+        }
+        assert_eq!(execute(1).tmp_data, cross.tmp_data);
+
+        let mut weighted = transform_executors.executors[2]
+            .namespace_to
+            .borrow()
+            .clone();
+        weighted.tmp_data.truncate(0);
+        for (hash_index, hash_value) in &cross.tmp_data {
+            weighted
+                .emit_i32::<{ SeedNumber::Default as usize }>(*hash_index as i32, hash_value * 2.0);
+        }
+        assert_eq!(execute(2).tmp_data, weighted.tmp_data);
+    }
 }
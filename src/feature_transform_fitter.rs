@@ -0,0 +1,316 @@
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::error::Error;
+
+use crate::feature_reader_float_namespace;
+use crate::feature_transform_executor;
+use crate::feature_transform_parser;
+use crate::parser;
+use crate::vwmap::{NamespaceDescriptor, NamespaceFormat};
+
+// Quantiles are computed on a uniform sample of at most this many values
+const MAX_QUANTILE_SAMPLE: usize = 1 << 20;
+
+// Collects what a transform needs to know about the training data
+trait TransformFitterTrait {
+    fn add_record(&mut self, record_buffer: &[u32]);
+    // None when there were no values to fit on
+    fn fitted_parameters(&mut self) -> Option<Vec<f32>>;
+}
+
+// Fits the parameters of transforms that are computed from the training data (e.g. BinnerQuantile boundaries)
+// All records of the training data are passed through add_record() before training, then finish() stores the
+// fitted parameters in the transforms, so they are saved with the model
+pub struct TransformFitter {
+    fitters: Vec<(usize, Box<dyn TransformFitterTrait>)>, // (index of the transform, its fitter)
+}
+
+impl TransformFitter {
+    pub fn new(
+        namespace_transforms: &feature_transform_parser::NamespaceTransforms,
+    ) -> TransformFitter {
+        let mut fitters: Vec<(usize, Box<dyn TransformFitterTrait>)> = Vec::new();
+        for (i, namespace_transform) in namespace_transforms.v.iter().enumerate() {
+            if namespace_transform.fitted_parameters.is_some() {
+                continue;
+            }
+            let params = &namespace_transform.function_parameters;
+            let namespace_descriptor = namespace_transform.from_namespaces[0].namespace_descriptor;
+            let fitter: Box<dyn TransformFitterTrait> =
+                match namespace_transform.function_name.as_str() {
+                    "BinnerQuantile" => Box::new(QuantileFitter {
+                        namespace_descriptor: namespace_descriptor,
+                        num_bins: params[0] as usize,
+                        sample: ValueSample::new(),
+                    }),
+                    // Standardize and Clip are only fitted when their parameters are not given
+                    "Standardize" if params.is_empty() => Box::new(StandardizeFitter {
+                        namespace_descriptor: namespace_descriptor,
+                        num_values: 0,
+                        mean: 0.0,
+                        sum_squared_deviations: 0.0,
+                    }),
+                    "Clip" if params.is_empty() => Box::new(ClipFitter {
+                        namespace_descriptor: namespace_descriptor,
+                        sample: ValueSample::new(),
+                    }),
+                    _ => continue,
+                };
+            fitters.push((i, fitter));
+        }
+        TransformFitter { fitters: fitters }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fitters.is_empty()
+    }
+
+    pub fn add_record(&mut self, record_buffer: &[u32]) {
+        for (_, fitter) in self.fitters.iter_mut() {
+            fitter.add_record(record_buffer);
+        }
+    }
+
+    pub fn finish(
+        &mut self,
+        namespace_transforms: &mut feature_transform_parser::NamespaceTransforms,
+    ) -> Result<(), Box<dyn Error>> {
+        for (i, fitter) in self.fitters.iter_mut() {
+            let namespace_transform = &mut namespace_transforms.v[*i];
+            let fitted_parameters = fitter.fitted_parameters().ok_or(format!(
+                "Couldn't fit transform {}: namespace {} has no values in the training data",
+                namespace_transform.to_namespace.namespace_verbose,
+                namespace_transform.from_namespaces[0].namespace_verbose
+            ))?;
+            namespace_transform.fitted_parameters = Some(fitted_parameters);
+            // Fitted parameters have to be valid for the transform, just like the ones from the command line
+            let _ = feature_transform_executor::TransformExecutor::from_namespace_transform(
+                namespace_transform,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A uniform sample (reservoir sampling) of the finite values of a float namespace
+struct ValueSample {
+    values: Vec<f32>,
+    num_values: u64,
+    rng: Xoshiro256PlusPlus,
+}
+
+impl ValueSample {
+    fn new() -> ValueSample {
+        ValueSample {
+            values: Vec::new(),
+            num_values: 0,
+            rng: Xoshiro256PlusPlus::seed_from_u64(0),
+        }
+    }
+
+    fn add_record(&mut self, record_buffer: &[u32], namespace_descriptor: NamespaceDescriptor) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            namespace_descriptor,
+            hash_index,
+            hash_value,
+            float_value,
+            {
+                if float_value.is_finite() {
+                    self.num_values += 1;
+                    if self.values.len() < MAX_QUANTILE_SAMPLE {
+                        self.values.push(float_value);
+                    } else {
+                        let j = self.rng.gen_range(0..self.num_values);
+                        if j < MAX_QUANTILE_SAMPLE as u64 {
+                            self.values[j as usize] = float_value;
+                        }
+                    }
+                }
+            }
+        );
+    }
+
+    fn sorted(&mut self) -> &Vec<f32> {
+        self.values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        &self.values
+    }
+}
+
+// Cut points that split the sample into bins of equal size. Equal cut points are merged, so there can be fewer bins.
+struct QuantileFitter {
+    namespace_descriptor: NamespaceDescriptor,
+    num_bins: usize,
+    sample: ValueSample,
+}
+
+impl TransformFitterTrait for QuantileFitter {
+    fn add_record(&mut self, record_buffer: &[u32]) {
+        self.sample
+            .add_record(record_buffer, self.namespace_descriptor);
+    }
+
+    fn fitted_parameters(&mut self) -> Option<Vec<f32>> {
+        let num_bins = self.num_bins;
+        let values = self.sample.sorted();
+        if values.is_empty() {
+            return None;
+        }
+        let mut boundaries: Vec<f32> = Vec::new();
+        for bin in 1..num_bins {
+            let boundary = values[bin * values.len() / num_bins];
+            // The smallest value is never a cut point, it would leave the first bin empty
+            if boundary > values[0] && boundaries.last().map_or(true, |&b| boundary > b) {
+                boundaries.push(boundary);
+            }
+        }
+        Some(boundaries)
+    }
+}
+
+// 1st and 99th percentile of the sample
+struct ClipFitter {
+    namespace_descriptor: NamespaceDescriptor,
+    sample: ValueSample,
+}
+
+impl TransformFitterTrait for ClipFitter {
+    fn add_record(&mut self, record_buffer: &[u32]) {
+        self.sample
+            .add_record(record_buffer, self.namespace_descriptor);
+    }
+
+    fn fitted_parameters(&mut self) -> Option<Vec<f32>> {
+        let values = self.sample.sorted();
+        if values.is_empty() {
+            return None;
+        }
+        Some(vec![
+            values[values.len() / 100],
+            values[values.len() * 99 / 100],
+        ])
+    }
+}
+
+// Mean and standard deviation of all finite values (Welford's online algorithm)
+struct StandardizeFitter {
+    namespace_descriptor: NamespaceDescriptor,
+    num_values: u64,
+    mean: f64,
+    sum_squared_deviations: f64,
+}
+
+impl TransformFitterTrait for StandardizeFitter {
+    fn add_record(&mut self, record_buffer: &[u32]) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            self.namespace_descriptor,
+            hash_index,
+            hash_value,
+            float_value,
+            {
+                if float_value.is_finite() {
+                    let value = float_value as f64;
+                    self.num_values += 1;
+                    let delta = value - self.mean;
+                    self.mean += delta / self.num_values as f64;
+                    self.sum_squared_deviations += delta * (value - self.mean);
+                }
+            }
+        );
+    }
+
+    fn fitted_parameters(&mut self) -> Option<Vec<f32>> {
+        if self.num_values == 0 {
+            return None;
+        }
+        let std = (self.sum_squared_deviations / self.num_values as f64).sqrt() as f32;
+        // A constant namespace is only centered
+        let std = if std > 0.0 { std } else { 1.0 };
+        Some(vec![self.mean as f32, std])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser::IS_NOT_SINGLE_MASK;
+    use crate::vwmap;
+
+    fn float_record(value: f32) -> Vec<u32> {
+        vec![
+            8,
+            0,
+            1.0f32.to_bits(),
+            0,
+            parser::NO_TAG,
+            (6 << 16) + 8 | IS_NOT_SINGLE_MASK,
+            1775699190 & parser::MASK31,
+            value.to_bits(),
+        ]
+    }
+
+    fn transforms(transform: &str) -> feature_transform_parser::NamespaceTransforms {
+        let vw = vwmap::VwNamespaceMap::new("A,price,f32\n").unwrap();
+        let mut nstp = feature_transform_parser::NamespaceTransformsParser::new();
+        nstp.add_transform_namespace(&vw, transform).unwrap();
+        nstp.resolve(&vw).unwrap()
+    }
+
+    #[test]
+    fn test_quantile_fitting() {
+        let mut nst = transforms("p=BinnerQuantile(price)(4)");
+        let mut fitter = TransformFitter::new(&nst);
+        assert!(!fitter.is_empty());
+        for i in 0..100 {
+            fitter.add_record(&float_record(i as f32));
+        }
+        fitter.add_record(&float_record(f32::NAN)); // NaN is not a value to be binned
+        fitter.finish(&mut nst).unwrap();
+        assert_eq!(nst.v[0].fitted_parameters, Some(vec![25.0, 50.0, 75.0]));
+
+        // Fitted transforms are not fitted again
+        assert!(TransformFitter::new(&nst).is_empty());
+
+        // Repeated values make fewer bins
+        let mut nst = transforms("p=BinnerQuantile(price)(4)");
+        let mut fitter = TransformFitter::new(&nst);
+        for i in 0..100 {
+            fitter.add_record(&float_record(if i < 50 { 1.0 } else { i as f32 }));
+        }
+        fitter.finish(&mut nst).unwrap();
+        assert_eq!(nst.v[0].fitted_parameters, Some(vec![50.0, 75.0]));
+
+        // Without values there is nothing to fit on
+        let mut nst = transforms("p=BinnerQuantile(price)(4)");
+        let mut fitter = TransformFitter::new(&nst);
+        fitter.add_record(&float_record(f32::NAN));
+        assert!(fitter.finish(&mut nst).is_err());
+    }
+
+    #[test]
+    fn test_standardize_and_clip_fitting() {
+        // Transforms with parameters given on the command line are not fitted
+        for transform in vec!["p=Standardize(price)(1.0, 2.0)", "p=Clip(price)(1.0, 2.0)"] {
+            assert!(TransformFitter::new(&transforms(transform)).is_empty());
+        }
+
+        let mut nst = transforms("p=Standardize(price)()");
+        let mut fitter = TransformFitter::new(&nst);
+        for value in vec![2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0, f32::INFINITY] {
+            fitter.add_record(&float_record(value));
+        }
+        fitter.finish(&mut nst).unwrap();
+        assert_eq!(nst.v[0].fitted_parameters, Some(vec![5.0, 2.0]));
+
+        let mut nst = transforms("p=Clip(price)()");
+        let mut fitter = TransformFitter::new(&nst);
+        for i in 0..1000 {
+            fitter.add_record(&float_record(i as f32));
+        }
+        fitter.finish(&mut nst).unwrap();
+        assert_eq!(nst.v[0].fitted_parameters, Some(vec![10.0, 990.0]));
+    }
+}
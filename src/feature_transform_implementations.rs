@@ -1,3 +1,6 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::error::Error;
 use std::io::Error as IOError;
 use std::io::ErrorKind;
@@ -11,7 +14,7 @@ use crate::feature_transform_executor::{
     TransformExecutors,
 };
 use crate::feature_transform_parser;
-use crate::vwmap::{NamespaceDescriptor, NamespaceFormat, NamespaceType};
+use crate::vwmap::{NamespaceFormat, NamespaceType};
 
 // Basic example of a "full blown" simple FunctionExecutorTrait
 #[derive(Clone)]
@@ -48,11 +51,31 @@ impl FunctionExampleSqrt {
         from_namespaces: &Vec<feature_transform_parser::Namespace>,
         function_params: &Vec<f32>,
     ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
-        // For simplicity of example, we just assert instead of full error reporting
-        assert!(function_params.len() == 0);
-        assert!(from_namespaces.len() == 1);
-        assert!(from_namespaces[0].namespace_descriptor.namespace_type == NamespaceType::Primitive);
-        assert!(from_namespaces[0].namespace_descriptor.namespace_format == NamespaceFormat::F32);
+        // Bad arguments are errors that name the function, they are reported with the --transform definition
+        if function_params.len() != 0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes no float arguments {}(A)()",
+                    function_name, function_name
+                ),
+            )));
+        }
+        if from_namespaces.len() != 1 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes exactly one namespace argument, example {}(A)()",
+                    function_name, function_name
+                ),
+            )));
+        }
+        if from_namespaces[0].namespace_descriptor.namespace_format != NamespaceFormat::F32 {
+            return Err(not_float_namespace_error(
+                function_name,
+                &from_namespaces[0],
+            ));
+        }
         Ok(Box::new(Self {
             from_namespace: ExecutorFromNamespace {
                 namespace_descriptor: from_namespaces[0].namespace_descriptor,
@@ -61,6 +84,18 @@ impl FunctionExampleSqrt {
     }
 }
 
+// Functions that read float values can't take categorical namespaces, and namespaces made by transforms are categorical
+fn not_float_namespace_error(
+    function_name: &str,
+    namespace: &feature_transform_parser::Namespace,
+) -> Box<dyn Error> {
+    let message = match namespace.namespace_descriptor.namespace_type {
+        NamespaceType::Transformed => format!("All namespaces of function {} have to be of type f32: From namespace ({}) is made by a transform, so it is categorical", function_name, namespace.namespace_verbose),
+        NamespaceType::Primitive => format!("All namespaces of function {} have to be of type f32: From namespace ({}) should be typed in vw_namespace_map.csv", function_name, namespace.namespace_verbose),
+    };
+    Box::new(IOError::new(ErrorKind::Other, message))
+}
+
 // -------------------------------------------------------------------
 // TransformerBinner - A basic binner
 // It can take any function as a binning function f32 -> f32. Then output is rounded to integer
@@ -168,7 +203,7 @@ impl TransformerBinner {
 
         for namespace in from_namespaces.iter() {
             if namespace.namespace_descriptor.namespace_format != NamespaceFormat::F32 {
-                return Err(Box::new(IOError::new(ErrorKind::Other, format!("All namespaces of function {} have to be of type f32: From namespace ({}) should be typed in vw_namespace_map.csv", function_name, namespace.namespace_verbose))));
+                return Err(not_float_namespace_error(function_name, namespace));
             }
         }
 
@@ -300,7 +335,7 @@ impl TransformerLogRatioBinner {
         }
         for namespace in from_namespaces.iter() {
             if namespace.namespace_descriptor.namespace_format != NamespaceFormat::F32 {
-                return Err(Box::new(IOError::new(ErrorKind::Other, format!("All namespaces of function {} have to be of type f32: From namespace ({}) should be typed in vw_namespace_map.csv", function_name, namespace.namespace_verbose))));
+                return Err(not_float_namespace_error(function_name, namespace));
             }
         }
 
@@ -318,6 +353,136 @@ impl TransformerLogRatioBinner {
     }
 }
 
+// -------------------------------------------------------------------
+// TransformerBoundariesBinner - A binner with explicit cut points
+// Example: BinnerBoundaries(price)(10, 100, 1000)
+// Values below 10 go to bin 0, values from 10 to (but not including) 100 go to bin 1 and so on. NaN gets a bin of its own.
+// BinnerQuantile(price)(10) computes up to 9 cut points in a pass over the training data, so every bin gets about the same number of values
+// Computed cut points are stored in the model (in NamespaceTransform.fitted_parameters), so serving uses exactly the same bins
+
+#[derive(Clone)]
+pub struct TransformerBoundariesBinner {
+    from_namespace: ExecutorFromNamespace,
+    boundaries: Vec<f32>,
+}
+
+impl FunctionExecutorTrait for TransformerBoundariesBinner {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        _transform_executors: &TransformExecutors,
+    ) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            float_value,
+            {
+                if float_value.is_nan() {
+                    to_namespace.emit_f32::<{ SeedNumber::One as usize }>(
+                        float_value,
+                        hash_value,
+                        false,
+                    );
+                } else {
+                    let bin = self
+                        .boundaries
+                        .partition_point(|&boundary| boundary <= float_value);
+                    to_namespace
+                        .emit_i32::<{ SeedNumber::Default as usize }>(bin as i32, hash_value);
+                }
+            }
+        );
+    }
+}
+
+impl TransformerBoundariesBinner {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if function_params.len() == 0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes the cut points of the bins as float arguments, example {}(A)(1.0, 10.0, 100.0)",
+                    function_name, function_name
+                ),
+            )));
+        }
+        Self::create_with_boundaries(function_name, from_namespaces, function_params)
+    }
+
+    pub fn create_quantile_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+        fitted_params: &Option<Vec<f32>>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if function_params.len() != 1
+            || function_params[0] < 2.0
+            || function_params[0].fract() != 0.0
+        {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes one float argument, the number of bins (at least 2), example {}(A)(10)",
+                    function_name, function_name
+                ),
+            )));
+        }
+        // Until the boundaries are fitted on the training data, all values are in the first bin
+        let boundaries = match fitted_params {
+            Some(boundaries) => boundaries.clone(),
+            None => Vec::new(),
+        };
+        Self::create_with_boundaries(function_name, from_namespaces, &boundaries)
+    }
+
+    fn create_with_boundaries(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        boundaries: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if from_namespaces.len() != 1 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes exactly one namespace argument, example {}(A)(2.0)",
+                    function_name, function_name
+                ),
+            )));
+        }
+        if from_namespaces[0].namespace_descriptor.namespace_format != NamespaceFormat::F32 {
+            return Err(not_float_namespace_error(
+                function_name,
+                &from_namespaces[0],
+            ));
+        }
+        if boundaries.iter().any(|boundary| !boundary.is_finite())
+            || boundaries.windows(2).any(|pair| pair[0] >= pair[1])
+        {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} needs finite and strictly increasing cut points (passed: {:?})",
+                    function_name, boundaries
+                ),
+            )));
+        }
+
+        Ok(Box::new(Self {
+            from_namespace: ExecutorFromNamespace {
+                namespace_descriptor: from_namespaces[0].namespace_descriptor,
+            },
+            boundaries: boundaries.clone(),
+        }))
+    }
+}
+
 // Value multiplier transformer
 // -------------------------------------------------------------------
 // TransformerWeight - A basic weight multiplier transformer
@@ -389,15 +554,741 @@ impl TransformerWeight {
     }
 }
 
-// Combine Binner
-// Supporting max 5 input namespaces. Because 5 ought to be enough for everybody!
-// There is an issue that compilation time here is immense
-//
+// -------------------------------------------------------------------
+// TransformerMissing - Missing value indicator
+// Emits a single feature when the namespace has no features in the example, and nothing otherwise
+// Example of use: Missing(publisher)() lets the model learn from unknown publisher
+// Works with any namespace, also float and transformed ones
+
+#[derive(Clone)]
+pub struct TransformerMissing {
+    from_namespace: ExecutorFromNamespace,
+}
+
+impl FunctionExecutorTrait for TransformerMissing {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        transform_executors: &TransformExecutors,
+    ) {
+        let mut missing = true;
+        feature_reader!(
+            record_buffer,
+            transform_executors,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            {
+                missing = false;
+            }
+        );
+        if missing {
+            to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(0, 1.0);
+        }
+    }
+}
+
+impl TransformerMissing {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if function_params.len() != 0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes no float arguments {}(A)()",
+                    function_name, function_name
+                ),
+            )));
+        }
+        if from_namespaces.len() != 1 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes exactly one namespace argument, example {}(A)()",
+                    function_name, function_name
+                ),
+            )));
+        }
+
+        Ok(Box::new(Self {
+            from_namespace: ExecutorFromNamespace {
+                namespace_descriptor: from_namespaces[0].namespace_descriptor,
+            },
+        }))
+    }
+}
+
+// -------------------------------------------------------------------
+// TransformerCountFeatures - Number of features present in the namespace, as a categorical feature
+// Zero features (missing namespace) is a bin like any other
+// Example of use: CountFeatures(segments)(10.0, 2.0)
+// Counts below 10 get a bin of their own, larger counts are binned by sqrt(count - 10) * 2.0 (like BinnerSqrtPlain)
+// Without parameters every count gets a bin of its own
+
+#[derive(Clone)]
+pub struct TransformerCountFeatures {
+    from_namespace: ExecutorFromNamespace,
+    greater_than: f32,
+    resolution: f32,
+}
+
+impl FunctionExecutorTrait for TransformerCountFeatures {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        transform_executors: &TransformExecutors,
+    ) {
+        let mut count: u32 = 0;
+        feature_reader!(
+            record_buffer,
+            transform_executors,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            {
+                count += 1;
+            }
+        );
+        let count = count as f32;
+        if count < self.greater_than {
+            to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(count as i32, 1.0);
+        } else {
+            to_namespace.emit_f32::<{ SeedNumber::One as usize }>(
+                (count - self.greater_than).sqrt() * self.resolution,
+                1.0,
+                false,
+            );
+        }
+    }
+}
+
+impl TransformerCountFeatures {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if function_params.len() > 2 {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Function {} takes up to two float arguments, example {}(A)(10.0, 2.0). Both are optional.\nFirst parameter is the count to start sqrt binning at (default: none), second parameter is resolution (default: 1.0))", function_name, function_name))));
+        }
+
+        let greater_than = match function_params.get(0) {
+            Some(&greater_than) => greater_than,
+            None => f32::MAX,
+        };
+        if greater_than < 0.0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} parameter greater_than cannot be negative (passed : {}))",
+                    function_name, greater_than
+                ),
+            )));
+        }
+
+        let resolution = match function_params.get(1) {
+            Some(&resolution) => resolution,
+            None => 1.0,
+        };
+
+        if from_namespaces.len() != 1 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes exactly one namespace argument, example {}(A)(10.0)",
+                    function_name, function_name
+                ),
+            )));
+        }
+
+        Ok(Box::new(Self {
+            from_namespace: ExecutorFromNamespace {
+                namespace_descriptor: from_namespaces[0].namespace_descriptor,
+            },
+            greater_than: greater_than,
+            resolution: resolution,
+        }))
+    }
+}
+
+// -------------------------------------------------------------------
+// Continuous transformers - Standardize, Clip and Log1p
+// Unlike binners, these keep float values continuous: every value is emitted under a single hash of the to namespace,
+// with the transformed value as the feature value. Useful as inputs of neural networks.
+// (Hashes of float features are hashes of their values, so they are not kept)
+// Non-finite values (NaN, INF) are not emitted, as if the feature was missing.
+// Standardize(price)(100.0, 20.0) emits (price - 100.0) / 20.0
+// Clip(price)(0.0, 1000.0) emits price limited to the range from 0.0 to 1000.0
+// Log1p(price)() emits ln(1 + price), negative values are mirrored: -ln(1 - price)
+// Standardize(price)() and Clip(price)() are fitted in a pass over the training data (mean and standard deviation,
+// 1st and 99th percentile) and stored in the model, so serving uses exactly the same parameters
+
+fn float_from_namespace(
+    function_name: &str,
+    from_namespaces: &Vec<feature_transform_parser::Namespace>,
+) -> Result<ExecutorFromNamespace, Box<dyn Error>> {
+    if from_namespaces.len() != 1 {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!(
+                "Function {} takes exactly one namespace argument, example {}(A)()",
+                function_name, function_name
+            ),
+        )));
+    }
+    if from_namespaces[0].namespace_descriptor.namespace_format != NamespaceFormat::F32 {
+        return Err(not_float_namespace_error(
+            function_name,
+            &from_namespaces[0],
+        ));
+    }
+    Ok(ExecutorFromNamespace {
+        namespace_descriptor: from_namespaces[0].namespace_descriptor,
+    })
+}
+
+// Parameters are either given on the command line or fitted, until they are fitted None is returned
+fn given_or_fitted_params(
+    function_name: &str,
+    function_params: &Vec<f32>,
+    fitted_params: &Option<Vec<f32>>,
+    example: &str,
+) -> Result<Option<(f32, f32)>, Box<dyn Error>> {
+    let params = match (function_params.len(), fitted_params) {
+        (2, _) => function_params,
+        (0, Some(fitted_params)) if fitted_params.len() == 2 => fitted_params,
+        (0, None) => return Ok(None),
+        _ => {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Function {} takes two float arguments, example {}, or none to fit them on the training data", function_name, example))));
+        }
+    };
+    if !params[0].is_finite() || !params[1].is_finite() {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!(
+                "Function {} needs finite parameters (passed: {:?})",
+                function_name, params
+            ),
+        )));
+    }
+    Ok(Some((params[0], params[1])))
+}
+
+#[derive(Clone)]
+pub struct TransformerStandardize {
+    from_namespace: ExecutorFromNamespace,
+    mean: f32,
+    std: f32,
+}
+
+impl FunctionExecutorTrait for TransformerStandardize {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        _transform_executors: &TransformExecutors,
+    ) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            float_value,
+            {
+                if float_value.is_finite() {
+                    to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(
+                        0,
+                        hash_value * (float_value - self.mean) / self.std,
+                    );
+                }
+            }
+        );
+    }
+}
+
+impl TransformerStandardize {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+        fitted_params: &Option<Vec<f32>>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        let from_namespace = float_from_namespace(function_name, from_namespaces)?;
+        let (mean, std) = match given_or_fitted_params(
+            function_name,
+            function_params,
+            fitted_params,
+            &format!(
+                "{}(A)(100.0, 20.0) for mean and standard deviation",
+                function_name
+            ),
+        )? {
+            Some(params) => params,
+            None => (0.0, 1.0),
+        };
+        if std <= 0.0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} parameter std has to be positive (passed : {}))",
+                    function_name, std
+                ),
+            )));
+        }
+
+        Ok(Box::new(Self {
+            from_namespace: from_namespace,
+            mean: mean,
+            std: std,
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct TransformerClip {
+    from_namespace: ExecutorFromNamespace,
+    lo: f32,
+    hi: f32,
+}
+
+impl FunctionExecutorTrait for TransformerClip {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        _transform_executors: &TransformExecutors,
+    ) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            float_value,
+            {
+                if float_value.is_finite() {
+                    to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(
+                        0,
+                        hash_value * float_value.max(self.lo).min(self.hi),
+                    );
+                }
+            }
+        );
+    }
+}
+
+impl TransformerClip {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+        fitted_params: &Option<Vec<f32>>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        let from_namespace = float_from_namespace(function_name, from_namespaces)?;
+        let (lo, hi) = match given_or_fitted_params(
+            function_name,
+            function_params,
+            fitted_params,
+            &format!(
+                "{}(A)(0.0, 1000.0) for the lowest and highest value",
+                function_name
+            ),
+        )? {
+            Some(params) => params,
+            None => (f32::MIN, f32::MAX),
+        };
+        if lo > hi {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} parameter lo cannot be larger than hi (passed : {}, {}))",
+                    function_name, lo, hi
+                ),
+            )));
+        }
+
+        Ok(Box::new(Self {
+            from_namespace: from_namespace,
+            lo: lo,
+            hi: hi,
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct TransformerLog1p {
+    from_namespace: ExecutorFromNamespace,
+}
+
+impl FunctionExecutorTrait for TransformerLog1p {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        _transform_executors: &TransformExecutors,
+    ) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            float_value,
+            {
+                if float_value.is_finite() {
+                    to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(
+                        0,
+                        hash_value * float_value.abs().ln_1p().copysign(float_value),
+                    );
+                }
+            }
+        );
+    }
+}
+
+impl TransformerLog1p {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if function_params.len() != 0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes no float arguments {}(A)()",
+                    function_name, function_name
+                ),
+            )));
+        }
+
+        Ok(Box::new(Self {
+            from_namespace: float_from_namespace(function_name, from_namespaces)?,
+        }))
+    }
+}
+
+// -------------------------------------------------------------------
+// Timestamp transformers - HourOfDay, DayOfWeek and AgeBucket
+// Values of the from namespaces are unix timestamps in seconds, every transformer emits categorical features
+// (with a SeedNumber of its own, so hours, days and ages never share hashes). Non-finite timestamps are skipped.
+// Timestamps are f32, so around today they are precise to about two minutes.
+// HourOfDay(ts)(2.0) emits the hour (0-23) in the time zone two hours east of UTC, the offset is optional (default: 0.0)
+// DayOfWeek(ts)(2.0) emits the day of week (0 is Monday, 6 is Sunday), with the same optional time zone offset
+// AgeBucket(ts, now)(3600, 86400, 604800) emits the bucket of age (now - ts): 0 for less than an hour, 1 for less
+// than a day, 2 for less than a week and 3 for older. Timestamps in the future (negative ages) get a bucket of their own.
+
+const SECONDS_IN_HOUR: f64 = 3600.0;
+const SECONDS_IN_DAY: f64 = 86400.0;
+
+fn tz_offset_seconds(
+    function_name: &str,
+    function_params: &Vec<f32>,
+) -> Result<f64, Box<dyn Error>> {
+    if function_params.len() > 1 {
+        return Err(Box::new(IOError::new(ErrorKind::Other, format!("Function {} takes up to one float argument, example {}(A)(2.0). It is optional.\nThe parameter is time zone offset from UTC in hours (default: 0.0)", function_name, function_name))));
+    }
+    let tz_offset = match function_params.get(0) {
+        Some(&tz_offset) => tz_offset,
+        None => 0.0,
+    };
+    if !(tz_offset.abs() <= 24.0) {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!(
+                "Function {} parameter tz_offset has to be between -24 and 24 hours (passed : {}))",
+                function_name, tz_offset
+            ),
+        )));
+    }
+    Ok(tz_offset as f64 * SECONDS_IN_HOUR)
+}
+
+#[derive(Clone)]
+pub struct TransformerHourOfDay {
+    from_namespace: ExecutorFromNamespace,
+    tz_offset_seconds: f64,
+}
+
+impl FunctionExecutorTrait for TransformerHourOfDay {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        _transform_executors: &TransformExecutors,
+    ) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            float_value,
+            {
+                if float_value.is_finite() {
+                    let seconds_of_day =
+                        (float_value as f64 + self.tz_offset_seconds).rem_euclid(SECONDS_IN_DAY);
+                    to_namespace.emit_i32::<{ SeedNumber::One as usize }>(
+                        (seconds_of_day / SECONDS_IN_HOUR) as i32,
+                        hash_value,
+                    );
+                }
+            }
+        );
+    }
+}
+
+impl TransformerHourOfDay {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        Ok(Box::new(Self {
+            from_namespace: float_from_namespace(function_name, from_namespaces)?,
+            tz_offset_seconds: tz_offset_seconds(function_name, function_params)?,
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct TransformerDayOfWeek {
+    from_namespace: ExecutorFromNamespace,
+    tz_offset_seconds: f64,
+}
+
+impl FunctionExecutorTrait for TransformerDayOfWeek {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        _transform_executors: &TransformExecutors,
+    ) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            float_value,
+            {
+                if float_value.is_finite() {
+                    let days = ((float_value as f64 + self.tz_offset_seconds) / SECONDS_IN_DAY)
+                        .floor() as i64;
+                    // 1970-01-01 was a Thursday
+                    to_namespace.emit_i32::<{ SeedNumber::Two as usize }>(
+                        (days + 3).rem_euclid(7) as i32,
+                        hash_value,
+                    );
+                }
+            }
+        );
+    }
+}
+
+impl TransformerDayOfWeek {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        Ok(Box::new(Self {
+            from_namespace: float_from_namespace(function_name, from_namespaces)?,
+            tz_offset_seconds: tz_offset_seconds(function_name, function_params)?,
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct TransformerAgeBucket {
+    from_namespace: ExecutorFromNamespace,
+    now_namespace: ExecutorFromNamespace,
+    boundaries: Vec<f32>,
+}
+
+impl FunctionExecutorTrait for TransformerAgeBucket {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        _transform_executors: &TransformExecutors,
+    ) {
+        feature_reader_float_namespace!(
+            record_buffer,
+            self.from_namespace.namespace_descriptor,
+            hash_index1,
+            hash_value1,
+            float_value1,
+            {
+                feature_reader_float_namespace!(
+                    record_buffer,
+                    self.now_namespace.namespace_descriptor,
+                    hash_index2,
+                    hash_value2,
+                    float_value2,
+                    {
+                        let age = float_value2 - float_value1;
+                        if !age.is_finite() {
+                            // no age to bucket
+                        } else if age < 0.0 {
+                            to_namespace.emit_i32::<{ SeedNumber::Four as usize }>(
+                                0,
+                                hash_value1 * hash_value2,
+                            );
+                        } else {
+                            let bucket = self.boundaries.partition_point(|&b| b <= age);
+                            to_namespace.emit_i32::<{ SeedNumber::Three as usize }>(
+                                bucket as i32,
+                                hash_value1 * hash_value2,
+                            );
+                        }
+                    }
+                );
+            }
+        );
+    }
+}
+
+impl TransformerAgeBucket {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if from_namespaces.len() != 2 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes exactly two namespace arguments (timestamp and now), example {}(A,B)(3600, 86400)",
+                    function_name, function_name
+                ),
+            )));
+        }
+        if function_params.is_empty() {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Function {} takes bucket boundaries in seconds as float arguments, example {}(A,B)(3600, 86400)", function_name, function_name))));
+        }
+        for i in 0..function_params.len() {
+            if !function_params[i].is_finite()
+                || function_params[i] <= 0.0
+                || (i > 0 && function_params[i] <= function_params[i - 1])
+            {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Function {} needs positive and strictly increasing bucket boundaries (passed: {:?})",
+                        function_name, function_params
+                    ),
+                )));
+            }
+        }
+
+        Ok(Box::new(Self {
+            from_namespace: float_from_namespace(function_name, &from_namespaces[0..1].to_vec())?,
+            now_namespace: float_from_namespace(function_name, &from_namespaces[1..2].to_vec())?,
+            boundaries: function_params.clone(),
+        }))
+    }
+}
+
+// -------------------------------------------------------------------
+// TransformerCombine - Crosses of features of any number of namespaces
+// Every combination of one feature from each of the namespaces is emitted (the cartesian product), with
+// a hash made by xor-ing their hashes and a value that is the product of their values
+// Example of use: Combine(A,B,C)() or, with a weight for values of each namespace, Combine(A,B)(1.0, 0.5)
+//
+// CrossTopK(A,B)(k) emits only k crosses with the largest absolute values (the first ones, when values are equal),
+// so long multi-valued namespaces don't explode into a huge number of features. Weights follow k: CrossTopK(A,B)(k, 1.0, 0.5)
+
+// Iterates over the cartesian product of features of several namespaces, like nested loops would (last namespace fastest)
+#[derive(Clone)]
+struct CartesianProduct {
+    features: Vec<Vec<(u32, f32)>>, // features of each of the namespaces
+    indices: Vec<usize>,
+}
+
+impl CartesianProduct {
+    fn new(n_namespaces: usize) -> CartesianProduct {
+        CartesianProduct {
+            features: vec![Vec::new(); n_namespaces],
+            indices: vec![0; n_namespaces],
+        }
+    }
+
+    #[inline(always)]
+    fn cross(&self, indices: &[usize]) -> (u32, f32) {
+        let (mut hash_index, mut hash_value) = self.features[0][indices[0]];
+        for (namespace_features, &i) in self.features[1..].iter().zip(indices[1..].iter()) {
+            hash_index ^= namespace_features[i].0;
+            hash_value *= namespace_features[i].1;
+        }
+        (hash_index, hash_value)
+    }
+
+    fn emit_all(&mut self, to_namespace: &mut ExecutorToNamespace) {
+        for i in self.indices.iter_mut() {
+            *i = 0;
+        }
+        loop {
+            let (hash_index, hash_value) = self.cross(&self.indices);
+            to_namespace
+                .emit_i32::<{ SeedNumber::Default as usize }>(hash_index as i32, hash_value);
+            // Next combination, like an odometer
+            let mut n = self.indices.len();
+            loop {
+                if n == 0 {
+                    return;
+                }
+                n -= 1;
+                self.indices[n] += 1;
+                if self.indices[n] < self.features[n].len() {
+                    break;
+                }
+                self.indices[n] = 0;
+            }
+        }
+    }
+
+    // Best-first search over features sorted by absolute value: a cross is never larger than the ones it is reached from.
+    // Every cross is reached only once, by increasing indices in the order of namespaces (the last increased one is kept).
+    fn emit_top_k(&mut self, k: usize, to_namespace: &mut ExecutorToNamespace) {
+        for namespace_features in self.features.iter_mut() {
+            namespace_features.sort_by(|a, b| {
+                b.1.abs()
+                    .partial_cmp(&a.1.abs())
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+        // (absolute value as bits, which order like the values, earlier crosses first, last increased namespace)
+        let mut heap: BinaryHeap<(u32, Reverse<Vec<usize>>, usize)> = BinaryHeap::new();
+        let start = vec![0; self.features.len()];
+        heap.push((self.cross(&start).1.abs().to_bits(), Reverse(start), 0));
+        let mut emitted = 0;
+        while let Some((_, Reverse(indices), last_increased)) = heap.pop() {
+            let (hash_index, hash_value) = self.cross(&indices);
+            to_namespace
+                .emit_i32::<{ SeedNumber::Default as usize }>(hash_index as i32, hash_value);
+            emitted += 1;
+            if emitted == k {
+                return;
+            }
+            for n in last_increased..indices.len() {
+                if indices[n] + 1 < self.features[n].len() {
+                    let mut next = indices.clone();
+                    next[n] += 1;
+                    heap.push((self.cross(&next).1.abs().to_bits(), Reverse(next), n));
+                }
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct TransformerCombine {
-    from_namespaces: [ExecutorFromNamespace; 4],
-    n_namespaces: u8,
+    from_namespaces: Vec<ExecutorFromNamespace>,
+    weights: Vec<f32>,
+    top_k: Option<usize>,
+    product: RefCell<CartesianProduct>,
 }
 
 impl FunctionExecutorTrait for TransformerCombine {
@@ -407,129 +1298,29 @@ impl FunctionExecutorTrait for TransformerCombine {
         to_namespace: &mut ExecutorToNamespace,
         transform_executors: &TransformExecutors,
     ) {
-        // Sure this could have been written with either using:
-        //   - Stack machine: I didn't want to introduce another dynamic layer
-        //   - Automatic code generation: Didn't have time to learn macros that well
-        // So we are left with good old "spaghetti technique"
-        match self.n_namespaces {
-            2 => feature_reader!(
-                record_buffer,
-                transform_executors,
-                self.from_namespaces[0].namespace_descriptor,
-                hash_index0,
-                hash_value0,
-                {
-                    feature_reader!(
-                        record_buffer,
-                        transform_executors,
-                        self.from_namespaces[1].namespace_descriptor,
-                        hash_index1,
-                        hash_value1,
-                        {
-                            to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(
-                                (hash_index0 ^ hash_index1) as i32,
-                                hash_value0 * hash_value1,
-                            );
-                        }
-                    );
-                }
-            ),
-            3 => feature_reader!(
-                record_buffer,
-                transform_executors,
-                self.from_namespaces[0].namespace_descriptor,
-                hash_index0,
-                hash_value0,
-                {
-                    feature_reader!(
-                        record_buffer,
-                        transform_executors,
-                        self.from_namespaces[1].namespace_descriptor,
-                        hash_index1,
-                        hash_value1,
-                        {
-                            feature_reader!(
-                                record_buffer,
-                                transform_executors,
-                                self.from_namespaces[2].namespace_descriptor,
-                                hash_index2,
-                                hash_value2,
-                                {
-                                    to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(
-                                        (hash_index0 ^ hash_index1 ^ hash_index2) as i32,
-                                        hash_value0 * hash_value1 * hash_value2,
-                                    );
-                                }
-                            );
-                        }
-                    );
-                }
-            ),
-            4 => feature_reader!(
+        let mut product = self.product.borrow_mut();
+        for (n, from_namespace) in self.from_namespaces.iter().enumerate() {
+            let weight = self.weights[n];
+            let namespace_features = &mut product.features[n];
+            namespace_features.truncate(0);
+            feature_reader!(
                 record_buffer,
                 transform_executors,
-                self.from_namespaces[0].namespace_descriptor,
-                hash_index0,
-                hash_value0,
+                from_namespace.namespace_descriptor,
+                hash_index,
+                hash_value,
                 {
-                    feature_reader!(
-                        record_buffer,
-                        transform_executors,
-                        self.from_namespaces[1].namespace_descriptor,
-                        hash_index1,
-                        hash_value1,
-                        {
-                            feature_reader!(
-                                record_buffer,
-                                transform_executors,
-                                self.from_namespaces[2].namespace_descriptor,
-                                hash_index2,
-                                hash_value2,
-                                {
-                                    feature_reader!(
-                                        record_buffer,
-                                        transform_executors,
-                                        self.from_namespaces[3].namespace_descriptor,
-                                        hash_index3,
-                                        hash_value3,
-                                        {
-                                            to_namespace
-                                                .emit_i32::<{ SeedNumber::Default as usize }>(
-                                                    (hash_index0
-                                                        ^ hash_index1
-                                                        ^ hash_index2
-                                                        ^ hash_index3)
-                                                        as i32,
-                                                    hash_value0
-                                                        * hash_value1
-                                                        * hash_value2
-                                                        * hash_value3,
-                                                );
-                                        }
-                                    );
-                                }
-                            );
-                        }
-                    );
+                    namespace_features.push((hash_index, hash_value * weight));
                 }
-            ),
-            /* Disabled since we have compilation time issues */
-            /*            5 =>    feature_reader!(record_buffer, transform_executors, self.from_namespaces[0].namespace_descriptor, hash_index0, hash_value0, {
-                                    feature_reader!(record_buffer, transform_executors, self.from_namespaces[1].namespace_descriptor, hash_index1, hash_value1, {
-                                        feature_reader!(record_buffer, transform_executors, self.from_namespaces[2].namespace_descriptor, hash_index2, hash_value2, {
-                                            feature_reader!(record_buffer, transform_executors, self.from_namespaces[3].namespace_descriptor, hash_index3, hash_value3, {
-                                                feature_reader!(record_buffer, transform_executors, self.from_namespaces[4].namespace_descriptor, hash_index4, hash_value4, {
-                                                    to_namespace.emit_i32::<{SeedNumber::Default as usize}>((hash_index0 ^ hash_index1 ^ hash_index2 ^ hash_index3 ^ hash_index4) as i32,
-                                                                            hash_value0 * hash_value1 * hash_value2 * hash_value3 * hash_value4);
-                                                });
-                                            });
-                                        });
-                                    });
-                                }),*/
-            _ => {
-                panic!("Impossible number of from_namespaces in function TransformCombine - this should have been caught at parsing stage")
+            );
+            if namespace_features.is_empty() {
+                return;
             }
         }
+        match self.top_k {
+            Some(k) => product.emit_top_k(k, to_namespace),
+            None => product.emit_all(to_namespace),
+        }
     }
 }
 
@@ -539,43 +1330,75 @@ impl TransformerCombine {
         from_namespaces: &Vec<feature_transform_parser::Namespace>,
         function_params: &Vec<f32>,
     ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
-        if function_params.len() != 0 {
+        Self::create(function_name, from_namespaces, function_params, None)
+    }
+
+    pub fn create_top_k_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        let k = match function_params.get(0) {
+            Some(&k) if k >= 1.0 && k.fract() == 0.0 => k as usize,
+            _ => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Function {} takes the number of crosses to emit as the first float argument, example {}(A,B)(10)",
+                        function_name, function_name
+                    ),
+                )));
+            }
+        };
+        Self::create(
+            function_name,
+            from_namespaces,
+            &function_params[1..].to_vec(),
+            Some(k),
+        )
+    }
+
+    fn create(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        weights: &Vec<f32>,
+        top_k: Option<usize>,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if from_namespaces.len() < 2 {
             return Err(Box::new(IOError::new(
                 ErrorKind::Other,
                 format!(
-                    "Function {} takes no float arguments {}(A)()",
+                    "Function {} takes at least 2 namespace arguments, example {}(A,B)()",
                     function_name, function_name
                 ),
             )));
         }
-        if from_namespaces.len() < 2 || from_namespaces.len() > 4 {
+        if weights.len() != 0 && weights.len() != from_namespaces.len() {
             return Err(Box::new(IOError::new(
                 ErrorKind::Other,
                 format!(
-                    "Function {} takes between 2 and 4 namespace arguments, example {}(A,B)()",
-                    function_name, function_name
+                    "Function {} takes either no weights or a weight for each of its {} namespaces",
+                    function_name,
+                    from_namespaces.len()
                 ),
             )));
         }
         // We do not need to check if the input namespace is float, Combine does not require float namespace as input
 
-        // We use fixed arrays, so we need to fill the array with defaults first
-        let c = ExecutorFromNamespace {
-            namespace_descriptor: NamespaceDescriptor {
-                namespace_index: 0,
-                namespace_type: NamespaceType::Primitive,
-                namespace_format: NamespaceFormat::Categorical,
-            },
+        let weights = match weights.len() {
+            0 => vec![1.0; from_namespaces.len()],
+            _ => weights.clone(),
         };
-        let mut executor_from_namespaces: [ExecutorFromNamespace; 4] =
-            [c.clone(), c.clone(), c.clone(), c.clone()];
-        for (x, namespace) in from_namespaces.iter().enumerate() {
-            executor_from_namespaces[x].namespace_descriptor = namespace.namespace_descriptor;
-        }
-
         Ok(Box::new(Self {
-            from_namespaces: executor_from_namespaces,
-            n_namespaces: from_namespaces.len() as u8,
+            from_namespaces: from_namespaces
+                .iter()
+                .map(|namespace| ExecutorFromNamespace {
+                    namespace_descriptor: namespace.namespace_descriptor,
+                })
+                .collect(),
+            weights: weights,
+            top_k: top_k,
+            product: RefCell::new(CartesianProduct::new(from_namespaces.len())),
         }))
     }
 }
@@ -585,9 +1408,10 @@ mod tests {
     use super::*;
     use crate::feature_transform_executor::default_seeds;
     use crate::parser::{IS_NOT_SINGLE_MASK, MASK31};
+    use crate::vwmap::NamespaceDescriptor;
 
     fn add_header(v2: Vec<u32>) -> Vec<u32> {
-        let mut rr: Vec<u32> = vec![100, 1, 1.0f32.to_bits()];
+        let mut rr: Vec<u32> = vec![100, 1.0f32.to_bits(), 1.0f32.to_bits(), 0, parser::NO_TAG];
         rr.extend(v2);
         rr
     }
@@ -659,10 +1483,12 @@ mod tests {
         )
         .unwrap();
         let record_buffer = [
-            6,                   // length
+            8,                   // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(4, 6) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            nd(6, 8) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             3.0f32.to_bits(),
@@ -680,10 +1506,12 @@ mod tests {
 
         // Now let's try with value> 40.0
         let record_buffer = [
-            6,                   // length
+            8,                   // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(4, 6) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            nd(6, 8) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             300.0f32.to_bits(),
@@ -726,11 +1554,13 @@ mod tests {
         )
         .unwrap();
         let record_buffer = [
-            9,                   // length
+            11,                  // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(5, 7) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
             nd(7, 9) | IS_NOT_SINGLE_MASK,
+            nd(9, 11) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             3.0f32.to_bits(),
@@ -751,11 +1581,13 @@ mod tests {
 
         // Now let's have 30.0/60.0
         let record_buffer = [
-            9,                   // length
+            11,                  // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(5, 7) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
             nd(7, 9) | IS_NOT_SINGLE_MASK,
+            nd(9, 11) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             30.0f32.to_bits(),
@@ -779,11 +1611,13 @@ mod tests {
 
         // Now let's have 30.0/0.0
         let record_buffer = [
-            9,                   // length
+            11,                  // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(5, 7) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
             nd(7, 9) | IS_NOT_SINGLE_MASK,
+            nd(9, 11) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             30.0f32.to_bits(),
@@ -803,11 +1637,13 @@ mod tests {
 
         // Now let's have 0.0/50.0
         let record_buffer = [
-            9,                   // length
+            11,                  // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(5, 7) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
             nd(7, 9) | IS_NOT_SINGLE_MASK,
+            nd(9, 11) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             0.0f32.to_bits(),
@@ -831,11 +1667,13 @@ mod tests {
 
         // Now let's have 50.0/0.0
         let record_buffer = [
-            9,                   // length
+            11,                  // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(5, 7) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
             nd(7, 9) | IS_NOT_SINGLE_MASK,
+            nd(9, 11) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             50.0f32.to_bits(),
@@ -858,6 +1696,99 @@ mod tests {
         assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
     }
 
+    #[test]
+    fn test_transformerboundariesbinner() {
+        let from_namespace = feature_transform_parser::Namespace {
+            namespace_descriptor: ns_desc_f32(0),
+            namespace_verbose: "a".to_string(),
+        };
+        let to_namespace_index = 1;
+
+        let to_namespace_empty = ExecutorToNamespace {
+            namespace_descriptor: ns_desc(to_namespace_index),
+            namespace_seeds: default_seeds(to_namespace_index as u32), // These are precomputed namespace seeds
+            tmp_data: Vec::new(),
+        };
+
+        let transformer = TransformerBoundariesBinner::create_function(
+            "Blah",
+            &vec![from_namespace.clone()],
+            &vec![10.0, 100.0],
+        )
+        .unwrap();
+        let mut transform_executors = TransformExecutors { executors: vec![] }; // not used
+        for (value, bin) in vec![
+            (-5.0, 0),
+            (10.0, 1),
+            (99.0, 1),
+            (100.0, 2),
+            (f32::INFINITY, 2),
+        ] {
+            let record_buffer = [
+                8,                   // length
+                0,                   // label
+                (1.0_f32).to_bits(), // Example weight
+                0,                   // Initial prediction
+                parser::NO_TAG,
+                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                // Feature triple
+                1775699190 & MASK31, // Hash location
+                (value as f32).to_bits(),
+            ]; // Float feature value
+
+            let mut to_namespace = to_namespace_empty.clone();
+            transformer.execute_function(
+                &record_buffer,
+                &mut to_namespace,
+                &mut transform_executors,
+            );
+
+            let mut to_namespace_comparison = to_namespace_empty.clone();
+            to_namespace_comparison.emit_i32::<{ SeedNumber::Default as usize }>(bin, 1.0);
+            assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+        }
+
+        // Cut points have to be increasing
+        assert!(TransformerBoundariesBinner::create_function(
+            "Blah",
+            &vec![from_namespace.clone()],
+            &vec![10.0, 10.0],
+        )
+        .is_err());
+
+        // Quantile binner uses the fitted cut points and needs the number of bins
+        let transformer = TransformerBoundariesBinner::create_quantile_function(
+            "Blah",
+            &vec![from_namespace.clone()],
+            &vec![3.0],
+            &Some(vec![10.0, 100.0]),
+        )
+        .unwrap();
+        let record_buffer = [
+            8,                   // length
+            0,                   // label
+            (1.0_f32).to_bits(), // Example weight
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            nd(6, 8) | IS_NOT_SINGLE_MASK,
+            // Feature triple
+            1775699190 & MASK31, // Hash location
+            50.0f32.to_bits(),
+        ]; // Float feature value
+        let mut to_namespace = to_namespace_empty.clone();
+        transformer.execute_function(&record_buffer, &mut to_namespace, &mut transform_executors);
+        let mut to_namespace_comparison = to_namespace_empty.clone();
+        to_namespace_comparison.emit_i32::<{ SeedNumber::Default as usize }>(1, 1.0);
+        assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+        assert!(TransformerBoundariesBinner::create_quantile_function(
+            "Blah",
+            &vec![from_namespace],
+            &vec![],
+            &None,
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_transformerweightmutliplier() {
         let from_namespace_float = feature_transform_parser::Namespace {
@@ -876,10 +1807,12 @@ mod tests {
             TransformerWeight::create_function("Blah", &vec![from_namespace_float], &vec![40.])
                 .unwrap();
         let record_buffer = [
-            6,                   // length
+            8,                   // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(4, 6) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            nd(6, 8) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             3.0f32.to_bits(),
@@ -908,10 +1841,12 @@ mod tests {
             TransformerWeight::create_function("Blah", &vec![from_namespace_nonfloat], &vec![40.])
                 .unwrap();
         let record_buffer = [
-            7,                   // length
+            9,                   // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(4, 6) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            nd(6, 8) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             2.0f32.to_bits(),
@@ -931,6 +1866,345 @@ mod tests {
         assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
     }
 
+    #[test]
+    fn test_transformermissing_and_countfeatures() {
+        let from_namespace = feature_transform_parser::Namespace {
+            namespace_descriptor: ns_desc(0),
+            namespace_verbose: "a".to_string(),
+        };
+        let to_namespace_index = 1;
+
+        let to_namespace_empty = ExecutorToNamespace {
+            namespace_descriptor: ns_desc(to_namespace_index),
+            namespace_seeds: default_seeds(to_namespace_index as u32), // These are precomputed namespace seeds
+            tmp_data: Vec::new(),
+        };
+
+        let missing =
+            TransformerMissing::create_function("Blah", &vec![from_namespace.clone()], &vec![])
+                .unwrap();
+        let count_features = TransformerCountFeatures::create_function(
+            "Blah",
+            &vec![from_namespace.clone()],
+            &vec![2.0],
+        )
+        .unwrap();
+        let mut transform_executors = TransformExecutors { executors: vec![] }; // not used
+
+        let record_buffer_empty = [
+            6,                   // length
+            0,                   // label
+            (1.0_f32).to_bits(), // Example weight
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            parser::NO_FEATURES,
+        ];
+        let record_buffer_three = [
+            12,                  // length
+            0,                   // label
+            (1.0_f32).to_bits(), // Example weight
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            nd(6, 12) | IS_NOT_SINGLE_MASK,
+            // Feature triples
+            1 & MASK31,
+            1.0f32.to_bits(),
+            2 & MASK31,
+            1.0f32.to_bits(),
+            3 & MASK31,
+            2.0f32.to_bits(),
+        ];
+
+        // Missing namespace has the missing feature, present namespace has none
+        let mut to_namespace = to_namespace_empty.clone();
+        missing.execute_function(
+            &record_buffer_empty,
+            &mut to_namespace,
+            &mut transform_executors,
+        );
+        let mut to_namespace_comparison = to_namespace_empty.clone();
+        to_namespace_comparison.emit_i32::<{ SeedNumber::Default as usize }>(0, 1.0);
+        assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+
+        let mut to_namespace = to_namespace_empty.clone();
+        missing.execute_function(
+            &record_buffer_three,
+            &mut to_namespace,
+            &mut transform_executors,
+        );
+        assert_eq!(to_namespace.tmp_data, vec![]);
+
+        // Zero features is counted like any other number of features
+        let mut to_namespace = to_namespace_empty.clone();
+        count_features.execute_function(
+            &record_buffer_empty,
+            &mut to_namespace,
+            &mut transform_executors,
+        );
+        let mut to_namespace_comparison = to_namespace_empty.clone();
+        to_namespace_comparison.emit_i32::<{ SeedNumber::Default as usize }>(0, 1.0);
+        assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+
+        // Counts of at least 2.0 are binned by sqrt
+        let mut to_namespace = to_namespace_empty.clone();
+        count_features.execute_function(
+            &record_buffer_three,
+            &mut to_namespace,
+            &mut transform_executors,
+        );
+        let mut to_namespace_comparison = to_namespace_empty.clone();
+        to_namespace_comparison.emit_i32::<{ SeedNumber::One as usize }>(1, 1.0);
+        assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+    }
+
+    #[test]
+    fn test_continuous_transformers() {
+        let from_namespace = feature_transform_parser::Namespace {
+            namespace_descriptor: ns_desc_f32(0),
+            namespace_verbose: "a".to_string(),
+        };
+        let to_namespace_index = 1;
+
+        let to_namespace_empty = ExecutorToNamespace {
+            namespace_descriptor: ns_desc(to_namespace_index),
+            namespace_seeds: default_seeds(to_namespace_index as u32), // These are precomputed namespace seeds
+            tmp_data: Vec::new(),
+        };
+        let from_namespaces = vec![from_namespace];
+
+        let standardize = TransformerStandardize::create_function(
+            "Blah",
+            &from_namespaces,
+            &vec![100.0, 20.0],
+            &None,
+        )
+        .unwrap();
+        let clip =
+            TransformerClip::create_function("Blah", &from_namespaces, &vec![0.0, 100.0], &None)
+                .unwrap();
+        let log1p = TransformerLog1p::create_function("Blah", &from_namespaces, &vec![]).unwrap();
+        // Fitted parameters are used when there are none on the command line
+        let fitted_standardize = TransformerStandardize::create_function(
+            "Blah",
+            &from_namespaces,
+            &vec![],
+            &Some(vec![100.0, 20.0]),
+        )
+        .unwrap();
+        let mut transform_executors = TransformExecutors { executors: vec![] }; // not used
+
+        for (transformer, value, transformed_value) in vec![
+            (&standardize, 150.0, 2.5),
+            (&fitted_standardize, 150.0, 2.5),
+            (&clip, 150.0, 100.0),
+            (&clip, -3.0, 0.0),
+            (&clip, 50.0, 50.0),
+            (&log1p, 0.0, 0.0),
+            (&log1p, 1.0, 2.0_f32.ln()),
+            (&log1p, -1.0, -(2.0_f32.ln())),
+        ] {
+            let record_buffer = [
+                8,                   // length
+                0,                   // label
+                (1.0_f32).to_bits(), // Example weight
+                0,                   // Initial prediction
+                parser::NO_TAG,
+                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                // Feature triple
+                1775699190 & MASK31, // Hash location
+                (value as f32).to_bits(),
+            ]; // Float feature value
+
+            let mut to_namespace = to_namespace_empty.clone();
+            transformer.execute_function(
+                &record_buffer,
+                &mut to_namespace,
+                &mut transform_executors,
+            );
+
+            // The value is emitted under a single hash of the to namespace
+            let mut to_namespace_comparison = to_namespace_empty.clone();
+            to_namespace_comparison
+                .emit_i32::<{ SeedNumber::Default as usize }>(0, transformed_value);
+            assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+        }
+
+        // Non-finite values are not emitted
+        let record_buffer = [
+            8,                   // length
+            0,                   // label
+            (1.0_f32).to_bits(), // Example weight
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            nd(6, 8) | IS_NOT_SINGLE_MASK,
+            // Feature triple
+            1775699190 & MASK31, // Hash location
+            f32::NAN.to_bits(),
+        ]; // Float feature value
+        let mut to_namespace = to_namespace_empty.clone();
+        standardize.execute_function(&record_buffer, &mut to_namespace, &mut transform_executors);
+        assert_eq!(to_namespace.tmp_data, vec![]);
+
+        // Parameters have to make sense
+        assert!(TransformerStandardize::create_function(
+            "Blah",
+            &from_namespaces,
+            &vec![100.0, 0.0],
+            &None
+        )
+        .is_err());
+        assert!(
+            TransformerClip::create_function("Blah", &from_namespaces, &vec![1.0], &None).is_err()
+        );
+        assert!(TransformerClip::create_function(
+            "Blah",
+            &from_namespaces,
+            &vec![10.0, 1.0],
+            &None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_timestamp_transformers() {
+        let from_namespace = feature_transform_parser::Namespace {
+            namespace_descriptor: ns_desc_f32(0),
+            namespace_verbose: "ts".to_string(),
+        };
+        let now_namespace = feature_transform_parser::Namespace {
+            namespace_descriptor: ns_desc_f32(1),
+            namespace_verbose: "now".to_string(),
+        };
+        let to_namespace_index = 2;
+
+        let to_namespace_empty = ExecutorToNamespace {
+            namespace_descriptor: ns_desc(to_namespace_index),
+            namespace_seeds: default_seeds(to_namespace_index as u32), // These are precomputed namespace seeds
+            tmp_data: Vec::new(),
+        };
+        let mut transform_executors = TransformExecutors { executors: vec![] }; // not used
+        let record_buffer = |ts: f32, now: f32| {
+            vec![
+                11,                  // length
+                0,                   // label
+                (1.0_f32).to_bits(), // Example weight
+                0,                   // Initial prediction
+                parser::NO_TAG,
+                nd(7, 9) | IS_NOT_SINGLE_MASK,
+                nd(9, 11) | IS_NOT_SINGLE_MASK,
+                // Feature triples
+                1775699190 & MASK31, // Hash location
+                ts.to_bits(),
+                1775699191 & MASK31, // Hash location
+                now.to_bits(),
+            ]
+        };
+        let from_namespaces = vec![from_namespace.clone()];
+        let midnight = 1704067200.0; // Monday, 2024-01-01 00:00:00 UTC
+
+        // Hours and days of the time zone given by the offset
+        for (tz_offset, hour, day) in vec![
+            (vec![], 0, 0),
+            (vec![13.0], 13, 0),
+            (vec![5.5], 5, 0),
+            (vec![-1.0], 23, 6),
+        ] {
+            let hour_of_day =
+                TransformerHourOfDay::create_function("Blah", &from_namespaces, &tz_offset)
+                    .unwrap();
+            let mut to_namespace = to_namespace_empty.clone();
+            hour_of_day.execute_function(
+                &record_buffer(midnight, 0.0),
+                &mut to_namespace,
+                &mut transform_executors,
+            );
+            let mut to_namespace_comparison = to_namespace_empty.clone();
+            to_namespace_comparison.emit_i32::<{ SeedNumber::One as usize }>(hour, 1.0);
+            assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+
+            let day_of_week =
+                TransformerDayOfWeek::create_function("Blah", &from_namespaces, &tz_offset)
+                    .unwrap();
+            let mut to_namespace = to_namespace_empty.clone();
+            day_of_week.execute_function(
+                &record_buffer(midnight, 0.0),
+                &mut to_namespace,
+                &mut transform_executors,
+            );
+            let mut to_namespace_comparison = to_namespace_empty.clone();
+            to_namespace_comparison.emit_i32::<{ SeedNumber::Two as usize }>(day, 1.0);
+            assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+        }
+
+        // Hours and days don't share hashes
+        assert_ne!(
+            default_seeds(to_namespace_index as u32)[SeedNumber::One as usize],
+            default_seeds(to_namespace_index as u32)[SeedNumber::Two as usize]
+        );
+
+        let age_bucket = TransformerAgeBucket::create_function(
+            "Blah",
+            &vec![from_namespace.clone(), now_namespace.clone()],
+            &vec![3600.0, 86400.0],
+        )
+        .unwrap();
+        for (ts, now, bucket) in vec![
+            (1000.0, 1000.0, Some(0)),
+            (1000.0, 4599.0, Some(0)),
+            (1000.0, 4600.0, Some(1)),
+            (1000.0, 100000.0, Some(2)),
+            (1000.0, 999.0, None), // in the future
+        ] {
+            let mut to_namespace = to_namespace_empty.clone();
+            age_bucket.execute_function(
+                &record_buffer(ts, now),
+                &mut to_namespace,
+                &mut transform_executors,
+            );
+            let mut to_namespace_comparison = to_namespace_empty.clone();
+            match bucket {
+                Some(bucket) => {
+                    to_namespace_comparison.emit_i32::<{ SeedNumber::Three as usize }>(bucket, 1.0)
+                }
+                None => to_namespace_comparison.emit_i32::<{ SeedNumber::Four as usize }>(0, 1.0),
+            }
+            assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+        }
+
+        // Missing timestamps are skipped
+        let mut to_namespace = to_namespace_empty.clone();
+        age_bucket.execute_function(
+            &record_buffer(f32::NAN, 1000.0),
+            &mut to_namespace,
+            &mut transform_executors,
+        );
+        assert_eq!(to_namespace.tmp_data, vec![]);
+
+        // Parameters have to make sense
+        assert!(
+            TransformerHourOfDay::create_function("Blah", &from_namespaces, &vec![25.0]).is_err()
+        );
+        assert!(
+            TransformerDayOfWeek::create_function("Blah", &from_namespaces, &vec![1.0, 2.0])
+                .is_err()
+        );
+        assert!(TransformerAgeBucket::create_function(
+            "Blah",
+            &vec![from_namespace.clone(), now_namespace.clone()],
+            &vec![]
+        )
+        .is_err());
+        assert!(TransformerAgeBucket::create_function(
+            "Blah",
+            &vec![from_namespace.clone(), now_namespace.clone()],
+            &vec![86400.0, 3600.0]
+        )
+        .is_err());
+        assert!(
+            TransformerAgeBucket::create_function("Blah", &from_namespaces, &vec![3600.0]).is_err()
+        );
+    }
+
     #[test]
     fn test_transformercombine() {
         let from_namespace_1 = feature_transform_parser::Namespace {
@@ -959,11 +2233,13 @@ mod tests {
         .unwrap();
 
         let record_buffer = [
-            9,                   // length
+            11,                  // length
             0,                   // label
             (1.0_f32).to_bits(), // Example weight
-            nd(5, 7) | IS_NOT_SINGLE_MASK,
+            0,                   // Initial prediction
+            parser::NO_TAG,
             nd(7, 9) | IS_NOT_SINGLE_MASK,
+            nd(9, 11) | IS_NOT_SINGLE_MASK,
             // Feature triple
             1775699190 & MASK31, // Hash location
             3.0f32.to_bits(),    // Float value of the feature
@@ -983,4 +2259,108 @@ mod tests {
             .emit_i32::<{ SeedNumber::Default as usize }>((1775699190 ^ 1775699190) as i32, 3.0f32);
         assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
     }
+
+    #[test]
+    fn test_transformercombine_nary() {
+        let from_namespaces: Vec<feature_transform_parser::Namespace> = (0..3)
+            .map(|i| feature_transform_parser::Namespace {
+                namespace_descriptor: ns_desc(i),
+                namespace_verbose: format!("ns{}", i),
+            })
+            .collect();
+        let to_namespace_index = 3;
+
+        let to_namespace_empty = ExecutorToNamespace {
+            namespace_descriptor: ns_desc(to_namespace_index),
+            namespace_seeds: default_seeds(to_namespace_index as u32), // These are precomputed namespace seeds
+            tmp_data: Vec::new(),
+        };
+
+        let record_buffer = [
+            16,                  // length
+            0,                   // label
+            (1.0_f32).to_bits(), // Example weight
+            0,                   // Initial prediction
+            parser::NO_TAG,
+            nd(8, 12) | IS_NOT_SINGLE_MASK,
+            4, // Single feature with value 1.0
+            nd(12, 16) | IS_NOT_SINGLE_MASK,
+            // Features of the first namespace
+            1,
+            1.0f32.to_bits(),
+            2,
+            2.0f32.to_bits(),
+            // Features of the third namespace
+            8,
+            1.0f32.to_bits(),
+            16,
+            3.0f32.to_bits(),
+        ];
+        let transform_executors = TransformExecutors { executors: vec![] }; // not used
+        let execute = |transformer: Box<dyn FunctionExecutorTrait>| {
+            let mut to_namespace = to_namespace_empty.clone();
+            transformer.execute_function(&record_buffer, &mut to_namespace, &transform_executors);
+            to_namespace.tmp_data
+        };
+        let expected = |crosses: Vec<(u32, f32)>| {
+            let mut to_namespace_comparison = to_namespace_empty.clone();
+            for (hash_index, hash_value) in crosses {
+                to_namespace_comparison
+                    .emit_i32::<{ SeedNumber::Default as usize }>(hash_index as i32, hash_value);
+            }
+            to_namespace_comparison.tmp_data
+        };
+
+        // All crosses, in the order of nested loops
+        let transformer =
+            TransformerCombine::create_function("Blah", &from_namespaces, &vec![]).unwrap();
+        assert_eq!(
+            execute(transformer),
+            expected(vec![
+                (1 ^ 4 ^ 8, 1.0),
+                (1 ^ 4 ^ 16, 3.0),
+                (2 ^ 4 ^ 8, 2.0),
+                (2 ^ 4 ^ 16, 6.0)
+            ])
+        );
+
+        // Weights multiply values of their namespaces
+        let transformer =
+            TransformerCombine::create_function("Blah", &from_namespaces, &vec![1.0, 1.0, 0.5])
+                .unwrap();
+        assert_eq!(
+            execute(transformer),
+            expected(vec![
+                (1 ^ 4 ^ 8, 0.5),
+                (1 ^ 4 ^ 16, 1.5),
+                (2 ^ 4 ^ 8, 1.0),
+                (2 ^ 4 ^ 16, 3.0)
+            ])
+        );
+        assert!(TransformerCombine::create_function("Blah", &from_namespaces, &vec![1.0]).is_err());
+
+        // Only the largest crosses
+        let transformer =
+            TransformerCombine::create_top_k_function("Blah", &from_namespaces, &vec![2.0])
+                .unwrap();
+        assert_eq!(
+            execute(transformer),
+            expected(vec![(2 ^ 4 ^ 16, 6.0), (1 ^ 4 ^ 16, 3.0)])
+        );
+        let transformer =
+            TransformerCombine::create_top_k_function("Blah", &from_namespaces, &vec![10.0])
+                .unwrap();
+        assert_eq!(execute(transformer).len(), 4);
+        assert!(
+            TransformerCombine::create_top_k_function("Blah", &from_namespaces, &vec![]).is_err()
+        );
+
+        // Any number of namespaces
+        let many_namespaces: Vec<feature_transform_parser::Namespace> = (0..6)
+            .map(|i| from_namespaces[(i % 2) * 2].clone())
+            .collect();
+        let transformer =
+            TransformerCombine::create_function("Blah", &many_namespaces, &vec![]).unwrap();
+        assert_eq!(execute(transformer).len(), 64);
+    }
 }
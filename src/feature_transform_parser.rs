@@ -10,6 +10,7 @@ use std::io::Error as IOError;
 use std::io::ErrorKind;
 
 use crate::feature_transform_executor;
+use crate::feature_transform_string;
 
 pub const TRANSFORM_NAMESPACE_MARK: u32 = 1 << 31;
 
@@ -25,6 +26,9 @@ pub struct NamespaceTransform {
     pub from_namespaces: Vec<Namespace>,
     pub function_name: String,
     pub function_parameters: Vec<f32>,
+    // Parameters computed from the training data in a pass before training (e.g. quantile boundaries)
+    #[serde(default)]
+    pub fitted_parameters: Option<Vec<f32>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -61,6 +65,16 @@ impl NamespaceTransformsParser {
         vw: &vwmap::VwNamespaceMap,
         s: &str,
     ) -> Result<(), Box<dyn Error>> {
+        // String transforms are applied by the parsers, their namespaces are in vw namespace map already
+        if let Some(string_transform) = feature_transform_string::parse_string_transform(s)? {
+            if !vw.vw_source.string_transforms.contains(&string_transform) {
+                return Err(format!(
+                    "String transform {} is not in the vw namespace map",
+                    s
+                ))?;
+            }
+            return Ok(());
+        }
         let rr = parse_namespace_statement(s);
         if rr.is_err() {
             return Err(Box::new(IOError::new(
@@ -69,9 +83,15 @@ impl NamespaceTransformsParser {
             )));
         }
         let (
-            _,
+            rest,
             (to_namespace_verbose, function_name, from_namespaces_verbose, function_parameters),
         ) = rr.unwrap();
+        if !rest.trim().is_empty() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Error parsing {}, unexpected {:?} at the end", s, rest),
+            )));
+        }
 
         // Here we just check for clashes with namespaces from input file
         let namespace_descriptor = vw
@@ -87,6 +107,16 @@ impl NamespaceTransformsParser {
             )));
         }
 
+        if let Some(n) = self.denormalized.get(&to_namespace_verbose) {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Namespace {:?} is made by more than one --transform: {} and {}",
+                    to_namespace_verbose, n.definition, s
+                ),
+            )));
+        }
+
         self.denormalized.insert(
             to_namespace_verbose.to_owned(),
             NSStage1Parse {
@@ -108,16 +138,18 @@ impl NamespaceTransformsParser {
         let mut namespaces: Vec<&String> = self.denormalized.keys().collect();
         namespaces.sort(); // ensure determinism
         for key in &namespaces {
-            self.depth_first_search(vw, &mut nst, key)?;
+            self.depth_first_search(vw, &mut nst, key, &mut Vec::new())?;
         }
         Ok(nst)
     }
 
+    // path holds definitions of the transforms that led to this namespace, they name the culprits in errors
     pub fn depth_first_search(
         &self,
         vw: &vwmap::VwNamespaceMap,
         nst: &mut NamespaceTransforms,
         verbose_name: &str,
+        path: &mut Vec<String>,
     ) -> Result<(), Box<dyn Error>> {
         // If feature is primitive feature, we don't need to dive deeper
         if vw
@@ -133,7 +165,13 @@ impl NamespaceTransformsParser {
             None => {
                 return Err(Box::new(IOError::new(
                     ErrorKind::Other,
-                    format!("Could not find namespace {:?}", verbose_name),
+                    match path.last() {
+                        Some(definition) => format!(
+                            "Could not find namespace {:?} used by --transform {}",
+                            verbose_name, definition
+                        ),
+                        None => format!("Could not find namespace {:?}", verbose_name),
+                    },
                 )))
             }
         };
@@ -143,20 +181,31 @@ impl NamespaceTransformsParser {
         }
 
         if n.processing.get() {
+            // The cycle starts where this namespace was defined
+            let cycle_start = path.iter().position(|d| *d == n.definition).unwrap_or(0);
+            let mut cycle = path[cycle_start..].to_vec();
+            cycle.push(n.definition.clone());
             return Err(Box::new(IOError::new(
                 ErrorKind::Other,
                 format!(
-                    "Cyclic dependency detected, one of the namespaces involved is {:?}",
-                    verbose_name
+                    "Cyclic dependency among --transform definitions: {}",
+                    cycle.join(" -> ")
                 ),
             )));
         }
 
         n.processing.set(true);
+        path.push(n.definition.clone());
         for from_namespace in &n.from_namespaces {
-            self.depth_first_search(vw, nst, &from_namespace)?;
+            self.depth_first_search(vw, nst, &from_namespace, path)?;
+        }
+        path.pop();
+        if let Err(e) = nst.add_transform(vw, &n.definition) {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Error in --transform {}: {}", n.definition, e),
+            )));
         }
-        nst.add_transform(vw, &self.denormalized[verbose_name].definition)?;
 
         n.processing.set(false);
         n.done.set(true);
@@ -232,6 +281,7 @@ impl NamespaceTransforms {
             to_namespace: to_namespace,
             function_name: function_name,
             function_parameters: function_parameters,
+            fitted_parameters: None,
         };
 
         // Now we try to setup a function and then throw it away - for early validation
@@ -382,6 +432,66 @@ pub fn parse_namespace_statement(
     ))
 }
 
+// String transforms (see feature_transform_string) take strings as parameters, quoted or not, e.g. SplitTokens(tags)(",")
+pub fn parse_string_param(input: &str) -> IResult<&str, String> {
+    let quoted = nom::sequence::delimited(
+        complete::char('"'),
+        take_while(|c| c != '"'),
+        complete::char('"'),
+    );
+    let unquoted =
+        nom::bytes::complete::take_while1(|c: char| c != ',' && c != ')' && !c.is_whitespace());
+    let (input, (_, param, _)) = tuple((
+        character::complete::space0,
+        nom::branch::alt((quoted, unquoted)),
+        character::complete::space0,
+    ))(input)?;
+    Ok((input, param.to_string()))
+}
+
+pub fn parse_function_params_strings(input: &str) -> IResult<&str, Vec<String>> {
+    let take_open = complete::char('(');
+    let take_close = complete::char(')');
+    let take_separator = complete::char(',');
+    let (input, (_, params, _)) = tuple((
+        take_open,
+        nom::multi::separated_list0(take_separator, parse_string_param),
+        take_close,
+    ))(input)?;
+    Ok((input, params))
+}
+
+// Parameters of string transforms can be left out altogether, e.g. Domain(url)
+pub fn parse_string_transform_statement(
+    input: &str,
+) -> IResult<&str, (String, String, Vec<String>, Vec<String>)> {
+    let (input, (to_namespace_verbose, _, function_name, from_namespace_verbose, parameters)) =
+        tuple((
+            parse_identifier,
+            complete::char('='),
+            parse_identifier,
+            parse_function_params_namespaces,
+            nom::combinator::opt(parse_function_params_strings),
+        ))(input)?;
+
+    Ok((
+        input,
+        (
+            to_namespace_verbose,
+            function_name,
+            from_namespace_verbose,
+            parameters.unwrap_or_default(),
+        ),
+    ))
+}
+
+// Function name of a statement, without parsing the rest of it
+pub fn parse_function_name(input: &str) -> IResult<&str, String> {
+    let (input, (_, _, function_name)) =
+        tuple((parse_identifier, complete::char('='), parse_identifier))(input)?;
+    Ok((input, function_name))
+}
+
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
@@ -441,7 +551,7 @@ C,featureC,f32
             assert!(result.is_err());
             assert_eq!(
                 format!("{:?}", result),
-                "Err(Custom { kind: Other, error: \"Unknown transformer function: unknown\" })"
+                "Err(Custom { kind: Other, error: \"Error in --transform new=unknown(featureA,featureB)(): Unknown transformer function: unknown\" })"
             );
         }
 
@@ -458,7 +568,7 @@ C,featureC,f32
             let result = nstp.add_transform_namespace(&vw, "new=Combine(featureA,featureA)()"); // unknown function
             let result = nstp.resolve(&vw);
             assert!(result.is_err());
-            assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Using the same from namespace in multiple arguments to a function is not supported: \\\"featureA\\\"\" })");
+            assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Error in --transform new=Combine(featureA,featureA)(): Using the same from namespace in multiple arguments to a function is not supported: \\\"featureA\\\"\" })");
         }
 
         {
@@ -467,7 +577,7 @@ C,featureC,f32
                 .unwrap(); // unknown function
             let result = nstp.resolve(&vw);
             assert!(result.is_err());
-            assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Could not find namespace \\\"nonexistent\\\"\" })");
+            assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Could not find namespace \\\"nonexistent\\\" used by --transform new=unknown(nonexistent,featureB)()\" })");
         }
 
         {
@@ -539,7 +649,7 @@ C,featureC,f32
             assert!(result.is_ok());
             let nst = nstp.resolve(&vw);
             assert!(nst.is_err());
-            assert_eq!(format!("{:?}", nst), "Err(Custom { kind: Other, error: \"Cyclic dependency detected, one of the namespaces involved is \\\"new1\\\"\" })");
+            assert_eq!(format!("{:?}", nst), "Err(Custom { kind: Other, error: \"Cyclic dependency among --transform definitions: new1=Combine(new2,featureB)() -> new2=Combine(new1,featureB)() -> new1=Combine(new2,featureB)()\" })");
         }
 
         {
@@ -549,10 +659,58 @@ C,featureC,f32
             assert!(result.is_ok());
             let nst = nstp.resolve(&vw);
             assert!(nst.is_err());
-            assert_eq!(format!("{:?}", nst), "Err(Custom { kind: Other, error: \"Cyclic dependency detected, one of the namespaces involved is \\\"new1\\\"\" })");
+            assert_eq!(format!("{:?}", nst), "Err(Custom { kind: Other, error: \"Cyclic dependency among --transform definitions: new1=Combine(new1,featureB)() -> new1=Combine(new1,featureB)()\" })");
         }
     }
 
+    #[test]
+    fn test_namespace_transforms_errors() {
+        let vw = VwNamespaceMap::new("A,site\nB,price,f32\n").unwrap();
+        let resolve_error = |transforms: Vec<&str>| {
+            let mut nstp = NamespaceTransformsParser::new();
+            for transform in transforms {
+                nstp.add_transform_namespace(&vw, transform).unwrap();
+            }
+            nstp.resolve(&vw).unwrap_err().to_string()
+        };
+
+        // Only the transforms in the cycle are reported
+        assert_eq!(
+            resolve_error(vec![
+                "a=Combine(b,site)()",
+                "b=Combine(c,site)()",
+                "c=Combine(b,price)()"
+            ]),
+            "Cyclic dependency among --transform definitions: b=Combine(c,site)() -> c=Combine(b,price)() -> b=Combine(c,site)()"
+        );
+        // Arity and types are checked for chained transforms too
+        assert_eq!(
+            resolve_error(vec!["bin=BinnerLog(price)(20.0, 2.0)", "c=Combine(bin)()"]),
+            "Error in --transform c=Combine(bin)(): Function Combine takes at least 2 namespace arguments, example Combine(A,B)()"
+        );
+        assert_eq!(
+            resolve_error(vec!["c=Combine(price,site)()", "bin=BinnerLog(c)(20.0, 2.0)"]),
+            "Error in --transform bin=BinnerLog(c)(20.0, 2.0): All namespaces of function BinnerLog have to be of type f32: From namespace (c) is made by a transform, so it is categorical"
+        );
+        assert_eq!(
+            resolve_error(vec!["bin=BinnerLog(site)(20.0, 2.0)"]),
+            "Error in --transform bin=BinnerLog(site)(20.0, 2.0): All namespaces of function BinnerLog have to be of type f32: From namespace (site) should be typed in vw_namespace_map.csv"
+        );
+
+        let mut nstp = NamespaceTransformsParser::new();
+        nstp.add_transform_namespace(&vw, "c=Combine(price,site)()")
+            .unwrap();
+        assert_eq!(
+            nstp.add_transform_namespace(&vw, "c=Weight(site)(2.0)")
+                .unwrap_err()
+                .to_string(),
+            "Namespace \"c\" is made by more than one --transform: c=Combine(price,site)() and c=Weight(site)(2.0)"
+        );
+        assert!(nstp
+            .add_transform_namespace(&vw, "d=Weight(site)(2.0))")
+            .is_err());
+    }
+
     #[test]
     fn test_parser1() {
         let r = parse_identifier("a");
@@ -0,0 +1,325 @@
+use fasthash::murmur3;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::str;
+
+use crate::feature_transform_parser;
+use crate::parser::{FLOAT32_ONE, HEADER_LEN, IS_NOT_SINGLE_MASK, MASK31, NAMESPACE_DESC_LEN};
+use crate::vwmap;
+
+// String transforms work on feature names before they are hashed, so unlike other transforms they are applied by
+// the parsers. Each one makes a new categorical namespace of the vw namespace map, this way it is saved with the
+// model (and cache) and serving applies it just like training did.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub enum StringFunction {
+    Prefix(usize),       // first n characters of the name
+    Domain,              // lowercased host of an url
+    Lowercase,           // lowercased name
+    SplitTokens(String), // non-empty parts of the name, split by the separator
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct StringTransform {
+    pub to_namespace_verbose: String,
+    pub from_namespace_verbose: String,
+    pub function: StringFunction,
+}
+
+pub fn is_string_function(function_name: &str) -> bool {
+    match function_name {
+        "Prefix" | "Domain" | "Lowercase" | "SplitTokens" => true,
+        _ => false,
+    }
+}
+
+// Returns None when the --transform definition is not a string transform
+pub fn parse_string_transform(s: &str) -> Result<Option<StringTransform>, Box<dyn Error>> {
+    match feature_transform_parser::parse_function_name(s) {
+        Ok((_, function_name)) if is_string_function(&function_name) => {}
+        _ => return Ok(None),
+    }
+    let (to_namespace_verbose, function_name, from_namespaces_verbose, parameters) =
+        match feature_transform_parser::parse_string_transform_statement(s.trim()) {
+            Ok(("", statement)) => statement,
+            rr => return Err(format!("Error parsing {}\n{:?}", s, rr))?,
+        };
+    if from_namespaces_verbose.len() != 1 {
+        return Err(format!(
+            "String transform {} has to have exactly one from namespace",
+            s
+        ))?;
+    }
+    let function = match (function_name.as_str(), parameters.as_slice()) {
+        ("Prefix", [n]) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => StringFunction::Prefix(n),
+            _ => return Err(format!("Prefix length of {} has to be a positive integer", s))?,
+        },
+        ("Domain", []) => StringFunction::Domain,
+        ("Lowercase", []) => StringFunction::Lowercase,
+        ("SplitTokens", [separator]) if !separator.is_empty() => {
+            StringFunction::SplitTokens(separator.to_string())
+        }
+        _ => return Err(format!("Wrong parameters of string transform {}, the supported ones are Prefix(ns)(n), Domain(ns), Lowercase(ns) and SplitTokens(ns)(\"separator\")", s))?,
+    };
+    Ok(Some(StringTransform {
+        to_namespace_verbose: to_namespace_verbose,
+        from_namespace_verbose: from_namespaces_verbose[0].to_string(),
+        function: function,
+    }))
+}
+
+impl StringFunction {
+    // Calls emit with every feature name the function makes of the name
+    pub fn apply(&self, name: &[u8], scratch: &mut Vec<u8>, emit: &mut dyn FnMut(&[u8])) {
+        match self {
+            StringFunction::Prefix(n) => {
+                let len = match str::from_utf8(name) {
+                    Ok(s) => s.char_indices().nth(*n).map_or(name.len(), |(i, _)| i),
+                    Err(_) => name.len().min(*n),
+                };
+                emit(&name[..len]);
+            }
+            StringFunction::Domain => {
+                let host = url_host(name);
+                if !host.is_empty() {
+                    scratch.clear();
+                    scratch.extend(host.iter().map(|c| c.to_ascii_lowercase()));
+                    emit(scratch);
+                }
+            }
+            StringFunction::Lowercase => {
+                scratch.clear();
+                match str::from_utf8(name) {
+                    Ok(s) => scratch.extend_from_slice(s.to_lowercase().as_bytes()),
+                    Err(_) => scratch.extend(name.iter().map(|c| c.to_ascii_lowercase())),
+                }
+                emit(scratch);
+            }
+            StringFunction::SplitTokens(separator) => {
+                let separator = separator.as_bytes();
+                let mut rest = name;
+                loop {
+                    let (token, next) =
+                        match rest.windows(separator.len()).position(|w| w == separator) {
+                            Some(i) => (&rest[..i], Some(&rest[i + separator.len()..])),
+                            None => (rest, None),
+                        };
+                    if !token.is_empty() {
+                        emit(token);
+                    }
+                    match next {
+                        Some(next) => rest = next,
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Host of an url, with or without the scheme: "https://user@www.example.com:8080/path?q" -> "www.example.com"
+fn url_host(url: &[u8]) -> &[u8] {
+    let mut host = match url.windows(3).position(|w| w == b"://") {
+        Some(i) => &url[i + 3..],
+        None => url.strip_prefix(b"//").unwrap_or(url),
+    };
+    if let Some(i) = host
+        .iter()
+        .position(|c| *c == b'/' || *c == b'?' || *c == b'#')
+    {
+        host = &host[..i];
+    }
+    if let Some(i) = host.iter().rposition(|c| *c == b'@') {
+        host = &host[i + 1..];
+    }
+    let port_start = if host.starts_with(b"[") {
+        // IPv6 address
+        host.iter().position(|c| *c == b']').map(|i| i + 1)
+    } else {
+        host.iter().position(|c| *c == b':')
+    };
+    match port_start {
+        Some(i) => &host[..i],
+        None => host,
+    }
+}
+
+// Applies the string transforms of the vw namespace map while a parser goes through the features of a record
+#[derive(Clone)]
+pub struct StringTransformer {
+    functions: Vec<StringFunction>,
+    to_namespace_indices: Vec<usize>,
+    to_namespace_hash_seeds: Vec<u32>,
+    transforms_of_namespace: Vec<Vec<usize>>, // indices of transforms of each from namespace
+    features: Vec<(usize, u32, f32)>, // (transform index, hash, weight) of the current record
+    scratch: Vec<u8>,
+}
+
+impl StringTransformer {
+    pub fn new(vw: &vwmap::VwNamespaceMap) -> StringTransformer {
+        let mut st = StringTransformer {
+            functions: Vec::new(),
+            to_namespace_indices: Vec::new(),
+            to_namespace_hash_seeds: Vec::new(),
+            transforms_of_namespace: vec![Vec::new(); vw.num_namespaces],
+            features: Vec::new(),
+            scratch: Vec::new(),
+        };
+        // VwNamespaceMap::new_from_source() has checked that both namespaces exist
+        for (i, string_transform) in vw.vw_source.string_transforms.iter().enumerate() {
+            let from_namespace_index = vw.map_verbose_to_namespace_descriptor
+                [&string_transform.from_namespace_verbose]
+                .namespace_index as usize;
+            let to_namespace_index = vw.map_verbose_to_namespace_descriptor
+                [&string_transform.to_namespace_verbose]
+                .namespace_index as usize;
+            st.functions.push(string_transform.function.clone());
+            st.to_namespace_indices.push(to_namespace_index);
+            st.to_namespace_hash_seeds
+                .push(murmur3::hash32(&string_transform.to_namespace_verbose));
+            st.transforms_of_namespace[from_namespace_index].push(i);
+        }
+        st
+    }
+
+    pub fn start_record(&mut self) {
+        self.features.truncate(0);
+    }
+
+    #[inline(always)]
+    pub fn add_feature(&mut self, namespace_index: usize, name: &[u8], weight: f32) {
+        for &i in &self.transforms_of_namespace[namespace_index] {
+            let seed = self.to_namespace_hash_seeds[i];
+            let features = &mut self.features;
+            self.functions[i].apply(name, &mut self.scratch, &mut |transformed_name| {
+                let h = murmur3::hash32_with_seed(transformed_name, seed) & MASK31;
+                features.push((i, h, weight));
+            });
+        }
+    }
+
+    // Writes the namespaces made by string transforms to the record, the same way parsers write other namespaces
+    pub fn write_namespaces(&mut self, output_buffer: &mut Vec<u32>) {
+        for (i, to_namespace_index) in self.to_namespace_indices.iter().enumerate() {
+            let bufpos_namespace_start = output_buffer.len();
+            for (transform_index, h, weight) in &self.features {
+                if *transform_index == i {
+                    output_buffer.push(*h);
+                    output_buffer.push(weight.to_bits());
+                }
+            }
+            let bufpos_namespace_end = output_buffer.len();
+            let namespace_index_offset =
+                to_namespace_index * NAMESPACE_DESC_LEN as usize + HEADER_LEN as usize;
+            if bufpos_namespace_end == bufpos_namespace_start {
+                continue;
+            }
+            if bufpos_namespace_end - bufpos_namespace_start == 2
+                && output_buffer[bufpos_namespace_start + 1] == FLOAT32_ONE
+            {
+                output_buffer[namespace_index_offset] = output_buffer[bufpos_namespace_start];
+                output_buffer.truncate(bufpos_namespace_start);
+            } else {
+                output_buffer[namespace_index_offset] = IS_NOT_SINGLE_MASK
+                    | (((bufpos_namespace_start << 16) + bufpos_namespace_end) as u32);
+            }
+        }
+        self.features.truncate(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn apply(function: &StringFunction, name: &str) -> Vec<String> {
+        let mut out: Vec<String> = Vec::new();
+        function.apply(name.as_bytes(), &mut Vec::new(), &mut |n| {
+            out.push(String::from_utf8(n.to_vec()).unwrap())
+        });
+        out
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let prefix = StringFunction::Prefix(3);
+        assert_eq!(apply(&prefix, "abcdef"), vec!["abc"]);
+        assert_eq!(apply(&prefix, "ab"), vec!["ab"]);
+        assert_eq!(apply(&prefix, "čćžšđ"), vec!["čćž"]);
+
+        let domain = StringFunction::Domain;
+        assert_eq!(
+            apply(&domain, "https://user@WWW.Example.com:8080/a/b?c=d"),
+            vec!["www.example.com"]
+        );
+        assert_eq!(
+            apply(&domain, "example.com/index.html"),
+            vec!["example.com"]
+        );
+        assert_eq!(apply(&domain, "//cdn.example.com"), vec!["cdn.example.com"]);
+        assert_eq!(apply(&domain, "http://[::1]:80/"), vec!["[::1]"]);
+        assert_eq!(apply(&domain, "/relative/path"), Vec::<String>::new());
+
+        assert_eq!(apply(&StringFunction::Lowercase, "AbČ"), vec!["abč"]);
+
+        let split = StringFunction::SplitTokens(",".to_string());
+        assert_eq!(apply(&split, "a,b,,c,"), vec!["a", "b", "c"]);
+        assert_eq!(apply(&split, "abc"), vec!["abc"]);
+        let split = StringFunction::SplitTokens("::".to_string());
+        assert_eq!(apply(&split, "a::b:c"), vec!["a", "b:c"]);
+    }
+
+    #[test]
+    fn test_parse_string_transform() {
+        assert_eq!(
+            parse_string_transform("p=Prefix(query)(3)").unwrap(),
+            Some(StringTransform {
+                to_namespace_verbose: "p".to_string(),
+                from_namespace_verbose: "query".to_string(),
+                function: StringFunction::Prefix(3),
+            })
+        );
+        assert_eq!(
+            parse_string_transform("d=Domain(url)")
+                .unwrap()
+                .unwrap()
+                .function,
+            StringFunction::Domain
+        );
+        assert_eq!(
+            parse_string_transform("d=Lowercase(url)()")
+                .unwrap()
+                .unwrap()
+                .function,
+            StringFunction::Lowercase
+        );
+        assert_eq!(
+            parse_string_transform("t=SplitTokens(tags)(\",\")")
+                .unwrap()
+                .unwrap()
+                .function,
+            StringFunction::SplitTokens(",".to_string())
+        );
+        assert_eq!(
+            parse_string_transform("t=SplitTokens(tags)(|)")
+                .unwrap()
+                .unwrap()
+                .function,
+            StringFunction::SplitTokens("|".to_string())
+        );
+
+        // Other transforms are left to the namespace transforms parser
+        assert_eq!(
+            parse_string_transform("b=BinnerLog(price)(20.0, 2.0)").unwrap(),
+            None
+        );
+
+        assert!(parse_string_transform("p=Prefix(query)").is_err());
+        assert!(parse_string_transform("p=Prefix(query)(0)").is_err());
+        assert!(parse_string_transform("p=Prefix(query)(1.5)").is_err());
+        assert!(parse_string_transform("p=Prefix(query, url)(3)").is_err());
+        assert!(parse_string_transform("d=Domain(url)(3)").is_err());
+        assert!(parse_string_transform("t=SplitTokens(tags)(\"\")").is_err());
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::model_instance;
+use crate::vwmap::{NamespaceDescriptor, NamespaceType, VwNamespaceMap};
+
+// Distinct hashes are counted exactly up to this many, above that they are estimated
+const EXACT_COUNT_LIMIT: usize = 1 << 16;
+// 2^14 registers give HyperLogLog a standard error of about 0.8%
+const HLL_PRECISION: u32 = 14;
+// Recommended precision is the smallest one where at most this fraction of distinct features share their weight
+const RECOMMENDED_COLLISION_RATE: f64 = 0.01;
+const MAX_BIT_PRECISION: u32 = 31;
+
+#[derive(Clone, Debug)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; 1 << HLL_PRECISION],
+        }
+    }
+
+    pub fn add(&mut self, hash: u32) {
+        // Feature hashes are not uniform enough in every bit (think of combos), so they are mixed to 64 bits first
+        let h = mix64(hash as u64);
+        let index = (h >> (64 - HLL_PRECISION)) as usize;
+        // Position of the first set bit among the rest, with a sentinel bit so it is never over 64 - HLL_PRECISION + 1
+        let rank = ((h << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|r| 2.0f64.powi(-(*r as i32)))
+            .sum();
+        let raw_estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if raw_estimate <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for small cardinalities
+            m * (m / zeros as f64).ln()
+        } else {
+            raw_estimate
+        }
+    }
+}
+
+// splitmix64 finalizer
+fn mix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[derive(Clone, Debug)]
+pub enum DistinctCounter {
+    Exact(HashSet<u32>),
+    Estimated(HyperLogLog),
+}
+
+impl DistinctCounter {
+    pub fn new() -> DistinctCounter {
+        DistinctCounter::Exact(HashSet::new())
+    }
+
+    pub fn add(&mut self, hash: u32) {
+        let hll = match self {
+            DistinctCounter::Exact(set) => {
+                set.insert(hash);
+                if set.len() <= EXACT_COUNT_LIMIT {
+                    return;
+                }
+                let mut hll = HyperLogLog::new();
+                for hash in set.iter() {
+                    hll.add(*hash);
+                }
+                hll
+            }
+            DistinctCounter::Estimated(hll) => {
+                hll.add(hash);
+                return;
+            }
+        };
+        *self = DistinctCounter::Estimated(hll);
+    }
+
+    pub fn distinct(&self) -> f64 {
+        match self {
+            DistinctCounter::Exact(set) => set.len() as f64,
+            DistinctCounter::Estimated(hll) => hll.estimate(),
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        match self {
+            DistinctCounter::Exact(_) => true,
+            DistinctCounter::Estimated(_) => false,
+        }
+    }
+}
+
+// Expected fraction of distinct features that share their weight with at least one other feature,
+// when distinct features are hashed into slots uniformly
+pub fn collision_rate(distinct: f64, slots: f64) -> f64 {
+    if distinct <= 1.0 {
+        return 0.0;
+    }
+    1.0 - ((distinct - 1.0) * (-1.0 / slots).ln_1p()).exp()
+}
+
+// Counts distinct raw hashes (before masking) of each feature combo and each ffm field
+#[derive(Clone, Debug)]
+pub struct HashStatistics {
+    pub lr_counters: Vec<DistinctCounter>,
+    pub ffm_counters: Vec<DistinctCounter>,
+}
+
+impl HashStatistics {
+    pub fn new(mi: &model_instance::ModelInstance) -> HashStatistics {
+        HashStatistics {
+            lr_counters: mi
+                .feature_combo_descs
+                .iter()
+                .map(|_| DistinctCounter::new())
+                .collect(),
+            ffm_counters: mi
+                .ffm_fields
+                .iter()
+                .map(|_| DistinctCounter::new())
+                .collect(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn add_lr(&mut self, combo_index: u32, hash: u32) {
+        self.lr_counters[combo_index as usize].add(hash);
+    }
+
+    #[inline(always)]
+    pub fn add_ffm(&mut self, field_index: usize, hash: u32) {
+        self.ffm_counters[field_index].add(hash);
+    }
+
+    pub fn report(&self, mi: &model_instance::ModelInstance, vw: &VwNamespaceMap) -> String {
+        let mut s = String::new();
+        let names: Vec<String> = mi
+            .feature_combo_descs
+            .iter()
+            .map(|combo| namespaces_name(mi, vw, &combo.namespace_descriptors))
+            .collect();
+        let bit_precisions: Vec<Option<u32>> = mi
+            .feature_combo_descs
+            .iter()
+            .map(|combo| combo.bit_precision)
+            .collect();
+        report_hash_spaces(
+            &mut s,
+            HashSpacesDesc {
+                what: "Feature combos",
+                option_name: "--bit_precision",
+                own_option_name: "--combo_bit_precision",
+                bit_precision: mi.bit_precision as u32,
+                reserved_bits: 0,
+            },
+            &names,
+            &bit_precisions,
+            &self.lr_counters,
+        );
+        if mi.ffm_k > 0 {
+            let names: Vec<String> = mi
+                .ffm_fields
+                .iter()
+                .map(|field| namespaces_name(mi, vw, field))
+                .collect();
+            let bit_precisions: Vec<Option<u32>> = (0..mi.ffm_fields.len())
+                .map(|i| mi.ffm_fields_bit_precision.get(i).cloned().flatten())
+                .collect();
+            report_hash_spaces(
+                &mut s,
+                HashSpacesDesc {
+                    what: "FFM fields",
+                    option_name: "--ffm_bit_precision",
+                    own_option_name: "--ffm_field_bit_precision",
+                    bit_precision: mi.ffm_bit_precision,
+                    reserved_bits: mi.ffm_bits_for_dimensions(),
+                },
+                &names,
+                &bit_precisions,
+                &self.ffm_counters,
+            );
+        }
+        s
+    }
+}
+
+struct HashSpacesDesc {
+    what: &'static str,
+    option_name: &'static str,
+    own_option_name: &'static str,
+    bit_precision: u32,
+    reserved_bits: u32, // lower bits of ffm hashes are spared for k
+}
+
+// Combos (fields) without their own bit precision are reported together, as they share the weights
+fn report_hash_spaces(
+    s: &mut String,
+    desc: HashSpacesDesc,
+    names: &[String],
+    bit_precisions: &[Option<u32>],
+    counters: &[DistinctCounter],
+) {
+    let reserved_note = if desc.reserved_bits > 0 {
+        format!(" ({} of its bits are taken by ffm_k)", desc.reserved_bits)
+    } else {
+        String::new()
+    };
+    let shared: Vec<(&String, &DistinctCounter)> = names
+        .iter()
+        .zip(counters.iter())
+        .zip(bit_precisions.iter())
+        .filter(|(_, bits)| bits.is_none())
+        .map(|(name_and_counter, _)| name_and_counter)
+        .collect();
+    if !shared.is_empty() {
+        writeln!(
+            s,
+            "{} share the weights of {} {}{}:",
+            desc.what, desc.option_name, desc.bit_precision, reserved_note
+        )
+        .unwrap();
+        report_hash_space(
+            s,
+            &format!("{} ", desc.option_name),
+            desc.bit_precision,
+            desc.reserved_bits,
+            &shared,
+        );
+    }
+    for ((name, counter), bits) in names.iter().zip(counters.iter()).zip(bit_precisions.iter()) {
+        if let Some(bits) = bits {
+            let option = format!("{} {}=", desc.own_option_name, name);
+            writeln!(
+                s,
+                "{} has its own weights of {}{}{}:",
+                name, option, bits, reserved_note
+            )
+            .unwrap();
+            report_hash_space(s, &option, *bits, desc.reserved_bits, &[(name, counter)]);
+        }
+    }
+}
+
+fn report_hash_space(
+    s: &mut String,
+    option: &str,
+    bit_precision: u32,
+    reserved_bits: u32,
+    names_and_counters: &[(&String, &DistinctCounter)],
+) {
+    for (name, counter) in names_and_counters {
+        if counter.is_exact() {
+            writeln!(s, "  {}: {} distinct hashes", name, counter.distinct()).unwrap();
+        } else {
+            writeln!(
+                s,
+                "  {}: ~{:.0} distinct hashes (estimated)",
+                name,
+                counter.distinct()
+            )
+            .unwrap();
+        }
+    }
+    // Different combos (fields) practically never produce the same hash, so their counts add up
+    let distinct: f64 = names_and_counters.iter().map(|(_, c)| c.distinct()).sum();
+    let rate = |bits: u32| collision_rate(distinct, (1u64 << (bits - reserved_bits)) as f64);
+
+    let min_bits = reserved_bits + 1;
+    let recommended = (min_bits..=MAX_BIT_PRECISION)
+        .find(|bits| rate(*bits) <= RECOMMENDED_COLLISION_RATE)
+        .unwrap_or(MAX_BIT_PRECISION);
+    let from = bit_precision
+        .min(recommended)
+        .saturating_sub(2)
+        .max(min_bits);
+    let to = (bit_precision.max(recommended) + 2).min(MAX_BIT_PRECISION);
+    for bits in from..=to {
+        writeln!(
+            s,
+            "  {}{}: {:6.2}% of distinct features share their weight{}",
+            option,
+            bits,
+            rate(bits) * 100.0,
+            if bits == bit_precision {
+                " (current)"
+            } else {
+                ""
+            }
+        )
+        .unwrap();
+    }
+    writeln!(
+        s,
+        "  Recommended {}{} (the smallest with collision rate up to {}%)",
+        option,
+        recommended,
+        RECOMMENDED_COLLISION_RATE * 100.0
+    )
+    .unwrap();
+}
+
+fn namespaces_name(
+    mi: &model_instance::ModelInstance,
+    vw: &VwNamespaceMap,
+    namespace_descriptors: &[NamespaceDescriptor],
+) -> String {
+    let names: Vec<String> = namespace_descriptors
+        .iter()
+        .map(|namespace_descriptor| {
+            if namespace_descriptor.namespace_type == NamespaceType::Transformed {
+                mi.transform_namespaces.v[namespace_descriptor.namespace_index as usize]
+                    .to_namespace
+                    .namespace_verbose
+                    .clone()
+            } else {
+                vw.map_verbose_to_namespace_descriptor
+                    .iter()
+                    .find(|(_, d)| *d == namespace_descriptor)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_else(|| format!("#{}", namespace_descriptor.namespace_index))
+            }
+        })
+        .collect();
+    names.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.estimate(), 0.0);
+        for i in 0..1000u32 {
+            hll.add(i);
+            hll.add(i); // duplicates don't count
+        }
+        assert!((hll.estimate() - 1000.0).abs() < 1000.0 * 0.03);
+        for i in 0..1_000_000u32 {
+            hll.add(i.wrapping_mul(2654435761));
+        }
+        assert!((hll.estimate() - 1_000_000.0).abs() < 1_000_000.0 * 0.03);
+    }
+
+    #[test]
+    fn test_distinct_counter() {
+        let mut counter = DistinctCounter::new();
+        for i in 0..100u32 {
+            counter.add(i % 10);
+        }
+        assert!(counter.is_exact());
+        assert_eq!(counter.distinct(), 10.0);
+        for i in 0..(EXACT_COUNT_LIMIT as u32 * 2) {
+            counter.add(i);
+        }
+        assert!(!counter.is_exact());
+        let expected = EXACT_COUNT_LIMIT as f64 * 2.0;
+        assert!((counter.distinct() - expected).abs() < expected * 0.03);
+    }
+
+    #[test]
+    fn test_collision_rate() {
+        assert_eq!(collision_rate(0.0, 1024.0), 0.0);
+        assert_eq!(collision_rate(1.0, 1024.0), 0.0);
+        assert_eq!(collision_rate(2.0, 1.0), 1.0);
+        // birthday problem: 23 people, 365 days - one person shares the birthday with probability of 5.9%
+        assert!((collision_rate(23.0, 365.0) - 0.0586).abs() < 0.0001);
+        // about n / m for small loads
+        assert!((collision_rate(1000.0, 1048576.0) - 0.00095).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_report() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 18;
+        let desc_a = vw.map_verbose_to_namespace_descriptor["featureA"];
+        let desc_b = vw.map_verbose_to_namespace_descriptor["featureB"];
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![desc_a],
+                weight: 1.0,
+                bit_precision: None,
+            });
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![desc_a, desc_b],
+                weight: 1.0,
+                bit_precision: None,
+            });
+        let mut hs = HashStatistics::new(&mi);
+        for i in 0..100 {
+            hs.add_lr(0, i);
+        }
+        for i in 0..9900 {
+            hs.add_lr(1, i * 7);
+        }
+        let report = hs.report(&mi, &vw);
+        // 10000 features need a million slots
+        assert_eq!(
+            report,
+            "Feature combos share the weights of --bit_precision 18:
+  featureA: 100 distinct hashes
+  featureA,featureB: 9900 distinct hashes
+  --bit_precision 16:  14.15% of distinct features share their weight
+  --bit_precision 17:   7.34% of distinct features share their weight
+  --bit_precision 18:   3.74% of distinct features share their weight (current)
+  --bit_precision 19:   1.89% of distinct features share their weight
+  --bit_precision 20:   0.95% of distinct features share their weight
+  --bit_precision 21:   0.48% of distinct features share their weight
+  --bit_precision 22:   0.24% of distinct features share their weight
+  Recommended --bit_precision 20 (the smallest with collision rate up to 1%)
+"
+        );
+
+        // A combo with its own bit precision is reported alone
+        mi.feature_combo_descs[0].bit_precision = Some(8);
+        let report = hs.report(&mi, &vw);
+        assert!(report.starts_with(
+            "Feature combos share the weights of --bit_precision 18:
+  featureA,featureB: 9900 distinct hashes
+"
+        ));
+        assert!(report.contains(
+            "featureA has its own weights of --combo_bit_precision featureA=8:
+  featureA: 100 distinct hashes
+  --combo_bit_precision featureA=6:  78.97% of distinct features share their weight
+"
+        ));
+        assert!(report.contains(
+            "  --combo_bit_precision featureA=8:  32.12% of distinct features share their weight (current)\n"
+        ));
+        assert!(report.ends_with(
+            "  Recommended --combo_bit_precision featureA=14 (the smallest with collision rate up to 1%)\n"
+        ));
+
+        // ffm_k of 4 takes 2 bits of ffm_bit_precision
+        mi.feature_combo_descs.truncate(0);
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 10;
+        mi.ffm_fields.push(vec![desc_b]);
+        let mut hs = HashStatistics::new(&mi);
+        for i in 0..3 {
+            hs.add_ffm(0, i);
+        }
+        let report = hs.report(&mi, &vw);
+        // with 3 features the 256 slots of precision 10 are enough
+        assert_eq!(
+            report,
+            "FFM fields share the weights of --ffm_bit_precision 10 (2 of its bits are taken by ffm_k):
+  featureB: 3 distinct hashes
+  --ffm_bit_precision 8:   3.10% of distinct features share their weight
+  --ffm_bit_precision 9:   1.56% of distinct features share their weight
+  --ffm_bit_precision 10:   0.78% of distinct features share their weight (current)
+  --ffm_bit_precision 11:   0.39% of distinct features share their weight
+  --ffm_bit_precision 12:   0.20% of distinct features share their weight
+  Recommended --ffm_bit_precision 10 (the smallest with collision rate up to 1%)
+"
+        );
+    }
+}
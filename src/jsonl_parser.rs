@@ -0,0 +1,376 @@
+use crate::feature_transform_string;
+use crate::parser::*;
+use crate::vwmap;
+use fasthash::murmur3;
+use serde::Deserialize;
+use std::error::Error;
+use std::io::BufRead;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+/*
+JSON Lines input, one example per line:
+    {"label":1,"weight":0.5,"features":{"publisher":["a","b"],"price":3.2}}
+    - "label": missing or null means no label
+    - "weight" (optional): example importance
+    - "initial_prediction" (optional): added to the model output, like vw's initial prediction
+    - "tag" (optional): example id, written next to predictions
+    - "features" are keyed by verbose namespace names from vw_namespace_map.csv, a value can be:
+        - a string or a number: a single feature
+        - an array of strings/numbers: multiple features
+        - an object {"feature": weight, ...}: weighted features (categorical namespaces only)
+        - null: no features
+      f32 namespaces take the value of a number (or a string holding a number)
+Feature values are used verbatim, so there is no need to escape "|", ":" or spaces like in vw text.
+Output is the same records buffer that VowpalParser produces.
+*/
+
+#[derive(Deserialize)]
+struct JsonExample {
+    label: Option<f32>,
+    weight: Option<f32>,
+    initial_prediction: Option<f32>,
+    tag: Option<String>,
+    #[serde(default)]
+    features: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Clone)]
+pub struct JsonlParser {
+    vw_map: vwmap::VwNamespaceMap,
+    namespace_hash_seeds: Vec<u32>,
+    tmp_read_buf: Vec<u8>,
+    line_number: u64,
+    string_transformer: feature_transform_string::StringTransformer,
+    pub output_buffer: Vec<u32>,
+}
+
+impl JsonlParser {
+    pub fn new(vw: &vwmap::VwNamespaceMap) -> JsonlParser {
+        let mut namespace_hash_seeds: Vec<u32> = vec![0; vw.num_namespaces];
+        for (i, entry) in vw.vw_source.entries.iter().enumerate() {
+            namespace_hash_seeds[i] = murmur3::hash32(&entry.namespace_vwname);
+        }
+        JsonlParser {
+            vw_map: (*vw).clone(),
+            namespace_hash_seeds: namespace_hash_seeds,
+            tmp_read_buf: Vec::new(),
+            line_number: 0,
+            string_transformer: feature_transform_string::StringTransformer::new(vw),
+            output_buffer: Vec::new(),
+        }
+    }
+
+    // Pushes (hash, value) pair of a single feature to the dynamic part of the output buffer
+    fn push_feature(
+        &mut self,
+        namespace_descriptor: &vwmap::NamespaceDescriptor,
+        name: &str,
+        feature_weight: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let h = murmur3::hash32_with_seed(
+            name,
+            self.namespace_hash_seeds[namespace_descriptor.namespace_index as usize],
+        ) & MASK31;
+        self.output_buffer.push(h);
+        match namespace_descriptor.namespace_format {
+            vwmap::NamespaceFormat::Categorical => {
+                self.output_buffer.push(feature_weight.to_bits());
+                self.string_transformer.add_feature(
+                    namespace_descriptor.namespace_index as usize,
+                    name.as_bytes(),
+                    feature_weight,
+                );
+            }
+            vwmap::NamespaceFormat::F32 => {
+                if feature_weight != 1.0 {
+                    return Err(Box::new(IOError::new(ErrorKind::Other, format!("Namespaces that are f32 can not have weight attached to a single feature"))));
+                }
+                let float_value: f32 = match name.trim().parse() {
+                    Ok(f) => f,
+                    Err(_) => {
+                        return Err(Box::new(IOError::new(
+                            ErrorKind::Other,
+                            format!(
+                                "Failed parsing feature value to float (for float namespace): {}",
+                                name
+                            ),
+                        )))
+                    }
+                };
+                self.output_buffer.push(float_value.to_bits());
+            }
+        }
+        Ok(())
+    }
+
+    fn push_value(
+        &mut self,
+        namespace_descriptor: &vwmap::NamespaceDescriptor,
+        namespace_name: &str,
+        value: &serde_json::Value,
+        nested: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        match value {
+            serde_json::Value::Null => {}
+            serde_json::Value::String(s) => self.push_feature(namespace_descriptor, s, 1.0)?,
+            serde_json::Value::Number(n) => {
+                self.push_feature(namespace_descriptor, &n.to_string(), 1.0)?
+            }
+            serde_json::Value::Array(values) if !nested => {
+                for v in values {
+                    self.push_value(namespace_descriptor, namespace_name, v, true)?;
+                }
+            }
+            serde_json::Value::Object(weighted) if !nested => {
+                for (name, weight) in weighted {
+                    match weight.as_f64() {
+                        Some(w) => self.push_feature(namespace_descriptor, name, w as f32)?,
+                        None => {
+                            return Err(Box::new(IOError::new(
+                                ErrorKind::Other,
+                                format!(
+                                    "Failed parsing feature weight of {} in namespace {}: {}",
+                                    name, namespace_name, weight
+                                ),
+                            )))
+                        }
+                    }
+                }
+            }
+            _ => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Unsupported value of namespace {}: {}",
+                        namespace_name, value
+                    ),
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next_jsonl(
+        &mut self,
+        input_bufread: &mut dyn BufRead,
+    ) -> Result<&[u32], Box<dyn Error>> {
+        loop {
+            self.tmp_read_buf.truncate(0);
+            if input_bufread.read_until(0x0a, &mut self.tmp_read_buf)? == 0 {
+                return Ok(&[]);
+            }
+            self.line_number += 1;
+            if self.tmp_read_buf.iter().any(|c| !c.is_ascii_whitespace()) {
+                break;
+            }
+        }
+        let example: JsonExample = match serde_json::from_slice(&self.tmp_read_buf) {
+            Ok(example) => example,
+            Err(e) => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Cannot parse an example ({}): {}",
+                        e,
+                        String::from_utf8_lossy(&self.tmp_read_buf).trim_end()
+                    ),
+                )))
+            }
+        };
+
+        let bufpos: usize = self.vw_map.num_namespaces + HEADER_LEN as usize;
+        self.output_buffer.truncate(0);
+        self.output_buffer.resize(bufpos, NO_FEATURES);
+        self.output_buffer[LABEL_OFFSET] = match example.label {
+            Some(label) => label.to_bits(),
+            None => NO_LABEL,
+        };
+        self.output_buffer[INITIAL_PREDICTION_OFFSET] =
+            example.initial_prediction.unwrap_or(0.0).to_bits();
+        self.output_buffer[TAG_OFFSET] = NO_TAG;
+        if let Some(tag) = &example.tag {
+            push_tag(&mut self.output_buffer, tag.as_bytes())?;
+        }
+        let importance = example.weight.unwrap_or(1.0);
+        if importance < 0.0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Example importance cannot be negative: {:?}! ", importance),
+            )));
+        }
+        self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = importance.to_bits();
+        self.string_transformer.start_record();
+
+        for (namespace_name, value) in &example.features {
+            let namespace_descriptor = match self
+                .vw_map
+                .map_verbose_to_namespace_descriptor
+                .get(namespace_name)
+            {
+                Some(namespace_descriptor) => *namespace_descriptor,
+                None => {
+                    return Err(Box::new(IOError::new(
+                        ErrorKind::Other,
+                        format!(
+                            "Feature name was not predeclared in vw_namespace_map.csv: {}",
+                            namespace_name
+                        ),
+                    )))
+                }
+            };
+            if namespace_descriptor.namespace_index as usize >= self.vw_map.vw_source.entries.len()
+            {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Namespace {} is made by a string transform, it can't be given in an example",
+                        namespace_name
+                    ),
+                )));
+            }
+            let namespace_index_offset = namespace_descriptor.namespace_index as usize
+                * NAMESPACE_DESC_LEN as usize
+                + HEADER_LEN as usize;
+            if self.output_buffer[namespace_index_offset] != NO_FEATURES {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Namespace {} is given more than once in an example",
+                        namespace_name
+                    ),
+                )));
+            }
+            let bufpos_namespace_start = self.output_buffer.len();
+            self.push_value(&namespace_descriptor, namespace_name, value, false)?;
+            let bufpos_namespace_end = self.output_buffer.len();
+            if bufpos_namespace_end == bufpos_namespace_start {
+                continue;
+            }
+            // Same as in VowpalParser: a single categorical feature with weight 1.0 is stored in-place
+            if bufpos_namespace_end - bufpos_namespace_start == 2
+                && namespace_descriptor.namespace_format == vwmap::NamespaceFormat::Categorical
+                && self.output_buffer[bufpos_namespace_start + 1] == FLOAT32_ONE
+            {
+                self.output_buffer[namespace_index_offset] =
+                    self.output_buffer[bufpos_namespace_start];
+                self.output_buffer.truncate(bufpos_namespace_start);
+            } else {
+                self.output_buffer[namespace_index_offset] = IS_NOT_SINGLE_MASK
+                    | (((bufpos_namespace_start << 16) + bufpos_namespace_end) as u32);
+            }
+        }
+        self.string_transformer
+            .write_namespaces(&mut self.output_buffer);
+        self.output_buffer[0] = self.output_buffer.len() as u32;
+        Ok(&self.output_buffer)
+    }
+}
+
+impl RecordParser for JsonlParser {
+    fn next_record(&mut self, input_bufread: &mut dyn BufRead) -> Result<&[u32], Box<dyn Error>> {
+        self.next_jsonl(input_bufread)
+    }
+
+    fn last_line(&self) -> (u64, &[u8]) {
+        (self.line_number, &self.tmp_read_buf)
+    }
+
+    fn last_record(&self) -> &[u32] {
+        &self.output_buffer
+    }
+
+    fn reset_input(&mut self) {
+        self.line_number = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::io::Cursor;
+
+    fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+        Cursor::new(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_same_records_as_vowpal() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+C,featureC,f32
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut vp = VowpalParser::new(&vw);
+        let mut rr = JsonlParser::new(&vw);
+        let mut buf = str_to_cursor(
+            r#"{"label":1,"features":{"featureB":"b","featureA":"a","featureC":2.5}}
+{"label":-1,"weight":0.5,"features":{"featureA":["a","b"],"featureC":"3"}}
+
+{"features":{"featureA":{"a":0.5},"featureB":null,"featureC":[1,2]}}
+{"label":0,"features":{"featureA":7}}
+"#,
+        );
+        let mut vw_buf =
+            str_to_cursor("1 |A a |B b |C 2.5\n-1 0.5 |A a b |C 3\n|A a:0.5 |C 1 2\n0 |A 7\n");
+        for _ in 0..4 {
+            let expected = vp.next_vowpal(&mut vw_buf).unwrap().to_vec();
+            assert_eq!(rr.next_jsonl(&mut buf).unwrap(), expected.as_slice());
+        }
+        assert_eq!(rr.next_jsonl(&mut buf).unwrap().len(), 0);
+
+        let mut buf = str_to_cursor(
+            r#"{"label":2.5,"initial_prediction":0.5,"tag":"id 1","features":{"featureA":"a"}}"#,
+        );
+        let mut vw_buf = str_to_cursor("2.5 1 0.5 'id1 |A a\n");
+        let record = rr.next_jsonl(&mut buf).unwrap().to_vec();
+        let expected = vp.next_vowpal(&mut vw_buf).unwrap();
+        assert_eq!(record[LABEL_OFFSET], expected[LABEL_OFFSET]);
+        assert_eq!(
+            record[INITIAL_PREDICTION_OFFSET],
+            expected[INITIAL_PREDICTION_OFFSET]
+        );
+        // Tags can contain spaces in json
+        assert_eq!(get_tag(&record), b"id 1");
+        assert_eq!(record[HEADER_LEN as usize], expected[HEADER_LEN as usize]);
+    }
+
+    #[test]
+    fn test_special_characters_and_errors() {
+        let vw_map_string = r#"
+A,featureA
+C,featureC,f32
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut rr = JsonlParser::new(&vw);
+        // Feature values with characters that are special in vw text are hashed as they are
+        let mut buf = str_to_cursor(r#"{"label":1,"features":{"featureA":"a |b:c"}}"#);
+        assert_eq!(
+            rr.next_jsonl(&mut buf).unwrap(),
+            [
+                7,
+                FLOAT32_ONE,
+                FLOAT32_ONE,
+                0,
+                NO_TAG,
+                murmur3::hash32_with_seed("a |b:c", murmur3::hash32("A")) & MASK31,
+                NO_FEATURES
+            ]
+        );
+
+        for line in vec![
+            r#"{"label":1,"features":{"featureX":"a"}}"#,
+            r#"{"label":1,"weight":-1,"features":{"featureA":"a"}}"#,
+            r#"{"label":1,"features":{"featureC":"a"}}"#,
+            r#"{"label":1,"features":{"featureC":{"1":2}}}"#,
+            r#"{"label":1,"features":{"featureA":[["a"]]}}"#,
+            r#"{"label":1,"features":{"featureA":true}}"#,
+            r#"{"label":1,"features":"#,
+        ] {
+            let mut buf = str_to_cursor(line);
+            assert!(rr.next_jsonl(&mut buf).is_err(), "{}", line);
+        }
+    }
+}
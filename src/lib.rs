@@ -6,14 +6,17 @@ mod block_misc;
 mod block_neural;
 mod block_normalize;
 mod block_relu;
-mod cache;
 mod cmdline;
 mod consts;
+mod csv_parser;
 mod feature_buffer;
 mod feature_transform_executor;
 mod feature_transform_implementations;
 mod feature_transform_parser;
+mod feature_transform_string;
 mod graph;
+mod hash_statistics;
+mod jsonl_parser;
 mod model_instance;
 mod multithread_helpers;
 mod optimizer;
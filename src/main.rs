@@ -6,6 +6,9 @@
 #![allow(redundant_semicolons)]
 
 use flate2::read::MultiGzDecoder;
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
 use std::collections::VecDeque;
 use std::error::Error;
 use std::f32;
@@ -17,6 +20,8 @@ use std::io::Write;
 use std::path::Path;
 use std::time::Instant;
 
+use crate::parser::RecordParser;
+
 extern crate blas;
 extern crate intel_mkl_src;
 
@@ -31,14 +36,22 @@ mod block_neural;
 mod block_normalize;
 mod block_relu;
 mod cache;
+mod cache_builder;
+mod cache_tool;
 mod cmdline;
 mod consts;
+mod csv_parser;
 mod feature_buffer;
 mod feature_transform_executor;
+mod feature_transform_fitter;
 mod feature_transform_implementations;
 mod feature_transform_parser;
+mod feature_transform_string;
 mod graph;
+mod hash_statistics;
+mod jsonl_parser;
 mod model_instance;
+mod model_merge;
 mod multithread_helpers;
 mod optimizer;
 mod parser;
@@ -63,60 +76,426 @@ fn build_cache_without_training(cl: clap::ArgMatches) -> Result<(), Box<dyn Erro
     /*! A method that enables creating the cache file without training the first model instance.
     This is done in order to reduce building time of the cache and running the first model instance multi threaded. */
     // We'll parse once the command line into cl and then different objects will examine it
-    let input_filename = cl.value_of("data").expect("--data expected");
-    let vw_namespace_map_filepath = Path::new(input_filename)
-        .parent()
-        .expect("Couldn't access path given by --data")
-        .join("vw_namespace_map.csv");
-    let vw: vwmap::VwNamespaceMap;
-    vw = vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
-    let mut cache = cache::RecordCache::new(input_filename, true, &vw);
-    let input = File::open(input_filename)?;
-    let mut aa;
-    let mut bb;
-    let mut bufferred_input: &mut dyn BufRead = match input_filename.ends_with(".gz") {
-        true => {
-            aa = io::BufReader::new(MultiGzDecoder::new(input));
-            &mut aa
-        }
-        false => {
-            bb = io::BufReader::new(input);
-            &mut bb
-        }
+    let input_filenames = data_filenames_from_cmdline(&cl)?;
+    let vw = vw_namespace_map_from_cmdline(&cl, &input_filenames)?;
+    let cache_filenames = cache_filenames_from_cmdline(&cl, &input_filenames, true)?;
+    let mut pa = new_record_parser_from_cmdline(&cl, &vw)?;
+    let has_header = data_format_from_cmdline(&cl)?.has_header();
+    let cache_threads: usize = match cl.value_of("cache_threads") {
+        Some(s) => s.parse()?,
+        None => 1,
     };
-    let mut pa = parser::VowpalParser::new(&vw);
+    if cache_threads == 0 {
+        return Err("--cache_threads has to be at least 1")?;
+    }
+    let cache_builder =
+        cache_builder::ParallelCacheBuilder::new(cache_threads, cl.is_present("cache_unordered"));
     let mut example_num = 0;
-    loop {
-        let reading_result;
-        let buffer: &[u32];
-        if !cache.reading {
-            reading_result = pa.next_vowpal(&mut bufferred_input);
-            buffer = match reading_result {
+    for (input_filename, cache_filename) in input_filenames.iter().zip(cache_filenames.iter()) {
+        let mut cache = cache::RecordCache::new(
+            cache_filename.as_deref(),
+            &vw,
+            cache_compression_from_cmdline(&cl, input_filename)?,
+        );
+        if cache.reading {
+            continue;
+        }
+        let mut bufferred_input = open_data_file(input_filename)?;
+        pa.start_input(input_filename);
+        if cache_threads > 1 {
+            cache_builder.build(&mut *bufferred_input, has_header, &mut pa, &mut cache)?;
+            cache.write_finish()?;
+            continue;
+        }
+        loop {
+            let buffer = match pa.next_record(&mut *bufferred_input) {
                 Ok([]) => break, // EOF
-                Ok(buffer2) => buffer2,
+                Ok(buffer) => buffer,
                 Err(_e) => return Err(_e),
             };
-            if cache.writing {
-                cache.push_record(buffer)?;
+            cache.push_record(buffer)?;
+            example_num += 1;
+        }
+        cache.write_finish()?;
+    }
+    pa.report_bad_rows()?;
+    Ok(())
+}
+
+const STDIN_FILENAME: &str = "-";
+
+// Expands --data arguments in order, globs are expanded to sorted lists of files
+fn data_filenames_from_cmdline(cl: &clap::ArgMatches) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut input_filenames: Vec<String> = Vec::new();
+    for pattern in cl.values_of("data").ok_or("--data expected")? {
+        if pattern == STDIN_FILENAME || !pattern.contains(|c| c == '*' || c == '?' || c == '[') {
+            input_filenames.push(pattern.to_string());
+            continue;
+        }
+        let mut matched: Vec<String> = Vec::new();
+        for path in glob::glob(pattern)? {
+            let filename = path?.to_string_lossy().to_string();
+            // Cache files live next to data files, so they would be matched by a glob like data/*
+            if !cache::is_cache_filename(&filename) {
+                matched.push(filename);
             }
-        } else {
-            reading_result = cache.get_next_record();
-            buffer = match reading_result {
+        }
+        if matched.is_empty() {
+            return Err(format!("No files match --data {}", pattern))?;
+        }
+        matched.sort();
+        input_filenames.extend(matched);
+    }
+    if input_filenames
+        .iter()
+        .filter(|f| *f == STDIN_FILENAME)
+        .count()
+        > 1
+    {
+        return Err("--data - can be given only once")?;
+    }
+    Ok(input_filenames)
+}
+
+// We load vw_namespace_map.csv just so we know all the namespaces ahead of time
+// This is one of the major differences from vowpal
+fn vw_namespace_map_from_cmdline(
+    cl: &clap::ArgMatches,
+    input_filenames: &Vec<String>,
+) -> Result<vwmap::VwNamespaceMap, Box<dyn Error>> {
+    let vw_namespace_map_filepath = match cl.value_of("vw_namespace_map") {
+        Some(filename) => Path::new(filename).to_path_buf(),
+        None => {
+            let input_filename = match input_filenames.iter().find(|f| *f != STDIN_FILENAME) {
+                Some(input_filename) => input_filename,
+                None => return Err("--vw_namespace_map is needed when reading --data from stdin")?,
+            };
+            Path::new(input_filename)
+                .parent()
+                .expect("Couldn't access path given by --data")
+                .join("vw_namespace_map.csv")
+        }
+    };
+    let vw = vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
+    // String transforms are applied when parsing, so their namespaces are a part of the namespace map
+    match cl.values_of("transform") {
+        Some(transforms) => vw.with_string_transforms(&transforms.collect::<Vec<&str>>()),
+        None => Ok(vw),
+    }
+}
+
+// Caches are next to --data files, or given by --cache_file (a file for a single --data, otherwise a directory)
+fn cache_filenames_from_cmdline(
+    cl: &clap::ArgMatches,
+    input_filenames: &Vec<String>,
+    required: bool,
+) -> Result<Vec<Option<String>>, Box<dyn Error>> {
+    let mut cache_filenames: Vec<Option<String>> = Vec::new();
+    // Reading parts of caches implies using them
+    let use_cache = ["cache", "shard", "shuffle_seed", "cache_seek"]
+        .iter()
+        .any(|arg| cl.is_present(arg));
+    for input_filename in input_filenames {
+        let cache_filename = match cl.value_of("cache_file") {
+            Some(cache_file) if Path::new(cache_file).is_dir() => {
+                if input_filename == STDIN_FILENAME {
+                    return Err("--cache_file has to be a file when reading --data from stdin")?;
+                }
+                let input_basename = Path::new(input_filename).file_name().ok_or(format!(
+                    "Couldn't get the file name of --data {}",
+                    input_filename
+                ))?;
+                Some(
+                    Path::new(cache_file)
+                        .join(cache::default_cache_filename(
+                            &input_basename.to_string_lossy(),
+                        ))
+                        .to_string_lossy()
+                        .to_string(),
+                )
+            }
+            Some(cache_file) => {
+                if input_filenames.len() > 1 {
+                    return Err("--cache_file has to be an existing directory when there are multiple --data files")?;
+                }
+                Some(cache_file.to_string())
+            }
+            None if use_cache && input_filename != STDIN_FILENAME => {
+                Some(cache::default_cache_filename(input_filename))
+            }
+            None if required => {
+                return Err(format!(
+                    "Cache of --data {} needs --cache_file",
+                    input_filename
+                ))?
+            }
+            None => None,
+        };
+        if cache_filename.is_some() && cache_filenames.contains(&cache_filename) {
+            return Err(format!(
+                "Two --data files would use the same cache file {}",
+                cache_filename.unwrap()
+            ))?;
+        }
+        cache_filenames.push(cache_filename);
+    }
+    Ok(cache_filenames)
+}
+
+fn cache_compression_from_cmdline(
+    cl: &clap::ArgMatches,
+    input_filename: &str,
+) -> Result<cache::CacheCompression, Box<dyn Error>> {
+    match cl.value_of("cache_compression") {
+        Some(s) => cache::CacheCompression::from_cmdline_str(s),
+        // Caches of gzipped inputs have always been compressed with lz4
+        None if input_filename.ends_with(".gz") => Ok(cache::CacheCompression::Lz4),
+        None => Ok(cache::CacheCompression::None),
+    }
+}
+
+// Parses --shard i/N into (i, N)
+fn shard_from_cmdline(cl: &clap::ArgMatches) -> Result<Option<(usize, usize)>, Box<dyn Error>> {
+    let s = match cl.value_of("shard") {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let parts: Vec<&str> = s.split('/').collect();
+    if parts.len() == 2 {
+        if let (Ok(shard), Ok(num_shards)) = (parts[0].parse::<usize>(), parts[1].parse::<usize>())
+        {
+            if shard < num_shards {
+                return Ok(Some((shard, num_shards)));
+            }
+        }
+    }
+    Err(format!("--shard has to be i/N with 0 <= i < N, got: {}", s))?
+}
+
+// Opens a file given by --data, gzipped files are recognized by .gz suffix
+fn open_data_file(input_filename: &str) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
+    if input_filename == STDIN_FILENAME {
+        return Ok(Box::new(io::BufReader::new(io::stdin())));
+    }
+    let input = File::open(input_filename)?;
+    if input_filename.ends_with(".gz") {
+        Ok(Box::new(io::BufReader::new(MultiGzDecoder::new(input))))
+    } else {
+        Ok(Box::new(io::BufReader::new(input)))
+    }
+}
+
+fn data_format_from_cmdline(cl: &clap::ArgMatches) -> Result<parser::DataFormat, Box<dyn Error>> {
+    match cl.value_of("data_format") {
+        Some(s) => parser::DataFormat::from_cmdline_str(s),
+        None => Ok(parser::DataFormat::Vw),
+    }
+}
+
+fn new_record_parser_from_cmdline(
+    cl: &clap::ArgMatches,
+    vw: &vwmap::VwNamespaceMap,
+) -> Result<parser::FaultTolerantParser, Box<dyn Error>> {
+    let data_format = data_format_from_cmdline(cl)?;
+    let max_bad_rows: u64 = match cl.value_of("max_bad_rows") {
+        Some(s) => s.parse()?,
+        None => 0,
+    };
+    parser::FaultTolerantParser::new(
+        parser::new_record_parser(data_format, vw),
+        max_bad_rows,
+        cl.value_of("bad_rows_file"),
+    )
+}
+
+// Transforms with parameters computed from the training data (e.g. BinnerQuantile) are fitted in a pass over it before training
+fn fit_transforms(
+    cl: &clap::ArgMatches,
+    input_filenames: &Vec<String>,
+    vw: &vwmap::VwNamespaceMap,
+    mi: &mut model_instance::ModelInstance,
+) -> Result<(), Box<dyn Error>> {
+    let mut fitter = feature_transform_fitter::TransformFitter::new(&mi.transform_namespaces);
+    if fitter.is_empty() {
+        return Ok(());
+    }
+    if input_filenames.iter().any(|f| f == STDIN_FILENAME) {
+        return Err("Transforms fitted on the training data (e.g. BinnerQuantile) need an extra pass over --data, which can not be read from stdin")?;
+    }
+    let now = Instant::now();
+    let cache_filenames = cache_filenames_from_cmdline(cl, input_filenames, false)?;
+    let mut pa = new_record_parser_from_cmdline(cl, vw)?;
+    for (input_filename, cache_filename) in input_filenames.iter().zip(cache_filenames.iter()) {
+        match cache_filename {
+            Some(cache_filename) if Path::new(cache_filename).exists() => {
+                let (mut cache, _) = cache::RecordCache::open_existing(cache_filename)?;
+                loop {
+                    match cache.get_next_record()? {
+                        [] => break,
+                        record => fitter.add_record(record),
+                    }
+                }
+            }
+            _ => {
+                let mut input = open_data_file(input_filename)?;
+                pa.start_input(input_filename);
+                loop {
+                    match pa.next_record(&mut *input)? {
+                        [] => break,
+                        record => fitter.add_record(record),
+                    }
+                }
+            }
+        }
+    }
+    fitter.finish(&mut mi.transform_namespaces)?;
+    println!("Fitted transforms: {:.2?}", now.elapsed());
+    Ok(())
+}
+
+fn upgrade_model(cl: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let input_filename = cl.value_of("initial_regressor").unwrap();
+    let output_filename = cl.value_of("final_regressor").unwrap();
+    println!("initial_regressor = {}", input_filename);
+    println!("final_regressor = {}", output_filename);
+    persistence::upgrade_regressor_file(input_filename, output_filename)
+}
+
+// Returns (label, prediction) pairs for every example in the files
+fn predict_validation_files(
+    input_filenames: &Vec<String>,
+    cl: &clap::ArgMatches,
+    vw: &vwmap::VwNamespaceMap,
+    mi: &model_instance::ModelInstance,
+    re: &regressor::Regressor,
+) -> Result<Vec<(f32, f32)>, Box<dyn Error>> {
+    let mut pa = new_record_parser_from_cmdline(cl, &vw)?;
+    let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+    let mut pb = re.new_portbuffer();
+    let mut results: Vec<(f32, f32)> = Vec::new();
+    for input_filename in input_filenames {
+        let mut bufferred_input = open_data_file(input_filename)?;
+        pa.start_input(input_filename);
+        loop {
+            let buffer = match pa.next_record(&mut *bufferred_input) {
                 Ok([]) => break, // EOF
                 Ok(buffer) => buffer,
                 Err(_e) => return Err(_e),
             };
+            fbt.translate(buffer, results.len() as u64);
+            let prediction = re.predict(&fbt.feature_buffer, &mut pb);
+            results.push((fbt.feature_buffer.label, prediction));
         }
-        example_num += 1;
     }
-    cache.write_finish()?;
-    Ok(())
+    pa.report_bad_rows()?;
+    Ok(results)
+}
+
+fn logloss(results: &Vec<(f32, f32)>) -> f64 {
+    let mut loss: f64 = 0.0;
+    let mut count: u64 = 0;
+    for (label, prediction) in results {
+        let p = (*prediction as f64).max(1e-15).min(1.0 - 1e-15);
+        if *label == 1.0 {
+            loss -= p.ln();
+        } else if *label == 0.0 {
+            loss -= (1.0 - p).ln();
+        } else {
+            continue; // unlabeled example
+        }
+        count += 1;
+    }
+    loss / count.max(1) as f64
+}
+
+fn merge_models(cl: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let input_filenames: Vec<&str> = cl.values_of("input_regressors").unwrap().collect();
+    let output_filename = cl.value_of("final_regressor").unwrap();
+    let example_counts: Option<Vec<u64>> = match cl.value_of("example_counts") {
+        Some(s) => Some(
+            s.split(",")
+                .map(|c| c.trim().parse::<u64>())
+                .collect::<Result<Vec<u64>, _>>()?,
+        ),
+        None => None,
+    };
+    println!("input regressors = {}", input_filenames.join(", "));
+    println!("final_regressor = {}", output_filename);
+    model_merge::merge_regressor_files(&input_filenames, example_counts, output_filename)
+}
+
+fn cache_command(cl: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mut stdout = io::stdout();
+    match cl.subcommand() {
+        ("stats", Some(stats_cl)) => {
+            cache_tool::write_stats(stats_cl.value_of("cache_file").unwrap(), &mut stdout)
+        }
+        ("head", Some(head_cl)) => {
+            let num_examples: u64 = match head_cl.value_of("num_examples") {
+                Some(s) => s.parse()?,
+                None => 10,
+            };
+            cache_tool::write_head(
+                head_cl.value_of("cache_file").unwrap(),
+                num_examples,
+                &mut stdout,
+            )
+        }
+        ("validate", Some(validate_cl)) => {
+            cache_tool::validate(validate_cl.value_of("cache_file").unwrap(), &mut stdout)
+        }
+        ("filter", Some(filter_cl)) => {
+            let example_filter = cache_tool::ExampleFilter {
+                sample_rate: match filter_cl.value_of("sample_rate") {
+                    Some(s) => s.parse()?,
+                    None => 1.0,
+                },
+                seed: match filter_cl.value_of("seed") {
+                    Some(s) => s.parse()?,
+                    None => 0,
+                },
+                labels: match filter_cl.value_of("labels") {
+                    Some(s) => Some(
+                        s.split(",")
+                            .map(|label| match label.trim() {
+                                "none" => Ok(None),
+                                label => label.parse::<f32>().map(Some),
+                            })
+                            .collect::<Result<Vec<Option<f32>>, _>>()?,
+                    ),
+                    None => None,
+                },
+            };
+            let compression = match filter_cl.value_of("cache_compression") {
+                Some(s) => Some(cache::CacheCompression::from_cmdline_str(s)?),
+                None => None,
+            };
+            let (num_read, num_written) = cache_tool::filter(
+                filter_cl.value_of("cache_file").unwrap(),
+                filter_cl.value_of("output_cache_file").unwrap(),
+                &example_filter,
+                compression,
+            )?;
+            println!("Examples read: {}, written: {}", num_read, num_written);
+            Ok(())
+        }
+        _ => Err("Unknown cache subcommand")?,
+    }
 }
 
 fn main2() -> Result<(), Box<dyn Error>> {
     // We'll parse once the command line into cl and then different objects will examine it
     let cl = cmdline::parse();
-    if cl.is_present("build_cache_without_training") {
+    if let Some(upgrade_cl) = cl.subcommand_matches("upgrade-model") {
+        return upgrade_model(upgrade_cl);
+    }
+    if let Some(merge_cl) = cl.subcommand_matches("merge") {
+        return merge_models(merge_cl);
+    }
+    if let Some(cache_cl) = cl.subcommand_matches("cache") {
+        return cache_command(cache_cl);
+    }
+    if cl.is_present("build_cache_only") {
         return build_cache_without_training(cl);
     }
     // Where will we be putting perdictions (if at all)
@@ -163,9 +542,83 @@ fn main2() -> Result<(), Box<dyn Error>> {
         let filename = cl
             .value_of("initial_regressor")
             .expect("Convert mode requires --initial regressor");
-        let (mut mi2, vw2, re_fixed) =
+        let (mut mi2, vw2, mut re_fixed) =
             persistence::new_regressor_from_filename(filename, true, Option::Some(&cl))?;
         mi2.optimizer = model_instance::Optimizer::SGD;
+        // Page aligned inference regressors are memory-mapped when loaded
+        mi2.weights_page_aligned = cl.is_present("weights_page_aligned");
+        let quantization = match cl.value_of("weights_quantization") {
+            Some(s) => model_instance::WeightsQuantization::from_cmdline_str(s)?,
+            None => model_instance::WeightsQuantization::None,
+        };
+        let prune = cl.is_present("prune");
+        if quantization != model_instance::WeightsQuantization::None || prune {
+            if mi2.weights_quantization != model_instance::WeightsQuantization::None
+                || mi2.weights_sparse
+            {
+                return Err(
+                    "Regressor given by --initial_regressor is already quantized or pruned",
+                )?;
+            }
+            let validation_filenames = match cl.is_present("data") {
+                true => Some(data_filenames_from_cmdline(&cl)?),
+                false => None,
+            };
+            let results_before = match &validation_filenames {
+                Some(filenames) => {
+                    if filenames.iter().any(|f| f == STDIN_FILENAME) {
+                        // Validation data is read twice, before and after the conversion
+                        return Err("Validation --data can not be read from stdin")?;
+                    }
+                    Some(predict_validation_files(
+                        filenames, &cl, &vw2, &mi2, &re_fixed,
+                    )?)
+                }
+                None => None,
+            };
+            if prune {
+                let lr_threshold: f32 = match cl.value_of("prune_lr_threshold") {
+                    Some(s) => s.parse()?,
+                    None => 0.0,
+                };
+                for pruned in re_fixed.prune_weights(&mi2, lr_threshold) {
+                    println!(
+                        "Pruning {} weights: kept {} of {}",
+                        pruned.block_name, pruned.kept, pruned.total
+                    );
+                }
+                mi2.weights_sparse = true;
+            }
+            if quantization != model_instance::WeightsQuantization::None {
+                re_fixed.quantize_weights(quantization);
+                mi2.weights_quantization = quantization;
+            }
+            if let Some(results_before) = results_before {
+                let results_after = predict_validation_files(
+                    validation_filenames.as_ref().unwrap(),
+                    &cl,
+                    &vw2,
+                    &mi2,
+                    &re_fixed,
+                )?;
+                let deltas: Vec<f32> = results_before
+                    .iter()
+                    .zip(results_after.iter())
+                    .map(|((_, p1), (_, p2))| (p1 - p2).abs())
+                    .collect();
+                println!(
+                    "Converted weights on {} examples: mean abs prediction delta: {:.6}, max abs prediction delta: {:.6}",
+                    deltas.len(),
+                    deltas.iter().map(|d| *d as f64).sum::<f64>() / deltas.len().max(1) as f64,
+                    deltas.iter().cloned().fold(0.0, f32::max)
+                );
+                println!(
+                    "Logloss before conversion: {:.6}, after conversion: {:.6}",
+                    logloss(&results_before),
+                    logloss(&results_after)
+                );
+            }
+        }
         match inference_regressor_filename {
             Some(filename1) => {
                 persistence::save_regressor_to_filename(filename1, &mi2, &vw2, re_fixed).unwrap()
@@ -177,26 +630,19 @@ fn main2() -> Result<(), Box<dyn Error>> {
         let mut re: regressor::Regressor;
         let mi: model_instance::ModelInstance;
 
+        let input_filenames = data_filenames_from_cmdline(&cl)?;
         if let Some(filename) = cl.value_of("initial_regressor") {
             println!("initial_regressor = {}", filename);
             (mi, vw, re) =
                 persistence::new_regressor_from_filename(filename, testonly, Option::Some(&cl))?;
         } else {
-            // We load vw_namespace_map.csv just so we know all the namespaces ahead of time
-            // This is one of the major differences from vowpal
-
-            let input_filename = cl.value_of("data").expect("--data expected");
-            let vw_namespace_map_filepath = Path::new(input_filename)
-                .parent()
-                .expect("Couldn't access path given by --data")
-                .join("vw_namespace_map.csv");
-            vw = vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
-            mi = model_instance::ModelInstance::new_from_cmdline(&cl, &vw)?;
+            vw = vw_namespace_map_from_cmdline(&cl, &input_filenames)?;
+            let mut mi_from_cmdline = model_instance::ModelInstance::new_from_cmdline(&cl, &vw)?;
+            fit_transforms(&cl, &input_filenames, &vw, &mut mi_from_cmdline)?;
+            mi = mi_from_cmdline;
             re = regressor::get_regressor_with_weights(&mi);
         };
 
-        let input_filename = cl.value_of("data").expect("--data expected");
-        let mut cache = cache::RecordCache::new(input_filename, cl.is_present("cache"), &vw);
         let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
         let mut pb = re.new_portbuffer();
 
@@ -208,6 +654,15 @@ fn main2() -> Result<(), Box<dyn Error>> {
         let holdout_after_option: Option<u64> =
             cl.value_of("holdout_after").map(|s| s.parse().unwrap());
 
+        let debug_transforms: u64 = match cl.value_of("debug_transforms") {
+            Some(examples) => examples.parse()?,
+            None => 0,
+        };
+
+        if cl.is_present("hash_statistics") {
+            fbt.hash_statistics = Some(hash_statistics::HashStatistics::new(&mi));
+        }
+
         let prediction_model_delay: u64 = match cl.value_of("prediction_model_delay") {
             Some(delay) => delay.parse()?,
             None => 0,
@@ -216,78 +671,142 @@ fn main2() -> Result<(), Box<dyn Error>> {
         let mut delayed_learning_fbs: VecDeque<feature_buffer::FeatureBuffer> =
             VecDeque::with_capacity(prediction_model_delay as usize);
 
-        // Setup Parser, is rust forcing this disguisting way to do it, or I just don't know the pattern?
-        let input = File::open(input_filename)?;
-        let mut aa;
-        let mut bb;
-        let mut bufferred_input: &mut dyn BufRead = match input_filename.ends_with(".gz") {
-            true => {
-                aa = io::BufReader::new(MultiGzDecoder::new(input));
-                &mut aa
-            }
-            false => {
-                bb = io::BufReader::new(input);
-                &mut bb
-            }
-        };
+        let mut pa = new_record_parser_from_cmdline(&cl, &vw)?;
 
-        let mut pa = parser::VowpalParser::new(&vw);
+        // Negatives are sampled with the rate the model is trained with (and recalibrated for)
+        let negative_sampling_rate = mi.negative_sampling_rate;
+        let mut negative_sampling_rng =
+            Xoshiro256PlusPlus::seed_from_u64(match cl.value_of("negative_sampling_seed") {
+                Some(seed) => seed.parse()?,
+                None => 0,
+            });
 
         let now = Instant::now();
         let mut example_num = 0;
-        loop {
-            let reading_result;
-            let buffer: &[u32];
-            if !cache.reading {
-                reading_result = pa.next_vowpal(&mut bufferred_input);
-                buffer = match reading_result {
-                    Ok([]) => break, // EOF
-                    Ok(buffer2) => buffer2,
-                    Err(_e) => return Err(_e),
-                };
-                if cache.writing {
-                    cache.push_record(buffer)?;
-                }
-            } else {
-                reading_result = cache.get_next_record();
-                buffer = match reading_result {
-                    Ok([]) => break, // EOF
-                    Ok(buffer) => buffer,
-                    Err(_e) => return Err(_e),
-                };
+        let cache_filenames = cache_filenames_from_cmdline(&cl, &input_filenames, false)?;
+        let shard = shard_from_cmdline(&cl)?;
+        let shuffle_seed: Option<u64> = match cl.value_of("shuffle_seed") {
+            Some(seed) => Some(seed.parse()?),
+            None => None,
+        };
+        let mut examples_to_skip: u64 = match cl.value_of("cache_seek") {
+            Some(examples) => examples.parse()?,
+            None => 0,
+        };
+        for (input_filename, cache_filename) in input_filenames.iter().zip(cache_filenames.iter()) {
+            // Every file has its own cache, so caches of e.g. hourly partitions can be reused in any combination
+            let mut cache = cache::RecordCache::new(
+                cache_filename.as_deref(),
+                &vw,
+                cache_compression_from_cmdline(&cl, input_filename)?,
+            );
+            if (shard.is_some() || shuffle_seed.is_some() || examples_to_skip > 0) && !cache.reading
+            {
+                return Err(format!("--shard, --shuffle_seed and --cache_seek need an existing cache of {} (it can be built with --build_cache_without_training)", input_filename))?;
             }
-            example_num += 1;
-            fbt.translate(buffer, example_num);
-            let mut prediction: f32 = 0.0;
+            if shard.is_some() || shuffle_seed.is_some() {
+                let (shard, num_shards) = shard.unwrap_or((0, 1));
+                cache.select_blocks(shard, num_shards, shuffle_seed)?;
+            }
+            if examples_to_skip > 0 {
+                cache.seek(examples_to_skip)?;
+                examples_to_skip -= examples_to_skip.min(cache.num_examples().unwrap());
+            }
+            let mut bufferred_input: Box<dyn BufRead> = match cache.reading {
+                true => Box::new(io::empty()),
+                false => open_data_file(input_filename)?,
+            };
+            pa.start_input(input_filename);
+            loop {
+                let reading_result;
+                let buffer: &[u32];
+                if !cache.reading {
+                    reading_result = pa.next_record(&mut *bufferred_input);
+                    buffer = match reading_result {
+                        Ok([]) => break, // EOF
+                        Ok(buffer2) => buffer2,
+                        Err(_e) => return Err(_e),
+                    };
+                    if cache.writing {
+                        cache.push_record(buffer)?;
+                    }
+                } else {
+                    reading_result = cache.get_next_record();
+                    buffer = match reading_result {
+                        Ok([]) => break, // EOF
+                        Ok(buffer) => buffer,
+                        Err(_e) => return Err(_e),
+                    };
+                }
+                example_num += 1;
+                fbt.translate(buffer, example_num);
+                if example_num <= debug_transforms {
+                    fbt.print_transforms(buffer, example_num);
+                }
+                let mut prediction: f32 = 0.0;
 
-            if prediction_model_delay == 0 {
-                let update = match holdout_after_option {
-                    Some(holdout_after) => !testonly && example_num < holdout_after,
-                    None => !testonly,
-                };
-                prediction = re.learn(&fbt.feature_buffer, &mut pb, update);
-            } else {
-                if example_num > predictions_after {
-                    prediction = re.learn(&fbt.feature_buffer, &mut pb, false);
+                // Dropped negatives aren't learned from, but they are still predicted.
+                // Kept negatives keep their importance, as the regressor recalibrates predictions for the rate,
+                // unless --negative_sampling_importance weighs them by 1/r instead
+                let mut sampled_out = false;
+                if negative_sampling_rate < 1.0 && !testonly && fbt.feature_buffer.label <= 0.0 {
+                    if negative_sampling_rng.gen::<f32>() >= negative_sampling_rate {
+                        sampled_out = true;
+                        if predictions_file.is_none() || example_num <= predictions_after {
+                            continue;
+                        }
+                    } else if mi.negative_sampling_importance {
+                        fbt.feature_buffer.example_importance /= negative_sampling_rate;
+                    }
                 }
-                delayed_learning_fbs.push_back(fbt.feature_buffer.clone());
-                if (prediction_model_delay as usize) < delayed_learning_fbs.len() {
-                    let delayed_buffer = delayed_learning_fbs.pop_front().unwrap();
-                    re.learn(&delayed_buffer, &mut pb, !testonly);
+
+                if prediction_model_delay == 0 {
+                    let update = match holdout_after_option {
+                        Some(holdout_after) => !testonly && example_num < holdout_after,
+                        None => !testonly,
+                    };
+                    prediction = re.learn(&fbt.feature_buffer, &mut pb, update && !sampled_out);
+                } else {
+                    if example_num > predictions_after {
+                        prediction = re.learn(&fbt.feature_buffer, &mut pb, false);
+                    }
+                    if !sampled_out {
+                        delayed_learning_fbs.push_back(fbt.feature_buffer.clone());
+                    }
+                    if (prediction_model_delay as usize) < delayed_learning_fbs.len() {
+                        let delayed_buffer = delayed_learning_fbs.pop_front().unwrap();
+                        re.learn(&delayed_buffer, &mut pb, !testonly);
+                    }
                 }
-            }
 
-            if example_num > predictions_after {
-                match predictions_file.as_mut() {
-                    Some(file) => write!(file, "{:.6}\n", prediction)?,
-                    None => {}
+                if example_num > predictions_after {
+                    match predictions_file.as_mut() {
+                        Some(file) => {
+                            if fbt.feature_buffer.tag.is_empty() {
+                                write!(file, "{:.6}\n", prediction)?
+                            } else {
+                                // Same as vw, tag follows the prediction
+                                write!(
+                                    file,
+                                    "{:.6} {}\n",
+                                    prediction,
+                                    String::from_utf8_lossy(&fbt.feature_buffer.tag)
+                                )?
+                            }
+                        }
+                        None => {}
+                    }
                 }
             }
+            cache.write_finish()?;
         }
-        cache.write_finish()?;
+        pa.report_bad_rows()?;
 
         let elapsed = now.elapsed();
         println!("Elapsed: {:.2?} rows: {}", elapsed, example_num);
+        if let Some(hs) = &fbt.hash_statistics {
+            print!("{}", hs.report(&mi, &vw));
+        }
 
         match final_regressor_filename {
             Some(filename) => {
@@ -14,6 +14,9 @@ use crate::vwmap::NamespaceDescriptor;
 pub struct FeatureComboDesc {
     pub namespace_descriptors: Vec<vwmap::NamespaceDescriptor>,
     pub weight: f32,
+    // A combo with its own bit precision doesn't share the weights of --bit_precision with other combos
+    #[serde(default)]
+    pub bit_precision: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Copy)]
@@ -23,8 +26,63 @@ pub enum Optimizer {
     AdagradLUT = 300,
 }
 
+// How weights of inference regressors are stored (and computed from)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Copy)]
+pub enum WeightsQuantization {
+    None,
+    F16,
+    Int8,
+}
+
+impl WeightsQuantization {
+    pub fn from_cmdline_str(s: &str) -> Result<WeightsQuantization, Box<dyn Error>> {
+        match s {
+            "none" => Ok(WeightsQuantization::None),
+            "f16" => Ok(WeightsQuantization::F16),
+            "int8" => Ok(WeightsQuantization::Int8),
+            _ => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Unknown weights quantization: \"{}\", possible values are none, f16 and int8",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
+// Logistic loss takes labels clamped to [0, 1] (so vw's -1 is a negative example), squared loss takes them as they are
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Copy)]
+pub enum LossFunction {
+    Logistic,
+    Squared,
+}
+
+impl LossFunction {
+    pub fn from_cmdline_str(s: &str) -> Result<LossFunction, Box<dyn Error>> {
+        match s {
+            "logistic" => Ok(LossFunction::Logistic),
+            "squared" => Ok(LossFunction::Squared),
+            _ => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Unknown loss function: \"{}\", possible values are logistic and squared",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
 pub type FieldDesc = Vec<vwmap::NamespaceDescriptor>;
 
+// Hashes of a feature combo (or ffm field) are masked to its bit precision and moved to its part of the weights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashSpace {
+    pub mask: u32,
+    pub offset: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NNConfig {
     pub layers: Vec<HashMap<String, String>>,
@@ -54,6 +112,9 @@ pub struct ModelInstance {
     pub ffm_k: u32,
     #[serde(default = "default_u32_zero")]
     pub ffm_bit_precision: u32,
+    // Own bit precision of each ffm field (empty when no field has one), others share the weights of ffm_bit_precision
+    #[serde(default)]
+    pub ffm_fields_bit_precision: Vec<Option<u32>>,
     #[serde(default = "default_bool_false")]
     pub fastmath: bool,
 
@@ -88,6 +149,25 @@ pub struct ModelInstance {
     pub optimizer: Optimizer,
 
     pub transform_namespaces: feature_transform_parser::NamespaceTransforms,
+
+    #[serde(default = "default_weights_quantization_none")]
+    pub weights_quantization: WeightsQuantization,
+    // Inference regressors saved with --weights_page_aligned have page aligned weights, so they can be memory-mapped
+    #[serde(default = "default_bool_false")]
+    pub weights_page_aligned: bool,
+    // Pruned inference regressors keep LR and FFM weights in a sparse table
+    #[serde(default = "default_bool_false")]
+    pub weights_sparse: bool,
+
+    #[serde(default = "default_loss_function_logistic")]
+    pub loss_function: LossFunction,
+
+    // Negatives were kept with this probability in training, predictions are recalibrated for it
+    #[serde(default = "default_f32_one")]
+    pub negative_sampling_rate: f32,
+    // Kept negatives were weighed by 1/negative_sampling_rate instead, predictions are not recalibrated
+    #[serde(default = "default_bool_false")]
+    pub negative_sampling_importance: bool,
 }
 
 fn default_u32_zero() -> u32 {
@@ -96,12 +176,51 @@ fn default_u32_zero() -> u32 {
 fn default_f32_zero() -> f32 {
     0.0
 }
+fn default_f32_one() -> f32 {
+    1.0
+}
 fn default_bool_false() -> bool {
     false
 }
 fn default_optimizer_adagrad() -> Optimizer {
     Optimizer::AdagradFlex
 }
+fn default_weights_quantization_none() -> WeightsQuantization {
+    WeightsQuantization::None
+}
+fn default_loss_function_logistic() -> LossFunction {
+    LossFunction::Logistic
+}
+
+fn hash_spaces(
+    shared_bit_precision: u32,
+    bit_precisions: &[Option<u32>],
+    dimensions_bits: u32,
+    spillover_len: u64,
+) -> (Vec<HashSpace>, u64) {
+    let dimensions_mask = (1u64 << dimensions_bits) - 1;
+    let mask = |bits: u32| (((1u64 << bits) - 1) ^ dimensions_mask) as u32;
+    let shared = HashSpace {
+        mask: mask(shared_bit_precision),
+        offset: 0,
+    };
+    let mut weights_len = (1u64 << shared_bit_precision) + spillover_len;
+    let hash_spaces = bit_precisions
+        .iter()
+        .map(|bit_precision| match bit_precision {
+            None => shared,
+            Some(bits) => {
+                let hash_space = HashSpace {
+                    mask: mask(*bits),
+                    offset: weights_len as u32,
+                };
+                weights_len += (1u64 << bits) + spillover_len;
+                hash_space
+            }
+        })
+        .collect();
+    (hash_spaces, weights_len)
+}
 
 fn parse_float(s: &str, default: f32, cl: &clap::ArgMatches) -> f32 {
     match cl.value_of(s) {
@@ -111,6 +230,46 @@ fn parse_float(s: &str, default: f32, cl: &clap::ArgMatches) -> f32 {
 }
 
 impl ModelInstance {
+    // Immutable LR and FFM blocks of such regressors don't use the regular weights layout
+    pub fn has_inference_weights_layout(&self) -> bool {
+        self.weights_quantization != WeightsQuantization::None
+            || self.weights_page_aligned
+            || self.weights_sparse
+    }
+
+    // Lower bits of ffm hashes are spared for k
+    pub fn ffm_bits_for_dimensions(&self) -> u32 {
+        let mut ffm_bits_for_dimensions = 0;
+        while self.ffm_k > (1 << (ffm_bits_for_dimensions)) {
+            ffm_bits_for_dimensions += 1;
+        }
+        ffm_bits_for_dimensions
+    }
+
+    // LR weights of --bit_precision are shared by feature combos without their own bit precision (and the constant),
+    // the ones with it follow. Returns the hash space of each combo and the number of LR weights.
+    pub fn lr_hash_spaces(&self) -> (Vec<HashSpace>, u64) {
+        let bit_precisions: Vec<Option<u32>> = self
+            .feature_combo_descs
+            .iter()
+            .map(|combo| combo.bit_precision)
+            .collect();
+        hash_spaces(self.bit_precision as u32, &bit_precisions, 0, 0)
+    }
+
+    // Same for ffm fields, except that each part of the weights is followed by a spillover buffer
+    pub fn ffm_hash_spaces(&self) -> (Vec<HashSpace>, u64) {
+        let bit_precisions: Vec<Option<u32>> = (0..self.ffm_fields.len())
+            .map(|i| self.ffm_fields_bit_precision.get(i).cloned().flatten())
+            .collect();
+        hash_spaces(
+            self.ffm_bit_precision,
+            &bit_precisions,
+            self.ffm_bits_for_dimensions(),
+            self.ffm_fields.len() as u64 * self.ffm_k as u64,
+        )
+    }
+
     pub fn new_empty() -> Result<ModelInstance, Box<dyn Error>> {
         let mi = ModelInstance {
             learning_rate: 0.5,     // vw default
@@ -124,6 +283,7 @@ impl ModelInstance {
             ffm_fields: Vec::new(),
             ffm_k: 0,
             ffm_bit_precision: 18,
+            ffm_fields_bit_precision: Vec::new(),
             fastmath: true,
             ffm_initialization_type: String::from("default"),
             ffm_k_threshold: 0.0,
@@ -138,6 +298,12 @@ impl ModelInstance {
             optimizer: Optimizer::SGD,
             transform_namespaces: feature_transform_parser::NamespaceTransforms::new(),
             nn_config: NNConfig::new(),
+            weights_quantization: WeightsQuantization::None,
+            weights_page_aligned: false,
+            weights_sparse: false,
+            loss_function: LossFunction::Logistic,
+            negative_sampling_rate: 1.0,
+            negative_sampling_importance: false,
         };
         Ok(mi)
     }
@@ -176,6 +342,7 @@ impl ModelInstance {
         Ok(FeatureComboDesc {
             namespace_descriptors: namespace_descriptors,
             weight: combo_weight,
+            bit_precision: None,
         })
     }
 
@@ -224,6 +391,7 @@ impl ModelInstance {
         Ok(FeatureComboDesc {
             namespace_descriptors: namespace_descriptors,
             weight: combo_weight,
+            bit_precision: None,
         })
     }
 
@@ -255,6 +423,42 @@ impl ModelInstance {
         Ok(field)
     }
 
+    // Parses verbose_namespace,verbose_namespace,...=bits of --combo_bit_precision and --ffm_field_bit_precision
+    fn parse_namespaces_bit_precision(
+        &self,
+        vw: &vwmap::VwNamespaceMap,
+        s: &str,
+        min_bits: u32,
+    ) -> Result<(Vec<vwmap::NamespaceDescriptor>, u32), Box<dyn Error>> {
+        let vsplit: Vec<&str> = s.split("=").collect();
+        let bits: Option<u32> = if vsplit.len() == 2 {
+            vsplit[1].parse().ok()
+        } else {
+            None
+        };
+        let bits = match bits {
+            Some(bits) if bits >= min_bits && bits <= 31 => bits,
+            _ => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Bit precision has to be given as verbose_namespace,verbose_namespace,...=bits, with bits from {} to 31: {:?}",
+                        min_bits, s
+                    ),
+                )))
+            }
+        };
+        let mut namespace_descriptors: Vec<vwmap::NamespaceDescriptor> = Vec::new();
+        for namespace_verbose in vsplit[0].split(",") {
+            namespace_descriptors.push(feature_transform_parser::get_namespace_descriptor_verbose(
+                &self.transform_namespaces,
+                vw,
+                namespace_verbose,
+            )?);
+        }
+        Ok((namespace_descriptors, bits))
+    }
+
     fn parse_nn(&mut self, s: &str) -> Result<(), Box<dyn Error>> {
         // Examples: 0:activation:relu
         // Examples: 4:maxnorm:5.0
@@ -414,6 +618,63 @@ impl ModelInstance {
             mi.bit_precision = val.parse()?;
         }
 
+        if let Some(in_v) = cl.values_of("combo_bit_precision") {
+            for value_str in in_v {
+                let (namespace_descriptors, bits) =
+                    mi.parse_namespaces_bit_precision(vw, value_str, 1)?;
+                let mut found = false;
+                for combo in mi.feature_combo_descs.iter_mut() {
+                    if combo.namespace_descriptors == namespace_descriptors {
+                        combo.bit_precision = Some(bits);
+                        found = true;
+                    }
+                }
+                if !found {
+                    return Err(Box::new(IOError::new(
+                        ErrorKind::Other,
+                        format!("--combo_bit_precision {}: there is no such feature combo (of --keep, --interactions or --linear)", value_str),
+                    )));
+                }
+            }
+        }
+
+        if let Some(in_v) = cl.values_of("ffm_field_bit_precision") {
+            mi.ffm_fields_bit_precision = vec![None; mi.ffm_fields.len()];
+            for value_str in in_v {
+                // ffm_k has to fit in the lower bits
+                let (namespace_descriptors, bits) = mi.parse_namespaces_bit_precision(
+                    vw,
+                    value_str,
+                    mi.ffm_bits_for_dimensions() + 1,
+                )?;
+                let mut found = false;
+                for (field, field_bit_precision) in mi
+                    .ffm_fields
+                    .iter()
+                    .zip(mi.ffm_fields_bit_precision.iter_mut())
+                {
+                    if *field == namespace_descriptors {
+                        *field_bit_precision = Some(bits);
+                        found = true;
+                    }
+                }
+                if !found {
+                    return Err(Box::new(IOError::new(
+                        ErrorKind::Other,
+                        format!("--ffm_field_bit_precision {}: there is no such ffm field (of --ffm_field or --ffm_field_verbose)", value_str),
+                    )));
+                }
+            }
+        }
+
+        // Weights are indexed by u32
+        if mi.lr_hash_spaces().1 > u32::MAX as u64 || mi.ffm_hash_spaces().1 > u32::MAX as u64 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Bit precisions add up to more than 2^32 LR or FFM weights"),
+            )));
+        }
+
         mi.learning_rate = parse_float("learning_rate", mi.learning_rate, &cl);
         mi.init_acc_gradient = parse_float("init_acc_gradient", mi.init_acc_gradient, &cl);
         mi.power_t = parse_float("power_t", mi.power_t, &cl);
@@ -448,21 +709,40 @@ impl ModelInstance {
             mi.minimum_learning_rate = val.parse()?;
         }
 
+        if let Some(val) = cl.value_of("loss_function") {
+            mi.loss_function = LossFunction::from_cmdline_str(val)?;
+        }
         if let Some(val) = cl.value_of("link") {
-            if val != "logistic" {
+            let expected_link = match mi.loss_function {
+                LossFunction::Logistic => "logistic",
+                LossFunction::Squared => "identity",
+            };
+            if val != expected_link {
                 return Err(Box::new(IOError::new(
                     ErrorKind::Other,
-                    format!("--link only supports 'logistic'"),
+                    format!(
+                        "--link only supports '{}' with --loss_function {}",
+                        expected_link,
+                        cl.value_of("loss_function").unwrap_or("logistic")
+                    ),
                 )));
             }
         }
-        if let Some(val) = cl.value_of("loss_function") {
-            if val != "logistic" {
+        if let Some(val) = cl.value_of("negative_sampling_rate") {
+            mi.negative_sampling_rate = val.parse()?;
+            if !(mi.negative_sampling_rate > 0.0 && mi.negative_sampling_rate <= 1.0) {
                 return Err(Box::new(IOError::new(
                     ErrorKind::Other,
-                    format!("--loss_function only supports 'logistic'"),
+                    format!("--negative_sampling_rate has to be more than 0 and at most 1"),
                 )));
             }
+            if mi.loss_function != LossFunction::Logistic {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("--negative_sampling_rate is only supported with logistic loss"),
+                )));
+            }
+            mi.negative_sampling_importance = cl.is_present("negative_sampling_importance");
         }
         if let Some(val) = cl.value_of("l2") {
             let v2: f32 = val.parse()?;
@@ -540,6 +820,26 @@ impl ModelInstance {
             }
         }
 
+        // Continuing training with a different sampling changes how predictions are recalibrated
+        if let Some(val) = cmd_arguments.value_of("negative_sampling_rate") {
+            let hvalue = val.parse::<f32>()?;
+            if !(hvalue > 0.0 && hvalue <= 1.0) {
+                return Err("--negative_sampling_rate has to be more than 0 and at most 1")?;
+            }
+            if mi.loss_function != LossFunction::Logistic {
+                return Err("--negative_sampling_rate is only supported with logistic loss")?;
+            }
+            mi.negative_sampling_rate = hvalue;
+            replacement_hyperparam_ids
+                .push(("negative_sampling_rate".to_string(), hvalue.to_string()));
+            mi.negative_sampling_importance =
+                cmd_arguments.is_present("negative_sampling_importance");
+            replacement_hyperparam_ids.push((
+                "negative_sampling_importance".to_string(),
+                mi.negative_sampling_importance.to_string(),
+            ));
+        }
+
         for (hyper_name, hyper_value) in replacement_hyperparam_ids.into_iter() {
             println!(
                 "Warning! Updated hyperparameter {} to value {}",
@@ -555,6 +855,7 @@ impl ModelInstance {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::cmdline;
 
     fn ns_desc(i: u16) -> NamespaceDescriptor {
         NamespaceDescriptor {
@@ -579,7 +880,8 @@ C,featureC
             result,
             FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(0)],
-                weight: 1.0
+                weight: 1.0,
+                bit_precision: None,
             }
         );
 
@@ -588,7 +890,8 @@ C,featureC
             result,
             FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(1), ns_desc(0)],
-                weight: 1.5
+                weight: 1.5,
+                bit_precision: None,
             }
         );
     }
@@ -607,7 +910,8 @@ B,featureB:3
             result,
             FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(1), ns_desc(0)],
-                weight: 1.5
+                weight: 1.5,
+                bit_precision: None,
             }
         );
     }
@@ -629,7 +933,8 @@ C,featureC
             FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(0)],
 
-                weight: 1.0
+                weight: 1.0,
+                bit_precision: None,
             }
         );
 
@@ -640,7 +945,8 @@ C,featureC
             result,
             FeatureComboDesc {
                 namespace_descriptors: vec![ns_desc(1), ns_desc(0)],
-                weight: 1.5
+                weight: 1.5,
+                bit_precision: None,
             }
         );
 
@@ -672,6 +978,133 @@ C,featureC
         assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Fields currently do not support passing a value via : \\\"featureA,featureC:3\\\"\" })");
     }
 
+    #[test]
+    fn test_bit_precision_parsing() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let new_from_args = |args: &str| {
+            let cl = cmdline::create_expected_args()
+                .get_matches_from(format!("fw --linear featureA --linear featureA,featureB -b 10 --ffm_k 4 --ffm_field_verbose featureA --ffm_field_verbose featureB {}", args).split_whitespace());
+            ModelInstance::new_from_cmdline(&cl, &vw)
+        };
+
+        let mi = new_from_args(
+            "--combo_bit_precision featureA,featureB=20 --ffm_field_bit_precision featureB=12",
+        )
+        .unwrap();
+        assert_eq!(mi.feature_combo_descs[0].bit_precision, None);
+        assert_eq!(mi.feature_combo_descs[1].bit_precision, Some(20));
+        assert_eq!(mi.ffm_fields_bit_precision, vec![None, Some(12)]);
+        // The first combo (and the constant) share 2^10 weights, the second one's follow
+        assert_eq!(
+            mi.lr_hash_spaces(),
+            (
+                vec![
+                    HashSpace {
+                        mask: (1 << 10) - 1,
+                        offset: 0
+                    },
+                    HashSpace {
+                        mask: (1 << 20) - 1,
+                        offset: 1 << 10
+                    }
+                ],
+                (1 << 10) + (1 << 20)
+            )
+        );
+        // ffm_k of 4 takes the lower 2 bits, each hash space is followed by spillover of ffm_k * 2 fields
+        assert_eq!(
+            mi.ffm_hash_spaces(),
+            (
+                vec![
+                    HashSpace {
+                        mask: ((1 << 18) - 1) ^ 3,
+                        offset: 0
+                    },
+                    HashSpace {
+                        mask: ((1 << 12) - 1) ^ 3,
+                        offset: (1 << 18) + 8
+                    }
+                ],
+                (1 << 18) + 8 + (1 << 12) + 8
+            )
+        );
+
+        // Without own bit precisions the weights are laid out as before
+        let mi = new_from_args("").unwrap();
+        assert!(mi.ffm_fields_bit_precision.is_empty());
+        assert_eq!(mi.lr_hash_spaces().1, 1 << 10);
+        assert_eq!(mi.ffm_hash_spaces().1, (1 << 18) + 8);
+
+        let error = |args: &str| new_from_args(args).unwrap_err().to_string();
+        assert_eq!(
+            error("--combo_bit_precision featureB=20"),
+            "--combo_bit_precision featureB=20: there is no such feature combo (of --keep, --interactions or --linear)"
+        );
+        assert_eq!(
+            error("--ffm_field_bit_precision featureA,featureB=20"),
+            "--ffm_field_bit_precision featureA,featureB=20: there is no such ffm field (of --ffm_field or --ffm_field_verbose)"
+        );
+        assert_eq!(
+            error("--ffm_field_bit_precision featureA=2"),
+            "Bit precision has to be given as verbose_namespace,verbose_namespace,...=bits, with bits from 3 to 31: \"featureA=2\""
+        );
+        assert!(new_from_args("--combo_bit_precision featureA=32").is_err());
+        assert!(new_from_args("--combo_bit_precision featureA").is_err());
+        assert!(new_from_args("--combo_bit_precision featureC=10").is_err());
+        assert_eq!(
+            error("--combo_bit_precision featureA=31 --combo_bit_precision featureA,featureB=31"),
+            "Bit precisions add up to more than 2^32 LR or FFM weights"
+        );
+    }
+
+    #[test]
+    fn test_negative_sampling_parsing() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let parse = |args: &str| {
+            cmdline::create_expected_args()
+                .get_matches_from(format!("fw --linear featureA {}", args).split_whitespace())
+        };
+
+        let mi = ModelInstance::new_from_cmdline(
+            &parse("--negative_sampling_rate 0.25 --negative_sampling_importance"),
+            &vw,
+        )
+        .unwrap();
+        assert_eq!(mi.negative_sampling_rate, 0.25);
+        assert!(mi.negative_sampling_importance);
+        assert!(ModelInstance::new_from_cmdline(
+            &parse("--negative_sampling_rate 0.25 --loss_function squared"),
+            &vw
+        )
+        .is_err());
+
+        // Continuing training of a squared loss model can't switch sampling on either
+        let mut mi =
+            ModelInstance::new_from_cmdline(&parse("--loss_function squared"), &vw).unwrap();
+        let error = ModelInstance::update_hyperparameters_from_cmd(
+            &parse("--negative_sampling_rate 0.25"),
+            &mut mi,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--negative_sampling_rate is only supported with logistic loss"
+        );
+        assert_eq!(mi.negative_sampling_rate, 1.0);
+
+        // --negative_sampling_importance alone would be silently ignored
+        assert!(cmdline::create_expected_args()
+            .get_matches_from_safe("fw --negative_sampling_importance".split_whitespace())
+            .is_err());
+    }
+
     #[test]
     fn test_nn_parsing() {
         let mut mi = ModelInstance::new_empty().unwrap();
@@ -0,0 +1,360 @@
+use std::error::Error;
+
+use crate::model_instance;
+use crate::persistence;
+use crate::regressor;
+use crate::vwmap;
+
+// Appends a line for every leaf in which the two json values differ
+fn describe_json_differences(
+    path: &str,
+    a: &serde_json::Value,
+    b: &serde_json::Value,
+    differences: &mut Vec<String>,
+) {
+    match (a, b) {
+        (serde_json::Value::Object(a_map), serde_json::Value::Object(b_map)) => {
+            let mut keys: Vec<&String> = a_map.keys().chain(b_map.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let null = serde_json::Value::Null;
+                describe_json_differences(
+                    &format!("{}.{}", path, key),
+                    a_map.get(key).unwrap_or(&null),
+                    b_map.get(key).unwrap_or(&null),
+                    differences,
+                );
+            }
+        }
+        (serde_json::Value::Array(a_vec), serde_json::Value::Array(b_vec))
+            if a_vec.len() == b_vec.len() =>
+        {
+            for (i, (a_item, b_item)) in a_vec.iter().zip(b_vec.iter()).enumerate() {
+                describe_json_differences(&format!("{}[{}]", path, i), a_item, b_item, differences);
+            }
+        }
+        _ => {
+            if a != b {
+                differences.push(format!("{}: {} vs {}", path, a, b));
+            }
+        }
+    }
+}
+
+fn describe_model_differences(
+    mi_a: &model_instance::ModelInstance,
+    vw_a: &vwmap::VwNamespaceMap,
+    re_a: &regressor::Regressor,
+    mi_b: &model_instance::ModelInstance,
+    vw_b: &vwmap::VwNamespaceMap,
+    re_b: &regressor::Regressor,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut differences: Vec<String> = Vec::new();
+    describe_json_differences(
+        "model_instance",
+        &serde_json::to_value(mi_a)?,
+        &serde_json::to_value(mi_b)?,
+        &mut differences,
+    );
+    describe_json_differences(
+        "vw_namespace_map",
+        &serde_json::to_value(&vw_a.vw_source)?,
+        &serde_json::to_value(&vw_b.vw_source)?,
+        &mut differences,
+    );
+    let blocks_a = re_a.get_blocks_serialized_len();
+    let blocks_b = re_b.get_blocks_serialized_len();
+    if blocks_a.len() != blocks_b.len() {
+        differences.push(format!(
+            "number of blocks: {} vs {}",
+            blocks_a.len(),
+            blocks_b.len()
+        ));
+    } else {
+        for (i, (len_a, len_b)) in blocks_a.iter().zip(blocks_b.iter()).enumerate() {
+            if len_a != len_b {
+                differences.push(format!("block {} weights: {} vs {}", i, len_a, len_b));
+            }
+        }
+    }
+    Ok(differences)
+}
+
+// Averages weights of regressors that were trained with the same configuration (e.g. on different shards of data).
+// With example_counts, every regressor is weighted by the number of examples it was trained on.
+// Optimizer state (Adagrad accumulators) is added up, so training can continue from the merged regressor.
+pub fn merge_regressor_files(
+    input_filenames: &Vec<&str>,
+    example_counts: Option<Vec<u64>>,
+    output_filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    if input_filenames.len() < 2 {
+        return Err("Merging needs at least two regressors")?;
+    }
+    let model_weights: Vec<f32> = match example_counts {
+        Some(counts) => {
+            if counts.len() != input_filenames.len() {
+                return Err(format!(
+                    "Got {} example counts for {} regressors",
+                    counts.len(),
+                    input_filenames.len()
+                ))?;
+            }
+            let total: u64 = counts.iter().sum();
+            if total == 0 {
+                return Err("Example counts add up to zero")?;
+            }
+            counts
+                .iter()
+                .map(|c| (*c as f64 / total as f64) as f32)
+                .collect()
+        }
+        None => vec![1.0 / input_filenames.len() as f32; input_filenames.len()],
+    };
+
+    let (mi, vw, mut re) =
+        persistence::new_regressor_from_filename(input_filenames[0], false, None)?;
+    re.scale_weights(model_weights[0]);
+    for (filename, model_weight) in input_filenames.iter().zip(model_weights.iter()).skip(1) {
+        let (mi2, vw2, mut re2) = persistence::new_regressor_from_filename(filename, false, None)?;
+        let differences = describe_model_differences(&mi, &vw, &re, &mi2, &vw2, &re2)?;
+        if !differences.is_empty() {
+            return Err(format!(
+                "Regressors {} and {} cannot be merged, they differ in:\n{}",
+                input_filenames[0],
+                filename,
+                differences.join("\n")
+            ))?;
+        }
+        re.add_weights_from(&mut re2, *model_weight)?;
+    }
+    persistence::save_regressor_to_filename(output_filename, &mi, &vw, re)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_ffm;
+    use crate::block_neural;
+    use crate::feature_buffer;
+    use crate::feature_buffer::{HashAndValue, HashAndValueAndSeq};
+    use crate::optimizer::OptimizerAdagradFlex;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    fn lr_vec(v: Vec<feature_buffer::HashAndValue>) -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
+            lr_buffer: v,
+            ffm_buffer: Vec::new(),
+            ffm_fields_count: 0,
+        }
+    }
+
+    fn lr_mi() -> model_instance::ModelInstance {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        mi.init_acc_gradient = 0.0;
+        mi
+    }
+
+    fn save_lr_with_weight(
+        filename: &str,
+        mi: &model_instance::ModelInstance,
+        vw: &vwmap::VwNamespaceMap,
+        weight: f32,
+    ) {
+        let mut re = regressor::Regressor::new(&mi);
+        re.blocks_boxes[0]
+            .testing_set_weights(0, 0, 1, &[weight])
+            .unwrap();
+        persistence::save_regressor_to_filename(filename, &mi, &vw, re).unwrap();
+    }
+
+    #[test]
+    fn test_merge_averages_weights() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mi = lr_mi();
+        let dir = tempdir().unwrap();
+        let filename_1 = dir.path().join("r1.fw");
+        let filename_1 = filename_1.to_str().unwrap();
+        let filename_2 = dir.path().join("r2.fw");
+        let filename_2 = filename_2.to_str().unwrap();
+        let merged_filename = dir.path().join("merged.fw");
+        let merged_filename = merged_filename.to_str().unwrap();
+        save_lr_with_weight(filename_1, &mi, &vw, 1.0);
+        save_lr_with_weight(filename_2, &mi, &vw, 3.0);
+
+        // single feature with value 1.0 hits weight 1, so the prediction is logistic(weight)
+        let fbuf = &lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+
+        merge_regressor_files(&vec![filename_1, filename_2], None, merged_filename).unwrap();
+        let (_mi, _vw, re) =
+            persistence::new_regressor_from_filename(merged_filename, true, None).unwrap();
+        let mut pb = re.new_portbuffer();
+        assert_epsilon!(re.predict(fbuf, &mut pb), 1.0 / (1.0 + (-2.0f32).exp()));
+
+        merge_regressor_files(
+            &vec![filename_1, filename_2],
+            Some(vec![300, 100]),
+            merged_filename,
+        )
+        .unwrap();
+        let (_mi, _vw, re) =
+            persistence::new_regressor_from_filename(merged_filename, true, None).unwrap();
+        assert_epsilon!(re.predict(fbuf, &mut pb), 1.0 / (1.0 + (-1.5f32).exp()));
+    }
+
+    #[test]
+    fn test_merge_refuses_different_models() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mi = lr_mi();
+        let mut mi2 = lr_mi();
+        mi2.bit_precision = 17;
+        let dir = tempdir().unwrap();
+        let filename_1 = dir.path().join("r1.fw");
+        let filename_1 = filename_1.to_str().unwrap();
+        let filename_2 = dir.path().join("r2.fw");
+        let filename_2 = filename_2.to_str().unwrap();
+        save_lr_with_weight(filename_1, &mi, &vw, 1.0);
+        save_lr_with_weight(filename_2, &mi2, &vw, 1.0);
+
+        let merged_filename = dir.path().join("merged.fw");
+        let err = merge_regressor_files(
+            &vec![filename_1, filename_2],
+            None,
+            merged_filename.to_str().unwrap(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("model_instance.bit_precision: 18 vs 17"));
+        assert!(err.contains("block 0 weights: 262144 vs 131072"));
+        assert!(!merged_filename.exists());
+    }
+
+    fn ffm_nn_mi() -> model_instance::ModelInstance {
+        let mut mi = lr_mi();
+        mi.bit_precision = 8;
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 8;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_init_acc_gradient = 0.0;
+        mi.nn_init_acc_gradient = 0.0;
+        let mut layer = HashMap::new();
+        layer.insert("width".to_string(), "3".to_string());
+        mi.nn_config.layers.push(layer);
+        mi
+    }
+
+    fn ffm_nn_vec(label: f32, ffm_k: u32) -> feature_buffer::FeatureBuffer {
+        let mut fb = lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+        fb.label = label;
+        fb.ffm_buffer = vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: ffm_k,
+            },
+        ];
+        fb.ffm_fields_count = 2;
+        fb
+    }
+
+    // Returns (weights, accumulators) of every ffm and nn block, in the order of blocks
+    fn ffm_nn_weights(filename: &str) -> Vec<(Vec<f32>, Vec<f32>)> {
+        let (_mi, _vw, mut re) =
+            persistence::new_regressor_from_filename(filename, false, None).unwrap();
+        let mut result = Vec::new();
+        for block in re.blocks_boxes.iter_mut() {
+            if let Some(ffm) = block
+                .as_any()
+                .downcast_mut::<block_ffm::BlockFFM<OptimizerAdagradFlex>>()
+            {
+                result.push((
+                    ffm.weights.iter().map(|w| w.weight).collect(),
+                    ffm.weights.iter().map(|w| w.optimizer_data).collect(),
+                ));
+            } else if let Some(nn) = block
+                .as_any()
+                .downcast_mut::<block_neural::BlockNeuronLayer<OptimizerAdagradFlex>>()
+            {
+                result.push((
+                    nn.weights.iter().map(|w| w.weight).collect(),
+                    nn.weights_optimizer
+                        .iter()
+                        .map(|o| o.optimizer_data)
+                        .collect(),
+                ));
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_merge_ffm_and_nn_blocks() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mi = ffm_nn_mi();
+        let dir = tempdir().unwrap();
+        let filename_1 = dir.path().join("r1.fw");
+        let filename_1 = filename_1.to_str().unwrap();
+        let filename_2 = dir.path().join("r2.fw");
+        let filename_2 = filename_2.to_str().unwrap();
+        let merged_filename = dir.path().join("merged.fw");
+        let merged_filename = merged_filename.to_str().unwrap();
+
+        // Two regressors that learned from examples with different labels
+        for (filename, label, times) in [(filename_1, 1.0, 1), (filename_2, 0.0, 3)].iter() {
+            let mut re = regressor::Regressor::new(&mi);
+            let mut pb = re.new_portbuffer();
+            for _ in 0..*times {
+                re.learn(&ffm_nn_vec(*label, mi.ffm_k), &mut pb, true);
+            }
+            persistence::save_regressor_to_filename(filename, &mi, &vw, re).unwrap();
+        }
+
+        merge_regressor_files(&vec![filename_1, filename_2], None, merged_filename).unwrap();
+        let blocks_1 = ffm_nn_weights(filename_1);
+        let blocks_2 = ffm_nn_weights(filename_2);
+        let blocks_merged = ffm_nn_weights(merged_filename);
+        // ffm block, the nn layer and the final neuron
+        assert_eq!(blocks_merged.len(), 3);
+        for ((block_1, block_2), block_merged) in blocks_1
+            .iter()
+            .zip(blocks_2.iter())
+            .zip(blocks_merged.iter())
+        {
+            let (weights_1, acc_1) = block_1;
+            let (weights_2, acc_2) = block_2;
+            let (weights_merged, acc_merged) = block_merged;
+            assert!(acc_merged.iter().any(|acc| *acc > 0.0));
+            for i in 0..weights_merged.len() {
+                assert_epsilon!(weights_merged[i], (weights_1[i] + weights_2[i]) / 2.0);
+                assert_epsilon!(acc_merged[i], acc_1[i] + acc_2[i]);
+            }
+        }
+    }
+}
@@ -6,6 +6,8 @@ pub trait OptimizerTrait: std::clone::Clone {
     fn init(&mut self, learning_rate: f32, power_t: f32, initial_acc_gradient: f32);
     unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32;
     fn initial_data(&self) -> Self::PerWeightStore;
+    // Combines optimizer state of the same weight from two models that were trained separately
+    fn merge_data(&self, data: &mut Self::PerWeightStore, other: &Self::PerWeightStore);
     fn get_name() -> &'static str;
 }
 
@@ -39,6 +41,8 @@ impl OptimizerTrait for OptimizerSGD {
     fn initial_data(&self) -> Self::PerWeightStore {
         std::marker::PhantomData {}
     }
+
+    fn merge_data(&self, _data: &mut Self::PerWeightStore, _other: &Self::PerWeightStore) {}
 }
 
 /******************* Adagrad with flexible power_t  **************************/
@@ -91,6 +95,11 @@ impl OptimizerTrait for OptimizerAdagradFlex {
     fn initial_data(&self) -> Self::PerWeightStore {
         self.initial_acc_gradient
     }
+
+    fn merge_data(&self, data: &mut Self::PerWeightStore, other: &Self::PerWeightStore) {
+        // Both accumulators started at initial_acc_gradient, count it only once
+        *data += *other - self.initial_acc_gradient;
+    }
 }
 
 /***************** Adagrad using Look Up Table ******************/
@@ -159,6 +168,10 @@ impl OptimizerTrait for OptimizerAdagradLUT {
         // We took it into account when calcualting lookup table, so look at init()
         0.0
     }
+
+    fn merge_data(&self, data: &mut Self::PerWeightStore, other: &Self::PerWeightStore) {
+        *data += *other;
+    }
 }
 
 mod tests {
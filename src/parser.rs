@@ -1,22 +1,34 @@
+use crate::csv_parser;
+use crate::feature_transform_string;
+use crate::jsonl_parser;
 use crate::vwmap;
+use dyn_clone::{clone_trait_object, DynClone};
 use fasthash::murmur3;
 use std::error::Error;
 use std::fmt;
+use std::fs;
+use std::io;
 use std::io::BufRead;
 use std::io::Error as IOError;
 use std::io::ErrorKind;
+use std::io::Write;
 use std::str;
 use std::string::String;
+use std::sync::{Arc, Mutex};
 
 const RECBUF_LEN: usize = 2048;
-pub const HEADER_LEN: u32 = 3;
+const BAD_ROW_SNIPPET_LEN: usize = 200;
+pub const HEADER_LEN: u32 = 5;
 pub const NAMESPACE_DESC_LEN: u32 = 1;
 pub const LABEL_OFFSET: usize = 1;
 pub const EXAMPLE_IMPORTANCE_OFFSET: usize = 2;
+pub const INITIAL_PREDICTION_OFFSET: usize = 3;
+pub const TAG_OFFSET: usize = 4;
 pub const IS_NOT_SINGLE_MASK: u32 = 1u32 << 31;
 pub const MASK31: u32 = !IS_NOT_SINGLE_MASK;
 pub const NO_FEATURES: u32 = IS_NOT_SINGLE_MASK; // null is just an exact IS_NOT_SINGLE_MASK
-pub const NO_LABEL: u32 = 0xff;
+pub const NO_LABEL: u32 = 0xffffffff; // a NaN that parsing a float never returns
+pub const NO_TAG: u32 = 0;
 pub const FLOAT32_ONE: u32 = 1065353216; // 1.0f32.to_bits()
 
 #[derive(Clone)]
@@ -24,6 +36,8 @@ pub struct VowpalParser {
     vw_map: vwmap::VwNamespaceMap,
     tmp_read_buf: Vec<u8>,
     namespace_hash_seeds: [u32; 256], // Each namespace has its hash seed
+    line_number: u64,
+    string_transformer: feature_transform_string::StringTransformer,
     pub output_buffer: Vec<u32>,
 }
 
@@ -35,6 +49,12 @@ pub struct HogwildLoadCommand {
     pub filename: String,
 }
 
+#[derive(Debug)]
+pub struct FatalParseError {
+    // Parser returns it when skipping the offending row can't help (e.g. broken csv header)
+    pub message: String,
+}
+
 impl Error for FlushCommand {}
 impl fmt::Display for FlushCommand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -53,11 +73,315 @@ impl fmt::Display for HogwildLoadCommand {
     }
 }
 
+impl Error for FatalParseError {}
+impl fmt::Display for FatalParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataFormat {
+    Vw,
+    Csv,
+    Tsv,
+    Jsonl,
+}
+
+impl DataFormat {
+    pub fn from_cmdline_str(s: &str) -> Result<DataFormat, Box<dyn Error>> {
+        match s {
+            "vw" => Ok(DataFormat::Vw),
+            "csv" => Ok(DataFormat::Csv),
+            "tsv" => Ok(DataFormat::Tsv),
+            "jsonl" => Ok(DataFormat::Jsonl),
+            _ => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Unknown data format: \"{}\", possible values are vw, csv, tsv and jsonl",
+                    s
+                ),
+            ))),
+        }
+    }
+
+    // The first line of the input is a header and not an example
+    pub fn has_header(&self) -> bool {
+        *self == DataFormat::Csv || *self == DataFormat::Tsv
+    }
+}
+
+// All input formats produce the same records buffer, so caching, transforms and training don't depend on the format.
+// Parsers are cloned to parse chunks of the input on several threads (see cache_builder).
+pub trait RecordParser: DynClone + Send {
+    fn next_record(&mut self, input_bufread: &mut dyn BufRead) -> Result<&[u32], Box<dyn Error>>;
+    // Number (starting with 1) and raw contents of the last line read from the input
+    fn last_line(&self) -> (u64, &[u8]);
+    // Record returned by the last successful next_record()
+    fn last_record(&self) -> &[u32];
+    // Called before the parser is given the next input file, line numbers (and csv header) start over
+    fn reset_input(&mut self);
+}
+
+clone_trait_object!(RecordParser);
+
+impl RecordParser for VowpalParser {
+    fn next_record(
+        &mut self,
+        mut input_bufread: &mut dyn BufRead,
+    ) -> Result<&[u32], Box<dyn Error>> {
+        self.next_vowpal(&mut input_bufread)
+    }
+
+    fn last_line(&self) -> (u64, &[u8]) {
+        (self.line_number, &self.tmp_read_buf)
+    }
+
+    fn last_record(&self) -> &[u32] {
+        &self.output_buffer
+    }
+
+    fn reset_input(&mut self) {
+        self.line_number = 0;
+    }
+}
+
+pub fn new_record_parser(
+    data_format: DataFormat,
+    vw: &vwmap::VwNamespaceMap,
+) -> Box<dyn RecordParser> {
+    match data_format {
+        DataFormat::Vw => Box::new(VowpalParser::new(vw)),
+        DataFormat::Csv => Box::new(csv_parser::CsvParser::new(vw, b',')),
+        DataFormat::Tsv => Box::new(csv_parser::CsvParser::new(vw, b'\t')),
+        DataFormat::Jsonl => Box::new(jsonl_parser::JsonlParser::new(vw)),
+    }
+}
+
+// Adds the line number and the offending text to parse errors.
+// Up to max_bad_rows malformed rows are skipped (and written to the bad rows file) instead of failing.
+// Clones share the bad rows file.
+#[derive(Clone)]
+pub struct FaultTolerantParser {
+    parser: Box<dyn RecordParser>,
+    max_bad_rows: u64,
+    pub bad_rows: u64,
+    bad_rows_filename: Option<String>,
+    bad_rows_file: Option<Arc<Mutex<io::BufWriter<fs::File>>>>,
+    input_name: Option<String>, // used in error messages when reading multiple inputs
+    line_number_offset: u64,    // lines of the input before the chunk being parsed
+}
+
+impl FaultTolerantParser {
+    pub fn new(
+        parser: Box<dyn RecordParser>,
+        max_bad_rows: u64,
+        bad_rows_filename: Option<&str>,
+    ) -> Result<FaultTolerantParser, Box<dyn Error>> {
+        let bad_rows_file = match bad_rows_filename {
+            Some(filename) => Some(Arc::new(Mutex::new(io::BufWriter::new(fs::File::create(
+                filename,
+            )?)))),
+            None => None,
+        };
+        Ok(FaultTolerantParser {
+            parser: parser,
+            max_bad_rows: max_bad_rows,
+            bad_rows: 0,
+            bad_rows_filename: bad_rows_filename.map(|f| f.to_string()),
+            bad_rows_file: bad_rows_file,
+            input_name: None,
+            line_number_offset: 0,
+        })
+    }
+
+    // Bad rows are counted over all inputs, while line numbers start over with every input
+    pub fn start_input(&mut self, input_name: &str) {
+        self.parser.reset_input();
+        self.input_name = Some(input_name.to_string());
+        self.line_number_offset = 0;
+    }
+
+    // For a clone that parses a chunk of the input on its own: lines_before is the number of lines
+    // of the input before the chunk, so errors are still reported with line numbers of the input.
+    // Bad rows of the chunk are counted from zero, the limit for the whole input is checked by add_bad_rows()
+    pub fn start_chunk(&mut self, lines_before: u64) {
+        self.line_number_offset = lines_before - self.parser.last_line().0;
+        self.bad_rows = 0;
+    }
+
+    fn handle_error(
+        &mut self,
+        e: Box<dyn Error>,
+        line_number_before: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let (line_number, line) = self.parser.last_line();
+        if line_number == line_number_before || e.is::<FatalParseError>() {
+            // Nothing was read (an I/O error) or the error is not about a single row
+            return Err(e);
+        }
+        let mut line_len = line.len();
+        while line_len > 0 && (line[line_len - 1] == 0x0a || line[line_len - 1] == 0x0d) {
+            line_len -= 1;
+        }
+        let line = &line[..line_len];
+        if self.bad_rows >= self.max_bad_rows {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Error on line {}{}: {} (line: \"{}\")",
+                    line_number + self.line_number_offset,
+                    match &self.input_name {
+                        Some(input_name) => format!(" of {}", input_name),
+                        None => String::new(),
+                    },
+                    e,
+                    String::from_utf8_lossy(&line[..line_len.min(BAD_ROW_SNIPPET_LEN)])
+                ),
+            )));
+        }
+        self.bad_rows += 1;
+        if let Some(bad_rows_file) = &self.bad_rows_file {
+            let mut bad_rows_file = bad_rows_file.lock().unwrap();
+            bad_rows_file.write_all(line)?;
+            bad_rows_file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    // Adds the bad rows skipped by clones of this parser (see start_chunk)
+    pub fn add_bad_rows(&mut self, bad_rows: u64) -> Result<(), Box<dyn Error>> {
+        self.bad_rows += bad_rows;
+        if self.bad_rows > self.max_bad_rows {
+            return Err(format!(
+                "There are more than {} bad rows (--max_bad_rows)",
+                self.max_bad_rows
+            ))?;
+        }
+        Ok(())
+    }
+
+    pub fn report_bad_rows(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(bad_rows_file) = &self.bad_rows_file {
+            bad_rows_file.lock().unwrap().flush()?;
+        }
+        if self.max_bad_rows > 0 {
+            println!(
+                "Skipped bad rows: {} (--max_bad_rows {})",
+                self.bad_rows, self.max_bad_rows
+            );
+            if let Some(filename) = &self.bad_rows_filename {
+                println!("Bad rows written to: {}", filename);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RecordParser for FaultTolerantParser {
+    fn next_record(&mut self, input_bufread: &mut dyn BufRead) -> Result<&[u32], Box<dyn Error>> {
+        loop {
+            let (line_number_before, _) = self.parser.last_line();
+            match self.parser.next_record(input_bufread) {
+                Ok(buffer) if buffer.is_empty() => return Ok(&[]), // EOF
+                Ok(_) => break,
+                Err(e) => self.handle_error(e, line_number_before)?,
+            }
+        }
+        Ok(self.parser.last_record())
+    }
+
+    fn last_line(&self) -> (u64, &[u8]) {
+        self.parser.last_line()
+    }
+
+    fn last_record(&self) -> &[u32] {
+        self.parser.last_record()
+    }
+
+    fn reset_input(&mut self) {
+        self.parser.reset_input();
+    }
+}
+
+// Appends the tag to the dynamic buffer of the record (packed 4 bytes per u32) and points TAG_OFFSET to it
+pub fn push_tag(output_buffer: &mut Vec<u32>, tag: &[u8]) -> Result<(), Box<dyn Error>> {
+    if tag.is_empty() {
+        output_buffer[TAG_OFFSET] = NO_TAG;
+        return Ok(());
+    }
+    let start = output_buffer.len();
+    if tag.len() > 0xffff || start > 0x7fff {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!("Tag is too long: {}", String::from_utf8_lossy(tag)),
+        )));
+    }
+    for chunk in tag.chunks(4) {
+        let mut word: [u8; 4] = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        output_buffer.push(u32::from_ne_bytes(word));
+    }
+    output_buffer[TAG_OFFSET] = ((start << 16) | tag.len()) as u32;
+    Ok(())
+}
+
+pub fn get_tag(record_buffer: &[u32]) -> &[u8] {
+    let tag = record_buffer[TAG_OFFSET];
+    if tag == NO_TAG {
+        return &[];
+    }
+    let start = (tag >> 16) as usize;
+    let len = (tag & 0xffff) as usize;
+    let words = &record_buffer[start..start + (len + 3) / 4];
+    unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, len) }
+}
+
+// Checks that the offsets in a record (of the layout below) point into its dynamic buffer
+pub fn check_record(record_buffer: &[u32], num_namespaces: usize) -> Result<(), String> {
+    let dynamic_start = HEADER_LEN as usize + num_namespaces;
+    let record_len = record_buffer.len();
+    if record_len < dynamic_start || record_buffer[0] as usize != record_len {
+        return Err(format!(
+            "record length {} is wrong (the record has {} words, its header and namespaces {})",
+            record_buffer[0], record_len, dynamic_start
+        ));
+    }
+    let tag = record_buffer[TAG_OFFSET];
+    if tag != NO_TAG {
+        let start = (tag >> 16) as usize;
+        let end = start + ((tag & 0xffff) as usize + 3) / 4;
+        if start < dynamic_start || end > record_len {
+            return Err(format!(
+                "tag at words {}..{} is outside the record",
+                start, end
+            ));
+        }
+    }
+    for namespace_index in 0..num_namespaces {
+        let namespace_desc = record_buffer[HEADER_LEN as usize + namespace_index];
+        if namespace_desc & IS_NOT_SINGLE_MASK == 0 || namespace_desc == NO_FEATURES {
+            continue;
+        }
+        let start = ((namespace_desc >> 16) & 0x3fff) as usize;
+        let end = (namespace_desc & 0xffff) as usize;
+        if start < dynamic_start || end > record_len || start > end || (end - start) % 2 != 0 {
+            return Err(format!(
+                "features of namespace {} at words {}..{} are outside the record",
+                namespace_index, start, end
+            ));
+        }
+    }
+    Ok(())
+}
+
 /*
 organization of records buffer
 (u32) length of the output record
-(u32) label
+(f32) label (NO_LABEL when there is none)
 (f32) Example importance (default: 1.0)
+(f32) Initial prediction, added to the model output (default: 0.0)
+(u32) Tag: NO_TAG or 15 bits start offset and 16 bits length in bytes of the tag in the dynamic buffer
 (union_u u32)[number of features], where:
     -- if the most significant bit is zero
             - this is a a binary namespace with a single feature
@@ -79,14 +403,15 @@ impl VowpalParser {
             tmp_read_buf: Vec::with_capacity(RECBUF_LEN),
             output_buffer: Vec::with_capacity(RECBUF_LEN * 2),
             namespace_hash_seeds: [0; 256],
+            line_number: 0,
+            string_transformer: feature_transform_string::StringTransformer::new(vw),
         };
         rr.output_buffer.resize(
             (vw.num_namespaces as u32 * NAMESPACE_DESC_LEN + HEADER_LEN) as usize,
             0,
         );
-        for i in 0..vw.num_namespaces {
-            let namespace_vwname_str = &vw.vw_source.entries[i].namespace_vwname;
-            rr.namespace_hash_seeds[i] = murmur3::hash32(namespace_vwname_str);
+        for (i, entry) in vw.vw_source.entries.iter().enumerate() {
+            rr.namespace_hash_seeds[i] = murmur3::hash32(&entry.namespace_vwname);
         }
         rr
     }
@@ -147,16 +472,136 @@ impl VowpalParser {
         Ok(o)
     }
 
+    // Parses everything before the first namespace: [label [importance [initial prediction]]] ['tag]
+    // or a command. Returns the position of the first "|" (or the end of the row).
+    fn parse_label_section(&mut self, rowlen: usize) -> Result<usize, Box<dyn Error>> {
+        let bar = match self.tmp_read_buf[..rowlen].iter().position(|c| *c == 0x7c) {
+            Some(i) => i,
+            None => rowlen,
+        };
+        let mut tokens: [(usize, usize); 4] = [(0, 0); 4];
+        let mut num_tokens: usize = 0;
+        let mut i: usize = 0;
+        loop {
+            while i < bar && self.tmp_read_buf[i] == 0x20 {
+                i += 1;
+            }
+            if i == bar {
+                break;
+            }
+            let i_start = i;
+            while i < bar && self.tmp_read_buf[i] != 0x20 {
+                i += 1;
+            }
+            if num_tokens == tokens.len() {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("Cannot parse an example"),
+                )));
+            }
+            tokens[num_tokens] = (i_start, i);
+            num_tokens += 1;
+        }
+
+        if num_tokens > 0 && bar == rowlen {
+            let first_token = &self.tmp_read_buf[tokens[0].0..tokens[0].1];
+            if first_token.starts_with(b"flush") {
+                return Err(Box::new(FlushCommand));
+            } else if first_token == b"hogwild_load" {
+                // THIS IS SLOW, BUT IT IS CALLED VERY RARELY
+                // IF WE WILL AVE COMMANDS CALLED MORE FREQUENTLY, WE WILL NEED A FASTER IMPLEMENTATION
+                let vecs = self.parse_cmd(0, rowlen)?;
+                if vecs.len() == 2 {
+                    let filename = String::from_utf8_lossy(&vecs[1]);
+                    return Err(Box::new(HogwildLoadCommand {
+                        filename: filename.to_string(),
+                    }));
+                }
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("Cannot parse an example"),
+                )));
+            }
+        }
+
+        // The tag is the last token, if it starts with ' or if it is attached to the first namespace (vw rules)
+        let mut num_values = num_tokens;
+        self.output_buffer[TAG_OFFSET] = NO_TAG;
+        if num_tokens > 0 {
+            let (tag_start, tag_end) = tokens[num_tokens - 1];
+            if self.tmp_read_buf[tag_start] == 0x27 {
+                num_values -= 1;
+                let tag = self.tmp_read_buf[tag_start + 1..tag_end].to_vec();
+                push_tag(&mut self.output_buffer, &tag)?;
+            } else if num_tokens > 1 && tag_end == bar && bar < rowlen {
+                num_values -= 1;
+                let tag = self.tmp_read_buf[tag_start..tag_end].to_vec();
+                push_tag(&mut self.output_buffer, &tag)?;
+            }
+        }
+        if num_values > 3 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Cannot parse an example"),
+            )));
+        }
+
+        self.output_buffer[LABEL_OFFSET] = NO_LABEL;
+        self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = FLOAT32_ONE;
+        self.output_buffer[INITIAL_PREDICTION_OFFSET] = 0.0f32.to_bits();
+        if num_values >= 1 {
+            let label = match self.parse_float_or_error(tokens[0].0, tokens[0].1, "") {
+                Ok(label) => label,
+                Err(_) => {
+                    return Err(Box::new(IOError::new(
+                        ErrorKind::Other,
+                        format!("Cannot parse an example"),
+                    )))
+                }
+            };
+            self.output_buffer[LABEL_OFFSET] = label.to_bits();
+        }
+        if num_values >= 2 {
+            let importance = self.parse_float_or_error(
+                tokens[1].0,
+                tokens[1].1,
+                "Failed parsing example importance",
+            )?;
+            if importance < 0.0 {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("Example importance cannot be negative: {:?}! ", importance),
+                )));
+            }
+            self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = importance.to_bits();
+        }
+        if num_values >= 3 {
+            let initial_prediction = self.parse_float_or_error(
+                tokens[2].0,
+                tokens[2].1,
+                "Failed parsing initial prediction",
+            )?;
+            self.output_buffer[INITIAL_PREDICTION_OFFSET] = initial_prediction.to_bits();
+        }
+        Ok(bar)
+    }
+
     pub fn next_vowpal(
         &mut self,
         input_bufread: &mut impl BufRead,
     ) -> Result<&[u32], Box<dyn Error>> {
         self.tmp_read_buf.truncate(0);
-        let rowlen1 = match input_bufread.read_until(0x0a, &mut self.tmp_read_buf) {
+        let mut rowlen1 = match input_bufread.read_until(0x0a, &mut self.tmp_read_buf) {
             Ok(0) => return Ok(&[]),
             Ok(n) => n,
             Err(e) => Err(e)?,
         };
+        self.line_number += 1;
+        if self.tmp_read_buf[rowlen1 - 1] != 0x0a {
+            // last line without a newline
+            self.tmp_read_buf.push(0x0a);
+            rowlen1 += 1;
+        }
 
         let bufpos: usize = (self.vw_map.num_namespaces + HEADER_LEN as usize) as usize;
         self.output_buffer.truncate(bufpos);
@@ -165,96 +610,16 @@ impl VowpalParser {
         }
 
         let mut current_namespace_num_of_features = 0;
+        self.string_transformer.start_record();
 
         unsafe {
             let p = self.tmp_read_buf.as_ptr();
             let mut i_start: usize;
-            let mut i_end: usize = 0;
-
-            // first token is a label or "flush" command
-            match *p.add(0) {
-                0x31 => self.output_buffer[LABEL_OFFSET] = 1, // 1
-                0x2d => self.output_buffer[LABEL_OFFSET] = 0, // -1
-                0x7c => self.output_buffer[LABEL_OFFSET] = NO_LABEL, // when first character is |, this means there is no label
-                _ => {
-                    // "flush" ascii 66, 6C, 75, 73, 68
-                    if rowlen1 >= 5
-                        && *p.add(0) == 0x66
-                        && *p.add(1) == 0x6C
-                        && *p.add(2) == 0x75
-                        && *p.add(3) == 0x73
-                        && *p.add(4) == 0x68
-                    {
-                        return Err(Box::new(FlushCommand));
-                    } else if rowlen1 >= "hogwild_load ".len() {
-                        // THIS IS SLOW, BUT IT IS CALLED VERY RARELY
-                        // IF WE WILL AVE COMMANDS CALLED MORE FREQUENTLY, WE WILL NEED A FASTER IMPLEMENTATION
-                        let vecs = self.parse_cmd(0, rowlen1)?;
-                        if vecs.len() == 2 {
-                            let command = String::from_utf8_lossy(&vecs[0]);
-                            if command == "hogwild_load" {
-                                let filename = String::from_utf8_lossy(&vecs[1]);
-                                return Err(Box::new(HogwildLoadCommand {
-                                    filename: filename.to_string(),
-                                }));
-                            }
-                        } else {
-                            return Err(Box::new(IOError::new(
-                                ErrorKind::Other,
-                                format!("Cannot parse an example"),
-                            )));
-                        }
-                    } else {
-                        return Err(Box::new(IOError::new(
-                            ErrorKind::Other,
-                            format!("Cannot parse an example"),
-                        )));
-                        //                            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown first character of the label: ascii {:?}", *p.add(0)))))
-                    }
-                }
-            };
-
             let rowlen = rowlen1 - 1; // ignore last newline byte
-            if self.output_buffer[LABEL_OFFSET] != NO_LABEL {
-                // if we have a label, let's check if we also have label weight
-                while *p.add(i_end) != 0x20 && i_end < rowlen {
-                    i_end += 1;
-                } // find space
-                while *p.add(i_end) == 0x20 && i_end < rowlen {
-                    i_end += 1;
-                } // find first non-space
-                  //if next character is not "|", we assume it's a example importance
-                  //i_end +=1;
-                if *p.add(i_end) != 0x7c {
-                    // this token does not start with "|", so it has to be example improtance floating point
-                    i_start = i_end;
-                    while *p.add(i_end) != 0x20 && i_end < rowlen {
-                        i_end += 1;
-                    } // find end of token (space)
-                    let importance = self.parse_float_or_error(
-                        i_start,
-                        i_end,
-                        "Failed parsing example importance",
-                    )?;
-                    if importance < 0.0 {
-                        return Err(Box::new(IOError::new(
-                            ErrorKind::Other,
-                            format!("Example importance cannot be negative: {:?}! ", importance),
-                        )));
-                    }
-                    self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = importance.to_bits();
-                } else {
-                    self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = FLOAT32_ONE;
-                }
-            } else {
-                self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = FLOAT32_ONE;
-            }
-            // Then we look for first namespace
-            while *p.add(i_end) != 0x7c && i_end < rowlen {
-                i_end += 1;
-            }
+            let mut i_end: usize = self.parse_label_section(rowlen)?;
 
             let mut current_namespace_hash_seed: u32 = 0;
+            let mut current_namespace_index: usize = 0;
             let mut current_namespace_index_offset: usize = HEADER_LEN as usize;
             let mut current_namespace_format = vwmap::NamespaceFormat::Categorical;
 
@@ -312,8 +677,7 @@ impl VowpalParser {
                             )))
                         }
                     };
-                    let current_namespace_index =
-                        current_namespace_descriptor.namespace_index as usize;
+                    current_namespace_index = current_namespace_descriptor.namespace_index as usize;
                     current_namespace_hash_seed = *self
                         .namespace_hash_seeds
                         .get_unchecked(current_namespace_index);
@@ -339,6 +703,11 @@ impl VowpalParser {
                             "Failed parsing feature weight",
                         )?,
                     };
+                    self.string_transformer.add_feature(
+                        current_namespace_index,
+                        &self.tmp_read_buf[i_start..i_end_first_part],
+                        current_namespace_weight * feature_weight,
+                    );
 
                     // We have three options:
                     // - first feature, no weights -> put it in-place
@@ -413,6 +782,8 @@ impl VowpalParser {
         }
 
         //            println!("item out {:?} {}", self.output_buffer, bufpos);
+        self.string_transformer
+            .write_namespaces(&mut self.output_buffer);
         self.output_buffer[0] = self.output_buffer.len() as u32;
         Ok(&self.output_buffer)
     }
@@ -425,6 +796,8 @@ mod tests {
     use std::io::Cursor;
     use vwmap;
 
+    const MINUS_ONE: u32 = 3212836864; // (-1.0f32).to_bits()
+
     fn nd(start: u32, end: u32) -> u32 {
         return (start << 16) + end;
     }
@@ -449,9 +822,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                1,
+                8,
                 FLOAT32_ONE,
+                FLOAT32_ONE,
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -463,9 +838,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                1,
+                8,
+                FLOAT32_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -477,9 +854,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                1,
+                8,
+                FLOAT32_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -491,9 +870,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                1,
+                8,
                 FLOAT32_ONE,
+                FLOAT32_ONE,
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -504,9 +885,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                0,
+                8,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 NO_FEATURES,
                 2422381320 & MASK31,
                 NO_FEATURES
@@ -517,10 +900,12 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                10,
-                1,
+                12,
                 FLOAT32_ONE,
-                nd(6, 10) | IS_NOT_SINGLE_MASK, // |A
+                FLOAT32_ONE,
+                0,
+                NO_TAG,
+                nd(8, 12) | IS_NOT_SINGLE_MASK, // |A
                 NO_FEATURES,                    // |B
                 NO_FEATURES,                    // |C
                 2988156968 & MASK31,
@@ -534,9 +919,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                0,
+                8,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 2422381320 & MASK31,
                 NO_FEATURES
@@ -548,9 +935,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                0,
+                8,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 2422381320 & MASK31,
                 NO_FEATURES
@@ -567,9 +956,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                1,
+                8,
                 FLOAT32_ONE,
+                FLOAT32_ONE,
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -595,10 +986,12 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                8,
-                1,
+                10,
                 FLOAT32_ONE,
-                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                FLOAT32_ONE,
+                0,
+                NO_TAG,
+                nd(8, 10) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 NO_FEATURES,
                 2988156968 & MASK31,
@@ -610,10 +1003,12 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                8,
-                1,
+                10,
+                FLOAT32_ONE,
                 FLOAT32_ONE,
-                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                0,
+                NO_TAG,
+                nd(8, 10) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 NO_FEATURES,
                 2988156968 & MASK31,
@@ -626,10 +1021,12 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                10,
-                1,
+                12,
+                FLOAT32_ONE,
                 FLOAT32_ONE,
-                nd(6, 10) | IS_NOT_SINGLE_MASK,
+                0,
+                NO_TAG,
+                nd(8, 12) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 NO_FEATURES,
                 2988156968 & MASK31,
@@ -644,10 +1041,12 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                8,
-                1,
+                10,
+                FLOAT32_ONE,
                 FLOAT32_ONE,
-                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                0,
+                NO_TAG,
+                nd(8, 10) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 NO_FEATURES,
                 2988156968 & MASK31,
@@ -669,10 +1068,12 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                12,
-                1,
+                14,
+                FLOAT32_ONE,
                 FLOAT32_ONE,
-                nd(6, 12) | IS_NOT_SINGLE_MASK,
+                0,
+                NO_TAG,
+                nd(8, 14) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 NO_FEATURES,
                 2988156968 & MASK31,
@@ -690,9 +1091,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
+                8,
                 NO_LABEL,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -701,7 +1104,7 @@ C,featureC
 
         /* Should we support this ?
         let mut buf = str_to_cursor(" |A a\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, NO_LABEL, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, NO_LABEL, FLOAT32_ONE, 0, NO_TAG,
                                                         2988156968 & MASK31,
                                                         NO_FEATURES,
                                                         NO_FEATURES]);
@@ -751,9 +1154,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                1,
+                8,
+                FLOAT32_ONE,
                 0.1f32.to_bits(),
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -765,9 +1170,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                1,
+                8,
+                FLOAT32_ONE,
                 0.1f32.to_bits(),
+                0,
+                NO_TAG,
                 2988156968 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -820,6 +1227,61 @@ C,featureC
         );
     }
 
+    #[test]
+    fn test_labels_tags_and_initial_predictions() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+            Cursor::new(s.as_bytes().to_vec())
+        }
+
+        // Labels are real valued and kept as they are
+        let mut buf = str_to_cursor("3.5 |A a\n0 |A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(record[LABEL_OFFSET], 3.5f32.to_bits());
+        assert_eq!(record[TAG_OFFSET], NO_TAG);
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap()[LABEL_OFFSET], 0);
+
+        // Label, importance and initial prediction
+        let mut buf = str_to_cursor("1 2 -0.5 |A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(record[LABEL_OFFSET], FLOAT32_ONE);
+        assert_eq!(record[EXAMPLE_IMPORTANCE_OFFSET], 2.0f32.to_bits());
+        assert_eq!(record[INITIAL_PREDICTION_OFFSET], (-0.5f32).to_bits());
+
+        // Tag starting with ', tag attached to the namespace bar, tag without a label
+        let mut buf = str_to_cursor("1 0.5 'example_1 |A a\n-1 ex2|A a\n'third |A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(record[EXAMPLE_IMPORTANCE_OFFSET], 0.5f32.to_bits());
+        assert_eq!(get_tag(record), b"example_1");
+        assert_eq!(record[HEADER_LEN as usize], 2988156968 & MASK31);
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(record[LABEL_OFFSET], MINUS_ONE);
+        assert_eq!(get_tag(record), b"ex2");
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(record[LABEL_OFFSET], NO_LABEL);
+        assert_eq!(get_tag(record), b"third");
+
+        // Namespaces with multiple features still point past the tag
+        let mut buf = str_to_cursor("1 'tag_of_9_b |A a b\n");
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(get_tag(record), b"tag_of_9_b");
+        assert_eq!(record[0], 13);
+        assert_eq!(record[HEADER_LEN as usize], nd(9, 13) | IS_NOT_SINGLE_MASK);
+
+        let mut buf = str_to_cursor("1 1 x |A a\n");
+        let result = rr.next_vowpal(&mut buf);
+        assert_eq!(
+            format!("{:?}", result),
+            "Err(Custom { kind: Other, error: \"Failed parsing initial prediction: x\" })"
+        );
+        let mut buf = str_to_cursor("1 1 0 0 |A a\n");
+        assert!(rr.next_vowpal(&mut buf).is_err());
+    }
+
     #[test]
     fn test_float_namespaces() {
         fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
@@ -838,9 +1300,11 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                0,
+                8,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 NO_FEATURES,
                 1775699190 & MASK31,
                 NO_FEATURES
@@ -860,11 +1324,13 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                8,
-                0,
+                10,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 NO_FEATURES,
-                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                nd(8, 10) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 1775699190 & MASK31,
                 3.0f32.to_bits()
@@ -875,11 +1341,13 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                10,
-                0,
+                12,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 NO_FEATURES,
-                nd(6, 10) | IS_NOT_SINGLE_MASK,
+                nd(8, 12) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 1775699190 & MASK31,
                 3.0f32.to_bits(),
@@ -897,11 +1365,13 @@ C,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                10,
-                0,
+                12,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 NO_FEATURES,
-                nd(6, 10) | IS_NOT_SINGLE_MASK,
+                nd(8, 12) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 1775699190 & MASK31,
                 3.0f32.to_bits(),
@@ -936,11 +1406,13 @@ _namespace_skip_prefix,1
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                8,
-                0,
+                10,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 NO_FEATURES,
-                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                nd(8, 10) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 1416737454 & MASK31,
                 3.0f32.to_bits()
@@ -952,11 +1424,13 @@ _namespace_skip_prefix,1
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                8,
-                0,
+                10,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 NO_FEATURES,
-                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                nd(8, 10) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 25602353 & MASK31,
                 f32::NAN.to_bits()
@@ -967,11 +1441,13 @@ _namespace_skip_prefix,1
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                8,
-                0,
+                10,
+                MINUS_ONE,
                 FLOAT32_ONE,
+                0,
+                NO_TAG,
                 NO_FEATURES,
-                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                nd(8, 10) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 1846432377 & MASK31,
                 f32::NAN.to_bits()
@@ -999,9 +1475,11 @@ CC,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                6,
-                1,
+                8,
                 FLOAT32_ONE,
+                FLOAT32_ONE,
+                0,
+                NO_TAG,
                 292540976 & MASK31,
                 NO_FEATURES,
                 NO_FEATURES
@@ -1013,10 +1491,12 @@ CC,featureC
         assert_eq!(
             rr.next_vowpal(&mut buf).unwrap(),
             [
-                8,
-                1,
+                10,
                 FLOAT32_ONE,
-                nd(6, 8) | IS_NOT_SINGLE_MASK,
+                FLOAT32_ONE,
+                0,
+                NO_TAG,
+                nd(8, 10) | IS_NOT_SINGLE_MASK,
                 NO_FEATURES,
                 NO_FEATURES,
                 292540976 & MASK31,
@@ -1024,4 +1504,147 @@ CC,featureC
             ]
         );
     }
+
+    #[test]
+    fn test_fault_tolerant_parser() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let input = "1 |A a\nbogus\n-1 |A b\n1 |X x\n-1 |A c\n";
+
+        // Without max_bad_rows, the first bad row is an error
+        let mut pa = FaultTolerantParser::new(Box::new(VowpalParser::new(&vw)), 0, None).unwrap();
+        let mut buf = Cursor::new(input.as_bytes().to_vec());
+        assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], FLOAT32_ONE);
+        let err = pa.next_record(&mut buf).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Error on line 2: Cannot parse an example (line: \"bogus\")"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let bad_rows_filename = dir.path().join("bad_rows.txt");
+        let bad_rows_filename = bad_rows_filename.to_str().unwrap();
+        let mut pa =
+            FaultTolerantParser::new(Box::new(VowpalParser::new(&vw)), 2, Some(bad_rows_filename))
+                .unwrap();
+        let mut buf = Cursor::new(input.as_bytes().to_vec());
+        assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], FLOAT32_ONE);
+        assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], MINUS_ONE);
+        assert_eq!(pa.last_line().0, 3);
+        assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], MINUS_ONE);
+        assert_eq!(pa.last_line().0, 5);
+        assert_eq!(pa.bad_rows, 2);
+        pa.report_bad_rows().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(bad_rows_filename).unwrap(),
+            "bogus\n1 |X x\n"
+        );
+
+        // The row over the limit fails
+        let mut pa = FaultTolerantParser::new(Box::new(VowpalParser::new(&vw)), 1, None).unwrap();
+        let mut buf = Cursor::new(input.as_bytes().to_vec());
+        assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], FLOAT32_ONE);
+        assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], MINUS_ONE);
+        let err = pa.next_record(&mut buf).unwrap_err().to_string();
+        assert!(err.starts_with("Error on line 4: Feature name was not predeclared"));
+
+        // Line numbers start over with every input, bad rows are counted over all of them
+        let mut pa = FaultTolerantParser::new(Box::new(VowpalParser::new(&vw)), 1, None).unwrap();
+        for input_name in vec!["first.vw", "second.vw"] {
+            pa.start_input(input_name);
+            let mut buf = Cursor::new(input.as_bytes().to_vec());
+            assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], FLOAT32_ONE);
+            assert_eq!(pa.last_line().0, 1);
+            if input_name == "first.vw" {
+                assert_eq!(pa.next_record(&mut buf).unwrap()[LABEL_OFFSET], MINUS_ONE);
+            } else {
+                let err = pa.next_record(&mut buf).unwrap_err().to_string();
+                assert_eq!(
+                    err,
+                    "Error on line 2 of second.vw: Cannot parse an example (line: \"bogus\")"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_string_transforms() {
+        let vw = vwmap::VwNamespaceMap::new("A,url\nB,tags\nC,price,f32\n")
+            .unwrap()
+            .with_string_transforms(&vec![
+                "domain=Domain(url)",
+                "tag=SplitTokens(tags)(\",\")",
+                "b=BinnerLog(price)(20.0, 2.0)", // not a string transform
+            ])
+            .unwrap();
+        assert_eq!(vw.num_namespaces, 5);
+        let h = |name: &str, namespace: &str| {
+            murmur3::hash32_with_seed(name, murmur3::hash32(namespace)) & MASK31
+        };
+
+        let mut rr = VowpalParser::new(&vw);
+        let mut buf = Cursor::new(b"1 |A www.Example.com/a |B x,y:2\n|C 1.5\n".to_vec());
+        let record = rr.next_vowpal(&mut buf).unwrap().to_vec();
+        assert_eq!(record[HEADER_LEN as usize], h("www.Example.com/a", "A"));
+        assert_eq!(
+            record[HEADER_LEN as usize + 3],
+            h("www.example.com", "domain")
+        );
+        // Features made by a string transform keep the weight of the original feature
+        assert_eq!(
+            record[HEADER_LEN as usize + 4],
+            IS_NOT_SINGLE_MASK | nd(12, 16)
+        );
+        assert_eq!(
+            record[12..16],
+            [
+                h("x", "tag"),
+                2.0f32.to_bits(),
+                h("y", "tag"),
+                2.0f32.to_bits()
+            ]
+        );
+
+        // No features of a from namespace, no features of its string transforms
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(record[HEADER_LEN as usize + 3], NO_FEATURES);
+        assert_eq!(record[HEADER_LEN as usize + 4], NO_FEATURES);
+
+        // Other formats apply the same string transforms
+        let mut pa = jsonl_parser::JsonlParser::new(&vw);
+        let mut buf = Cursor::new(
+            b"{\"label\": 1, \"features\": {\"url\": \"www.Example.com/a\", \"tags\": {\"x,y\": 2}}}\n"
+                .to_vec(),
+        );
+        assert_eq!(
+            pa.next_jsonl(&mut buf).unwrap(),
+            &record_of_first_line(&vw)[..]
+        );
+
+        fn record_of_first_line(vw: &vwmap::VwNamespaceMap) -> Vec<u32> {
+            let mut rr = VowpalParser::new(vw);
+            let mut buf = Cursor::new(b"1 |A www.Example.com/a |B x,y:2\n".to_vec());
+            rr.next_vowpal(&mut buf).unwrap().to_vec()
+        }
+
+        // String transforms can only be applied to categorical namespaces of the input
+        let vw = vwmap::VwNamespaceMap::new("A,url\nC,price,f32\n").unwrap();
+        assert!(vw
+            .clone()
+            .with_string_transforms(&vec!["p=Prefix(price)(2)"])
+            .is_err());
+        assert!(vw
+            .clone()
+            .with_string_transforms(&vec!["p=Prefix(nope)(2)"])
+            .is_err());
+        assert!(vw
+            .clone()
+            .with_string_transforms(&vec!["d=Domain(url)", "p=Prefix(d)(2)"])
+            .is_err());
+        assert!(vw
+            .with_string_transforms(&vec!["url=Lowercase(url)"])
+            .is_err());
+    }
 }
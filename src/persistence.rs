@@ -2,11 +2,15 @@ use std::error::Error;
 use std::str;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap2::Mmap;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::sync::Arc;
 
+use crate::feature_transform_parser;
 use crate::model_instance;
 use crate::optimizer;
 use crate::regressor;
@@ -14,7 +18,22 @@ use crate::vwmap;
 use clap;
 
 const REGRESSOR_HEADER_MAGIC_STRING: &[u8; 4] = b"FWRE"; // Fwumious Wabbit REgressor
-const REGRESSOR_HEADER_VERSION: u32 = 6; // Change to 5: introduce namespace descriptors which changes regressor
+
+// Regressor version history (weights layout of non-quantized regressors has stayed the same):
+// 14->15: importance correction of negative sampling, model instance gained negative_sampling_importance
+// 13->14: own bit precision of feature combos and ffm fields (their weights follow the shared ones), feature combo
+//         descs gained bit_precision and model instance gained ffm_fields_bit_precision
+// 12->13: string transforms applied when parsing, vw namespace map gained string_transforms
+// 11->12: transforms fitted on the training data, namespace transforms gained fitted_parameters
+// 10->11: negative sampling, model instance gained negative_sampling_rate
+// 9->10: squared loss, model instance gained loss_function
+// 8->9: sparse weights of pruned inference regressors, model instance gained weights_sparse
+// 7->8: page aligned weights of inference regressors, model instance gained weights_page_aligned
+// 6->7: weights quantization of inference regressors, model instance gained weights_quantization
+// 5->6: neural network blocks, model instance gained nn_config
+// 4->5: namespace descriptors instead of plain namespace indices (transformed namespaces were marked with TRANSFORM_NAMESPACE_MARK)
+const REGRESSOR_HEADER_VERSION: u32 = 15;
+const REGRESSOR_OLDEST_MIGRATABLE_VERSION: u32 = 4; // Older regressors have to be retrained
 
 impl model_instance::ModelInstance {
     pub fn save_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
@@ -31,6 +50,189 @@ impl model_instance::ModelInstance {
             serde_json::from_reader(input_bufreader.take(len as u64))?;
         Ok(mi)
     }
+
+    // Loads model instance written by an older version of the regressor format and migrates it to the current one
+    pub fn new_from_buf_with_version(
+        input_bufreader: &mut dyn io::Read,
+        version: u32,
+        vw: &vwmap::VwNamespaceMap,
+    ) -> Result<model_instance::ModelInstance, Box<dyn Error>> {
+        if version == REGRESSOR_HEADER_VERSION {
+            return model_instance::ModelInstance::new_from_buf(input_bufreader);
+        }
+        let len = input_bufreader.read_u64::<LittleEndian>()?;
+        let mut mi_json: serde_json::Value =
+            serde_json::from_reader(input_bufreader.take(len as u64))?;
+        migrate_model_instance_json(&mut mi_json, version, vw)?;
+        let mi: model_instance::ModelInstance = serde_json::from_value(mi_json)?;
+        Ok(mi)
+    }
+}
+
+// Each migration step takes model instance json of version N and turns it into version N+1
+fn migrate_model_instance_json(
+    mi_json: &mut serde_json::Value,
+    version: u32,
+    vw: &vwmap::VwNamespaceMap,
+) -> Result<(), Box<dyn Error>> {
+    let mut version = version;
+    while version < REGRESSOR_HEADER_VERSION {
+        match version {
+            4 => migrate_model_instance_json_v4_to_v5(mi_json, vw)?,
+            5 => migrate_model_instance_json_v5_to_v6(mi_json)?,
+            6 => {}  // weights_quantization defaults to none
+            7 => {}  // weights_page_aligned defaults to false
+            8 => {}  // weights_sparse defaults to false
+            9 => {}  // loss_function defaults to logistic
+            10 => {} // negative_sampling_rate defaults to 1.0
+            11 => {} // fitted_parameters of namespace transforms default to none
+            12 => {} // model instance is unchanged, string_transforms of vw namespace map default to none
+            13 => {} // bit_precision of feature combos and ffm_fields_bit_precision default to none
+            14 => {} // negative_sampling_importance defaults to false
+            _ => {
+                return Err(format!(
+                    "Don't know how to migrate regressor of version {}",
+                    version
+                ))?
+            }
+        }
+        version += 1;
+    }
+    Ok(())
+}
+
+fn namespace_descriptor_from_v4_index(
+    index: u64,
+    vw: &vwmap::VwNamespaceMap,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let mark = feature_transform_parser::TRANSFORM_NAMESPACE_MARK as u64;
+    let namespace_descriptor = if index & mark != 0 {
+        vwmap::NamespaceDescriptor {
+            namespace_index: (index & !mark) as u16,
+            namespace_type: vwmap::NamespaceType::Transformed,
+            namespace_format: vwmap::NamespaceFormat::Categorical,
+        }
+    } else {
+        match vw
+            .map_vwname_to_namespace_descriptor
+            .values()
+            .find(|nd| nd.namespace_index as u64 == index)
+        {
+            Some(namespace_descriptor) => *namespace_descriptor,
+            None => {
+                return Err(format!(
+                    "Regressor references namespace index {} that is not in its vw namespace map",
+                    index
+                ))?
+            }
+        }
+    };
+    Ok(serde_json::to_value(namespace_descriptor)?)
+}
+
+fn namespace_descriptors_from_v4_indices(
+    indices: &serde_json::Value,
+    vw: &vwmap::VwNamespaceMap,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let indices = match indices.as_array() {
+        Some(indices) => indices,
+        None => return Err("Expected a list of namespace indices in version 4 regressor")?,
+    };
+    let mut namespace_descriptors: Vec<serde_json::Value> = Vec::new();
+    for index in indices {
+        match index.as_u64() {
+            Some(index) => {
+                namespace_descriptors.push(namespace_descriptor_from_v4_index(index, vw)?)
+            }
+            None => return Err(format!("Namespace index is not a number: {}", index))?,
+        }
+    }
+    Ok(serde_json::Value::Array(namespace_descriptors))
+}
+
+fn migrate_model_instance_json_v4_to_v5(
+    mi_json: &mut serde_json::Value,
+    vw: &vwmap::VwNamespaceMap,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(combos) = mi_json
+        .get_mut("feature_combo_descs")
+        .and_then(|x| x.as_array_mut())
+    {
+        for combo in combos {
+            let combo = match combo.as_object_mut() {
+                Some(combo) => combo,
+                None => continue,
+            };
+            if let Some(indices) = combo.remove("namespace_indices") {
+                combo.insert(
+                    "namespace_descriptors".to_string(),
+                    namespace_descriptors_from_v4_indices(&indices, vw)?,
+                );
+            }
+        }
+    }
+
+    if let Some(fields) = mi_json.get_mut("ffm_fields").and_then(|x| x.as_array_mut()) {
+        for field in fields {
+            *field = namespace_descriptors_from_v4_indices(field, vw)?;
+        }
+    }
+
+    if let Some(transforms) = mi_json
+        .get_mut("transform_namespaces")
+        .and_then(|x| x.get_mut("v"))
+        .and_then(|x| x.as_array_mut())
+    {
+        for transform in transforms {
+            if let Some(to_namespace) = transform.get_mut("to_namespace") {
+                migrate_namespace_json_v4_to_v5(to_namespace, vw)?;
+            }
+            if let Some(from_namespaces) = transform
+                .get_mut("from_namespaces")
+                .and_then(|x| x.as_array_mut())
+            {
+                for namespace in from_namespaces {
+                    migrate_namespace_json_v4_to_v5(namespace, vw)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn migrate_namespace_json_v4_to_v5(
+    namespace: &mut serde_json::Value,
+    vw: &vwmap::VwNamespaceMap,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(namespace) = namespace.as_object_mut() {
+        if let Some(index) = namespace.remove("namespace_index") {
+            let index = match index.as_u64() {
+                Some(index) => index,
+                None => return Err(format!("Namespace index is not a number: {}", index))?,
+            };
+            namespace.insert(
+                "namespace_descriptor".to_string(),
+                namespace_descriptor_from_v4_index(index, vw)?,
+            );
+        }
+    }
+    Ok(())
+}
+
+fn migrate_model_instance_json_v5_to_v6(
+    mi_json: &mut serde_json::Value,
+) -> Result<(), Box<dyn Error>> {
+    let mi_json = match mi_json.as_object_mut() {
+        Some(mi_json) => mi_json,
+        None => return Err("Model instance in regressor is not a json object")?,
+    };
+    if !mi_json.contains_key("nn_config") {
+        mi_json.insert(
+            "nn_config".to_string(),
+            serde_json::to_value(model_instance::NNConfig::new())?,
+        );
+    }
+    Ok(())
 }
 
 impl vwmap::VwNamespaceMap {
@@ -65,7 +267,11 @@ pub fn save_regressor_to_filename(
     write_regressor_header(output_bufwriter)?;
     vwmap.save_to_buf(output_bufwriter)?;
     mi.save_to_buf(output_bufwriter)?;
-    re.write_weights_to_buf(output_bufwriter)?;
+    if mi.weights_page_aligned {
+        re.write_weights_to_buf_page_aligned(output_bufwriter)?;
+    } else {
+        re.write_weights_to_buf(output_bufwriter)?;
+    }
     Ok(())
 }
 
@@ -88,12 +294,13 @@ fn load_regressor_without_weights(
     ),
     Box<dyn Error>,
 > {
-    verify_header(input_bufreader).expect("Regressor header error");
+    let version = verify_header(input_bufreader).expect("Regressor header error");
     let vw = vwmap::VwNamespaceMap::new_from_buf(input_bufreader)
         .expect("Loading vwmap from regressor failed");
 
-    let mut mi = model_instance::ModelInstance::new_from_buf(input_bufreader)
-        .expect("Loading model instance from regressor failed");
+    let mut mi =
+        model_instance::ModelInstance::new_from_buf_with_version(input_bufreader, version, &vw)
+            .expect("Loading model instance from regressor failed");
 
     match cmd_arguments {
         Some(cmd_args) => {
@@ -123,9 +330,24 @@ pub fn new_regressor_from_filename(
     let mut input_bufreader = io::BufReader::new(fs::File::open(filename).unwrap());
     let (mut mi, vw, mut re) = load_regressor_without_weights(&mut input_bufreader, cmd_arguments)?;
     if !immutable {
+        if mi.has_inference_weights_layout() {
+            return Err(format!(
+                "Regressor {} is an inference regressor, it can only be loaded as immutable",
+                filename
+            ))?;
+        }
         re.allocate_and_init_weights(&mi);
         re.overwrite_weights_from_buf(&mut input_bufreader)?;
         Ok((mi, vw, re))
+    } else if mi.weights_page_aligned {
+        // Weights are not read, immutable blocks point directly into the read-only mapping
+        let weights_offset = input_bufreader.stream_position()? as usize;
+        let mmap = Arc::new(unsafe { Mmap::map(input_bufreader.get_ref())? });
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let mut immutable_re = re.immutable_regressor_without_weights(&mi)?;
+        immutable_re.allocate_and_init_weights(&mi);
+        re.into_immutable_regressor_from_mmap(&mut immutable_re, &mmap, weights_offset)?;
+        Ok((mi, vw, immutable_re))
     } else {
         mi.optimizer = model_instance::Optimizer::SGD;
         let mut immutable_re = re.immutable_regressor_without_weights(&mi)?;
@@ -139,7 +361,20 @@ pub fn hogwild_load(re: &mut regressor::Regressor, filename: &str) -> Result<(),
     let mut input_bufreader = io::BufReader::new(fs::File::open(filename)?);
     let (mi_hw, vw_hw, mut re_hw) = load_regressor_without_weights(&mut input_bufreader, None)?;
     // TODO: Here we should do safety comparison that the regressor is really the same;
+    if re.memory_mapped || mi_hw.weights_page_aligned {
+        // Memory-mapped weights are read-only, and swapping the mapping would pull it from under other threads
+        return Err(format!(
+            "hogwild_load of {} is not supported for memory-mapped regressors (converted with --weights_page_aligned)",
+            filename
+        ))?;
+    }
     if !re.immutable {
+        if mi_hw.has_inference_weights_layout() {
+            return Err(format!(
+                "Regressor {} is an inference regressor, it can only be loaded as immutable",
+                filename
+            ))?;
+        }
         re.overwrite_weights_from_buf(&mut input_bufreader)?;
     } else {
         re_hw.into_immutable_regressor_from_buf(re, &mut input_bufreader)?;
@@ -147,21 +382,32 @@ pub fn hogwild_load(re: &mut regressor::Regressor, filename: &str) -> Result<(),
     Ok(())
 }
 
-fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+// Returns the version of the regressor file, so older versions can be migrated while loading
+fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<u32, Box<dyn Error>> {
     let mut magic_string: [u8; 4] = [0; 4];
     input_bufreader.read(&mut magic_string)?;
     if &magic_string != REGRESSOR_HEADER_MAGIC_STRING {
-        return Err("Cache header does not begin with magic bytes FWFW")?;
+        return Err("Regressor header does not begin with magic bytes FWRE")?;
     }
 
     let version = input_bufreader.read_u32::<LittleEndian>()?;
-    if REGRESSOR_HEADER_VERSION != version {
+    if version > REGRESSOR_HEADER_VERSION || version < REGRESSOR_OLDEST_MIGRATABLE_VERSION {
         return Err(format!(
-            "Cache file version of this binary: {}, version of the cache file: {}",
-            REGRESSOR_HEADER_VERSION, version
+            "Regressor file version of this binary: {} (can migrate from {}), version of the regressor file: {}",
+            REGRESSOR_HEADER_VERSION, REGRESSOR_OLDEST_MIGRATABLE_VERSION, version
         ))?;
     }
-    Ok(())
+    Ok(version)
+}
+
+// Rewrites a regressor of any supported older version in the current format
+// Optimizer state is kept, so save_resume regressors stay resumable
+pub fn upgrade_regressor_file(
+    input_filename: &str,
+    output_filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    let (mi, vw, re) = new_regressor_from_filename(input_filename, false, None)?;
+    save_regressor_to_filename(output_filename, &mi, &vw, re)
 }
 
 #[cfg(test)]
@@ -174,9 +420,11 @@ mod tests {
     use crate::feature_buffer::HashAndValue;
     use crate::feature_buffer::HashAndValueAndSeq;
     use crate::model_instance::Optimizer;
+    use crate::parser;
     use regressor::BlockTrait;
     use regressor::Regressor;
 
+    use std::io::Write;
     use tempfile::tempdir;
     #[test]
     fn save_empty_model() {
@@ -201,6 +449,8 @@ B,featureB
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: v,
             ffm_buffer: Vec::new(),
             ffm_fields_count: 0,
@@ -295,6 +545,8 @@ B,featureB
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: Vec::new(),
             ffm_buffer: v,
             ffm_fields_count: ffm_fields_count,
@@ -394,12 +646,243 @@ B,featureB
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: v1,
             ffm_buffer: v2,
             ffm_fields_count: ffm_fields_count,
         }
     }
 
+    #[test]
+    fn save_load_quantized_lr_and_ffm() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+
+        ffm_fixed_init(&mut re);
+        let fbuf = &lr_and_ffm_vec(
+            vec![HashAndValue {
+                hash: 52,
+                value: 0.5,
+                combo_index: 0,
+            }],
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 100,
+                    value: 2.0,
+                    contra_field_index: 1,
+                },
+            ],
+            2,
+        );
+        re.learn(fbuf, &mut pb, true);
+        let const_result = re.predict(fbuf, &mut pb);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        save_regressor_to_filename(regressor_filepath, &mi, &vw, re).unwrap();
+
+        for quantization in vec![
+            model_instance::WeightsQuantization::F16,
+            model_instance::WeightsQuantization::Int8,
+        ] {
+            let (mut mi2, vw2, mut re2) =
+                new_regressor_from_filename(regressor_filepath, true, None).unwrap();
+            re2.quantize_weights(quantization);
+            mi2.weights_quantization = quantization;
+            assert!((re2.predict(fbuf, &mut pb) - const_result).abs() < 0.01);
+
+            let quantized_filepath = dir
+                .path()
+                .join(format!("test_regressor_{:?}.fw", quantization));
+            let quantized_filepath = quantized_filepath.to_str().unwrap();
+            save_regressor_to_filename(quantized_filepath, &mi2, &vw2, re2).unwrap();
+            let (mi3, _vw3, re3) =
+                new_regressor_from_filename(quantized_filepath, true, None).unwrap();
+            assert_eq!(mi3.weights_quantization, quantization);
+            assert!((re3.predict(fbuf, &mut pb) - const_result).abs() < 0.01);
+
+            // Quantized weights can't be trained further
+            assert!(new_regressor_from_filename(quantized_filepath, false, None).is_err());
+        }
+    }
+
+    #[test]
+    fn save_load_memory_mapped() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+
+        ffm_fixed_init(&mut re);
+        let fbuf = &lr_and_ffm_vec(
+            vec![HashAndValue {
+                hash: 52,
+                value: 0.5,
+                combo_index: 0,
+            }],
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 100,
+                    value: 2.0,
+                    contra_field_index: 4,
+                },
+            ],
+            2,
+        );
+        re.learn(fbuf, &mut pb, true);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        save_regressor_to_filename(regressor_filepath, &mi, &vw, re).unwrap();
+
+        for quantization in vec![
+            model_instance::WeightsQuantization::None,
+            model_instance::WeightsQuantization::F16,
+            model_instance::WeightsQuantization::Int8,
+        ] {
+            let (mut mi2, vw2, mut re2) =
+                new_regressor_from_filename(regressor_filepath, true, None).unwrap();
+            if quantization != model_instance::WeightsQuantization::None {
+                re2.quantize_weights(quantization);
+                mi2.weights_quantization = quantization;
+            }
+            let expected = re2.predict(fbuf, &mut pb);
+            mi2.weights_page_aligned = true;
+
+            let mapped_filepath = dir
+                .path()
+                .join(format!("test_regressor_mapped_{:?}.fw", quantization));
+            let mapped_filepath = mapped_filepath.to_str().unwrap();
+            save_regressor_to_filename(mapped_filepath, &mi2, &vw2, re2).unwrap();
+            let (_mi3, _vw3, mut re3) =
+                new_regressor_from_filename(mapped_filepath, true, None).unwrap();
+            assert!(re3.memory_mapped);
+            assert_eq!(re3.predict(fbuf, &mut pb), expected);
+
+            assert!(new_regressor_from_filename(mapped_filepath, false, None).is_err());
+            assert!(hogwild_load(&mut re3, regressor_filepath).is_err());
+        }
+    }
+
+    #[test]
+    fn save_load_pruned_lr_and_ffm() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+
+        let fbuf = &lr_and_ffm_vec(
+            vec![HashAndValue {
+                hash: 52,
+                value: 0.5,
+                combo_index: 0,
+            }],
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 100,
+                    value: 2.0,
+                    contra_field_index: 4,
+                },
+            ],
+            2,
+        );
+        re.learn(fbuf, &mut pb, true);
+        let const_result = re.predict(fbuf, &mut pb);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        save_regressor_to_filename(regressor_filepath, &mi, &vw, re).unwrap();
+
+        for page_aligned in vec![false, true] {
+            let (mut mi2, vw2, mut re2) =
+                new_regressor_from_filename(regressor_filepath, true, None).unwrap();
+            re2.prune_weights(&mi2, 0.0);
+            mi2.weights_sparse = true;
+            mi2.weights_page_aligned = page_aligned;
+            // Only weights that were never trained are dropped, so seen features predict the same
+            assert_eq!(re2.predict(fbuf, &mut pb), const_result);
+
+            let pruned_filepath = dir
+                .path()
+                .join(format!("test_regressor_pruned_{}.fw", page_aligned));
+            let pruned_filepath = pruned_filepath.to_str().unwrap();
+            save_regressor_to_filename(pruned_filepath, &mi2, &vw2, re2).unwrap();
+            assert!(
+                fs::metadata(pruned_filepath).unwrap().len()
+                    < fs::metadata(regressor_filepath).unwrap().len() / 10
+            );
+            let (mi3, _vw3, mut re3) =
+                new_regressor_from_filename(pruned_filepath, true, None).unwrap();
+            assert!(mi3.weights_sparse);
+            assert_eq!(re3.memory_mapped, page_aligned);
+            assert_eq!(re3.predict(fbuf, &mut pb), const_result);
+
+            // Pruned weights can't be trained further
+            assert!(new_regressor_from_filename(pruned_filepath, false, None).is_err());
+            assert!(hogwild_load(&mut re3, regressor_filepath).is_err());
+        }
+    }
+
     #[test]
     fn test_hogwild_load() {
         let vw_map_string = r#"
@@ -611,4 +1094,203 @@ B,featureB
             assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), CONST_RESULT_2_ON_1);
         }
     }
+
+    fn save_regressor_with_version(
+        filename: &str,
+        version: u32,
+        mi_json: &serde_json::Value,
+        vw: &vwmap::VwNamespaceMap,
+        re: &regressor::Regressor,
+    ) {
+        let output_bufwriter = &mut io::BufWriter::new(fs::File::create(filename).unwrap());
+        output_bufwriter
+            .write_all(REGRESSOR_HEADER_MAGIC_STRING)
+            .unwrap();
+        output_bufwriter.write_u32::<LittleEndian>(version).unwrap();
+        vw.save_to_buf(output_bufwriter).unwrap();
+        let serialized = serde_json::to_vec_pretty(mi_json).unwrap();
+        output_bufwriter
+            .write_u64::<LittleEndian>(serialized.len() as u64)
+            .unwrap();
+        output_bufwriter.write_all(&serialized).unwrap();
+        re.write_weights_to_buf(output_bufwriter).unwrap();
+    }
+
+    #[test]
+    fn test_load_v6_regressors() {
+        // Written by the release binary of version 6, see tests/fixtures/regressor_v6/README.md
+        let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/regressor_v6/");
+        let expected_predictions =
+            fs::read_to_string(format!("{}predictions_v6.txt", fixtures_dir)).unwrap();
+        let dir = tempdir().unwrap();
+        let upgraded_filepath = dir.path().join("regressor_v6_upgraded.fw");
+        let upgraded_filepath = upgraded_filepath.to_str().unwrap();
+        for regressor_filename in vec!["regressor_v6.fw", "regressor_v6_inference.fw"] {
+            let regressor_filepath = format!("{}{}", fixtures_dir, regressor_filename);
+            upgrade_regressor_file(&regressor_filepath, upgraded_filepath).unwrap();
+            for filepath in vec![regressor_filepath.as_str(), upgraded_filepath] {
+                let (mi, vw, re) = new_regressor_from_filename(filepath, true, None).unwrap();
+                assert_eq!(mi.transform_namespaces.v.len(), 1);
+                let mut pa = parser::VowpalParser::new(&vw);
+                let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+                let mut pb = re.new_portbuffer();
+                let mut input =
+                    io::BufReader::new(fs::File::open(format!("{}test.vw", fixtures_dir)).unwrap());
+                let mut predictions = String::new();
+                loop {
+                    let record_buffer = pa.next_vowpal(&mut input).unwrap();
+                    if record_buffer.is_empty() {
+                        break;
+                    }
+                    fbt.translate(record_buffer, 0);
+                    let prediction = re.predict(&fbt.feature_buffer, &mut pb);
+                    predictions.push_str(&format!("{:.6}\n", prediction));
+                }
+                assert_eq!(predictions, expected_predictions, "{}", filepath);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_older_versions() {
+        // Older model instances are made by taking fields away from the current one. Regressors really written by a
+        // release binary are only available for version 6 (test_load_v6_regressors), not for versions 4 and 5.
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        mi.init_acc_gradient = 0.0;
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![vw.map_verbose_to_namespace_descriptor["featureB"]],
+                weight: 1.0,
+                bit_precision: None,
+            });
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+
+        let fbuf = &lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+        re.learn(fbuf, &mut pb, true);
+        let const_result = re.learn(fbuf, &mut pb, false);
+
+        // version 14 had no negative_sampling_importance
+        let mut mi_json_v14 = serde_json::to_value(&mi).unwrap();
+        mi_json_v14
+            .as_object_mut()
+            .unwrap()
+            .remove("negative_sampling_importance");
+        // version 13 had no bit_precision of feature combos and no ffm_fields_bit_precision
+        let mut mi_json_v13 = mi_json_v14.clone();
+        mi_json_v13
+            .as_object_mut()
+            .unwrap()
+            .remove("ffm_fields_bit_precision");
+        mi_json_v13["feature_combo_descs"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("bit_precision");
+        // version 12 had the same model instance, only vw namespace map had no string_transforms
+        let mi_json_v12 = mi_json_v13.clone();
+        // version 11 had no fitted_parameters, there are no namespace transforms here
+        let mi_json_v11 = mi_json_v12.clone();
+        // version 10 had no negative_sampling_rate
+        let mut mi_json_v10 = mi_json_v11.clone();
+        mi_json_v10
+            .as_object_mut()
+            .unwrap()
+            .remove("negative_sampling_rate");
+        // version 9 had no loss_function
+        let mut mi_json_v9 = mi_json_v10.clone();
+        mi_json_v9.as_object_mut().unwrap().remove("loss_function");
+        // version 8 had no weights_sparse
+        let mut mi_json_v8 = mi_json_v9.clone();
+        mi_json_v8.as_object_mut().unwrap().remove("weights_sparse");
+        // version 7 had no weights_page_aligned
+        let mut mi_json_v7 = mi_json_v8.clone();
+        mi_json_v7
+            .as_object_mut()
+            .unwrap()
+            .remove("weights_page_aligned");
+        // version 6 had no weights_quantization
+        let mut mi_json_v6 = mi_json_v7.clone();
+        mi_json_v6
+            .as_object_mut()
+            .unwrap()
+            .remove("weights_quantization");
+        // version 5 had no nn_config
+        let mut mi_json_v5 = mi_json_v6.clone();
+        mi_json_v5.as_object_mut().unwrap().remove("nn_config");
+        // version 4 had plain namespace indices instead of namespace descriptors
+        let mut mi_json_v4 = mi_json_v5.clone();
+        let combo = mi_json_v4["feature_combo_descs"][0]
+            .as_object_mut()
+            .unwrap();
+        combo.remove("namespace_descriptors");
+        combo.insert("namespace_indices".to_string(), serde_json::json!([1]));
+
+        let dir = tempdir().unwrap();
+        for (version, mi_json) in vec![
+            (14, &mi_json_v14),
+            (13, &mi_json_v13),
+            (12, &mi_json_v12),
+            (11, &mi_json_v11),
+            (10, &mi_json_v10),
+            (9, &mi_json_v9),
+            (8, &mi_json_v8),
+            (7, &mi_json_v7),
+            (6, &mi_json_v6),
+            (5, &mi_json_v5),
+            (4, &mi_json_v4),
+        ] {
+            let regressor_filepath = dir.path().join(format!("test_regressor_v{}.fw", version));
+            let regressor_filepath = regressor_filepath.to_str().unwrap();
+            save_regressor_with_version(regressor_filepath, version, mi_json, &vw, &re);
+
+            let (mi2, _vw2, mut re2) =
+                new_regressor_from_filename(regressor_filepath, false, None).unwrap();
+            assert_eq!(mi2.feature_combo_descs, mi.feature_combo_descs);
+            assert_eq!(mi2.nn_config, mi.nn_config);
+            assert_eq!(re2.learn(fbuf, &mut pb, false), const_result);
+
+            // Upgraded regressor is written with the current version
+            let upgraded_filepath = dir
+                .path()
+                .join(format!("test_regressor_v{}_upgraded.fw", version));
+            let upgraded_filepath = upgraded_filepath.to_str().unwrap();
+            upgrade_regressor_file(regressor_filepath, upgraded_filepath).unwrap();
+            let mut input_bufreader =
+                io::BufReader::new(fs::File::open(upgraded_filepath).unwrap());
+            assert_eq!(
+                verify_header(&mut input_bufreader).unwrap(),
+                REGRESSOR_HEADER_VERSION
+            );
+            let (_mi3, _vw3, re3) =
+                new_regressor_from_filename(upgraded_filepath, true, None).unwrap();
+            assert_eq!(re3.predict(fbuf, &mut pb), const_result);
+        }
+
+        // Too old and too new versions are refused
+        let mi_json = serde_json::to_value(&mi).unwrap();
+        for version in vec![
+            REGRESSOR_OLDEST_MIGRATABLE_VERSION - 1,
+            REGRESSOR_HEADER_VERSION + 1,
+        ] {
+            let regressor_filepath = dir.path().join(format!("test_regressor_v{}.fw", version));
+            let regressor_filepath = regressor_filepath.to_str().unwrap();
+            save_regressor_with_version(regressor_filepath, version, &mi_json, &vw, &re);
+            let mut input_bufreader =
+                io::BufReader::new(fs::File::open(regressor_filepath).unwrap());
+            assert!(verify_header(&mut input_bufreader).is_err());
+        }
+    }
 }
@@ -1,8 +1,10 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap2::Mmap;
 use std::any::Any;
 use std::error::Error;
 use std::io;
 use std::io::Cursor;
+use std::sync::Arc;
 
 use crate::block_ffm;
 use crate::block_helpers;
@@ -19,6 +21,13 @@ use crate::model_instance;
 use crate::optimizer;
 use crate::port_buffer;
 
+// How many weights of a block were kept in the sparse table by prune_weights()
+pub struct PrunedWeights {
+    pub block_name: &'static str,
+    pub kept: usize,
+    pub total: usize,
+}
+
 pub trait BlockTrait {
     fn as_any(&mut self) -> &mut dyn Any; // This enables downcasting
     fn forward_backward(
@@ -71,6 +80,51 @@ pub trait BlockTrait {
         Ok(())
     }
 
+    // Blocks that can compute directly from the mapped file override this, the rest copy their weights
+    fn read_weights_from_mmap_into_forward_only(
+        &self,
+        mmap: &Arc<Mmap>,
+        offset: &mut usize,
+        forward: &mut Box<dyn BlockTrait>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut cursor = Cursor::new(&mmap[*offset..]);
+        self.read_weights_from_buf_into_forward_only(&mut cursor, forward)?;
+        *offset += cursor.position() as usize;
+        Ok(())
+    }
+
+    // Used when merging models: weights are multiplied by scale
+    fn scale_weights(&mut self, _scale: f32) {}
+
+    // Used when merging models: adds scale * weights of other, which is the same kind of block, and merges optimizer state
+    // Blocks with weights have to override this, merging them is refused otherwise
+    fn add_weights_from(
+        &mut self,
+        _other: &mut Box<dyn BlockTrait>,
+        _scale: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.get_serialized_len() > 0 {
+            return Err(format!(
+                "Merging weights of block {} is not supported",
+                std::any::type_name::<Self>()
+            ))?;
+        }
+        Ok(())
+    }
+
+    // Only called on immutable regressors, when converting them to the inference format
+    // Keeps only weights that matter in a sparse table. initial is the same block with freshly initialized weights
+    fn prune_weights(
+        &mut self,
+        _initial: &mut Box<dyn BlockTrait>,
+        _lr_threshold: f32,
+    ) -> Option<PrunedWeights> {
+        None
+    }
+
+    // Only called on immutable regressors, when converting them to the inference format
+    fn quantize_weights(&mut self, _quantization: model_instance::WeightsQuantization) {}
+
     /// Sets internal state of weights based on some completely object-dependent parameters
     fn testing_set_weights(
         &mut self,
@@ -88,6 +142,15 @@ pub struct Regressor {
     pub blocks_boxes: Vec<Box<dyn BlockTrait>>,
     pub tape_len: usize,
     pub immutable: bool,
+    pub memory_mapped: bool,
+    pub negative_sampling_rate: f32, // predictions are recalibrated for it
+}
+
+// Smallest page size we support memory-mapping on
+pub const WEIGHTS_PAGE_SIZE: usize = 4096;
+
+fn align_to_page(offset: usize) -> usize {
+    (offset + WEIGHTS_PAGE_SIZE - 1) / WEIGHTS_PAGE_SIZE * WEIGHTS_PAGE_SIZE
 }
 
 pub fn get_regressor_without_weights(mi: &model_instance::ModelInstance) -> Regressor {
@@ -119,7 +182,13 @@ impl Regressor {
             blocks_boxes: Vec::new(),
             regressor_name: format!("Regressor with optimizer \"{:?}\"", mi.optimizer),
             immutable: false,
+            memory_mapped: false,
             tape_len: usize::MAX,
+            // Kept negatives weighed by 1/r already correct for the sampling
+            negative_sampling_rate: match mi.negative_sampling_importance {
+                true => 1.0,
+                false => mi.negative_sampling_rate,
+            },
         };
 
         let mut bg = graph::BlockGraph::new();
@@ -272,8 +341,15 @@ impl Regressor {
             .unwrap();
         }
 
-        // now sigmoid has a single input
-        let lossf = block_loss_functions::new_logloss_block(&mut bg, output, true).unwrap();
+        // now the loss function has a single input
+        let lossf = match mi.loss_function {
+            model_instance::LossFunction::Logistic => {
+                block_loss_functions::new_logloss_block(&mut bg, output, true).unwrap()
+            }
+            model_instance::LossFunction::Squared => {
+                block_loss_functions::new_squared_loss_block(&mut bg, output, true).unwrap()
+            }
+        };
         bg.finalize();
         rg.tape_len = bg.get_tape_size();
 
@@ -319,7 +395,8 @@ impl Regressor {
             // Important to know: learn() functions in blocks aren't guaranteed to be thread-safe
             panic!("This regressor is immutable, you cannot call learn() with update = true");
         }
-        let update: bool = update && (fb.example_importance != 0.0);
+        // Unlabeled examples are never learned from
+        let update: bool = update && (fb.example_importance != 0.0) && !fb.label.is_nan();
         if !update {
             // Fast-path for no-update case
             return self.predict(fb, pb);
@@ -332,7 +409,7 @@ impl Regressor {
         assert_eq!(pb.observations.len(), 1);
         let prediction_probability = pb.observations.pop().unwrap();
 
-        return prediction_probability;
+        return self.recalibrate(prediction_probability);
     }
 
     pub fn predict(
@@ -349,7 +426,17 @@ impl Regressor {
         assert_eq!(pb.observations.len(), 1);
         let prediction_probability = pb.observations.pop().unwrap();
 
-        return prediction_probability;
+        return self.recalibrate(prediction_probability);
+    }
+
+    // A model trained on negatives kept with probability r overestimates p, this undoes it
+    fn recalibrate(&self, prediction_probability: f32) -> f32 {
+        if self.negative_sampling_rate == 1.0 {
+            return prediction_probability;
+        }
+        prediction_probability
+            / (prediction_probability
+                + (1.0 - prediction_probability) / self.negative_sampling_rate)
     }
 
     // Yeah, this is weird. I just didn't want to break the format compatibility at this point
@@ -434,6 +521,116 @@ impl Regressor {
         Ok(())
     }
 
+    pub fn get_blocks_serialized_len(&self) -> Vec<usize> {
+        self.blocks_boxes
+            .iter()
+            .map(|block| block.get_serialized_len())
+            .collect()
+    }
+
+    pub fn scale_weights(&mut self, scale: f32) {
+        for block in &mut self.blocks_boxes {
+            block.scale_weights(scale);
+        }
+    }
+
+    pub fn add_weights_from(
+        &mut self,
+        other: &mut Regressor,
+        scale: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        assert!(!self.immutable && !other.immutable);
+        for (block, other_block) in self
+            .blocks_boxes
+            .iter_mut()
+            .zip(other.blocks_boxes.iter_mut())
+        {
+            block.add_weights_from(other_block, scale)?;
+        }
+        Ok(())
+    }
+
+    pub fn prune_weights(
+        &mut self,
+        mi: &model_instance::ModelInstance,
+        lr_threshold: f32,
+    ) -> Vec<PrunedWeights> {
+        assert!(self.immutable);
+        // Untouched weights are recognized by comparing them to initialization
+        let mut mi_initial = mi.clone();
+        mi_initial.weights_quantization = model_instance::WeightsQuantization::None;
+        mi_initial.weights_page_aligned = false;
+        mi_initial.weights_sparse = false;
+        let mut initial = Regressor::new(&mi_initial);
+        let mut pruned = Vec::new();
+        for (block, initial_block) in self
+            .blocks_boxes
+            .iter_mut()
+            .zip(initial.blocks_boxes.iter_mut())
+        {
+            pruned.extend(block.prune_weights(initial_block, lr_threshold));
+        }
+        pruned
+    }
+
+    pub fn quantize_weights(&mut self, quantization: model_instance::WeightsQuantization) {
+        assert!(self.immutable);
+        for block in &mut self.blocks_boxes {
+            block.quantize_weights(quantization);
+        }
+    }
+
+    // Page aligned layout is the same as the regular one, except that weights of each block start
+    // at a page boundary of the file, so they can be memory-mapped
+    pub fn write_weights_to_buf_page_aligned<W: io::Write + io::Seek>(
+        &self,
+        output_bufwriter: &mut W,
+    ) -> Result<(), Box<dyn Error>> {
+        let length = self
+            .blocks_boxes
+            .iter()
+            .map(|block| block.get_serialized_len())
+            .sum::<usize>() as u64;
+        output_bufwriter.write_u64::<LittleEndian>(length as u64)?;
+
+        for v in &self.blocks_boxes {
+            let position = output_bufwriter.stream_position()? as usize;
+            let padding = align_to_page(position) - position;
+            output_bufwriter.write_all(&vec![0; padding])?;
+            v.write_weights_to_buf(output_bufwriter)?;
+        }
+        Ok(())
+    }
+
+    // offset is where write_weights_to_buf_page_aligned() started writing in the mapped file
+    pub fn into_immutable_regressor_from_mmap(
+        &mut self,
+        rg: &mut Regressor,
+        mmap: &Arc<Mmap>,
+        offset: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut offset = offset;
+        let len = (&mmap[offset..]).read_u64::<LittleEndian>()?;
+        offset += 8;
+        let expected_length = self
+            .blocks_boxes
+            .iter()
+            .map(|bb| bb.get_serialized_len())
+            .sum::<usize>() as u64;
+        if len != expected_length {
+            return Err(format!(
+                "Lenghts of weights array in regressor file differ: got {}, expected {}",
+                len, expected_length
+            ))?;
+        }
+        for (i, v) in &mut self.blocks_boxes.iter().enumerate() {
+            offset = align_to_page(offset);
+            v.read_weights_from_mmap_into_forward_only(mmap, &mut offset, &mut rg.blocks_boxes[i])?;
+        }
+        rg.memory_mapped = true;
+        Ok(())
+    }
+
     // Create immutable regressor from current regressor
     pub fn immutable_regressor(
         &mut self,
@@ -467,6 +664,8 @@ mod tests {
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: v,
             ffm_buffer: Vec::new(),
             ffm_fields_count: 0,
@@ -803,4 +1002,36 @@ mod tests {
         assert_eq!(re.learn(&fb_instance, &mut pb, true), 0.49375027);
         assert_eq!(re.learn(&fb_instance, &mut pb, true), 0.4875807);
     }
+
+    #[test]
+    fn test_negative_sampling_recalibration() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        let mut re_unsampled = Regressor::new(&mi);
+        mi.negative_sampling_rate = 0.25;
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let fb_instance = lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+        // p / (p + (1 - p) / r) of the empty model's 0.5
+        assert_eq!(re.learn(&fb_instance, &mut pb, true), 0.2);
+        // Learning uses uncalibrated predictions, so only the returned predictions differ
+        re_unsampled.learn(&fb_instance, &mut pb, true);
+        let p = re_unsampled.predict(&fb_instance, &mut pb);
+        assert!(p < 0.5);
+        assert_eq!(
+            re.predict(&fb_instance, &mut pb),
+            p / (p + (1.0 - p) / 0.25)
+        );
+
+        // Kept negatives weighed by 1/r need no recalibration
+        mi.negative_sampling_importance = true;
+        let mut re_importance = Regressor::new(&mi);
+        assert_eq!(re_importance.learn(&fb_instance, &mut pb, true), 0.5);
+    }
 }
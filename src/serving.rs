@@ -372,6 +372,8 @@ C,featureC
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
+            initial_prediction: 0.0,
+            tag: Vec::new(),
             lr_buffer: v1,
             ffm_buffer: v2,
             ffm_fields_count: ffm_fields_count,
@@ -484,4 +486,81 @@ C,featureC
             assert_eq!(str::from_utf8(&x), str::from_utf8(b""));
         }
     }
+
+    // Regressors saved by --convert_inference_regressor can be hot-swapped, unless they are page aligned
+    #[test]
+    fn test_hogwild_converted_regressor() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir
+            .path()
+            .join("test_regressor.fw")
+            .to_str()
+            .unwrap()
+            .to_owned();
+        persistence::save_regressor_to_filename(
+            &regressor_filepath,
+            &mi,
+            &vw,
+            regressor::Regressor::new(&mi),
+        )
+        .unwrap();
+
+        // Converted the same way as --convert_inference_regressor does it
+        let mut converted_filepaths: Vec<String> = Vec::new();
+        for page_aligned in vec![false, true] {
+            let (mut mi2, vw2, re2) =
+                persistence::new_regressor_from_filename(&regressor_filepath, true, None).unwrap();
+            mi2.optimizer = model_instance::Optimizer::SGD;
+            mi2.weights_page_aligned = page_aligned;
+            let converted_filepath = dir
+                .path()
+                .join(format!("test_regressor_converted_{}.fw", page_aligned))
+                .to_str()
+                .unwrap()
+                .to_owned();
+            persistence::save_regressor_to_filename(&converted_filepath, &mi2, &vw2, re2).unwrap();
+            converted_filepaths.push(converted_filepath);
+        }
+
+        let (mi2, vw2, re2) =
+            persistence::new_regressor_from_filename(&converted_filepaths[0], true, None).unwrap();
+        let re_fixed = BoxedRegressorTrait::new(Box::new(re2));
+        let pb = re_fixed.new_portbuffer();
+        let mut newt = WorkerThread {
+            id: 1,
+            fbt: feature_buffer::FeatureBufferTranslator::new(&mi2),
+            pa: parser::VowpalParser::new(&vw2),
+            re_fixed: re_fixed,
+            pb,
+        };
+
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream
+            .push_bytes_to_read(&format!("hogwild_load {}", &converted_filepaths[0]).as_bytes());
+        assert_eq!(
+            ConnectionEnd::EndOfStream,
+            newt.handle_connection(&mut reader, &mut writer)
+        );
+        let x = mocked_stream.pop_bytes_written();
+        assert_eq!(
+            str::from_utf8(&x),
+            str::from_utf8(b"hogwild_load success\n")
+        );
+
+        // Page aligned weights are memory-mapped when loaded, they can not be swapped in place
+        mocked_stream
+            .push_bytes_to_read(&format!("hogwild_load {}", &converted_filepaths[1]).as_bytes());
+        assert_eq!(
+            ConnectionEnd::StreamWriteError,
+            newt.handle_connection(&mut reader, &mut writer)
+        );
+    }
 }
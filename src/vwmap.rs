@@ -1,3 +1,4 @@
+use crate::feature_transform_string;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
@@ -32,6 +33,7 @@ pub struct VwNamespaceMap {
     pub map_verbose_to_namespace_descriptor: HashMap<std::string::String, NamespaceDescriptor>,
     pub map_vwname_to_namespace_descriptor: HashMap<Vec<u8>, NamespaceDescriptor>,
     pub map_vwname_to_name: HashMap<Vec<u8>, std::string::String>,
+    pub map_column_to_namespace_descriptor: HashMap<std::string::String, NamespaceDescriptor>, // columns of csv/tsv input
     pub vw_source: VwNamespaceMapSource, // this is the source from which VwNamespaceMap can be constructed - for persistence
 }
 
@@ -42,14 +44,31 @@ pub struct VwNamespaceMapEntry {
     namespace_verbose: std::string::String,
     namespace_index: u16,
     namespace_format: NamespaceFormat,
+    // column of csv/tsv input, when it differs from namespace_verbose
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace_column: Option<std::string::String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct VwNamespaceMapSource {
     pub namespace_skip_prefix: u32,
     pub entries: Vec<VwNamespaceMapEntry>,
+    // columns of csv/tsv input holding the label (default: "label"), example importance, initial prediction and tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_column: Option<std::string::String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub importance_column: Option<std::string::String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial_prediction_column: Option<std::string::String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_column: Option<std::string::String>,
+    // namespaces made by parsers from feature names of other namespaces, they come after the namespaces of the input
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub string_transforms: Vec<feature_transform_string::StringTransform>,
 }
 
+pub const DEFAULT_LABEL_COLUMN: &str = "label";
+
 impl VwNamespaceMap {
     pub fn new_from_source(
         vw_source: VwNamespaceMapSource,
@@ -59,6 +78,7 @@ impl VwNamespaceMap {
             map_verbose_to_namespace_descriptor: HashMap::new(),
             map_vwname_to_namespace_descriptor: HashMap::new(),
             map_vwname_to_name: HashMap::new(),
+            map_column_to_namespace_descriptor: HashMap::new(),
             vw_source: vw_source,
         };
 
@@ -79,15 +99,86 @@ impl VwNamespaceMap {
                 .insert(vwname_str.as_bytes().to_vec(), namespace_descriptor.clone());
             vw.map_verbose_to_namespace_descriptor
                 .insert(String::from(name_str), namespace_descriptor.clone());
+            let column_str = vw_entry.namespace_column.as_ref().unwrap_or(name_str);
+            if vw
+                .map_column_to_namespace_descriptor
+                .insert(String::from(column_str), namespace_descriptor.clone())
+                .is_some()
+            {
+                return Err(format!(
+                    "Column \"{}\" is mapped to more than one namespace in vw_namespace_map.csv",
+                    column_str
+                ))?;
+            }
 
             if vw_entry.namespace_index as usize > vw.num_namespaces {
                 vw.num_namespaces = vw_entry.namespace_index as usize;
             }
         }
         vw.num_namespaces += 1;
+
+        for string_transform in &vw.vw_source.string_transforms {
+            let from_namespace_descriptor = match vw
+                .map_verbose_to_namespace_descriptor
+                .get(&string_transform.from_namespace_verbose)
+            {
+                Some(namespace_descriptor)
+                    if (namespace_descriptor.namespace_index as usize)
+                        < vw.vw_source.entries.len() =>
+                {
+                    namespace_descriptor
+                }
+                _ => {
+                    return Err(format!(
+                        "From namespace of string transform {} is not a namespace of the input: {}",
+                        string_transform.to_namespace_verbose,
+                        string_transform.from_namespace_verbose
+                    ))?
+                }
+            };
+            if from_namespace_descriptor.namespace_format != NamespaceFormat::Categorical {
+                return Err(format!(
+                    "String transforms can only be applied to categorical namespaces, {} is not",
+                    string_transform.from_namespace_verbose
+                ))?;
+            }
+            let namespace_descriptor = NamespaceDescriptor {
+                namespace_index: vw.num_namespaces as u16,
+                namespace_type: NamespaceType::Primitive,
+                namespace_format: NamespaceFormat::Categorical,
+            };
+            if vw
+                .map_verbose_to_namespace_descriptor
+                .insert(
+                    string_transform.to_namespace_verbose.clone(),
+                    namespace_descriptor,
+                )
+                .is_some()
+            {
+                return Err(format!(
+                    "To namespace of string transform {} already exists",
+                    string_transform.to_namespace_verbose
+                ))?;
+            }
+            vw.num_namespaces += 1;
+        }
         Ok(vw)
     }
 
+    // Adds string transforms among the --transform definitions, the rest are namespace transforms of the model
+    pub fn with_string_transforms(
+        self,
+        transform_definitions: &[&str],
+    ) -> Result<VwNamespaceMap, Box<dyn Error>> {
+        let mut vw_source = self.vw_source;
+        for s in transform_definitions {
+            if let Some(string_transform) = feature_transform_string::parse_string_transform(s)? {
+                vw_source.string_transforms.push(string_transform);
+            }
+        }
+        VwNamespaceMap::new_from_source(vw_source)
+    }
+
     pub fn new_from_csv_filepath(path: PathBuf) -> Result<VwNamespaceMap, Box<dyn Error>> {
         let mut input_bufreader = fs::File::open(&path).expect(
             &format!(
@@ -109,6 +200,11 @@ impl VwNamespaceMap {
         let mut vw_source = VwNamespaceMapSource {
             entries: vec![],
             namespace_skip_prefix: 0,
+            label_column: None,
+            importance_column: None,
+            initial_prediction_column: None,
+            tag_column: None,
+            string_transforms: Vec::new(),
         };
         for (i, record_w) in rdr.records().enumerate() {
             let record = record_w?;
@@ -128,6 +224,22 @@ impl VwNamespaceMap {
                 vw_source.namespace_skip_prefix = namespace_skip_prefix;
                 continue;
             }
+            if vwname_str == "_label_column" {
+                vw_source.label_column = Some(record[1].to_string());
+                continue;
+            }
+            if vwname_str == "_importance_column" {
+                vw_source.importance_column = Some(record[1].to_string());
+                continue;
+            }
+            if vwname_str == "_initial_prediction_column" {
+                vw_source.initial_prediction_column = Some(record[1].to_string());
+                continue;
+            }
+            if vwname_str == "_tag_column" {
+                vw_source.tag_column = Some(record[1].to_string());
+                continue;
+            }
 
             let name_str = &record[1];
             let namespace_format = match &record.get(2) {
@@ -140,8 +252,12 @@ impl VwNamespaceMap {
             vw_source.entries.push(VwNamespaceMapEntry {
                 namespace_vwname: vwname_str.to_string(),
                 namespace_verbose: name_str.to_string(),
-                namespace_index: i as u16,
+                namespace_index: vw_source.entries.len() as u16,
                 namespace_format: namespace_format,
+                namespace_column: match record.get(3) {
+                    Some("") | None => None,
+                    Some(column) => Some(column.to_string()),
+                },
             });
         }
 
@@ -170,7 +286,8 @@ C,featureC
                 namespace_vwname: "A".to_string(),
                 namespace_verbose: "featureA".to_string(),
                 namespace_index: 0,
-                namespace_format: NamespaceFormat::Categorical
+                namespace_format: NamespaceFormat::Categorical,
+                namespace_column: None,
             }
         );
 
@@ -180,7 +297,8 @@ C,featureC
                 namespace_vwname: "B".to_string(),
                 namespace_verbose: "featureB".to_string(),
                 namespace_index: 1,
-                namespace_format: NamespaceFormat::Categorical
+                namespace_format: NamespaceFormat::Categorical,
+                namespace_column: None,
             }
         );
 
@@ -190,7 +308,8 @@ C,featureC
                 namespace_vwname: "C".to_string(),
                 namespace_verbose: "featureC".to_string(),
                 namespace_index: 2,
-                namespace_format: NamespaceFormat::Categorical
+                namespace_format: NamespaceFormat::Categorical,
+                namespace_column: None,
             }
         );
     }
@@ -206,7 +325,8 @@ C,featureC
                     namespace_vwname: "A".to_string(),
                     namespace_verbose: "featureA".to_string(),
                     namespace_index: 0,
-                    namespace_format: NamespaceFormat::F32
+                    namespace_format: NamespaceFormat::F32,
+                    namespace_column: None,
                 }
             );
             assert_eq!(vw.vw_source.namespace_skip_prefix, 2);
@@ -218,4 +338,41 @@ C,featureC
             assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Unknown type used for the feature in vw_namespace_map.csv: \\\"blah\\\". Only \\\"f32\\\" is possible.\" })");
         }
     }
+
+    #[test]
+    fn test_columns() {
+        let vw_map_string = "_label_column,click\nA,featureA\nB,featureB,f32,price\n";
+        let vw = VwNamespaceMap::new(vw_map_string).unwrap();
+        assert_eq!(vw.vw_source.label_column, Some("click".to_string()));
+        assert_eq!(vw.vw_source.importance_column, None);
+        assert_eq!(vw.vw_source.entries[0].namespace_index, 0);
+        assert_eq!(
+            vw.map_column_to_namespace_descriptor["featureA"].namespace_index,
+            0
+        );
+        assert_eq!(
+            vw.map_column_to_namespace_descriptor["price"].namespace_index,
+            1
+        );
+        assert!(!vw
+            .map_column_to_namespace_descriptor
+            .contains_key("featureB"));
+
+        let result = VwNamespaceMap::new("A,featureA\nB,featureB,,featureA\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_special_rows_keep_namespace_indices_contiguous() {
+        let vw_map_string = "_namespace_skip_prefix,1\n_label_column,click\nA,featureA\n_tag_column,id\nB,featureB\nC,featureC,f32\n";
+        let vw = VwNamespaceMap::new(vw_map_string).unwrap();
+        assert_eq!(vw.num_namespaces, 3);
+        for (i, entry) in vw.vw_source.entries.iter().enumerate() {
+            assert_eq!(entry.namespace_index, i as u16);
+        }
+        assert_eq!(
+            vw.map_verbose_to_namespace_descriptor["featureC"].namespace_index,
+            2
+        );
+    }
 }